@@ -0,0 +1,74 @@
+use http::{header, HeaderMap};
+
+/// Returns true if the `Accept-Encoding` headers accept gzip-encoded content.
+///
+/// Codings are matched case-insensitively, and a coding with a quality value
+/// of zero (e.g. `gzip;q=0`) is not acceptable. As described in RFC 7231
+/// §5.3.4, the `*` coding accepts gzip unless gzip is listed explicitly.
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    let mut gzip = None;
+    let mut any = None;
+
+    let codings = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for coding in codings {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or("");
+        let q = params
+            .filter(|p| p.starts_with("q=") || p.starts_with("Q="))
+            .filter_map(|p| p[2..].parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case("gzip") {
+            gzip = Some(gzip.map_or(q, |g: f32| g.max(q)));
+        } else if name == "*" {
+            any = Some(any.map_or(q, |a: f32| a.max(q)));
+        }
+    }
+
+    gzip.or(any).map(|q| q > 0.0).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::HeaderValue;
+
+    use super::*;
+
+    fn accepts(values: &[&'static str]) -> bool {
+        let mut headers = HeaderMap::new();
+        for v in values {
+            headers.append(header::ACCEPT_ENCODING, HeaderValue::from_static(v));
+        }
+        accepts_gzip(&headers)
+    }
+
+    #[test]
+    fn gzip_is_accepted() {
+        assert!(accepts(&["gzip"]));
+        assert!(accepts(&["GZIP"]));
+        assert!(accepts(&["deflate, gzip;q=0.8"]));
+        assert!(accepts(&["deflate", "gzip"]));
+    }
+
+    #[test]
+    fn gzip_is_not_accepted() {
+        assert!(!accepts(&[]));
+        assert!(!accepts(&["deflate"]));
+        assert!(!accepts(&["gzip;q=0"]));
+        assert!(!accepts(&["deflate, gzip; q=0.0"]));
+    }
+
+    #[test]
+    fn wildcard_accepts_gzip_unless_listed() {
+        assert!(accepts(&["*"]));
+        assert!(accepts(&["deflate, *;q=0.5"]));
+        assert!(!accepts(&["*;q=0"]));
+        assert!(!accepts(&["gzip;q=0, *"]));
+        assert!(accepts(&["gzip, *;q=0"]));
+    }
+}
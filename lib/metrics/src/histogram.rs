@@ -218,6 +218,19 @@ impl<V: Into<u64>> FmtMetric for Histogram<V> {
     }
 }
 
+// ===== impl Bounds =====
+
+impl Bounds {
+    /// Leaks `buckets` so that they may bound histograms for the remainder of
+    /// the process's lifetime.
+    ///
+    /// This is intended for bounds that are configured once, at startup.
+    pub fn leak(buckets: Vec<Bucket>) -> &'static Self {
+        let buckets: &'static [Bucket] = Box::leak(buckets.into_boxed_slice());
+        Box::leak(Box::new(Bounds(buckets)))
+    }
+}
+
 // ===== impl Key =====
 
 impl<A: fmt::Display, B: fmt::Display> fmt::Display for Key<A, B> {
@@ -8,9 +8,12 @@ extern crate hyper;
 #[macro_use]
 extern crate log;
 #[cfg(test)]
+extern crate flate2;
+#[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
 
+mod accept_encoding;
 mod counter;
 mod gauge;
 mod histogram;
@@ -19,9 +22,10 @@ mod prom;
 mod scopes;
 mod serve;
 
+pub use self::accept_encoding::accepts_gzip;
 pub use self::counter::Counter;
 pub use self::gauge::Gauge;
-pub use self::histogram::Histogram;
+pub use self::histogram::{Bounds, Bucket, Histogram};
 pub use self::prom::{FmtMetrics, FmtLabels, FmtMetric, Metric};
 pub use self::scopes::Scopes;
 pub use self::serve::Serve;
@@ -1,7 +1,12 @@
 use std::fmt;
 use std::marker::{PhantomData, Sized};
 
+const TOTAL_SUFFIX: &str = "_total";
+
 /// Writes a block of metrics in prometheus-formatted output.
+///
+/// When the formatter's alternate flag is set (i.e. `{:#}`), help messages are
+/// written in the OpenMetrics text format instead.
 pub trait FmtMetrics {
     fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result;
 
@@ -19,6 +24,8 @@ pub trait FmtMetrics {
 }
 
 /// Adapts `FmtMetrics` to `fmt::Display`.
+///
+/// Formatting with `{:#}` renders OpenMetrics text, terminated by `# EOF`.
 pub struct DisplayMetrics<F>(F);
 
 #[derive(Clone, Debug)]
@@ -26,7 +33,12 @@ pub struct AndThen<A, B>(A, B);
 
 impl<F: FmtMetrics> fmt::Display for DisplayMetrics<F> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.0.fmt_metrics(f)
+        self.0.fmt_metrics(f)?;
+        if f.alternate() {
+            writeln!(f, "# EOF")?;
+        }
+
+        Ok(())
     }
 }
 
@@ -76,11 +88,34 @@ impl<'a, M: FmtMetric> Metric<'a, M> {
 
     /// Formats help messages for this metric.
     pub fn fmt_help(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "# HELP {} {}", self.name, self.help)?;
-        writeln!(f, "# TYPE {} {}", self.name, M::KIND)?;
+        let name = if f.alternate() { self.family() } else { self.name };
+        writeln!(f, "# HELP {} {}", name, self.help)?;
+        writeln!(f, "# TYPE {} {}", name, M::KIND)?;
         Ok(())
     }
 
+    /// The name of this metric's OpenMetrics family.
+    ///
+    /// OpenMetrics requires that counter samples are suffixed by `_total` and
+    /// that their family is named without it.
+    fn family(&self) -> &'a str {
+        if M::KIND != "counter" {
+            return self.name;
+        }
+
+        debug_assert!(
+            self.name.ends_with(TOTAL_SUFFIX),
+            "counter {} must end with {}",
+            self.name,
+            TOTAL_SUFFIX
+        );
+        if self.name.ends_with(TOTAL_SUFFIX) {
+            &self.name[..self.name.len() - TOTAL_SUFFIX.len()]
+        } else {
+            self.name
+        }
+    }
+
     /// Formats a single metric without labels.
     pub fn fmt_metric(&self, f: &mut fmt::Formatter, metric: M) -> fmt::Result {
         metric.fmt_metric(f, self.name)
@@ -164,3 +199,55 @@ impl<A: FmtMetrics, B: FmtMetrics> FmtMetrics for AndThen<A, B> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Bounds, Bucket, Counter, Gauge, Histogram};
+
+    static BOUNDS: &'static Bounds = &Bounds(&[Bucket::Le(10), Bucket::Le(100), Bucket::Inf]);
+
+    struct Sample;
+
+    impl FmtMetrics for Sample {
+        fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let requests = Metric::<Counter>::new("requests_total", "Total requests.");
+            requests.fmt_help(f)?;
+            requests.fmt_metric(f, Counter::from(3))?;
+
+            let open = Metric::<Gauge>::new("open_connections", "Open connections.");
+            open.fmt_help(f)?;
+            open.fmt_metric(f, Gauge::from(2))?;
+
+            let latency = Metric::<Histogram<u64>>::new("latency_ms", "Request latency.");
+            let mut h = Histogram::new(BOUNDS);
+            h.add(5u64);
+            h.add(50u64);
+            latency.fmt_help(f)?;
+            latency.fmt_metric(f, h)
+        }
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let text = format!("{}", Sample.as_display());
+        assert!(text.contains("# TYPE requests_total counter\n"), "{}", text);
+        assert!(text.contains("\nrequests_total 3\n"), "{}", text);
+        assert!(text.contains("# TYPE latency_ms histogram\n"), "{}", text);
+        assert!(!text.contains("# EOF"), "{}", text);
+    }
+
+    #[test]
+    fn openmetrics_text() {
+        let text = format!("{:#}", Sample.as_display());
+        assert!(text.contains("# HELP requests Total requests.\n"), "{}", text);
+        assert!(text.contains("# TYPE requests counter\n"), "{}", text);
+        assert!(text.contains("\nrequests_total 3\n"), "{}", text);
+        assert!(text.contains("# TYPE open_connections gauge\n"), "{}", text);
+        assert!(text.contains("# TYPE latency_ms histogram\n"), "{}", text);
+        assert!(text.contains("\nlatency_ms_bucket{le=\"+Inf\"} 2\n"), "{}", text);
+        assert!(text.contains("\nlatency_ms_count 2\n"), "{}", text);
+        assert!(text.ends_with("\n# EOF\n"), "{}", text);
+        assert_eq!(text.matches("# EOF").count(), 1, "{}", text);
+    }
+}
@@ -12,7 +12,10 @@ use std::error::Error;
 use std::fmt;
 use std::io::{self, Write};
 
-use super::FmtMetrics;
+use super::{accepts_gzip, FmtMetrics};
+
+const TEXT_CONTENT_TYPE: &str = "text/plain";
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
 
 /// Serve Prometheues metrics.
 #[derive(Debug, Clone)]
@@ -35,14 +38,20 @@ impl<M: FmtMetrics> Serve<M> {
         }
     }
 
-    fn is_gzip<B>(req: &Request<B>) -> bool {
+    /// Returns true if the request accepts the OpenMetrics text format.
+    fn is_openmetrics<B>(req: &Request<B>) -> bool {
         req.headers()
-            .get_all(header::ACCEPT_ENCODING).iter()
-            .any(|value| {
-                value.to_str().ok()
-                    .map(|value| value.contains("gzip"))
-                    .unwrap_or(false)
-            })
+            .get_all(header::ACCEPT).iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.contains("application/openmetrics-text"))
+    }
+
+    fn write_metrics<W: Write>(&self, w: &mut W, openmetrics: bool) -> io::Result<()> {
+        if openmetrics {
+            write!(w, "{:#}", self.metrics.as_display())
+        } else {
+            write!(w, "{}", self.metrics.as_display())
+        }
     }
 }
 
@@ -61,26 +70,33 @@ impl<M: FmtMetrics> Service for Serve<M> {
             return future::ok(rsp);
         }
 
-        let resp = if Self::is_gzip(&req) {
+        let openmetrics = Self::is_openmetrics(&req);
+        let content_type = if openmetrics {
+            OPENMETRICS_CONTENT_TYPE
+        } else {
+            TEXT_CONTENT_TYPE
+        };
+
+        let resp = if accepts_gzip(req.headers()) {
             trace!("gzipping metrics");
             let mut writer = GzEncoder::new(Vec::<u8>::new(), CompressionOptions::fast());
-            write!(&mut writer, "{}", self.metrics.as_display())
+            self.write_metrics(&mut writer, openmetrics)
                 .and_then(|_| writer.finish())
                 .map_err(ServeError::from)
                 .and_then(|body| {
                     Response::builder()
                         .header(header::CONTENT_ENCODING, "gzip")
-                        .header(header::CONTENT_TYPE, "text/plain")
+                        .header(header::CONTENT_TYPE, content_type)
                         .body(Body::from(body))
                         .map_err(ServeError::from)
                 })
         } else {
             let mut writer = Vec::<u8>::new();
-            write!(&mut writer, "{}", self.metrics.as_display())
+            self.write_metrics(&mut writer, openmetrics)
                 .map_err(ServeError::from)
                 .and_then(|_| {
                     Response::builder()
-                        .header(header::CONTENT_TYPE, "text/plain")
+                        .header(header::CONTENT_TYPE, content_type)
                         .body(Body::from(writer))
                         .map_err(ServeError::from)
                 })
@@ -135,3 +151,87 @@ impl Error for ServeError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::GzDecoder;
+    use futures::{Future, Stream};
+    use std::io::Read;
+
+    use super::*;
+
+    struct Fixed;
+
+    impl FmtMetrics for Fixed {
+        fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            writeln!(f, "# HELP requests_total Total requests.")?;
+            writeln!(f, "# TYPE requests_total counter")?;
+            writeln!(f, "requests_total{{direction=\"inbound\"}} 42")
+        }
+    }
+
+    const EXPECTED: &str = "\
+        # HELP requests_total Total requests.\n\
+        # TYPE requests_total counter\n\
+        requests_total{direction=\"inbound\"} 42\n";
+
+    fn get(accept_encoding: Option<&'static str>) -> Response<Body> {
+        get_accept(None, accept_encoding)
+    }
+
+    fn get_accept(accept: Option<&'static str>, accept_encoding: Option<&'static str>) -> Response<Body> {
+        let mut req = Request::get("/metrics");
+        if let Some(accept) = accept {
+            req.header(header::ACCEPT, accept);
+        }
+        if let Some(enc) = accept_encoding {
+            req.header(header::ACCEPT_ENCODING, enc);
+        }
+        Serve::new(Fixed)
+            .call(req.body(Body::empty()).unwrap())
+            .wait()
+            .expect("response")
+    }
+
+    fn body(rsp: Response<Body>) -> Vec<u8> {
+        rsp.into_body().concat2().wait().expect("body").to_vec()
+    }
+
+    #[test]
+    fn gzip_when_accepted() {
+        let rsp = get(Some("deflate, gzip;q=0.8"));
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert_eq!(rsp.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let mut text = String::new();
+        GzDecoder::new(&body(rsp)[..])
+            .read_to_string(&mut text)
+            .expect("body must be gzipped");
+        assert_eq!(text, EXPECTED);
+    }
+
+    #[test]
+    fn plain_text_otherwise() {
+        for enc in &[None, Some("deflate"), Some("gzip;q=0")] {
+            let rsp = get(*enc);
+            assert_eq!(rsp.status(), StatusCode::OK);
+            assert!(rsp.headers().get(header::CONTENT_ENCODING).is_none(), "{:?}", enc);
+            assert_eq!(body(rsp), EXPECTED.as_bytes(), "{:?}", enc);
+        }
+    }
+
+    #[test]
+    fn openmetrics_when_accepted() {
+        let accept = "application/openmetrics-text; version=1.0.0,text/plain;q=0.5";
+        let rsp = get_accept(Some(accept), None);
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert_eq!(rsp.headers()[header::CONTENT_TYPE], OPENMETRICS_CONTENT_TYPE);
+
+        let text = String::from_utf8(body(rsp)).unwrap();
+        assert!(text.ends_with("\n# EOF\n"), "{}", text);
+
+        let rsp = get_accept(Some("text/plain"), None);
+        assert_eq!(rsp.headers()[header::CONTENT_TYPE], TEXT_CONTENT_TYPE);
+        assert_eq!(body(rsp), EXPECTED.as_bytes());
+    }
+}
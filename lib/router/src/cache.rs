@@ -1,5 +1,6 @@
 use indexmap::IndexMap;
 use std::{hash::Hash, ops::{Deref, DerefMut}, time::{Duration, Instant}};
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
 
 // Reexported so IndexMap isn't exposed.
 pub use indexmap::Equivalent;
@@ -28,10 +29,22 @@ pub struct Cache<K: Hash + Eq, V, N: Now = ()> {
     capacity: usize,
     max_idle_age: Duration,
 
+    /// Publishes the number of stored values as they are stored and evicted.
+    occupancy: Occupancy,
+
     /// The time source.
     now: N,
 }
 
+/// A shared handle that reports how many values a `Cache` holds.
+///
+/// The count is updated as values are stored and as idle values are evicted.
+#[derive(Clone, Debug)]
+pub struct Occupancy {
+    len: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
 /// Provides the current time within the module. Useful for testing.
 pub trait Now {
     fn now(&self) -> Instant;
@@ -60,6 +73,7 @@ pub struct Access<'a, T: 'a, N: Now + 'a = ()> {
 #[derive(Debug)]
 pub struct Reserve<'a, K: Hash + Eq + 'a, V: 'a, N: 'a> {
     vals: &'a mut IndexMap<K, Node<V>>,
+    occupancy: &'a Occupancy,
     now: &'a N,
 }
 
@@ -76,12 +90,18 @@ impl<K: Hash + Eq, V> Cache<K, V, ()> {
             capacity,
             vals: IndexMap::default(),
             max_idle_age,
+            occupancy: Occupancy::new(capacity),
             now: (),
         }
     }
 }
 
 impl<K: Hash + Eq, V, N: Now> Cache<K, V, N> {
+    /// Returns a handle that tracks the number of values in this cache.
+    pub fn occupancy(&self) -> Occupancy {
+        self.occupancy.clone()
+    }
+
     /// Accesses a route.
     ///
     /// A mutable reference to the route is wrapped in the returned `Access` to
@@ -111,6 +131,7 @@ impl<K: Hash + Eq, V, N: Now> Cache<K, V, N> {
                 let age = now - n.last_access();
                 age.as_secs() <= max_age
             });
+            self.occupancy.set(self.vals.len());
 
             if self.vals.len() == self.capacity {
                 return Err(CapacityExhausted {
@@ -121,10 +142,17 @@ impl<K: Hash + Eq, V, N: Now> Cache<K, V, N> {
 
         Ok(Reserve {
             vals: &mut self.vals,
+            occupancy: &self.occupancy,
             now: &self.now,
         })
     }
 
+    /// Drops all values in the cache.
+    pub fn clear(&mut self) {
+        self.vals.clear();
+        self.occupancy.set(0);
+    }
+
     /// Overrides the time source for tests.
     #[cfg(test)]
     fn with_clock<M: Now>(self, now: M) -> Cache<K, V, M> {
@@ -133,6 +161,7 @@ impl<K: Hash + Eq, V, N: Now> Cache<K, V, N> {
             vals: self.vals,
             capacity: self.capacity,
             max_idle_age: self.max_idle_age,
+            occupancy: self.occupancy,
         }
     }
 }
@@ -144,6 +173,37 @@ impl<'a, K: Hash + Eq + 'a, V: 'a, N: Now + 'a> Reserve<'a, K, V, N> {
     pub fn store(self, key: K, val: V) {
         let node = Node::new(val.into(), self.now.now());
         self.vals.insert(key, node);
+        self.occupancy.set(self.vals.len());
+    }
+}
+
+// ===== impl Occupancy =====
+
+impl Occupancy {
+    fn new(capacity: usize) -> Self {
+        Occupancy {
+            len: Arc::new(AtomicUsize::new(0)),
+            capacity,
+        }
+    }
+
+    fn set(&self, len: usize) {
+        self.len.store(len, Ordering::Release);
+    }
+
+    /// The number of values currently stored in the cache.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Indicates whether the cache holds no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The maximum number of values the cache may store.
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 }
 
@@ -290,6 +350,23 @@ mod tests {
         assert!(cache.access(&2).is_some());
     }
 
+    #[test]
+    fn clear() {
+        let mut cache = Cache::<_, MultiplyAndAssign>::new(2, Duration::from_secs(1));
+        let occupancy = cache.occupancy();
+        for n in 1..3 {
+            let r = cache.reserve().expect("reserve");
+            r.store(n, MultiplyAndAssign::default());
+        }
+        assert_eq!(occupancy.len(), 2);
+
+        cache.clear();
+        assert!(cache.access(&1).is_none());
+        assert!(cache.access(&2).is_none());
+        assert!(occupancy.is_empty());
+        assert!(cache.reserve().is_ok());
+    }
+
     #[test]
     fn reserve_does_nothing_when_capacity_exists() {
         let mut cache = Cache::<_, MultiplyAndAssign, _>::new(2, Duration::from_secs(0));
@@ -346,6 +423,33 @@ mod tests {
         assert_eq!(cache.vals.len(), 0);
     }
 
+    #[test]
+    fn occupancy_tracks_stores_and_evictions() {
+        let mut clock = Clock::default();
+        let mut cache = Cache::<_, MultiplyAndAssign, _>::new(2, Duration::from_secs(0))
+            .with_clock(clock.clone());
+        let occupancy = cache.occupancy();
+        assert_eq!(occupancy.capacity(), 2);
+        assert_eq!(occupancy.len(), 0);
+
+        cache
+            .reserve()
+            .expect("capacity")
+            .store(1, MultiplyAndAssign::default());
+        assert_eq!(occupancy.len(), 1);
+
+        cache
+            .reserve()
+            .expect("capacity")
+            .store(2, MultiplyAndAssign::default());
+        assert_eq!(occupancy.len(), 2);
+
+        // Both routes are evicted once they go idle.
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.reserve().is_ok());
+        assert_eq!(occupancy.len(), 0);
+    }
+
     #[test]
     fn last_access() {
         let mut clock = Clock::default();
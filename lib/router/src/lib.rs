@@ -1,11 +1,15 @@
 extern crate futures;
 extern crate indexmap;
+extern crate linkerd2_never as never;
 extern crate linkerd2_stack as stack;
+extern crate tokio_timer;
 extern crate tower_service as svc;
 
-use futures::{Future, Poll};
+use futures::{future, Async, Future, Poll};
+use never::Never;
+use tokio_timer::{clock, Delay};
 
-use std::{error, fmt, mem};
+use std::{cmp, error, fmt, mem};
 use std::hash::Hash;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -13,11 +17,12 @@ use std::time::Duration;
 mod cache;
 
 use self::cache::Cache;
+pub use self::cache::Occupancy;
 
 /// Routes requests based on a configurable `Key`.
 pub struct Router<Req, Rec, Stk>
 where
-    Rec: Recognize<Req>,
+    Rec: RecognizeAsync<Req>,
     Stk: stack::Stack<Rec::Target>,
     Stk::Value: svc::Service<Req>,
 {
@@ -38,6 +43,29 @@ pub trait Recognize<Request> {
     fn recognize(&self, req: &Request) -> Option<Self::Target>;
 }
 
+/// Provides a strategy for routing a Request to a Service when determining
+/// its target may require I/O.
+///
+/// Every `Recognize` is also a `RecognizeAsync` whose targets are determined
+/// immediately. A request whose recognition fails is not recognized.
+pub trait RecognizeAsync<Request> {
+    /// Identifies a Route.
+    type Target: Clone + Eq + Hash;
+
+    type Future: Future<Item = Option<Self::Target>>;
+
+    /// Determines the target for a route to handle the given request.
+    fn recognize(&self, req: &Request) -> Self::Future;
+}
+
+/// Determines whether a route that fails to be built is built again before
+/// the request is failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MakeRetry {
+    max_attempts: usize,
+    backoff: Duration,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Error<T, U> {
     Inner(T),
@@ -46,30 +74,46 @@ pub enum Error<T, U> {
     NotRecognized,
 }
 
-pub struct ResponseFuture<F, E>
+pub struct ResponseFuture<Req, Rec, Stk>
 where
-    F: Future,
+    Rec: RecognizeAsync<Req>,
+    Stk: stack::Stack<Rec::Target>,
+    Stk::Value: svc::Service<Req>,
 {
-    state: State<F, E>,
+    state: State<Req, Rec, Stk>,
 }
 
 struct Inner<Req, Rec, Stk>
 where
-    Rec: Recognize<Req>,
+    Rec: RecognizeAsync<Req>,
     Stk: stack::Stack<Rec::Target>,
     Stk::Value: svc::Service<Req>,
 {
     recognize: Rec,
     make: Stk,
     cache: Mutex<Cache<Rec::Target, Stk::Value>>,
+    occupancy: Occupancy,
+    make_retry: MakeRetry,
 }
 
-enum State<F, E>
+enum State<Req, Rec, Stk>
 where
-    F: Future,
+    Rec: RecognizeAsync<Req>,
+    Stk: stack::Stack<Rec::Target>,
+    Stk::Value: svc::Service<Req>,
 {
-    Inner(F),
-    RouteError(E),
+    /// The request's target is being recognized.
+    Recognize(Rec::Future, Option<Req>, Arc<Inner<Req, Rec, Stk>>),
+    /// The request's route failed to be built and is built again once the
+    /// delay elapses. Holds the number of attempts made so far.
+    Backoff(
+        Delay,
+        Option<(Rec::Target, Req)>,
+        usize,
+        Arc<Inner<Req, Rec, Stk>>,
+    ),
+    Inner(<Stk::Value as svc::Service<Req>>::Future),
+    RouteError(Stk::Error),
     NoCapacity(usize),
     NotRecognized,
     Invalid,
@@ -89,34 +133,138 @@ where
     }
 }
 
+// ===== impl RecognizeAsync =====
+
+impl<Req, R> RecognizeAsync<Req> for R
+where
+    R: Recognize<Req>,
+{
+    type Target = R::Target;
+    type Future = future::FutureResult<Option<R::Target>, Never>;
+
+    fn recognize(&self, req: &Req) -> Self::Future {
+        future::ok(Recognize::recognize(self, req))
+    }
+}
+
+// ===== impl MakeRetry =====
+
+/// Bounds the growth of the backoff, so that it is at most `2^16` times the
+/// initial backoff.
+const MAX_BACKOFF_DOUBLINGS: usize = 16;
+
+impl MakeRetry {
+    /// Fails requests as soon as their route fails to be built.
+    pub fn disabled() -> Self {
+        MakeRetry {
+            max_attempts: 1,
+            backoff: Duration::from_secs(0),
+        }
+    }
+
+    /// Attempts to build each route up to `max_attempts` times before failing
+    /// the request.
+    ///
+    /// The router waits `backoff` before the first retry, doubling the wait
+    /// before each subsequent retry.
+    pub fn new(max_attempts: usize, backoff: Duration) -> Self {
+        MakeRetry {
+            max_attempts,
+            backoff,
+        }
+    }
+
+    /// Returns the time to wait before building a route again, once
+    /// `attempts` attempts have failed, if another attempt may be made.
+    fn backoff(&self, attempts: usize) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+
+        let doublings = cmp::min(attempts.saturating_sub(1), MAX_BACKOFF_DOUBLINGS);
+        Some(self.backoff * (1u32 << doublings))
+    }
+}
+
 // ===== impl Router =====
 
 impl<Req, Rec, Stk> Router<Req, Rec, Stk>
 where
-    Rec: Recognize<Req>,
+    Rec: RecognizeAsync<Req>,
     Stk: stack::Stack<Rec::Target>,
     Stk::Value: svc::Service<Req>,
 {
-    pub fn new(recognize: Rec, make: Stk, capacity: usize, max_idle_age: Duration) -> Self {
+    /// Creates a router that holds up to `capacity` routes.
+    ///
+    /// Routes that fail to be built are built again according to
+    /// `make_retry`.
+    pub fn new(
+        recognize: Rec,
+        make: Stk,
+        capacity: usize,
+        max_idle_age: Duration,
+        make_retry: MakeRetry,
+    ) -> Self {
+        let cache = Cache::new(capacity, max_idle_age);
+        let occupancy = cache.occupancy();
         Router {
             inner: Arc::new(Inner {
                 recognize,
                 make,
-                cache: Mutex::new(Cache::new(capacity, max_idle_age)),
+                cache: Mutex::new(cache),
+                occupancy,
+                make_retry,
             }),
         }
     }
+
+    /// Returns a handle that reports the number of routes held by this router.
+    pub fn occupancy(&self) -> Occupancy {
+        self.inner.occupancy.clone()
+    }
+
+    /// Builds and caches the route for `target`, if one isn't cached, without
+    /// dispatching a request on it.
+    ///
+    /// This allows routes to known targets to be built before they are needed.
+    /// Warmed routes are subject to the router's capacity and idle age like any
+    /// other route.
+    pub fn warm(
+        &self,
+        target: Rec::Target,
+    ) -> Result<(), Error<<Stk::Value as svc::Service<Req>>::Error, Stk::Error>> {
+        let cache = &mut *self.inner.cache.lock().expect("lock router cache");
+        if cache.access(&target).is_some() {
+            return Ok(());
+        }
+
+        let reserve = cache
+            .reserve()
+            .map_err(|cache::CapacityExhausted { capacity }| Error::NoCapacity(capacity))?;
+        let service = self.inner.make.make(&target).map_err(Error::Route)?;
+        reserve.store(target, service);
+
+        Ok(())
+    }
+
+    /// Drops all cached routes, so that subsequent requests are served by
+    /// newly-built routes.
+    ///
+    /// Requests that are already being served by a route are unaffected.
+    pub fn clear(&self) {
+        self.inner.cache.lock().expect("lock router cache").clear();
+    }
 }
 
 impl<Req, Rec, Stk> svc::Service<Req> for Router<Req, Rec, Stk>
 where
-    Rec: Recognize<Req>,
+    Rec: RecognizeAsync<Req>,
     Stk: stack::Stack<Rec::Target>,
     Stk::Value: svc::Service<Req>,
 {
     type Response = <Stk::Value as svc::Service<Req>>::Response;
     type Error = Error<<Stk::Value as svc::Service<Req>>::Error, Stk::Error>;
-    type Future = ResponseFuture<<Stk::Value as svc::Service<Req>>::Future, Stk::Error>;
+    type Future = ResponseFuture<Req, Rec, Stk>;
 
     /// Always ready to serve.
     ///
@@ -131,18 +279,50 @@ where
 
     /// Routes the request through an underlying service.
     ///
-    /// The response fails when the request cannot be routed.
+    /// The request is routed once its target is recognized. The response
+    /// fails when the request cannot be routed.
     fn call(&mut self, request: Req) -> Self::Future {
-        let target = match self.inner.recognize.recognize(&request) {
-            Some(target) => target,
-            None => return ResponseFuture::not_recognized(),
-        };
+        let recognize = RecognizeAsync::recognize(&self.inner.recognize, &request);
+        ResponseFuture {
+            state: State::Recognize(recognize, Some(request), self.inner.clone()),
+        }
+    }
+}
 
-        let cache = &mut *self.inner.cache.lock().expect("lock router cache");
+impl<Req, Rec, Stk> Clone for Router<Req, Rec, Stk>
+where
+    Rec: RecognizeAsync<Req>,
+    Stk: stack::Stack<Rec::Target>,
+    Stk::Value: svc::Service<Req>,
+{
+    fn clone(&self) -> Self {
+        Router { inner: self.inner.clone() }
+    }
+}
+
+// ===== impl Inner =====
+
+impl<Req, Rec, Stk> Inner<Req, Rec, Stk>
+where
+    Rec: RecognizeAsync<Req>,
+    Stk: stack::Stack<Rec::Target>,
+    Stk::Value: svc::Service<Req>,
+{
+    /// Sends the request on the route for `target`, binding a new route if
+    /// one isn't cached.
+    ///
+    /// If a new route cannot be built, the target and request are returned
+    /// with the error, so that the route may be built again.
+    fn route(
+        &self,
+        target: Rec::Target,
+        request: Req,
+    ) -> Result<State<Req, Rec, Stk>, (Stk::Error, Rec::Target, Req)> {
+        let cache = &mut *self.cache.lock().expect("lock router cache");
 
         // First, try to load a cached route for `target`.
         if let Some(mut service) = cache.access(&target) {
-            return ResponseFuture::new(service.call(request));
+            return Ok(State::Inner(service.call(request)));
         }
 
         // Since there wasn't a cached route, ensure that there is capacity for a
@@ -150,74 +330,109 @@ where
         let reserve = match cache.reserve() {
             Ok(r) => r,
             Err(cache::CapacityExhausted { capacity }) => {
-                return ResponseFuture::no_capacity(capacity);
+                return Ok(State::NoCapacity(capacity));
             }
         };
 
         // Bind a new route, send the request on the route, and cache the route.
-        let mut service = match self.inner.make.make(&target) {
+        let mut service = match self.make.make(&target) {
             Ok(svc) => svc,
-            Err(e) => return ResponseFuture { state: State::RouteError(e) },
+            Err(e) => return Err((e, target, request)),
         };
 
         let response = service.call(request);
         reserve.store(target, service);
 
-        ResponseFuture::new(response)
+        Ok(State::Inner(response))
     }
 }
 
-impl<Req, Rec, Stk> Clone for Router<Req, Rec, Stk>
+// ===== impl State =====
+
+impl<Req, Rec, Stk> State<Req, Rec, Stk>
 where
-    Rec: Recognize<Req>,
+    Rec: RecognizeAsync<Req>,
     Stk: stack::Stack<Rec::Target>,
     Stk::Value: svc::Service<Req>,
 {
-    fn clone(&self) -> Self {
-        Router { inner: self.inner.clone() }
+    /// Routes the request, backing off before building the route again if it
+    /// fails to be built and the router's `MakeRetry` permits another attempt.
+    ///
+    /// `attempts` is the number of attempts that have already failed.
+    fn route(
+        inner: &Arc<Inner<Req, Rec, Stk>>,
+        target: Rec::Target,
+        request: Req,
+        attempts: usize,
+    ) -> Self {
+        match inner.route(target, request) {
+            Ok(state) => state,
+            Err((e, target, request)) => {
+                let attempts = attempts + 1;
+                match inner.make_retry.backoff(attempts) {
+                    Some(backoff) => State::Backoff(
+                        Delay::new(clock::now() + backoff),
+                        Some((target, request)),
+                        attempts,
+                        inner.clone(),
+                    ),
+                    None => State::RouteError(e),
+                }
+            }
+        }
     }
 }
 
 // ===== impl ResponseFuture =====
 
-impl<F, E> ResponseFuture<F, E>
+impl<Req, Rec, Stk> Future for ResponseFuture<Req, Rec, Stk>
 where
-    F: Future,
-{
-    fn new(inner: F) -> Self {
-        ResponseFuture { state: State::Inner(inner) }
-    }
-
-    fn not_recognized() -> Self {
-        ResponseFuture { state: State::NotRecognized }
-    }
-
-    fn no_capacity(capacity: usize) -> Self {
-        ResponseFuture { state: State::NoCapacity(capacity) }
-    }
-}
-
-impl<F, E> Future for ResponseFuture<F, E>
-where
-    F: Future,
+    Rec: RecognizeAsync<Req>,
+    Stk: stack::Stack<Rec::Target>,
+    Stk::Value: svc::Service<Req>,
 {
-    type Item = F::Item;
-    type Error = Error<F::Error, E>;
+    type Item = <Stk::Value as svc::Service<Req>>::Response;
+    type Error = Error<<Stk::Value as svc::Service<Req>>::Error, Stk::Error>;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         use self::State::*;
 
-        match self.state {
-            Inner(ref mut fut) => fut.poll().map_err(Error::Inner),
-            RouteError(..) => {
-                match mem::replace(&mut self.state, Invalid) {
-                    RouteError(e) => Err(Error::Route(e)),
-                    _ => unreachable!(),
+        loop {
+            // Once the request's target is recognized (or its route's backoff
+            // elapses), route it.
+            let state = match self.state {
+                Recognize(ref mut recognize, ref mut request, ref inner) => {
+                    match recognize.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(Some(target))) => {
+                            let request = request.take().expect("request must be routed once");
+                            State::route(inner, target, request, 0)
+                        }
+                        // Requests whose recognition fails are not routed.
+                        Ok(Async::Ready(None)) | Err(_) => NotRecognized,
+                    }
                 }
-            }
-            NotRecognized => Err(Error::NotRecognized),
-            NoCapacity(capacity) => Err(Error::NoCapacity(capacity)),
-            Invalid => panic!("response future polled after ready"),
+                Backoff(ref mut delay, ref mut routing, attempts, ref inner) => {
+                    match delay.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        // A timer error is treated as the backoff elapsing.
+                        Ok(Async::Ready(())) | Err(_) => {}
+                    }
+                    let (target, request) = routing.take().expect("request must be routed once");
+                    State::route(inner, target, request, attempts)
+                }
+                Inner(ref mut fut) => return fut.poll().map_err(Error::Inner),
+                RouteError(..) => {
+                    return match mem::replace(&mut self.state, Invalid) {
+                        RouteError(e) => Err(Error::Route(e)),
+                        _ => unreachable!(),
+                    };
+                }
+                NotRecognized => return Err(Error::NotRecognized),
+                NoCapacity(capacity) => return Err(Error::NoCapacity(capacity)),
+                Invalid => panic!("response future polled after ready"),
+            };
+            self.state = state;
         }
     }
 }
@@ -338,11 +553,53 @@ mod test_util {
 
 #[cfg(test)]
 mod tests {
-    use futures::Future;
+    extern crate tokio;
+
+    use futures::{future, task, Async, Future, Poll};
+    use stack::Stack;
+    use std::cell::Cell;
     use std::time::Duration;
     use test_util::*;
     use svc::Service;
-    use super::{Error, Router};
+    use self::tokio::runtime::current_thread::Runtime;
+    use super::{Error, MakeRetry, RecognizeAsync, Router};
+
+    /// Recognizes requests as `Recognize` does, but only after first
+    /// yielding.
+    struct RecognizeLater;
+
+    struct Later {
+        target: Option<usize>,
+        yielded: bool,
+    }
+
+    impl RecognizeAsync<Request> for RecognizeLater {
+        type Target = usize;
+        type Future = Later;
+
+        fn recognize(&self, req: &Request) -> Later {
+            let target = match *req {
+                Request::NotRecognized => None,
+                Request::Recognized(n) => Some(n),
+            };
+            Later { target, yielded: false }
+        }
+    }
+
+    impl Future for Later {
+        type Item = Option<usize>;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Option<usize>, ()> {
+            if !self.yielded {
+                self.yielded = true;
+                task::current().notify();
+                return Ok(Async::NotReady);
+            }
+
+            Ok(Async::Ready(self.target.take()))
+        }
+    }
 
     impl Router<Request, Recognize, Recognize> {
         fn call_ok(&mut self, req: Request) -> usize {
@@ -356,7 +613,13 @@ mod tests {
 
     #[test]
     fn invalid() {
-        let mut router = Router::new(Recognize, Recognize, 1, Duration::from_secs(0));
+        let mut router = Router::new(
+            Recognize,
+            Recognize,
+            1,
+            Duration::from_secs(0),
+            MakeRetry::disabled(),
+        );
 
         let rsp = router.call_err(Request::NotRecognized);
         assert_eq!(rsp, Error::NotRecognized);
@@ -364,7 +627,13 @@ mod tests {
 
     #[test]
     fn cache_limited_by_capacity() {
-        let mut router = Router::new(Recognize, Recognize, 1, Duration::from_secs(1));
+        let mut router = Router::new(
+            Recognize,
+            Recognize,
+            1,
+            Duration::from_secs(1),
+            MakeRetry::disabled(),
+        );
 
         let rsp = router.call_ok(2.into());
         assert_eq!(rsp, 2);
@@ -373,9 +642,209 @@ mod tests {
         assert_eq!(rsp, Error::NoCapacity(1));
     }
 
+    #[test]
+    fn occupancy_raised_by_new_routes() {
+        let mut router = Router::new(
+            Recognize,
+            Recognize,
+            2,
+            Duration::from_secs(1),
+            MakeRetry::disabled(),
+        );
+        let occupancy = router.occupancy();
+        assert_eq!(occupancy.capacity(), 2);
+        assert_eq!(occupancy.len(), 0);
+
+        router.call_ok(2.into());
+        assert_eq!(occupancy.len(), 1);
+
+        // Cached routes don't change the occupancy.
+        router.call_ok(2.into());
+        assert_eq!(occupancy.len(), 1);
+
+        router.call_ok(3.into());
+        assert_eq!(occupancy.len(), 2);
+    }
+
+    #[test]
+    fn async_recognition() {
+        let mut router = Router::new(
+            RecognizeLater,
+            Recognize,
+            1,
+            Duration::from_secs(1),
+            MakeRetry::disabled(),
+        );
+
+        let rsp = router.call(2.into()).wait().expect("should route");
+        assert_eq!(rsp, 2);
+
+        // The route is cached once it is recognized.
+        let rsp = router.call(2.into()).wait().expect("should route");
+        assert_eq!(rsp, 4);
+
+        let rsp = router.call(Request::NotRecognized).wait().expect_err("should not route");
+        assert_eq!(rsp, Error::NotRecognized);
+    }
+
+    /// Counts the services it builds.
+    struct CountMakes(Cell<usize>);
+
+    impl Stack<usize> for CountMakes {
+        type Value = MultiplyAndAssign;
+        type Error = ();
+
+        fn make(&self, n: &usize) -> Result<Self::Value, Self::Error> {
+            self.0.set(self.0.get() + 1);
+            Recognize.make(n)
+        }
+    }
+
+    #[test]
+    fn warmed_routes_are_cached() {
+        let make = CountMakes(Cell::new(0));
+        let mut router = Router::new(
+            Recognize,
+            make,
+            2,
+            Duration::from_secs(1),
+            MakeRetry::disabled(),
+        );
+        let occupancy = router.occupancy();
+
+        router.warm(2).expect("should warm");
+        assert_eq!(occupancy.len(), 1);
+        assert_eq!(router.inner.make.0.get(), 1);
+
+        // Warming a cached route does not rebuild it.
+        router.warm(2).expect("should warm");
+        assert_eq!(router.inner.make.0.get(), 1);
+
+        // Requests are served by the warmed route, which has not yet served
+        // a request.
+        let rsp = router.call(2.into()).wait().expect("should route");
+        assert_eq!(rsp, 2);
+        assert_eq!(occupancy.len(), 1);
+        assert_eq!(router.inner.make.0.get(), 1);
+    }
+
+    #[test]
+    fn warming_honors_capacity() {
+        let router = Router::new(
+            Recognize,
+            Recognize,
+            1,
+            Duration::from_secs(1),
+            MakeRetry::disabled(),
+        );
+
+        router.warm(2).expect("should warm");
+        assert_eq!(router.warm(3), Err(Error::NoCapacity(1)));
+    }
+
+    #[test]
+    fn cleared_routes_are_rebuilt() {
+        let make = CountMakes(Cell::new(0));
+        let mut router = Router::new(
+            Recognize,
+            make,
+            1,
+            Duration::from_secs(1),
+            MakeRetry::disabled(),
+        );
+        let occupancy = router.occupancy();
+
+        assert_eq!(router.call(2.into()).wait().expect("should route"), 2);
+        assert_eq!(router.call(2.into()).wait().expect("should route"), 4);
+        assert_eq!(router.inner.make.0.get(), 1);
+
+        router.clear();
+        assert!(occupancy.is_empty());
+
+        // The route is rebuilt, so its state is reset.
+        assert_eq!(router.call(2.into()).wait().expect("should route"), 2);
+        assert_eq!(router.inner.make.0.get(), 2);
+        assert_eq!(occupancy.len(), 1);
+    }
+
+    /// Fails to build services until it has failed a given number of times.
+    struct FailFirst(Cell<usize>);
+
+    impl Stack<usize> for FailFirst {
+        type Value = MultiplyAndAssign;
+        type Error = ();
+
+        fn make(&self, n: &usize) -> Result<Self::Value, Self::Error> {
+            let failures = self.0.get();
+            if failures > 0 {
+                self.0.set(failures - 1);
+                return Err(());
+            }
+            Recognize.make(n)
+        }
+    }
+
+    fn retrying_router(
+        failures: usize,
+        make_retry: MakeRetry,
+    ) -> Router<Request, Recognize, FailFirst> {
+        let make = FailFirst(Cell::new(failures));
+        Router::new(Recognize, make, 1, Duration::from_secs(1), make_retry)
+    }
+
+    #[test]
+    fn make_failures_are_retried() {
+        let mut rt = Runtime::new().unwrap();
+        let mut router = retrying_router(2, MakeRetry::new(3, Duration::from_millis(1)));
+
+        let rsp = rt.block_on(future::lazy(|| router.call(2.into())));
+        assert_eq!(rsp, Ok(2));
+        assert_eq!(router.occupancy().len(), 1);
+    }
+
+    #[test]
+    fn make_retries_are_bounded_by_attempts() {
+        let mut rt = Runtime::new().unwrap();
+        let mut router = retrying_router(5, MakeRetry::new(3, Duration::from_millis(1)));
+
+        let rsp = rt.block_on(future::lazy(|| router.call(2.into())));
+        assert_eq!(rsp, Err(Error::Route(())));
+        assert_eq!(router.inner.make.0.get(), 2, "only 3 attempts may be made");
+        assert!(router.occupancy().is_empty());
+    }
+
+    #[test]
+    fn make_failures_fail_fast_without_retries() {
+        let mut router = retrying_router(1, MakeRetry::disabled());
+
+        let rsp = router.call(2.into()).wait();
+        assert_eq!(rsp, Err(Error::Route(())));
+
+        // The route is built for a subsequent request.
+        let rsp = router.call(2.into()).wait();
+        assert_eq!(rsp, Ok(2));
+    }
+
+    #[test]
+    fn make_retry_backoff_doubles() {
+        let retry = MakeRetry::new(4, Duration::from_millis(10));
+        assert_eq!(retry.backoff(1), Some(Duration::from_millis(10)));
+        assert_eq!(retry.backoff(2), Some(Duration::from_millis(20)));
+        assert_eq!(retry.backoff(3), Some(Duration::from_millis(40)));
+        assert_eq!(retry.backoff(4), None);
+
+        assert_eq!(MakeRetry::disabled().backoff(1), None);
+    }
+
     #[test]
     fn services_cached() {
-        let mut router = Router::new(Recognize, Recognize, 1, Duration::from_secs(0));
+        let mut router = Router::new(
+            Recognize,
+            Recognize,
+            1,
+            Duration::from_secs(0),
+            MakeRetry::disabled(),
+        );
 
         let rsp = router.call_ok(2.into());
         assert_eq!(rsp, 2);
@@ -42,6 +42,13 @@ where
             Either::B(ref b) => b.make(target).map(Either::B).map_err(Either::B),
         }
     }
+
+    fn describe(&self, target: &T) -> Vec<&'static str> {
+        match self {
+            Either::A(ref a) => a.describe(target),
+            Either::B(ref b) => b.describe(target),
+        }
+    }
 }
 
 impl<A, B, R> svc::Service<R> for Either<A, B>
@@ -12,10 +12,12 @@ pub mod phantom_data;
 pub mod stack_make_service;
 pub mod stack_per_request;
 pub mod watch;
+pub mod when;
 
 pub use self::either::Either;
 pub use self::layer::Layer;
 pub use self::stack_make_service::StackMakeService;
+pub use self::when::when;
 
 /// A composable builder.
 ///
@@ -33,6 +35,15 @@ pub trait Stack<T> {
 
     fn make(&self, target: &T) -> Result<Self::Value, Self::Error>;
 
+    /// Describes the layers that would be used to build a value for `target`,
+    /// from the outermost to the innermost.
+    ///
+    /// Stacks that wrap an inner stack should prepend their own name to the
+    /// inner stack's description. By default, a stack is not described.
+    fn describe(&self, _target: &T) -> Vec<&'static str> {
+        Vec::new()
+    }
+
     /// Wraps this `Stack` with an `L`-typed `Layer` to produce a `Stack<U>`.
     fn push<U, L>(self, layer: L) -> L::Stack
     where
@@ -70,5 +81,72 @@ pub mod shared {
         fn make(&self, _: &T) -> Result<V, Never> {
             Ok(self.0.clone())
         }
+
+        fn describe(&self, _: &T) -> Vec<&'static str> {
+            vec!["shared"]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use never::Never;
+
+    use super::*;
+
+    #[derive(Clone, Debug)]
+    struct AddOne;
+
+    impl map_target::MapTarget<usize> for AddOne {
+        type Target = usize;
+
+        fn map_target(&self, n: &usize) -> usize {
+            n + 1
+        }
+    }
+
+    #[test]
+    fn describe_lists_layers_from_outermost() {
+        let stack = shared::stack(());
+        let stack = phantom_data::layer::<usize, _>().bind(stack);
+        let stack = map_target::layer::<usize, _>(AddOne).bind(stack);
+        let stack = map_err::layer(|e: Never| e).bind(stack);
+
+        assert_eq!(
+            stack.describe(&1),
+            vec!["map_err", "map_target", "phantom_data", "shared"]
+        );
+    }
+
+    #[derive(Clone, Debug)]
+    struct PerRequest(bool);
+
+    impl stack_per_request::ShouldStackPerRequest for PerRequest {
+        fn should_stack_per_request(&self) -> bool {
+            self.0
+        }
+    }
+
+    #[test]
+    fn describe_is_forwarded_through_combinators() {
+        let stack = shared::stack(());
+        let stack = stack_per_request::layer().bind(stack);
+        let stack = when(|t: &PerRequest| t.0, phantom_data::layer::<PerRequest, _>()).bind(stack);
+        let stack = map_err::layer(|e: Never| e).bind(stack);
+
+        assert_eq!(
+            stack.describe(&PerRequest(true)),
+            vec!["map_err", "when", "phantom_data", "stack_per_request", "shared"]
+        );
+        assert_eq!(
+            stack.describe(&PerRequest(false)),
+            vec!["map_err", "when", "stack_per_request", "shared"]
+        );
+
+        let either = Either::<_, shared::Stack<()>>::A(stack);
+        assert_eq!(
+            either.describe(&PerRequest(false)),
+            vec!["map_err", "when", "stack_per_request", "shared"]
+        );
     }
 }
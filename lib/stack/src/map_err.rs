@@ -59,6 +59,12 @@ where
     fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
         self.inner.make(target).map_err(|e| self.map_err.map_err(e))
     }
+
+    fn describe(&self, target: &T) -> Vec<&'static str> {
+        let mut layers = vec!["map_err"];
+        layers.extend(self.inner.describe(target));
+        layers
+    }
 }
 
 impl<F, I, O> MapErr<I> for F
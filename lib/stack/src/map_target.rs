@@ -49,6 +49,12 @@ where
     fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
         self.inner.make(&self.map_target.map_target(target))
     }
+
+    fn describe(&self, target: &T) -> Vec<&'static str> {
+        let mut layers = vec!["map_target"];
+        layers.extend(self.inner.describe(&self.map_target.map_target(target)));
+        layers
+    }
 }
 
 impl<F, T, U> MapTarget<T> for F
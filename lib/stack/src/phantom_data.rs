@@ -36,4 +36,10 @@ impl<T, M: super::Stack<T>> super::Stack<T> for Stack<T, M> {
     fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
         self.inner.make(target)
     }
+
+    fn describe(&self, target: &T) -> Vec<&'static str> {
+        let mut layers = vec!["phantom_data"];
+        layers.extend(self.inner.describe(target));
+        layers
+    }
 }
@@ -86,6 +86,12 @@ where
             Ok(super::Either::B(inner))
         }
     }
+
+    fn describe(&self, target: &T) -> Vec<&'static str> {
+        let mut layers = vec!["stack_per_request"];
+        layers.extend(self.inner.describe(target));
+        layers
+    }
 }
 
 // === Service ===
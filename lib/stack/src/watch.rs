@@ -116,6 +116,12 @@ where
             stack: self.inner.clone(),
         })
     }
+
+    fn describe(&self, target: &T) -> Vec<&'static str> {
+        let mut layers = vec!["watch"];
+        layers.extend(self.inner.describe(&target.with_update(&*self.watch.borrow())));
+        layers
+    }
 }
 
 // === impl Service ===
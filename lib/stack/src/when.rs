@@ -0,0 +1,182 @@
+/// Applies an `L`-typed `Layer` only to targets that satisfy a predicate.
+///
+/// Values built for targets that match the predicate are produced by the
+/// layered stack; all others are produced by the inner stack directly.
+pub fn when<T, P, L>(predicate: P, layer: L) -> Layer<P, L>
+where
+    P: Predicate<T>,
+{
+    Layer { predicate, layer }
+}
+
+pub trait Predicate<T> {
+    fn apply(&self, target: &T) -> bool;
+}
+
+#[derive(Clone, Debug)]
+pub struct Layer<P, L> {
+    predicate: P,
+    layer: L,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<P, L, M> {
+    predicate: P,
+    layered: L,
+    inner: M,
+}
+
+impl<T, P, L, M> super::Layer<T, T, M> for Layer<P, L>
+where
+    P: Predicate<T> + Clone,
+    L: super::Layer<T, T, M, Error = M::Error>,
+    M: super::Stack<T> + Clone,
+{
+    type Value = <Stack<P, L::Stack, M> as super::Stack<T>>::Value;
+    type Error = <Stack<P, L::Stack, M> as super::Stack<T>>::Error;
+    type Stack = Stack<P, L::Stack, M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            predicate: self.predicate.clone(),
+            layered: self.layer.bind(inner.clone()),
+            inner,
+        }
+    }
+}
+
+impl<T, P, L, M> super::Stack<T> for Stack<P, L, M>
+where
+    P: Predicate<T>,
+    L: super::Stack<T, Error = M::Error>,
+    M: super::Stack<T>,
+{
+    type Value = super::Either<L::Value, M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        if self.predicate.apply(target) {
+            self.layered.make(target).map(super::Either::A)
+        } else {
+            self.inner.make(target).map(super::Either::B)
+        }
+    }
+
+    fn describe(&self, target: &T) -> Vec<&'static str> {
+        let mut layers = vec!["when"];
+        if self.predicate.apply(target) {
+            layers.extend(self.layered.describe(target));
+        } else {
+            layers.extend(self.inner.describe(target));
+        }
+        layers
+    }
+}
+
+impl<F, T> Predicate<T> for F
+where
+    F: Fn(&T) -> bool,
+{
+    fn apply(&self, target: &T) -> bool {
+        (self)(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future, Poll};
+    use never::Never;
+    use svc::{self, Service as _Service};
+
+    use super::*;
+    use {Either, Layer as _Layer, Stack as _Stack};
+
+    /// Responds with the target for which it was built.
+    #[derive(Clone, Debug)]
+    struct Svc(usize);
+
+    /// Wraps services so that they respond with negated targets.
+    #[derive(Clone, Debug)]
+    struct Negate<S>(S);
+
+    #[derive(Clone, Debug)]
+    struct NegateLayer;
+
+    #[derive(Clone, Debug)]
+    struct MakeSvc;
+
+    impl ::Stack<usize> for MakeSvc {
+        type Value = Svc;
+        type Error = Never;
+
+        fn make(&self, n: &usize) -> Result<Svc, Never> {
+            Ok(Svc(*n))
+        }
+    }
+
+    impl svc::Service<()> for Svc {
+        type Response = isize;
+        type Error = Never;
+        type Future = future::FutureResult<isize, Never>;
+
+        fn poll_ready(&mut self) -> Poll<(), Never> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            future::ok(self.0 as isize)
+        }
+    }
+
+    impl<M: ::Stack<usize>> ::Layer<usize, usize, M> for NegateLayer {
+        type Value = Negate<M::Value>;
+        type Error = M::Error;
+        type Stack = Negate<M>;
+
+        fn bind(&self, inner: M) -> Negate<M> {
+            Negate(inner)
+        }
+    }
+
+    impl<M: ::Stack<usize>> ::Stack<usize> for Negate<M> {
+        type Value = Negate<M::Value>;
+        type Error = M::Error;
+
+        fn make(&self, n: &usize) -> Result<Self::Value, M::Error> {
+            self.0.make(n).map(Negate)
+        }
+    }
+
+    impl<S: svc::Service<(), Response = isize>> svc::Service<()> for Negate<S> {
+        type Response = isize;
+        type Error = S::Error;
+        type Future = future::Map<S::Future, fn(isize) -> isize>;
+
+        fn poll_ready(&mut self) -> Poll<(), S::Error> {
+            self.0.poll_ready()
+        }
+
+        fn call(&mut self, req: ()) -> Self::Future {
+            self.0.call(req).map(|n| -n)
+        }
+    }
+
+    #[test]
+    fn layer_is_applied_when_predicate_holds() {
+        let stack = when(|n: &usize| n % 2 == 0, NegateLayer).bind(MakeSvc);
+
+        let mut even = stack.make(&2).unwrap();
+        match even {
+            Either::A(_) => {}
+            Either::B(_) => panic!("layer must be applied to even targets"),
+        }
+        assert_eq!(even.call(()).wait().ok(), Some(-2));
+
+        let mut odd = stack.make(&3).unwrap();
+        match odd {
+            Either::A(_) => panic!("layer must not be applied to odd targets"),
+            Either::B(_) => {}
+        }
+        assert_eq!(odd.call(()).wait().ok(), Some(3));
+    }
+}
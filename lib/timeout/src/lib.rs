@@ -122,6 +122,17 @@ where
 
 //===== impl Error =====
 
+impl<E> Error<E> {
+    /// Returns true if the underlying operation did not complete before the
+    /// timeout elapsed.
+    pub fn is_timeout(&self) -> bool {
+        match self.kind {
+            ErrorKind::Timeout(_) => true,
+            _ => false,
+        }
+    }
+}
+
 impl<E> fmt::Display for Error<E>
 where
     E: fmt::Display
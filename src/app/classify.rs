@@ -2,7 +2,7 @@ use h2;
 use http;
 
 pub use proxy::http::metrics::classify::{self, layer, CanClassify};
-use proxy::http::profiles;
+use proxy::http::{circuit_breaker, is_grpc, profiles};
 
 #[derive(Clone, Debug)]
 pub enum Request {
@@ -71,14 +71,7 @@ impl classify::Classify for Request {
         match self {
             Request::Profile(classes) => Response::Profile(classes.clone()),
             Request::Default => {
-                let is_grpc = req
-                    .headers()
-                    .get(http::header::CONTENT_TYPE)
-                    .and_then(|v| v.to_str().ok())
-                    .map(|ct| ct.starts_with("application/grpc+"))
-                    .unwrap_or(false);
-
-                if is_grpc {
+                if is_grpc(req.headers()) {
                     Response::Grpc
                 } else {
                     Response::Default
@@ -173,6 +166,18 @@ impl classify::ClassifyEos for Eos {
     }
 }
 
+// === impl Class ===
+
+impl circuit_breaker::IsFailure for Class {
+    fn is_failure(&self) -> bool {
+        match self {
+            Class::Default(result) | Class::Grpc(result, _) | Class::Stream(result, _) => {
+                *result == SuccessOrFailure::Failure
+            }
+        }
+    }
+}
+
 fn grpc_class(headers: &http::HeaderMap) -> Option<Class> {
     headers
         .get("grpc-status")
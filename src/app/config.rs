@@ -6,14 +6,18 @@ use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
 
+use h2;
 use http;
-use indexmap::IndexSet;
+use indexmap::{IndexMap, IndexSet};
+use metrics::{self, latency};
 use trust_dns_resolver::config::ResolverOpts;
 
 use addr;
 use dns;
 use convert::TryFrom;
-use transport::tls;
+use proxy::http::{balance, circuit_breaker, client, ratelimit};
+use proxy::{server, tcp};
+use transport::{connect, proxy_protocol, tls};
 use {Conditional, Addr};
 
 // TODO:
@@ -44,6 +48,30 @@ pub struct Config {
     /// The maximum amount of time to wait for a connection to a remote peer.
     pub outbound_connect_timeout: Duration,
 
+    /// The minimum time to wait before reconnecting to a peer after a
+    /// connect error.
+    pub connect_backoff_min: Duration,
+
+    /// The maximum time to wait before reconnecting to a peer after
+    /// consecutive connect errors.
+    pub connect_backoff_max: Duration,
+
+    /// The fraction (between 0 and 1) by which each reconnect backoff may be
+    /// randomly reduced.
+    pub connect_backoff_jitter: f64,
+
+    /// The number of consecutive failed connect attempts after which a
+    /// client fails, so that it may be evicted. When `None`, connects are
+    /// retried indefinitely.
+    pub connect_max_attempts: Option<usize>,
+
+    /// Options applied to each socket the proxy connects.
+    pub connect_socket_options: connect::SocketOptions,
+
+    /// The PROXY protocol version with which forwarded outbound TCP
+    /// connections describe their client, if any.
+    pub outbound_proxy_protocol: Option<proxy_protocol::Version>,
+
     pub inbound_ports_disable_protocol_detection: IndexSet<u16>,
 
     pub outbound_ports_disable_protocol_detection: IndexSet<u16>,
@@ -54,8 +82,157 @@ pub struct Config {
 
     pub inbound_router_max_idle_age: Duration,
 
+    /// The local ports to which inbound requests are routed by the SNI server
+    /// name of their TLS connection.
+    pub inbound_sni_ports: IndexMap<tls::Identity, u16>,
+
+    /// The addresses to which outbound opaque TLS connections are forwarded
+    /// by the SNI server name of their ClientHello.
+    pub outbound_sni_routes: IndexMap<tls::Identity, SocketAddr>,
+
+    /// The original destination ports of inbound requests whose bodies must
+    /// match their declared `Content-Type`.
+    pub inbound_content_sniff_ports: IndexSet<u16>,
+
     pub outbound_router_max_idle_age: Duration,
 
+    /// The delay advertised, via `Retry-After`, to clients whose requests are
+    /// rejected because a router is at capacity.
+    pub router_retry_after: Duration,
+
+    /// Determines how outbound requests are balanced over endpoints.
+    pub outbound_balance_strategy: balance::Strategy,
+
+    /// How long an outbound endpoint is ejected from its balancer after
+    /// repeated consecutive failures.
+    pub outbound_balance_ejection_window: Duration,
+
+    /// The number of consecutive failures after which an outbound endpoint
+    /// is ejected from its balancer.
+    pub outbound_balance_ejection_max_failures: usize,
+
+    /// The request attribute on which outbound requests are hashed when the
+    /// balance strategy is `consistent-hash`.
+    pub outbound_balance_hash_key: balance::hash::Key,
+
+    /// The number of endpoints an outbound balancer waits to discover before
+    /// it dispatches requests.
+    pub outbound_balance_min_ready_endpoints: usize,
+
+    /// The maximum amount of time an outbound balancer waits to discover
+    /// `outbound_balance_min_ready_endpoints` endpoints.
+    pub outbound_balance_warmup_timeout: Duration,
+
+    /// How long a removed outbound endpoint may continue to serve its
+    /// in-flight requests. When `None`, removed endpoints are dropped
+    /// immediately.
+    pub outbound_balance_drain_timeout: Option<Duration>,
+
+    /// The amount of time to wait for a client to send enough data for its
+    /// protocol to be detected.
+    pub detect_protocol_timeout: Duration,
+
+    /// Determines whether connections whose protocol is not detected before
+    /// the timeout are closed or forwarded.
+    pub on_detect_protocol_timeout: server::OnDetectTimeout,
+
+    /// Determines how forwarded TCP connections are closed when one side
+    /// finishes writing.
+    pub tcp_shutdown: tcp::Shutdown,
+
+    /// Whether HTTP/1 clients may synthesize a `Host` header for requests
+    /// that lack one.
+    pub http1_rewrite_host: bool,
+
+    /// Whether HTTP/1 requests that lack an authority share a client for their
+    /// original destination.
+    pub http1_reuse_without_authority: bool,
+
+    /// The minimum length of an uncompressed text response that is gzipped
+    /// for outbound clients that accept it, if compression is enabled.
+    pub outbound_gzip_min_length: Option<u64>,
+
+    /// Whether HTTP/1.1 connections may be upgraded to HTTP/2 via
+    /// `Upgrade: h2c`.
+    pub h2c_upgrades: bool,
+
+    /// The timeout of requests that do not match a route in their
+    /// destination's profile, if any.
+    pub route_default_timeout: Option<Duration>,
+
+    /// The amount of time after which HTTP/2 connections without open
+    /// streams are closed, if any.
+    pub h2_idle_timeout: Option<Duration>,
+
+    /// Configures the initial flow control windows of outbound HTTP/2 clients.
+    pub outbound_h2_windows: client::Http2Windows,
+
+    /// The maximum number of streams that a client may open concurrently on
+    /// each inbound HTTP/2 connection, if limited.
+    pub inbound_h2_max_concurrent_streams: Option<u32>,
+
+    /// The initial flow control window size, in bytes, of each stream on
+    /// inbound HTTP/2 connections, if not the protocol default.
+    pub inbound_h2_initial_stream_window: Option<u32>,
+
+    /// The initial flow control window size, in bytes, of each inbound HTTP/2
+    /// connection, if not the protocol default.
+    pub inbound_h2_initial_connection_window: Option<u32>,
+
+    /// Determines how HTTP/1 clients in both proxies retain idle connections
+    /// to each endpoint.
+    pub http1_pool: client::Http1Pool,
+
+    /// The maximum number of connections served by each proxy at once, if
+    /// any.
+    pub max_connections: Option<usize>,
+
+    /// The amount of time after shutdown begins after which connections that
+    /// have not finished are aborted, if any.
+    pub drain_timeout: Option<Duration>,
+
+    /// Whether responses report the time each request spent within each
+    /// layer of the proxy.
+    pub latency_breakdown: bool,
+
+    /// The maximum number of HTTP requests that may be in flight across the
+    /// inbound and outbound proxies.
+    pub global_max_in_flight: usize,
+
+    /// The maximum number of HTTP requests that may wait for the global
+    /// in-flight limit before requests are shed.
+    pub global_max_queued: usize,
+
+    /// The rate at which inbound HTTP requests are permitted to each
+    /// destination, if requests are rate limited.
+    pub inbound_rate_limit: Option<ratelimit::Limit>,
+
+    /// Determines when requests to a failing outbound endpoint fail fast, if
+    /// circuit breaking is enabled.
+    pub outbound_circuit_breaker: Option<circuit_breaker::Config>,
+
+    /// The header in which HTTP requests carry their request IDs, if request
+    /// IDs are injected.
+    pub request_id_header: Option<http::header::HeaderName>,
+
+    /// Whether requests' existing request IDs are replaced.
+    pub request_id_overwrite: bool,
+
+    /// The maximum number of trailer fields an HTTP request or response may
+    /// have.
+    pub max_trailer_fields: usize,
+
+    /// The maximum total size, in bytes, of an HTTP request's or response's
+    /// trailers.
+    pub max_trailer_bytes: usize,
+
+    /// The maximum number of long-lived streams that may be open concurrently
+    /// to each outbound destination.
+    pub outbound_max_long_lived_streams: usize,
+
+    /// How long a stream must be open before it is considered long-lived.
+    pub long_lived_stream_threshold: Duration,
+
     /// The maximum number of queries to the Destination service which may be
     /// active concurrently.
     pub destination_concurrency_limit: usize,
@@ -66,6 +243,21 @@ pub struct Config {
     /// Configured by `ENV_DESTINATION_PROFILE_SUFFIXES`.
     pub destination_profile_suffixes: Vec<dns::Suffix>,
 
+    /// Configured by `ENV_DESTINATION_SRV_SUFFIXES`.
+    pub destination_srv_suffixes: Vec<dns::Suffix>,
+
+    /// Configured by `ENV_DESTINATION_PROFILE_UPDATE_WINDOW`.
+    pub destination_profile_update_window: Duration,
+
+    /// Configured by `ENV_DESTINATION_PROFILE_BACKOFF_MAX`.
+    pub destination_profile_backoff_max: Duration,
+
+    /// Configured by `ENV_DESTINATION_PROFILE_FAILOVER_URLS`.
+    pub destination_profile_failover_hosts_and_ports: Vec<Addr>,
+
+    /// Configured by `ENV_DESTINATION_STARTUP_POLICY`.
+    pub destination_startup_policy: StartupPolicy,
+
     pub tls_settings: Conditional<tls::CommonSettings, tls::ReasonForNoTls>,
 
     /// The path to "/etc/resolv.conf"
@@ -91,6 +283,9 @@ pub struct Config {
     /// Age after which metrics may be dropped.
     pub metrics_retain_idle: Duration,
 
+    /// The buckets into which HTTP response latencies are recorded.
+    pub metrics_latency_bounds: &'static metrics::Bounds,
+
     /// Timeout after which to cancel binding a request.
     pub bind_timeout: Duration,
 
@@ -101,6 +296,29 @@ pub struct Config {
 
     /// Optional maximum TTL for DNS lookups.
     pub dns_max_ttl: Option<Duration>,
+
+    /// Whether DNS lookups are cached in-process until their records expire.
+    pub dns_cache: bool,
+
+    /// The maximum number of names whose lookups are cached.
+    pub dns_cache_size: usize,
+
+    /// How long to wait before refining a name again after its lookup fails.
+    pub dns_error_ttl: Duration,
+
+    /// Names matching these suffixes are not canonicalized via DNS.
+    pub dns_canonicalize_skip_suffixes: Vec<dns::Suffix>,
+
+    /// The response header in which the outbound endpoint's identity is
+    /// reported, if any.
+    pub response_server_id_header: Option<http::header::HeaderName>,
+
+    /// The response header in which the outbound endpoint's latency is
+    /// reported, if any.
+    pub response_latency_header: Option<http::header::HeaderName>,
+
+    /// The address family preferred when a name resolves to a single address.
+    pub dns_ip_family: dns::IpFamilyPreference,
 }
 
 #[derive(Clone, Debug)]
@@ -126,11 +344,35 @@ pub enum Error {
     InvalidEnvVar
 }
 
+/// Determines whether the proxy is ready to serve traffic before it has
+/// reached the Destination service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartupPolicy {
+    /// The proxy does not report readiness until it has connected to the
+    /// Destination service.
+    FailReady,
+    /// The proxy is ready immediately, falling back to the original
+    /// destination until the Destination service is reached.
+    ServeWithFallback,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum ParseError {
     EnvironmentUnsupported,
     NotADuration,
     NotADomainSuffix,
+    NotABalanceStrategy,
+    NotABalanceHashKey,
+    NotAHeaderName,
+    NotAnIpFamily,
+    NotATcpShutdown,
+    NotAProxyProtocolVersion,
+    NotALatencyBucketList,
+    NotADetectTimeoutAction,
+    NotAnSniPort,
+    NotAnSniRoute,
+    NotAStartupPolicy,
+    NotABoolean,
     NotANumber,
     HostIsNotAnIpAddress,
     NotUnicode,
@@ -177,8 +419,46 @@ pub const ENV_INBOUND_LISTENER: &str = "LINKERD2_PROXY_INBOUND_LISTENER";
 pub const ENV_CONTROL_LISTENER: &str = "LINKERD2_PROXY_CONTROL_LISTENER";
 pub const ENV_METRICS_LISTENER: &str = "LINKERD2_PROXY_METRICS_LISTENER";
 pub const ENV_METRICS_RETAIN_IDLE: &str = "LINKERD2_PROXY_METRICS_RETAIN_IDLE";
+
+/// Configures the upper bounds, in milliseconds, of the buckets into which
+/// HTTP response latencies are recorded, as a comma-separated list of
+/// increasing values. An unbounded bucket is always added.
+///
+/// By default, buckets range from 1ms to 50s.
+const ENV_METRICS_LATENCY_BUCKETS: &str = "LINKERD2_PROXY_METRICS_LATENCY_BUCKETS";
 const ENV_INBOUND_CONNECT_TIMEOUT: &str = "LINKERD2_PROXY_INBOUND_CONNECT_TIMEOUT";
 const ENV_OUTBOUND_CONNECT_TIMEOUT: &str = "LINKERD2_PROXY_OUTBOUND_CONNECT_TIMEOUT";
+
+/// Configures how long the proxy waits before reconnecting to a peer after a
+/// connect error. The backoff doubles with each consecutive error, from the
+/// minimum up to the maximum, and is randomly reduced by up to the jitter
+/// fraction.
+const ENV_CONNECT_BACKOFF_MIN: &str = "LINKERD2_PROXY_CONNECT_BACKOFF_MIN";
+const ENV_CONNECT_BACKOFF_MAX: &str = "LINKERD2_PROXY_CONNECT_BACKOFF_MAX";
+const ENV_CONNECT_BACKOFF_JITTER: &str = "LINKERD2_PROXY_CONNECT_BACKOFF_JITTER";
+
+/// Configures the number of consecutive failed connect attempts after which
+/// a client to a peer fails rather than reconnecting again.
+///
+/// By default, connects are retried indefinitely.
+const ENV_CONNECT_MAX_ATTEMPTS: &str = "LINKERD2_PROXY_CONNECT_MAX_ATTEMPTS";
+
+/// Configures whether `TCP_NODELAY` is set on connected sockets.
+///
+/// Defaults to true.
+const ENV_CONNECT_NODELAY: &str = "LINKERD2_PROXY_CONNECT_NODELAY";
+
+/// Configures the idle time after which `SO_KEEPALIVE` probes are sent on
+/// connected sockets.
+///
+/// By default, keepalive is not enabled.
+const ENV_CONNECT_KEEPALIVE: &str = "LINKERD2_PROXY_CONNECT_KEEPALIVE";
+
+/// Configures forwarded outbound TCP connections to begin with a PROXY
+/// protocol header describing the client's address. Either `v1` or `v2`.
+///
+/// By default, no header is sent.
+const ENV_OUTBOUND_PROXY_PROTOCOL: &str = "LINKERD2_PROXY_OUTBOUND_PROXY_PROTOCOL";
 pub const ENV_BIND_TIMEOUT: &str = "LINKERD2_PROXY_BIND_TIMEOUT";
 
 pub const DEPRECATED_ENV_PRIVATE_LISTENER: &str = "LINKERD2_PROXY_PRIVATE_LISTENER";
@@ -196,6 +476,255 @@ pub const ENV_OUTBOUND_ROUTER_CAPACITY: &str = "LINKERD2_PROXY_OUTBOUND_ROUTER_C
 pub const ENV_INBOUND_ROUTER_MAX_IDLE_AGE: &str = "LINKERD2_PROXY_INBOUND_ROUTER_MAX_IDLE_AGE";
 pub const ENV_OUTBOUND_ROUTER_MAX_IDLE_AGE: &str = "LINKERD2_PROXY_OUTBOUND_ROUTER_MAX_IDLE_AGE";
 
+/// The delay that clients are asked to wait (via `Retry-After`) before retrying
+/// requests that were rejected because a router was at capacity.
+const ENV_ROUTER_RETRY_AFTER: &str = "LINKERD2_PROXY_ROUTER_RETRY_AFTER";
+
+/// Routes inbound requests received over TLS to local ports by the SNI server
+/// name of their connection.
+///
+/// The value is a comma-separated list of `name=port` pairs. Requests on
+/// connections with other (or no) server names are routed to their original
+/// destination.
+pub const ENV_INBOUND_SNI_PORTS: &str = "LINKERD2_PROXY_INBOUND_SNI_PORTS";
+
+/// Forwards outbound connections that are not HTTP and begin with a TLS
+/// ClientHello to an address chosen by the ClientHello's SNI server name.
+///
+/// The value is a comma-separated list of `name=addr` pairs. Connections with
+/// other (or no) server names are forwarded to their original destination, as
+/// are connections to ports on which protocol detection is disabled.
+pub const ENV_OUTBOUND_SNI_ROUTES: &str = "LINKERD2_PROXY_OUTBOUND_SNI_ROUTES";
+
+/// Rejects inbound requests to these ports when the leading bytes of their
+/// bodies do not match their declared `Content-Type`.
+///
+/// The value is a comma-separated list of ports. By default, no requests are
+/// sniffed.
+pub const ENV_INBOUND_CONTENT_SNIFF_PORTS: &str = "LINKERD2_PROXY_INBOUND_CONTENT_SNIFF_PORTS";
+
+/// Configures the strategy used to balance outbound requests over endpoints.
+///
+/// The value is one of `p2c-peak-ewma` (the default), `round-robin`,
+/// `least-loaded`, or `consistent-hash`.
+pub const ENV_OUTBOUND_BALANCE_STRATEGY: &str = "LINKERD2_PROXY_OUTBOUND_BALANCE_STRATEGY";
+
+/// Configures how long an outbound endpoint is ejected from its balancer
+/// after repeated consecutive failures.
+pub const ENV_OUTBOUND_BALANCE_EJECTION_WINDOW: &str =
+    "LINKERD2_PROXY_OUTBOUND_BALANCE_EJECTION_WINDOW";
+
+/// Configures the number of consecutive failures after which an outbound
+/// endpoint is ejected from its balancer.
+pub const ENV_OUTBOUND_BALANCE_EJECTION_MAX_FAILURES: &str =
+    "LINKERD2_PROXY_OUTBOUND_BALANCE_EJECTION_MAX_FAILURES";
+
+/// Configures the request attribute on which outbound requests are hashed
+/// by the `consistent-hash` balance strategy.
+///
+/// The value is one of `source-ip` (the default), `header:<name>`, or
+/// `cookie:<name>`. Requests that lack the attribute are spread across
+/// endpoints.
+pub const ENV_OUTBOUND_BALANCE_HASH_KEY: &str = "LINKERD2_PROXY_OUTBOUND_BALANCE_HASH_KEY";
+
+/// Configures the number of endpoints a new outbound balancer waits to
+/// discover before it dispatches requests, so that a burst of requests is not
+/// sent to the first endpoint discovered.
+pub const ENV_OUTBOUND_BALANCE_MIN_READY_ENDPOINTS: &str =
+    "LINKERD2_PROXY_OUTBOUND_BALANCE_MIN_READY_ENDPOINTS";
+
+/// Configures the maximum amount of time a new outbound balancer waits for
+/// `LINKERD2_PROXY_OUTBOUND_BALANCE_MIN_READY_ENDPOINTS` endpoints to be
+/// discovered.
+pub const ENV_OUTBOUND_BALANCE_WARMUP_TIMEOUT: &str =
+    "LINKERD2_PROXY_OUTBOUND_BALANCE_WARMUP_TIMEOUT";
+
+/// Configures how long an outbound endpoint that is no longer resolved may
+/// continue to serve its in-flight requests before it is dropped.
+///
+/// When unset, such endpoints are dropped immediately.
+pub const ENV_OUTBOUND_BALANCE_DRAIN_TIMEOUT: &str =
+    "LINKERD2_PROXY_OUTBOUND_BALANCE_DRAIN_TIMEOUT";
+
+/// Configures how long the proxy waits for a client to send enough data for
+/// its protocol to be detected.
+pub const ENV_DETECT_PROTOCOL_TIMEOUT: &str = "LINKERD2_PROXY_DETECT_PROTOCOL_TIMEOUT";
+
+/// Configures what happens to a connection whose protocol has not been
+/// detected when the detection timeout elapses.
+///
+/// The value is either `forward` (the default), which forwards the
+/// connection as opaque TCP, or `close`.
+pub const ENV_DETECT_PROTOCOL_TIMEOUT_ACTION: &str =
+    "LINKERD2_PROXY_DETECT_PROTOCOL_TIMEOUT_ACTION";
+
+/// Configures how forwarded TCP connections are closed when one side finishes
+/// writing.
+///
+/// The value is either `half-close` (the default), which keeps the other
+/// direction open until it also finishes, or `full`, which tears down the
+/// connection immediately.
+pub const ENV_TCP_SHUTDOWN: &str = "LINKERD2_PROXY_TCP_SHUTDOWN";
+
+/// Configures whether the proxy may synthesize a `Host` header from the
+/// original destination for HTTP/1 requests that lack one.
+///
+/// Valid `Host` headers are always forwarded verbatim. When `false`, the
+/// proxy never modifies the `Host` header. Defaults to `false`.
+pub const ENV_HTTP1_REWRITE_HOST: &str = "LINKERD2_PROXY_HTTP1_REWRITE_HOST";
+
+/// Configures whether HTTP/1 requests without an authority (i.e. without an
+/// absolute-form URI or a `Host` header) share a client for their original
+/// destination.
+///
+/// When `false`, a new client is built for each such request. Defaults to
+/// `false`.
+const ENV_HTTP1_REUSE_WITHOUT_AUTHORITY: &str = "LINKERD2_PROXY_HTTP1_REUSE_WITHOUT_AUTHORITY";
+
+/// Enables gzip compression of outbound responses for clients that accept it.
+///
+/// The value is the minimum `Content-Length`, in bytes, of an uncompressed
+/// text response that is compressed. If unset, responses are not compressed.
+pub const ENV_OUTBOUND_GZIP_MIN_LENGTH: &str = "LINKERD2_PROXY_OUTBOUND_GZIP_MIN_LENGTH";
+
+/// Enables upgrading HTTP/1.1 connections to HTTP/2 when clients send an
+/// `Upgrade: h2c` request. Otherwise, h2c upgrade headers are stripped.
+///
+/// Defaults to `false`.
+pub const ENV_H2C_UPGRADES: &str = "LINKERD2_PROXY_H2C_UPGRADES";
+
+/// Responds with a 504 Gateway Timeout to requests that do not match a route
+/// in their destination's profile and that have not completed within this
+/// duration.
+///
+/// If unset, such requests do not time out. Only the default route supports a
+/// timeout: destination profiles cannot configure timeouts for their routes.
+pub const ENV_ROUTE_DEFAULT_TIMEOUT: &str = "LINKERD2_PROXY_ROUTE_DEFAULT_TIMEOUT";
+
+/// Closes inbound and outbound HTTP/2 connections from clients once they have
+/// had no open streams for this amount of time. A GOAWAY is sent before the
+/// connection is closed.
+///
+/// If unset, idle connections are not closed.
+pub const ENV_H2_IDLE_TIMEOUT: &str = "LINKERD2_PROXY_H2_IDLE_TIMEOUT";
+
+/// Limits the number of streams that a client may open concurrently on each
+/// inbound HTTP/2 connection. If unset, the number of streams is unlimited.
+pub const ENV_INBOUND_H2_MAX_CONCURRENT_STREAMS: &str =
+    "LINKERD2_PROXY_INBOUND_H2_MAX_CONCURRENT_STREAMS";
+
+/// Sets the initial flow control window size, in bytes, of streams and of
+/// connections (respectively) on inbound HTTP/2 connections. If unset, the
+/// protocol's default (64KB) is used.
+pub const ENV_INBOUND_H2_INITIAL_STREAM_WINDOW: &str =
+    "LINKERD2_PROXY_INBOUND_H2_INITIAL_STREAM_WINDOW";
+pub const ENV_INBOUND_H2_INITIAL_CONNECTION_WINDOW: &str =
+    "LINKERD2_PROXY_INBOUND_H2_INITIAL_CONNECTION_WINDOW";
+
+/// Sets the initial flow control window size, in bytes, of streams and of
+/// connections (respectively) on outbound HTTP/2 connections. If unset, the
+/// protocol's default (64KB) is used.
+pub const ENV_OUTBOUND_H2_INITIAL_STREAM_WINDOW: &str =
+    "LINKERD2_PROXY_OUTBOUND_H2_INITIAL_STREAM_WINDOW";
+pub const ENV_OUTBOUND_H2_INITIAL_CONNECTION_WINDOW: &str =
+    "LINKERD2_PROXY_OUTBOUND_H2_INITIAL_CONNECTION_WINDOW";
+
+/// Limits the number of idle HTTP/1 connections that the inbound and
+/// outbound proxies each keep open to an endpoint for reuse. Defaults to 100.
+pub const ENV_HTTP1_MAX_IDLE_CONNECTIONS: &str = "LINKERD2_PROXY_HTTP1_MAX_IDLE_CONNECTIONS";
+
+/// Closes HTTP/1 connections to endpoints once they have been idle for this
+/// amount of time. Defaults to 90 seconds.
+pub const ENV_HTTP1_IDLE_TIMEOUT: &str = "LINKERD2_PROXY_HTTP1_IDLE_TIMEOUT";
+
+/// Limits the number of connections that the inbound and outbound proxies
+/// each serve at once. Connections accepted beyond the limit are closed
+/// immediately.
+///
+/// If unset, connections are not limited.
+pub const ENV_MAX_CONNECTIONS: &str = "LINKERD2_PROXY_MAX_CONNECTIONS";
+
+/// Aborts connections that have not finished this long after the proxy begins
+/// shutting down, so that they cannot hold up the process's exit.
+///
+/// If unset, shutdown waits for all connections to finish.
+pub const ENV_DRAIN_TIMEOUT: &str = "LINKERD2_PROXY_DRAIN_TIMEOUT";
+
+/// Enables reporting a per-layer breakdown of each request's latency in the
+/// `l5d-latency-breakdown` response header.
+///
+/// This is intended for debugging and defaults to `false`.
+pub const ENV_LATENCY_BREAKDOWN: &str = "LINKERD2_PROXY_LATENCY_BREAKDOWN";
+
+/// Limits the number of HTTP requests that may be in flight across the
+/// inbound and outbound proxies at any time.
+///
+/// Requests in excess of this limit are queued (see `ENV_GLOBAL_MAX_QUEUED`).
+pub const ENV_GLOBAL_MAX_IN_FLIGHT: &str = "LINKERD2_PROXY_GLOBAL_MAX_IN_FLIGHT";
+
+/// Limits the number of HTTP requests that may wait for the global in-flight
+/// limit. Requests in excess of this limit fail with a 503.
+pub const ENV_GLOBAL_MAX_QUEUED: &str = "LINKERD2_PROXY_GLOBAL_MAX_QUEUED";
+
+/// Limits the number of inbound HTTP requests permitted to each destination
+/// per `ENV_INBOUND_RATE_LIMIT_INTERVAL`. Requests in excess of this limit
+/// fail with a 429.
+///
+/// If unset, inbound requests are not rate limited.
+pub const ENV_INBOUND_RATE_LIMIT_REQUESTS: &str = "LINKERD2_PROXY_INBOUND_RATE_LIMIT_REQUESTS";
+
+/// The interval over which `ENV_INBOUND_RATE_LIMIT_REQUESTS` are permitted.
+///
+/// Defaults to one second.
+pub const ENV_INBOUND_RATE_LIMIT_INTERVAL: &str = "LINKERD2_PROXY_INBOUND_RATE_LIMIT_INTERVAL";
+
+/// Enables circuit breaking for outbound endpoints. The value is the ratio
+/// (between 0 and 1) of failed responses among an endpoint's most recent
+/// responses at which requests to the endpoint begin to fail fast.
+///
+/// If unset, outbound circuits are never opened.
+pub const ENV_OUTBOUND_CIRCUIT_BREAKER_FAILURE_RATIO: &str =
+    "LINKERD2_PROXY_OUTBOUND_CIRCUIT_BREAKER_FAILURE_RATIO";
+
+/// The number of an endpoint's most recent responses from which its failure
+/// ratio is computed. Defaults to 20.
+pub const ENV_OUTBOUND_CIRCUIT_BREAKER_WINDOW: &str =
+    "LINKERD2_PROXY_OUTBOUND_CIRCUIT_BREAKER_WINDOW";
+
+/// How long requests to an endpoint fail fast before a probe request is sent.
+/// Defaults to 10 seconds.
+pub const ENV_OUTBOUND_CIRCUIT_BREAKER_COOLDOWN: &str =
+    "LINKERD2_PROXY_OUTBOUND_CIRCUIT_BREAKER_COOLDOWN";
+
+/// Enables request IDs. The value names the header (e.g. `l5d-request-id`)
+/// into which a generated ID is inserted on HTTP requests that lack one.
+///
+/// If unset, request IDs are neither generated nor inspected.
+pub const ENV_REQUEST_ID_HEADER: &str = "LINKERD2_PROXY_REQUEST_ID_HEADER";
+
+/// If true, requests' existing request IDs are replaced with generated IDs.
+/// Defaults to false.
+pub const ENV_REQUEST_ID_OVERWRITE: &str = "LINKERD2_PROXY_REQUEST_ID_OVERWRITE";
+
+/// Limits the number of trailer fields on HTTP requests and responses.
+/// Streams with more trailer fields are reset.
+pub const ENV_MAX_TRAILER_FIELDS: &str = "LINKERD2_PROXY_MAX_TRAILER_FIELDS";
+
+/// Limits the total size, in bytes, of the names and values of trailers on
+/// HTTP requests and responses. Streams with larger trailers are reset.
+pub const ENV_MAX_TRAILER_BYTES: &str = "LINKERD2_PROXY_MAX_TRAILER_BYTES";
+
+/// Limits the number of long-lived streams (e.g. gRPC streams or server-sent
+/// events) that may be open concurrently to each outbound destination.
+///
+/// New streams in excess of this limit fail with a 503.
+pub const ENV_OUTBOUND_MAX_LONG_LIVED_STREAMS: &str =
+    "LINKERD2_PROXY_OUTBOUND_MAX_LONG_LIVED_STREAMS";
+
+/// Configures how long a stream must be open before it counts against
+/// `ENV_OUTBOUND_MAX_LONG_LIVED_STREAMS`.
+pub const ENV_LONG_LIVED_STREAM_THRESHOLD: &str = "LINKERD2_PROXY_LONG_LIVED_STREAM_THRESHOLD";
+
 /// Constrains which destination names are resolved through the destination
 /// service.
 ///
@@ -219,6 +748,48 @@ pub const ENV_DESTINATION_GET_SUFFIXES: &str = "LINKERD2_PROXY_DESTINATION_GET_S
 /// If unspecified, a default value is used.
 pub const ENV_DESTINATION_PROFILE_SUFFIXES: &str = "LINKERD2_PROXY_DESTINATION_PROFILE_SUFFIXES";
 
+/// Constrains which destination names are resolved via DNS SRV records.
+///
+/// The value is a comma-separated list of domain name suffixes. Names matching
+/// these suffixes are load balanced over the targets of their SRV records,
+/// using each target's port and weight, instead of being resolved via the
+/// destination service.
+///
+/// If unspecified, no names are resolved via SRV records.
+pub const ENV_DESTINATION_SRV_SUFFIXES: &str = "LINKERD2_PROXY_DESTINATION_SRV_SUFFIXES";
+
+/// Configures how long a destination's routes must remain unchanged before a
+/// profile update is applied.
+///
+/// Updates received within this window of each other are coalesced, so that
+/// routes are not rebuilt for each update when the controller flaps.
+pub const ENV_DESTINATION_PROFILE_UPDATE_WINDOW: &str =
+    "LINKERD2_PROXY_DESTINATION_PROFILE_UPDATE_WINDOW";
+
+/// Configures the maximum time a profile lookup waits before reconnecting to
+/// the controller.
+///
+/// The backoff starts at 3 seconds and doubles with each consecutive error,
+/// up to this maximum. Defaults to 1 minute.
+const ENV_DESTINATION_PROFILE_BACKOFF_MAX: &str = "LINKERD2_PROXY_DESTINATION_PROFILE_BACKOFF_MAX";
+
+/// A comma-separated list of controller URLs from which profiles are fetched,
+/// in order, when the controller at `ENV_CONTROL_URL` fails repeatedly.
+///
+/// Only profile lookups fail over; destinations are always resolved via the
+/// controller at `ENV_CONTROL_URL`. If unspecified, profiles are only fetched
+/// from that controller.
+const ENV_DESTINATION_PROFILE_FAILOVER_URLS: &str =
+    "LINKERD2_PROXY_DESTINATION_PROFILE_FAILOVER_URLS";
+
+/// Configures whether the proxy reports readiness before it has reached the
+/// Destination service.
+///
+/// The value is one of `serve-with-fallback` (the default), in which the
+/// proxy is ready immediately, or `fail-ready`, in which the proxy is not
+/// ready until it has connected to the Destination service.
+pub const ENV_DESTINATION_STARTUP_POLICY: &str = "LINKERD2_PROXY_DESTINATION_STARTUP_POLICY";
+
 /// Limits the maximum number of outbound Destination service queries.
 ///
 /// Routes which do not result in service discovery lookups will not be capped
@@ -243,6 +814,7 @@ pub const ENV_POD_NAMESPACE: &str = "LINKERD2_PROXY_POD_NAMESPACE";
 pub const VAR_POD_NAMESPACE: &str = "$LINKERD2_PROXY_POD_NAMESPACE";
 
 pub const ENV_CONTROL_URL: &str = "LINKERD2_PROXY_CONTROL_URL";
+
 pub const ENV_CONTROL_BACKOFF_DELAY: &str = "LINKERD2_PROXY_CONTROL_BACKOFF_DELAY";
 const ENV_CONTROL_CONNECT_TIMEOUT: &str = "LINKERD2_PROXY_CONTROL_CONNECT_TIMEOUT";
 const ENV_RESOLV_CONF: &str = "LINKERD2_PROXY_RESOLV_CONF";
@@ -256,6 +828,51 @@ const ENV_DNS_MIN_TTL: &str = "LINKERD2_PROXY_DNS_MIN_TTL";
 /// Lookups with TTLs above this value will use this value instead.
 const ENV_DNS_MAX_TTL: &str = "LINKERD2_PROXY_DNS_MAX_TTL";
 
+/// Enables caching DNS lookups in-process until their records expire.
+///
+/// Defaults to `false`.
+const ENV_DNS_CACHE: &str = "LINKERD2_PROXY_DNS_CACHE";
+
+/// Limits the number of names whose DNS lookups are cached.
+const ENV_DNS_CACHE_SIZE: &str = "LINKERD2_PROXY_DNS_CACHE_SIZE";
+
+/// Configures how long to wait before canonicalizing a name via DNS again
+/// after its lookup fails (or the name is found not to exist without a TTL).
+///
+/// Defaults to 3 seconds.
+const ENV_DNS_ERROR_TTL: &str = "LINKERD2_PROXY_DNS_ERROR_TTL";
+
+/// Constrains which destination names are not canonicalized via DNS.
+///
+/// The value is a comma-separated list of domain name suffixes. Names matching
+/// these suffixes are assumed to already be canonical (e.g. fully-qualified
+/// cluster-local names), and so are used as-is without querying DNS.
+///
+/// If unspecified, all names are canonicalized.
+const ENV_DNS_CANONICALIZE_SKIP_SUFFIXES: &str = "LINKERD2_PROXY_DNS_CANONICALIZE_SKIP_SUFFIXES";
+
+/// Configures a response header in which the outbound proxy reports the
+/// identity of the endpoint that served each request (e.g. `l5d-server-id`).
+///
+/// The endpoint's TLS identity is used when it is known; otherwise, its
+/// address is reported. If unspecified, no header is added.
+const ENV_RESPONSE_SERVER_ID_HEADER: &str = "LINKERD2_PROXY_RESPONSE_SERVER_ID_HEADER";
+
+/// Configures a response header in which the outbound proxy reports how long,
+/// in milliseconds, each request's endpoint took to respond.
+///
+/// If unspecified, no header is added.
+const ENV_RESPONSE_LATENCY_HEADER: &str = "LINKERD2_PROXY_RESPONSE_LATENCY_HEADER";
+
+/// Configures which address family is preferred when a name that resolves to
+/// both IPv4 and IPv6 addresses must be resolved to a single address.
+///
+/// Valid values are `any`, `ipv4`, and `ipv6`. If no addresses of the
+/// preferred family exist, an address of the other family is used.
+///
+/// Defaults to `any`, which uses the first address returned by the resolver.
+const ENV_DNS_IP_FAMILY: &str = "LINKERD2_PROXY_DNS_IP_FAMILY";
+
 // Default values for various configuration fields
 const DEFAULT_OUTBOUND_LISTENER: &str = "tcp://127.0.0.1:4140";
 const DEFAULT_INBOUND_LISTENER: &str = "tcp://0.0.0.0:4143";
@@ -264,6 +881,9 @@ const DEFAULT_METRICS_LISTENER: &str = "tcp://127.0.0.1:4191";
 const DEFAULT_METRICS_RETAIN_IDLE: Duration = Duration::from_secs(10 * 60);
 const DEFAULT_INBOUND_CONNECT_TIMEOUT: Duration = Duration::from_millis(20);
 const DEFAULT_OUTBOUND_CONNECT_TIMEOUT: Duration = Duration::from_millis(300);
+const DEFAULT_CONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const DEFAULT_CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+const DEFAULT_CONNECT_BACKOFF_JITTER: f64 = 0.5;
 const DEFAULT_BIND_TIMEOUT: Duration = Duration::from_secs(10); // same as in Linkerd
 const DEFAULT_CONTROL_BACKOFF_DELAY: Duration = Duration::from_secs(5);
 const DEFAULT_CONTROL_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
@@ -276,11 +896,37 @@ const DEFAULT_OUTBOUND_ROUTER_CAPACITY: usize = 100;
 
 const DEFAULT_INBOUND_ROUTER_MAX_IDLE_AGE:  Duration = Duration::from_secs(60);
 const DEFAULT_OUTBOUND_ROUTER_MAX_IDLE_AGE: Duration = Duration::from_secs(60);
+const DEFAULT_ROUTER_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+const DEFAULT_OUTBOUND_BALANCE_MIN_READY_ENDPOINTS: usize = 1;
+const DEFAULT_OUTBOUND_BALANCE_WARMUP_TIMEOUT: Duration = Duration::from_secs(1);
 
 const DEFAULT_DESTINATION_CLIENT_CONCURRENCY_LIMIT: usize = 100;
 
+const DEFAULT_HTTP1_MAX_IDLE_CONNECTIONS: usize = 100;
+const DEFAULT_HTTP1_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+const DEFAULT_GLOBAL_MAX_IN_FLIGHT: usize = 20_000;
+const DEFAULT_GLOBAL_MAX_QUEUED: usize = 10_000;
+
+const DEFAULT_INBOUND_RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_OUTBOUND_CIRCUIT_BREAKER_WINDOW: usize = 20;
+const DEFAULT_OUTBOUND_CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(10);
+
+const DEFAULT_MAX_TRAILER_FIELDS: usize = 64;
+const DEFAULT_MAX_TRAILER_BYTES: usize = 16 * 1024;
+
+const DEFAULT_OUTBOUND_MAX_LONG_LIVED_STREAMS: usize = 10_000;
+const DEFAULT_LONG_LIVED_STREAM_THRESHOLD: Duration = Duration::from_secs(10);
+
+const DEFAULT_DNS_CACHE_SIZE: usize = 1_000;
+const DEFAULT_DNS_ERROR_TTL: Duration = Duration::from_secs(3);
+
 const DEFAULT_DESTINATION_GET_SUFFIXES: &str = "svc.cluster.local.";
 const DEFAULT_DESTINATION_PROFILE_SUFFIXES: &str = "svc.cluster.local.";
+const DEFAULT_DESTINATION_PROFILE_UPDATE_WINDOW: Duration = Duration::from_millis(100);
+const DEFAULT_DESTINATION_PROFILE_BACKOFF_MAX: Duration = Duration::from_secs(60);
 
 // By default, we keep a list of known assigned ports of server-first protocols.
 //
@@ -304,6 +950,21 @@ impl Config {
         opts.negative_max_ttl = self.dns_max_ttl;
         opts
     }
+
+    /// Builds the HTTP/2 settings for inbound connections.
+    pub fn inbound_h2_settings(&self) -> h2::server::Builder {
+        let mut h2 = h2::server::Builder::default();
+        if let Some(max) = self.inbound_h2_max_concurrent_streams {
+            h2.max_concurrent_streams(max);
+        }
+        if let Some(sz) = self.inbound_h2_initial_stream_window {
+            h2.initial_window_size(sz);
+        }
+        if let Some(sz) = self.inbound_h2_initial_connection_window {
+            h2.initial_connection_window_size(sz);
+        }
+        h2
+    }
 }
 
 impl<'a> TryFrom<&'a Strings> for Config {
@@ -325,18 +986,102 @@ impl<'a> TryFrom<&'a Strings> for Config {
             strings, ENV_INBOUND_CONNECT_TIMEOUT, DEPRECATED_ENV_PRIVATE_CONNECT_TIMEOUT, parse_duration);
         let outbound_connect_timeout = parse_deprecated(
             strings, ENV_OUTBOUND_CONNECT_TIMEOUT, DEPRECATED_ENV_PUBLIC_CONNECT_TIMEOUT, parse_duration);
+        let connect_backoff_min = parse(strings, ENV_CONNECT_BACKOFF_MIN, parse_duration);
+        let connect_backoff_max = parse(strings, ENV_CONNECT_BACKOFF_MAX, parse_duration);
+        let connect_backoff_jitter = parse(strings, ENV_CONNECT_BACKOFF_JITTER, parse_number);
+        let connect_max_attempts = parse(strings, ENV_CONNECT_MAX_ATTEMPTS, parse_number);
+        let connect_nodelay = parse(strings, ENV_CONNECT_NODELAY, parse_bool);
+        let connect_keepalive = parse(strings, ENV_CONNECT_KEEPALIVE, parse_duration);
+        let outbound_proxy_protocol = parse(strings, ENV_OUTBOUND_PROXY_PROTOCOL, parse_proxy_protocol);
         let inbound_disable_ports = parse(strings, ENV_INBOUND_PORTS_DISABLE_PROTOCOL_DETECTION, parse_port_set);
         let outbound_disable_ports = parse(strings, ENV_OUTBOUND_PORTS_DISABLE_PROTOCOL_DETECTION, parse_port_set);
         let inbound_router_capacity = parse(strings, ENV_INBOUND_ROUTER_CAPACITY, parse_number);
         let outbound_router_capacity = parse(strings, ENV_OUTBOUND_ROUTER_CAPACITY, parse_number);
         let inbound_router_max_idle_age = parse(strings, ENV_INBOUND_ROUTER_MAX_IDLE_AGE, parse_duration);
         let outbound_router_max_idle_age = parse(strings, ENV_OUTBOUND_ROUTER_MAX_IDLE_AGE, parse_duration);
+        let router_retry_after = parse(strings, ENV_ROUTER_RETRY_AFTER, parse_duration);
+        let inbound_sni_ports = parse(strings, ENV_INBOUND_SNI_PORTS, parse_sni_ports);
+        let outbound_sni_routes = parse(strings, ENV_OUTBOUND_SNI_ROUTES, parse_sni_routes);
+        let inbound_content_sniff_ports =
+            parse(strings, ENV_INBOUND_CONTENT_SNIFF_PORTS, parse_port_set);
+        let outbound_balance_strategy =
+            parse(strings, ENV_OUTBOUND_BALANCE_STRATEGY, parse_balance_strategy);
+        let outbound_balance_ejection_window =
+            parse(strings, ENV_OUTBOUND_BALANCE_EJECTION_WINDOW, parse_duration);
+        let outbound_balance_ejection_max_failures =
+            parse(strings, ENV_OUTBOUND_BALANCE_EJECTION_MAX_FAILURES, parse_number);
+        let outbound_balance_hash_key =
+            parse(strings, ENV_OUTBOUND_BALANCE_HASH_KEY, parse_balance_hash_key);
+        let outbound_balance_min_ready_endpoints =
+            parse(strings, ENV_OUTBOUND_BALANCE_MIN_READY_ENDPOINTS, parse_number);
+        let outbound_balance_warmup_timeout =
+            parse(strings, ENV_OUTBOUND_BALANCE_WARMUP_TIMEOUT, parse_duration);
+        let outbound_balance_drain_timeout =
+            parse(strings, ENV_OUTBOUND_BALANCE_DRAIN_TIMEOUT, parse_duration);
+        let detect_protocol_timeout =
+            parse(strings, ENV_DETECT_PROTOCOL_TIMEOUT, parse_duration);
+        let on_detect_protocol_timeout =
+            parse(strings, ENV_DETECT_PROTOCOL_TIMEOUT_ACTION, parse_detect_timeout_action);
+        let tcp_shutdown = parse(strings, ENV_TCP_SHUTDOWN, parse_tcp_shutdown);
+        let http1_rewrite_host = parse(strings, ENV_HTTP1_REWRITE_HOST, parse_bool);
+        let http1_reuse_without_authority =
+            parse(strings, ENV_HTTP1_REUSE_WITHOUT_AUTHORITY, parse_bool);
+        let outbound_gzip_min_length = parse(strings, ENV_OUTBOUND_GZIP_MIN_LENGTH, parse_number);
+        let h2c_upgrades = parse(strings, ENV_H2C_UPGRADES, parse_bool);
+        let route_default_timeout = parse(strings, ENV_ROUTE_DEFAULT_TIMEOUT, parse_duration);
+        let h2_idle_timeout = parse(strings, ENV_H2_IDLE_TIMEOUT, parse_duration);
+        let outbound_h2_initial_stream_window =
+            parse(strings, ENV_OUTBOUND_H2_INITIAL_STREAM_WINDOW, parse_number);
+        let outbound_h2_initial_connection_window =
+            parse(strings, ENV_OUTBOUND_H2_INITIAL_CONNECTION_WINDOW, parse_number);
+        let inbound_h2_max_concurrent_streams =
+            parse(strings, ENV_INBOUND_H2_MAX_CONCURRENT_STREAMS, parse_number);
+        let inbound_h2_initial_stream_window =
+            parse(strings, ENV_INBOUND_H2_INITIAL_STREAM_WINDOW, parse_number);
+        let inbound_h2_initial_connection_window =
+            parse(strings, ENV_INBOUND_H2_INITIAL_CONNECTION_WINDOW, parse_number);
+        let http1_max_idle_connections =
+            parse(strings, ENV_HTTP1_MAX_IDLE_CONNECTIONS, parse_number);
+        let http1_idle_timeout = parse(strings, ENV_HTTP1_IDLE_TIMEOUT, parse_duration);
+        let max_connections = parse(strings, ENV_MAX_CONNECTIONS, parse_number);
+        let drain_timeout = parse(strings, ENV_DRAIN_TIMEOUT, parse_duration);
+        let latency_breakdown = parse(strings, ENV_LATENCY_BREAKDOWN, parse_bool);
+        let global_max_in_flight = parse(strings, ENV_GLOBAL_MAX_IN_FLIGHT, parse_number);
+        let global_max_queued = parse(strings, ENV_GLOBAL_MAX_QUEUED, parse_number);
+        let inbound_rate_limit_requests =
+            parse(strings, ENV_INBOUND_RATE_LIMIT_REQUESTS, parse_number);
+        let inbound_rate_limit_interval =
+            parse(strings, ENV_INBOUND_RATE_LIMIT_INTERVAL, parse_duration);
+        let outbound_circuit_breaker_failure_ratio =
+            parse(strings, ENV_OUTBOUND_CIRCUIT_BREAKER_FAILURE_RATIO, parse_number);
+        let outbound_circuit_breaker_window =
+            parse(strings, ENV_OUTBOUND_CIRCUIT_BREAKER_WINDOW, parse_number);
+        let outbound_circuit_breaker_cooldown =
+            parse(strings, ENV_OUTBOUND_CIRCUIT_BREAKER_COOLDOWN, parse_duration);
+        let request_id_header = parse(strings, ENV_REQUEST_ID_HEADER, parse_header_name);
+        let request_id_overwrite = parse(strings, ENV_REQUEST_ID_OVERWRITE, parse_bool);
+        let max_trailer_fields = parse(strings, ENV_MAX_TRAILER_FIELDS, parse_number);
+        let max_trailer_bytes = parse(strings, ENV_MAX_TRAILER_BYTES, parse_number);
+        let outbound_max_long_lived_streams =
+            parse(strings, ENV_OUTBOUND_MAX_LONG_LIVED_STREAMS, parse_number);
+        let long_lived_stream_threshold =
+            parse(strings, ENV_LONG_LIVED_STREAM_THRESHOLD, parse_duration);
         let destination_concurrency_limit =
             parse(strings, ENV_DESTINATION_CLIENT_CONCURRENCY_LIMIT, parse_number);
         let destination_get_suffixes =
             parse(strings, ENV_DESTINATION_GET_SUFFIXES, parse_dns_suffixes);
         let destination_profile_suffixes =
             parse(strings, ENV_DESTINATION_PROFILE_SUFFIXES, parse_dns_suffixes);
+        let destination_srv_suffixes =
+            parse(strings, ENV_DESTINATION_SRV_SUFFIXES, parse_dns_suffixes);
+        let destination_profile_update_window =
+            parse(strings, ENV_DESTINATION_PROFILE_UPDATE_WINDOW, parse_duration);
+        let destination_profile_backoff_max =
+            parse(strings, ENV_DESTINATION_PROFILE_BACKOFF_MAX, parse_duration);
+        let destination_profile_failover_hosts_and_ports =
+            parse(strings, ENV_DESTINATION_PROFILE_FAILOVER_URLS, parse_urls);
+        let destination_startup_policy =
+            parse(strings, ENV_DESTINATION_STARTUP_POLICY, parse_startup_policy);
         let tls_trust_anchors = parse(strings, ENV_TLS_TRUST_ANCHORS, parse_path);
         let tls_end_entity_cert = parse(strings, ENV_TLS_CERT, parse_path);
         let tls_private_key = parse(strings, ENV_TLS_PRIVATE_KEY, parse_path);
@@ -345,8 +1090,19 @@ impl<'a> TryFrom<&'a Strings> for Config {
         let bind_timeout = parse(strings, ENV_BIND_TIMEOUT, parse_duration);
         let resolv_conf_path = strings.get(ENV_RESOLV_CONF);
         let metrics_retain_idle = parse(strings, ENV_METRICS_RETAIN_IDLE, parse_duration);
+        let metrics_latency_buckets = parse(strings, ENV_METRICS_LATENCY_BUCKETS, parse_latency_buckets);
         let dns_min_ttl = parse(strings, ENV_DNS_MIN_TTL, parse_duration);
         let dns_max_ttl = parse(strings, ENV_DNS_MAX_TTL, parse_duration);
+        let dns_cache = parse(strings, ENV_DNS_CACHE, parse_bool);
+        let dns_cache_size = parse(strings, ENV_DNS_CACHE_SIZE, parse_number);
+        let dns_error_ttl = parse(strings, ENV_DNS_ERROR_TTL, parse_duration);
+        let dns_canonicalize_skip_suffixes =
+            parse(strings, ENV_DNS_CANONICALIZE_SKIP_SUFFIXES, parse_dns_suffixes);
+        let response_server_id_header =
+            parse(strings, ENV_RESPONSE_SERVER_ID_HEADER, parse_header_name);
+        let response_latency_header =
+            parse(strings, ENV_RESPONSE_LATENCY_HEADER, parse_header_name);
+        let dns_ip_family = parse(strings, ENV_DNS_IP_FAMILY, parse_ip_family);
         let pod_namespace = strings.get(ENV_POD_NAMESPACE).and_then(|maybe_value| {
             // There cannot be a default pod namespace, and the pod namespace is required.
             maybe_value.ok_or_else(|| {
@@ -459,6 +1215,17 @@ impl<'a> TryFrom<&'a Strings> for Config {
             outbound_connect_timeout: outbound_connect_timeout?
                 .unwrap_or(DEFAULT_OUTBOUND_CONNECT_TIMEOUT),
 
+            connect_backoff_min: connect_backoff_min?.unwrap_or(DEFAULT_CONNECT_BACKOFF_MIN),
+            connect_backoff_max: connect_backoff_max?.unwrap_or(DEFAULT_CONNECT_BACKOFF_MAX),
+            connect_backoff_jitter: connect_backoff_jitter?
+                .unwrap_or(DEFAULT_CONNECT_BACKOFF_JITTER),
+            connect_max_attempts: connect_max_attempts?,
+            connect_socket_options: connect::SocketOptions {
+                nodelay: connect_nodelay?.unwrap_or(true),
+                keepalive: connect_keepalive?,
+            },
+            outbound_proxy_protocol: outbound_proxy_protocol?,
+
             inbound_ports_disable_protocol_detection: inbound_disable_ports?
                 .unwrap_or_else(|| default_disable_ports_protocol_detection()),
             outbound_ports_disable_protocol_detection: outbound_disable_ports?
@@ -473,6 +1240,82 @@ impl<'a> TryFrom<&'a Strings> for Config {
                 .unwrap_or(DEFAULT_INBOUND_ROUTER_MAX_IDLE_AGE),
             outbound_router_max_idle_age: outbound_router_max_idle_age?
                 .unwrap_or(DEFAULT_OUTBOUND_ROUTER_MAX_IDLE_AGE),
+            router_retry_after: router_retry_after?
+                .unwrap_or(DEFAULT_ROUTER_RETRY_AFTER),
+
+            inbound_sni_ports: inbound_sni_ports?.unwrap_or_default(),
+            outbound_sni_routes: outbound_sni_routes?.unwrap_or_default(),
+            inbound_content_sniff_ports: inbound_content_sniff_ports?.unwrap_or_default(),
+
+            outbound_balance_strategy: outbound_balance_strategy?.unwrap_or_default(),
+            outbound_balance_ejection_window: outbound_balance_ejection_window?
+                .unwrap_or(balance::eject::DEFAULT_WINDOW),
+            outbound_balance_ejection_max_failures: outbound_balance_ejection_max_failures?
+                .unwrap_or(balance::eject::DEFAULT_MAX_FAILURES),
+            outbound_balance_hash_key: outbound_balance_hash_key?.unwrap_or_default(),
+            outbound_balance_min_ready_endpoints: outbound_balance_min_ready_endpoints?
+                .unwrap_or(DEFAULT_OUTBOUND_BALANCE_MIN_READY_ENDPOINTS),
+            outbound_balance_warmup_timeout: outbound_balance_warmup_timeout?
+                .unwrap_or(DEFAULT_OUTBOUND_BALANCE_WARMUP_TIMEOUT),
+            outbound_balance_drain_timeout: outbound_balance_drain_timeout?,
+
+            detect_protocol_timeout: detect_protocol_timeout?
+                .unwrap_or(server::DEFAULT_DETECT_PROTOCOL_TIMEOUT),
+            on_detect_protocol_timeout: on_detect_protocol_timeout?
+                .unwrap_or(server::OnDetectTimeout::Forward),
+
+            tcp_shutdown: tcp_shutdown?.unwrap_or_default(),
+
+            http1_rewrite_host: http1_rewrite_host?.unwrap_or(false),
+            http1_reuse_without_authority: http1_reuse_without_authority?.unwrap_or(false),
+
+            outbound_gzip_min_length: outbound_gzip_min_length?,
+
+            h2c_upgrades: h2c_upgrades?.unwrap_or(false),
+
+            route_default_timeout: route_default_timeout?,
+
+            h2_idle_timeout: h2_idle_timeout?,
+            outbound_h2_windows: client::Http2Windows::new(
+                outbound_h2_initial_stream_window?,
+                outbound_h2_initial_connection_window?,
+            ),
+            inbound_h2_max_concurrent_streams: inbound_h2_max_concurrent_streams?,
+            inbound_h2_initial_stream_window: inbound_h2_initial_stream_window?,
+            inbound_h2_initial_connection_window: inbound_h2_initial_connection_window?,
+            http1_pool: client::Http1Pool::new(
+                http1_max_idle_connections?.unwrap_or(DEFAULT_HTTP1_MAX_IDLE_CONNECTIONS),
+                http1_idle_timeout?.unwrap_or(DEFAULT_HTTP1_IDLE_TIMEOUT),
+            ),
+            max_connections: max_connections?,
+            drain_timeout: drain_timeout?,
+
+            latency_breakdown: latency_breakdown?.unwrap_or(false),
+
+            global_max_in_flight: global_max_in_flight?
+                .unwrap_or(DEFAULT_GLOBAL_MAX_IN_FLIGHT),
+            global_max_queued: global_max_queued?.unwrap_or(DEFAULT_GLOBAL_MAX_QUEUED),
+            inbound_rate_limit: {
+                let interval = inbound_rate_limit_interval?
+                    .unwrap_or(DEFAULT_INBOUND_RATE_LIMIT_INTERVAL);
+                inbound_rate_limit_requests?.map(|n| ratelimit::Limit::new(n, interval))
+            },
+            outbound_circuit_breaker: {
+                let window = outbound_circuit_breaker_window?
+                    .unwrap_or(DEFAULT_OUTBOUND_CIRCUIT_BREAKER_WINDOW);
+                let cooldown = outbound_circuit_breaker_cooldown?
+                    .unwrap_or(DEFAULT_OUTBOUND_CIRCUIT_BREAKER_COOLDOWN);
+                outbound_circuit_breaker_failure_ratio?
+                    .map(|ratio| circuit_breaker::Config::new(window, ratio, cooldown))
+            },
+            request_id_header: request_id_header?,
+            request_id_overwrite: request_id_overwrite?.unwrap_or(false),
+            max_trailer_fields: max_trailer_fields?.unwrap_or(DEFAULT_MAX_TRAILER_FIELDS),
+            max_trailer_bytes: max_trailer_bytes?.unwrap_or(DEFAULT_MAX_TRAILER_BYTES),
+            outbound_max_long_lived_streams: outbound_max_long_lived_streams?
+                .unwrap_or(DEFAULT_OUTBOUND_MAX_LONG_LIVED_STREAMS),
+            long_lived_stream_threshold: long_lived_stream_threshold?
+                .unwrap_or(DEFAULT_LONG_LIVED_STREAM_THRESHOLD),
 
             destination_concurrency_limit: destination_concurrency_limit?
                 .unwrap_or(DEFAULT_DESTINATION_CLIENT_CONCURRENCY_LIMIT),
@@ -483,6 +1326,20 @@ impl<'a> TryFrom<&'a Strings> for Config {
             destination_profile_suffixes: destination_profile_suffixes?
                 .unwrap_or(parse_dns_suffixes(DEFAULT_DESTINATION_PROFILE_SUFFIXES).unwrap()),
 
+            destination_srv_suffixes: destination_srv_suffixes?.unwrap_or_default(),
+
+            destination_profile_update_window: destination_profile_update_window?
+                .unwrap_or(DEFAULT_DESTINATION_PROFILE_UPDATE_WINDOW),
+
+            destination_profile_backoff_max: destination_profile_backoff_max?
+                .unwrap_or(DEFAULT_DESTINATION_PROFILE_BACKOFF_MAX),
+
+            destination_profile_failover_hosts_and_ports:
+                destination_profile_failover_hosts_and_ports?.unwrap_or_default(),
+
+            destination_startup_policy: destination_startup_policy?
+                .unwrap_or(StartupPolicy::ServeWithFallback),
+
             tls_settings,
 
             resolv_conf_path: resolv_conf_path?
@@ -493,6 +1350,9 @@ impl<'a> TryFrom<&'a Strings> for Config {
             control_connect_timeout,
 
             metrics_retain_idle: metrics_retain_idle?.unwrap_or(DEFAULT_METRICS_RETAIN_IDLE),
+            metrics_latency_bounds: metrics_latency_buckets?
+                .map(metrics::Bounds::leak)
+                .unwrap_or(latency::BOUNDS),
 
             bind_timeout: bind_timeout?.unwrap_or(DEFAULT_BIND_TIMEOUT),
 
@@ -501,6 +1361,17 @@ impl<'a> TryFrom<&'a Strings> for Config {
             dns_min_ttl: dns_min_ttl?,
 
             dns_max_ttl: dns_max_ttl?,
+
+            dns_cache: dns_cache?.unwrap_or(false),
+
+            dns_cache_size: dns_cache_size?.unwrap_or(DEFAULT_DNS_CACHE_SIZE),
+            dns_error_ttl: dns_error_ttl?.unwrap_or(DEFAULT_DNS_ERROR_TTL),
+            dns_canonicalize_skip_suffixes: dns_canonicalize_skip_suffixes?.unwrap_or_default(),
+
+            response_server_id_header: response_server_id_header?,
+            response_latency_header: response_latency_header?,
+
+            dns_ip_family: dns_ip_family?.unwrap_or_default(),
         })
     }
 }
@@ -603,6 +1474,10 @@ fn parse_url(s: &str) -> Result<Addr, ParseError> {
         .map_err(|e| ParseError::UrlError(UrlError::AuthorityError(e)))
 }
 
+fn parse_urls(list: &str) -> Result<Vec<Addr>, ParseError> {
+    list.split(',').map(|s| parse_url(s.trim())).collect()
+}
+
 fn parse_port_set(s: &str) -> Result<IndexSet<u16>, ParseError> {
     let mut set = IndexSet::new();
     for num in s.split(',') {
@@ -611,6 +1486,50 @@ fn parse_port_set(s: &str) -> Result<IndexSet<u16>, ParseError> {
     Ok(set)
 }
 
+/// Parses a list of increasing bucket bounds, followed by an unbounded
+/// bucket.
+fn parse_latency_buckets(s: &str) -> Result<Vec<metrics::Bucket>, ParseError> {
+    let mut buckets = Vec::new();
+    let mut prior = 0;
+    for ms in s.split(',') {
+        let ms = parse_number::<u64>(ms.trim())?;
+        if ms <= prior {
+            return Err(ParseError::NotALatencyBucketList);
+        }
+        buckets.push(metrics::Bucket::Le(ms));
+        prior = ms;
+    }
+    buckets.push(metrics::Bucket::Inf);
+    Ok(buckets)
+}
+
+fn parse_sni_ports(s: &str) -> Result<IndexMap<tls::Identity, u16>, ParseError> {
+    let mut ports = IndexMap::new();
+    for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let name = parts.next().map(str::trim).ok_or(ParseError::NotAnSniPort)?;
+        let port = parts.next().ok_or(ParseError::NotAnSniPort)?;
+        let name = tls::Identity::from_sni_hostname(name.as_bytes())
+            .map_err(|()| ParseError::NotAnSniPort)?;
+        ports.insert(name, parse_number::<u16>(port.trim())?);
+    }
+    Ok(ports)
+}
+
+fn parse_sni_routes(s: &str) -> Result<IndexMap<tls::Identity, SocketAddr>, ParseError> {
+    let mut routes = IndexMap::new();
+    for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let name = parts.next().map(str::trim).ok_or(ParseError::NotAnSniRoute)?;
+        let addr = parts.next().ok_or(ParseError::NotAnSniRoute)?;
+        let name = tls::Identity::from_sni_hostname(name.as_bytes())
+            .map_err(|()| ParseError::NotAnSniRoute)?;
+        let addr = addr.trim().parse().map_err(|_| ParseError::NotAnSniRoute)?;
+        routes.insert(name, addr);
+    }
+    Ok(routes)
+}
+
 fn parse<T, Parse>(strings: &Strings, name: &str, parse: Parse) -> Result<Option<T>, Error>
     where Parse: FnOnce(&str) -> Result<T, ParseError> {
     match strings.get(name)? {
@@ -643,6 +1562,84 @@ where
     }
  }
 
+fn parse_balance_strategy(s: &str) -> Result<balance::Strategy, ParseError> {
+    match s.trim() {
+        "p2c-peak-ewma" => Ok(balance::Strategy::default()),
+        "round-robin" => Ok(balance::Strategy::RoundRobin),
+        "least-loaded" => Ok(balance::Strategy::LeastLoaded),
+        "consistent-hash" => Ok(balance::Strategy::ConsistentHash),
+        _ => Err(ParseError::NotABalanceStrategy),
+    }
+}
+
+fn parse_balance_hash_key(s: &str) -> Result<balance::hash::Key, ParseError> {
+    let s = s.trim();
+    if s == "source-ip" {
+        return Ok(balance::hash::Key::SourceIp);
+    }
+
+    let mut parts = s.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("header"), Some(name)) => http::header::HeaderName::from_bytes(name.as_bytes())
+            .map(balance::hash::Key::Header)
+            .map_err(|_| ParseError::NotABalanceHashKey),
+        (Some("cookie"), Some(name)) if !name.is_empty() => {
+            Ok(balance::hash::Key::Cookie(name.to_owned()))
+        }
+        _ => Err(ParseError::NotABalanceHashKey),
+    }
+}
+
+fn parse_header_name(s: &str) -> Result<http::header::HeaderName, ParseError> {
+    http::header::HeaderName::from_bytes(s.trim().as_bytes())
+        .map_err(|_| ParseError::NotAHeaderName)
+}
+
+fn parse_ip_family(s: &str) -> Result<dns::IpFamilyPreference, ParseError> {
+    match s.trim() {
+        "any" => Ok(dns::IpFamilyPreference::Any),
+        "ipv4" => Ok(dns::IpFamilyPreference::V4),
+        "ipv6" => Ok(dns::IpFamilyPreference::V6),
+        _ => Err(ParseError::NotAnIpFamily),
+    }
+}
+
+fn parse_startup_policy(s: &str) -> Result<StartupPolicy, ParseError> {
+    match s.trim() {
+        "fail-ready" => Ok(StartupPolicy::FailReady),
+        "serve-with-fallback" => Ok(StartupPolicy::ServeWithFallback),
+        _ => Err(ParseError::NotAStartupPolicy),
+    }
+}
+
+fn parse_bool(s: &str) -> Result<bool, ParseError> {
+    s.trim().parse().map_err(|_| ParseError::NotABoolean)
+}
+
+fn parse_detect_timeout_action(s: &str) -> Result<server::OnDetectTimeout, ParseError> {
+    match s.trim() {
+        "forward" => Ok(server::OnDetectTimeout::Forward),
+        "close" => Ok(server::OnDetectTimeout::Close),
+        _ => Err(ParseError::NotADetectTimeoutAction),
+    }
+}
+
+fn parse_tcp_shutdown(s: &str) -> Result<tcp::Shutdown, ParseError> {
+    match s.trim() {
+        "half-close" => Ok(tcp::Shutdown::HalfClose),
+        "full" => Ok(tcp::Shutdown::Full),
+        _ => Err(ParseError::NotATcpShutdown),
+    }
+}
+
+fn parse_proxy_protocol(s: &str) -> Result<proxy_protocol::Version, ParseError> {
+    match s.trim() {
+        "v1" => Ok(proxy_protocol::Version::V1),
+        "v2" => Ok(proxy_protocol::Version::V2),
+        _ => Err(ParseError::NotAProxyProtocolVersion),
+    }
+}
+
 fn parse_dns_suffixes(list: &str) -> Result<Vec<dns::Suffix>, ParseError> {
     let mut suffixes = Vec::new();
     for item in list.split(',') {
@@ -738,6 +1735,134 @@ mod tests {
         assert_eq!(parse_duration("1"), Err(ParseError::NotADuration));
     }
 
+    #[test]
+    fn balance_strategies() {
+        assert_eq!(parse_balance_strategy("p2c-peak-ewma"), Ok(balance::Strategy::default()));
+        assert_eq!(parse_balance_strategy(" round-robin "), Ok(balance::Strategy::RoundRobin));
+        assert_eq!(parse_balance_strategy("least-loaded"), Ok(balance::Strategy::LeastLoaded));
+        assert_eq!(parse_balance_strategy("consistent-hash"), Ok(balance::Strategy::ConsistentHash));
+        assert_eq!(parse_balance_strategy("random"), Err(ParseError::NotABalanceStrategy));
+    }
+
+    #[test]
+    fn balance_hash_keys() {
+        assert_eq!(parse_balance_hash_key(" source-ip "), Ok(balance::hash::Key::SourceIp));
+        assert_eq!(
+            parse_balance_hash_key("header:X-Session-Id"),
+            Ok(balance::hash::Key::Header(http::header::HeaderName::from_static("x-session-id")))
+        );
+        assert_eq!(
+            parse_balance_hash_key("cookie:session"),
+            Ok(balance::hash::Key::Cookie("session".to_owned()))
+        );
+        assert_eq!(parse_balance_hash_key("header:"), Err(ParseError::NotABalanceHashKey));
+        assert_eq!(parse_balance_hash_key("cookie:"), Err(ParseError::NotABalanceHashKey));
+        assert_eq!(parse_balance_hash_key("path"), Err(ParseError::NotABalanceHashKey));
+    }
+
+    #[test]
+    fn header_names() {
+        assert_eq!(
+            parse_header_name(" L5d-Request-Id "),
+            Ok(http::header::HeaderName::from_static("l5d-request-id"))
+        );
+        assert_eq!(parse_header_name(""), Err(ParseError::NotAHeaderName));
+        assert_eq!(parse_header_name("request id"), Err(ParseError::NotAHeaderName));
+    }
+
+    #[test]
+    fn urls() {
+        let addrs = parse_urls("tcp://a.example.com:8086, tcp://127.0.0.1:8087").unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0], parse_url("tcp://a.example.com:8086").unwrap());
+        assert_eq!(addrs[1], parse_url("tcp://127.0.0.1:8087").unwrap());
+        assert!(parse_urls("tcp://a.example.com:8086,http://b.example.com:8086").is_err());
+        assert!(parse_urls("").is_err());
+    }
+
+    #[test]
+    fn ip_families() {
+        assert_eq!(parse_ip_family("any"), Ok(dns::IpFamilyPreference::Any));
+        assert_eq!(parse_ip_family(" ipv4 "), Ok(dns::IpFamilyPreference::V4));
+        assert_eq!(parse_ip_family("ipv6"), Ok(dns::IpFamilyPreference::V6));
+        assert_eq!(parse_ip_family("inet6"), Err(ParseError::NotAnIpFamily));
+    }
+
+    #[test]
+    fn startup_policies() {
+        assert_eq!(parse_startup_policy("fail-ready"), Ok(StartupPolicy::FailReady));
+        assert_eq!(
+            parse_startup_policy(" serve-with-fallback "),
+            Ok(StartupPolicy::ServeWithFallback)
+        );
+        assert_eq!(parse_startup_policy("fail"), Err(ParseError::NotAStartupPolicy));
+    }
+
+    #[test]
+    fn booleans() {
+        assert_eq!(parse_bool("true"), Ok(true));
+        assert_eq!(parse_bool(" false "), Ok(false));
+        assert_eq!(parse_bool("yes"), Err(ParseError::NotABoolean));
+    }
+
+    #[test]
+    fn detect_timeout_actions() {
+        assert_eq!(parse_detect_timeout_action(" forward "), Ok(server::OnDetectTimeout::Forward));
+        assert_eq!(parse_detect_timeout_action("close"), Ok(server::OnDetectTimeout::Close));
+        assert_eq!(parse_detect_timeout_action("drop"), Err(ParseError::NotADetectTimeoutAction));
+    }
+
+    #[test]
+    fn tcp_shutdowns() {
+        assert_eq!(parse_tcp_shutdown("half-close"), Ok(tcp::Shutdown::default()));
+        assert_eq!(parse_tcp_shutdown(" full "), Ok(tcp::Shutdown::Full));
+        assert_eq!(parse_tcp_shutdown("half"), Err(ParseError::NotATcpShutdown));
+    }
+
+    #[test]
+    fn latency_buckets() {
+        use metrics::Bucket::{Inf, Le};
+        assert_eq!(parse_latency_buckets("1, 10,100"), Ok(vec![Le(1), Le(10), Le(100), Inf]));
+        assert_eq!(parse_latency_buckets("10,1"), Err(ParseError::NotALatencyBucketList));
+        assert_eq!(parse_latency_buckets("0"), Err(ParseError::NotALatencyBucketList));
+        assert_eq!(parse_latency_buckets("1,ten"), Err(ParseError::NotANumber));
+    }
+
+    #[test]
+    fn proxy_protocol_versions() {
+        assert_eq!(parse_proxy_protocol("v1"), Ok(proxy_protocol::Version::V1));
+        assert_eq!(parse_proxy_protocol(" v2 "), Ok(proxy_protocol::Version::V2));
+        assert_eq!(parse_proxy_protocol("2"), Err(ParseError::NotAProxyProtocolVersion));
+    }
+
+    #[test]
+    fn sni_ports() {
+        let ports = parse_sni_ports("web.ns.svc.cluster.local=8080, admin.ns.svc.cluster.local=9990")
+            .expect("valid");
+        let web = tls::Identity::from_sni_hostname(b"web.ns.svc.cluster.local").unwrap();
+        let admin = tls::Identity::from_sni_hostname(b"admin.ns.svc.cluster.local").unwrap();
+        assert_eq!(ports.get(&web), Some(&8080));
+        assert_eq!(ports.get(&admin), Some(&9990));
+
+        assert_eq!(parse_sni_ports("").map(|p| p.len()), Ok(0));
+        assert_eq!(parse_sni_ports("web.ns.svc.cluster.local").err(), Some(ParseError::NotAnSniPort));
+        assert_eq!(parse_sni_ports("web.ns.svc.cluster.local=http").err(), Some(ParseError::NotANumber));
+    }
+
+    #[test]
+    fn sni_routes() {
+        let routes = parse_sni_routes("db.example.com=10.1.1.1:5432, cache.example.com=10.1.1.2:6379")
+            .expect("valid");
+        let db = tls::Identity::from_sni_hostname(b"db.example.com").unwrap();
+        let cache = tls::Identity::from_sni_hostname(b"cache.example.com").unwrap();
+        assert_eq!(routes.get(&db), Some(&"10.1.1.1:5432".parse().unwrap()));
+        assert_eq!(routes.get(&cache), Some(&"10.1.1.2:6379".parse().unwrap()));
+
+        assert_eq!(parse_sni_routes("").map(|r| r.len()), Ok(0));
+        assert_eq!(parse_sni_routes("db.example.com").err(), Some(ParseError::NotAnSniRoute));
+        assert_eq!(parse_sni_routes("db.example.com=5432").err(), Some(ParseError::NotAnSniRoute));
+    }
+
     #[test]
     fn dns_suffixes() {
         fn p(s: &str) -> Result<Vec<String>, ParseError> {
@@ -770,4 +1895,26 @@ mod tests {
             "names are coerced to lowercase"
         );
      }
+
+    #[test]
+    fn inbound_h2_settings() {
+        let mut env = TestEnv::new();
+        env.put(ENV_POD_NAMESPACE, "ns".to_owned());
+        let config = Config::try_from(&env).unwrap();
+        assert_eq!(config.inbound_h2_max_concurrent_streams, None);
+        assert_eq!(config.inbound_h2_initial_stream_window, None);
+        assert_eq!(config.inbound_h2_initial_connection_window, None);
+
+        env.put(ENV_INBOUND_H2_MAX_CONCURRENT_STREAMS, "100".to_owned());
+        env.put(ENV_INBOUND_H2_INITIAL_STREAM_WINDOW, "1048576".to_owned());
+        env.put(ENV_INBOUND_H2_INITIAL_CONNECTION_WINDOW, "4194304".to_owned());
+        let config = Config::try_from(&env).unwrap();
+        assert_eq!(config.inbound_h2_max_concurrent_streams, Some(100));
+        assert_eq!(config.inbound_h2_initial_stream_window, Some(1_048_576));
+        assert_eq!(config.inbound_h2_initial_connection_window, Some(4_194_304));
+        let _ = config.inbound_h2_settings();
+
+        env.put(ENV_INBOUND_H2_MAX_CONCURRENT_STREAMS, "lots".to_owned());
+        assert!(Config::try_from(&env).is_err());
+    }
 }
@@ -1,11 +1,13 @@
 use http;
+use indexmap::IndexMap;
 use std::fmt;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use super::classify;
 use super::dst::DstAddr;
 use proxy::http::{router, settings};
-use proxy::server::Source;
+use proxy::server::{DetectionPort, Source};
 use tap;
 use transport::{connect, tls};
 use {Conditional, NameAddr};
@@ -20,8 +22,14 @@ pub struct Endpoint {
 #[derive(Clone, Debug, Default)]
 pub struct RecognizeEndpoint {
     default_addr: Option<SocketAddr>,
+    sni_ports: Arc<IndexMap<tls::Identity, u16>>,
 }
 
+/// Skips protocol detection based on the local port to which a connection's
+/// requests are routed by its SNI server name, if one is configured.
+#[derive(Clone, Debug, Default)]
+pub struct SniDetectionPort(Arc<IndexMap<tls::Identity, u16>>);
+
 // === impl Endpoint ===
 
 impl classify::CanClassify for Endpoint {
@@ -69,7 +77,22 @@ impl fmt::Display for Endpoint {
 
 impl RecognizeEndpoint {
     pub fn new(default_addr: Option<SocketAddr>) -> Self {
-        Self { default_addr }
+        Self {
+            default_addr,
+            sni_ports: Arc::new(IndexMap::new()),
+        }
+    }
+
+    /// Routes requests received over TLS connections to the local port
+    /// configured for the connection's SNI server name.
+    ///
+    /// Requests on connections without a configured server name are routed
+    /// to their original destination.
+    pub fn with_sni_ports(self, sni_ports: IndexMap<tls::Identity, u16>) -> Self {
+        Self {
+            sni_ports: Arc::new(sni_ports),
+            ..self
+        }
     }
 }
 
@@ -79,10 +102,19 @@ impl<A> router::Recognize<http::Request<A>> for RecognizeEndpoint {
     fn recognize(&self, req: &http::Request<A>) -> Option<Self::Target> {
         let src = req.extensions().get::<Source>();
         debug!("inbound endpoint: src={:?}", src);
-        let addr = src
+        let orig_dst = src
             .and_then(Source::orig_dst_if_not_local)
             .or(self.default_addr)?;
 
+        let sni_port = src.and_then(|s| sni_port(&self.sni_ports, s));
+        let addr = match sni_port {
+            Some(port) => {
+                debug!("inbound endpoint: sni port={}", port);
+                SocketAddr::new(orig_dst.ip(), port)
+            }
+            None => orig_dst,
+        };
+
         let source_tls_status = src
             .map(|s| s.tls_status.clone())
             .unwrap_or_else(|| Conditional::None(tls::ReasonForNoTls::Disabled));
@@ -102,6 +134,28 @@ impl<A> router::Recognize<http::Request<A>> for RecognizeEndpoint {
     }
 }
 
+// === impl SniDetectionPort ===
+
+impl SniDetectionPort {
+    pub fn new(sni_ports: IndexMap<tls::Identity, u16>) -> Self {
+        SniDetectionPort(Arc::new(sni_ports))
+    }
+}
+
+impl DetectionPort for SniDetectionPort {
+    fn detection_port(&self, src: &Source) -> Option<u16> {
+        sni_port(&self.0, src).or_else(|| src.orig_dst.map(|addr| addr.port()))
+    }
+}
+
+/// Returns the local port configured for a connection's SNI server name.
+fn sni_port(sni_ports: &IndexMap<tls::Identity, u16>, src: &Source) -> Option<u16> {
+    src.tls_server_name
+        .as_ref()
+        .and_then(|name| sni_ports.get(name))
+        .cloned()
+}
+
 pub mod orig_proto_downgrade {
     use std::marker::PhantomData;
     use http;
@@ -110,23 +164,33 @@ pub mod orig_proto_downgrade {
     use svc;
 
     #[derive(Debug)]
-    pub struct Layer<A, B>(PhantomData<fn(A) -> B>);
+    pub struct Layer<A, B> {
+        registry: orig_proto::Registry,
+        _marker: PhantomData<fn(A) -> B>,
+    }
 
     #[derive(Debug)]
     pub struct Stack<M, A, B> {
         inner: M,
+        registry: orig_proto::Registry,
         _marker: PhantomData<fn(A) -> B>,
     }
 
     // === impl Layer ===
 
-    pub fn layer<A, B>() -> Layer<A, B> {
-        Layer(PhantomData)
+    pub fn layer<A, B>(registry: orig_proto::Registry) -> Layer<A, B> {
+        Layer {
+            registry,
+            _marker: PhantomData,
+        }
     }
 
     impl<A, B> Clone for Layer<A, B> {
         fn clone(&self) -> Self {
-            Layer(PhantomData)
+            Layer {
+                registry: self.registry.clone(),
+                _marker: PhantomData,
+            }
         }
     }
 
@@ -142,6 +206,7 @@ pub mod orig_proto_downgrade {
         fn bind(&self, inner: M) -> Self::Stack {
             Stack {
                 inner,
+                registry: self.registry.clone(),
                 _marker: PhantomData,
             }
         }
@@ -153,6 +218,7 @@ pub mod orig_proto_downgrade {
         fn clone(&self) -> Self {
             Stack {
                 inner: self.inner.clone(),
+                registry: self.registry.clone(),
                 _marker: PhantomData,
             }
         }
@@ -168,10 +234,8 @@ pub mod orig_proto_downgrade {
 
         fn make(&self, target: &Source) -> Result<Self::Value, Self::Error> {
             debug!("downgrading requests; source={:?}", target);
-            self
-                .inner
-                .make(&target)
-                .map(orig_proto::Downgrade::new)
+            let inner = self.inner.make(&target)?;
+            Ok(orig_proto::Downgrade::new(inner).with_metrics(self.registry.clone()))
         }
     }
 }
@@ -235,11 +299,12 @@ pub mod rewrite_loopback_addr {
 #[cfg(test)]
 mod tests {
     use http;
+    use indexmap::IndexMap;
     use std::net;
 
-    use super::{Endpoint, RecognizeEndpoint};
+    use super::{Endpoint, RecognizeEndpoint, SniDetectionPort};
     use proxy::http::router::Recognize;
-    use proxy::server::Source;
+    use proxy::server::{DetectionPort, Source};
     use transport::tls;
     use Conditional;
 
@@ -299,4 +364,57 @@ mod tests {
             RecognizeEndpoint::new(default).recognize(&req) == default.map(make_h1_endpoint)
         }
     }
+
+    fn identity(name: &str) -> tls::Identity {
+        tls::Identity::from_sni_hostname(name.as_bytes()).unwrap()
+    }
+
+    fn recognize_sni(rec: &RecognizeEndpoint, server_name: Option<&str>) -> Option<net::SocketAddr> {
+        let orig_dst = net::SocketAddr::from(([10, 1, 1, 1], 8080));
+        let local = net::SocketAddr::from(([10, 1, 1, 1], 4143));
+        let remote = net::SocketAddr::from(([10, 2, 2, 2], 43210));
+        let mut src = Source::for_test(remote, local, Some(orig_dst), Conditional::Some(()));
+        src.tls_server_name = server_name.map(identity);
+
+        let mut req = http::Request::new(());
+        req.extensions_mut().insert(src);
+        rec.recognize(&req).map(|ep| ep.addr)
+    }
+
+    #[test]
+    fn recognize_sni_ports() {
+        let mut ports = IndexMap::new();
+        ports.insert(identity("web.ns.svc.cluster.local"), 8081);
+        ports.insert(identity("admin.ns.svc.cluster.local"), 9990);
+        let rec = RecognizeEndpoint::default().with_sni_ports(ports);
+
+        let web = recognize_sni(&rec, Some("web.ns.svc.cluster.local"));
+        assert_eq!(web, Some(net::SocketAddr::from(([10, 1, 1, 1], 8081))));
+
+        let admin = recognize_sni(&rec, Some("admin.ns.svc.cluster.local"));
+        assert_eq!(admin, Some(net::SocketAddr::from(([10, 1, 1, 1], 9990))));
+
+        // Unconfigured and missing server names fall back to the original
+        // destination.
+        let other = recognize_sni(&rec, Some("other.ns.svc.cluster.local"));
+        assert_eq!(other, Some(net::SocketAddr::from(([10, 1, 1, 1], 8080))));
+        let none = recognize_sni(&rec, None);
+        assert_eq!(none, Some(net::SocketAddr::from(([10, 1, 1, 1], 8080))));
+    }
+
+    #[test]
+    fn sni_detection_ports() {
+        let mut ports = IndexMap::new();
+        ports.insert(identity("web.ns.svc.cluster.local"), 8081);
+        let detection_port = SniDetectionPort::new(ports);
+
+        let orig_dst = net::SocketAddr::from(([10, 1, 1, 1], 8080));
+        let local = net::SocketAddr::from(([10, 1, 1, 1], 4143));
+        let remote = net::SocketAddr::from(([10, 2, 2, 2], 43210));
+        let mut src = Source::for_test(remote, local, Some(orig_dst), Conditional::Some(()));
+        assert_eq!(detection_port.detection_port(&src), Some(8080));
+
+        src.tls_server_name = Some(identity("web.ns.svc.cluster.local"));
+        assert_eq!(detection_port.detection_port(&src), Some(8081));
+    }
 }
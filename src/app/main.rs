@@ -2,7 +2,6 @@ use bytes;
 use futures::{self, future, Future, Poll};
 use h2;
 use http;
-use indexmap::IndexSet;
 use std::net::SocketAddr;
 use std::thread;
 use std::time::{Duration, SystemTime};
@@ -22,7 +21,9 @@ use never::Never;
 use proxy::{
     self, buffer,
     http::{
-        client, insert_target, metrics as http_metrics, normalize_uri, profiles, router, settings,
+        cancel, circuit_breaker, client, compress, content_sniff, deadline, global_limit,
+        grpc_timeout, insert_target, metrics as http_metrics, normalize_uri, orig_proto, profiles,
+        ratelimit, request_id, router, settings, stream_limit, timing, trailer_limit,
     },
     limit, reconnect, timeout,
 };
@@ -39,7 +40,8 @@ use {Addr, Conditional};
 
 use super::config::Config;
 use super::dst::DstAddr;
-use super::profiles::Client as ProfilesClient;
+use super::profiles::{Backoff as ProfilesBackoff, Client as ProfilesClient};
+use super::readiness;
 
 /// Runs a sidecar proxy.
 ///
@@ -165,6 +167,12 @@ where
         let control_host_and_port = config.control_host_and_port.clone();
 
         info!("using controller at {:?}", control_host_and_port);
+        if !config.destination_profile_failover_hosts_and_ports.is_empty() {
+            info!(
+                "failing over profile lookups to controllers at {:?}",
+                config.destination_profile_failover_hosts_and_ports
+            );
+        }
         info!("routing on {:?}", outbound_listener.local_addr());
         info!(
             "proxying on {:?} to {:?}",
@@ -196,15 +204,24 @@ where
         let (taps, observe) = control::Observe::new(100);
 
         let (ctl_http_metrics, ctl_http_report) = {
-            let (m, r) = http_metrics::new::<ControlLabels, Class>(config.metrics_retain_idle);
+            let (m, r) = http_metrics::new::<ControlLabels, Class>(
+                config.metrics_retain_idle,
+                config.metrics_latency_bounds,
+            );
             (m, r.with_prefix("control"))
         };
 
         let (endpoint_http_metrics, endpoint_http_report) =
-            http_metrics::new::<EndpointLabels, Class>(config.metrics_retain_idle);
+            http_metrics::new::<EndpointLabels, Class>(
+                config.metrics_retain_idle,
+                config.metrics_latency_bounds,
+            );
 
         let (route_http_metrics, route_http_report) = {
-            let (m, r) = http_metrics::new::<RouteLabels, Class>(config.metrics_retain_idle);
+            let (m, r) = http_metrics::new::<RouteLabels, Class>(
+                config.metrics_retain_idle,
+                config.metrics_latency_bounds,
+            );
             (m, r.with_prefix("route"))
         };
 
@@ -212,9 +229,36 @@ where
 
         let (tls_config_sensor, tls_config_report) = telemetry::tls_config_reload::new();
 
+        let (router_metrics, router_report) = router::metrics();
+
+        let (balance_metrics, balance_report) = proxy::http::balance::count::metrics();
+
+        let (cancel_metrics, cancel_report) = cancel::metrics();
+
+        let (downgrade_metrics, downgrade_report) = orig_proto::metrics();
+
+        let (profile_stream_metrics, profile_stream_report) = super::profiles::metrics();
+
+        // Limits the number of requests in flight across both proxies.
+        let global_limit =
+            global_limit::layer(config.global_max_in_flight, config.global_max_queued);
+
+        // Limits the trailers on requests and responses in both proxies.
+        let trailer_limit =
+            trailer_limit::layer(config.max_trailer_fields, config.max_trailer_bytes);
+
+        // Ensures that requests in both proxies carry a request ID.
+        let request_id = request_id::layer(config.request_id_header.clone())
+            .with_overwrite(config.request_id_overwrite);
+
         let report = endpoint_http_report
             .and_then(route_http_report)
             .and_then(transport_report)
+            .and_then(router_report)
+            .and_then(balance_report)
+            .and_then(cancel_report)
+            .and_then(downgrade_report)
+            .and_then(profile_stream_report)
             .and_then(tls_config_report)
             .and_then(ctl_http_report)
             .and_then(telemetry::process::Report::new(start_time));
@@ -222,6 +266,15 @@ where
         let tls_client_config = tls_config_watch.client.clone();
         let tls_cfg_bg = tls_config_watch.start(tls_config_sensor);
 
+        // Readiness is reported on the metrics server. Depending on the
+        // startup policy, the proxy may not be ready until the controller
+        // has been reached. It is no longer ready once it begins to drain.
+        let (readiness, readiness_latch) = readiness::new(config.destination_startup_policy);
+        if control_host_and_port.is_none() {
+            readiness_latch.release();
+        }
+        let readiness_drain_rx = drain_rx.clone();
+
         let controller_fut = {
             use super::control;
 
@@ -230,19 +283,33 @@ where
                 .as_ref()
                 .and_then(|s| s.controller_identity.clone().map(|id| id));
 
-            let control_config = control_host_and_port.map(|host_and_port| {
+            let control_config = |host_and_port: Addr| {
                 control::Config::new(
                     host_and_port,
-                    tls_server_identity,
+                    tls_server_identity.clone(),
                     config.control_backoff_delay,
                     config.control_connect_timeout,
                 )
-            });
+            };
+            // Failover controllers are only used when a primary controller is
+            // configured.
+            let failover_configs = match control_host_and_port {
+                Some(_) => config
+                    .destination_profile_failover_hosts_and_ports
+                    .iter()
+                    .cloned()
+                    .map(&control_config)
+                    .collect(),
+                None => Vec::new(),
+            };
+            let control_config = control_host_and_port.map(&control_config);
 
             let stack = connect::Stack::new()
+                .with_socket_options(config.connect_socket_options)
                 .push(control::client::layer())
                 .push(control::resolve::layer(dns_resolver.clone()))
                 .push(reconnect::layer().with_fixed_backoff(config.control_backoff_delay))
+                .push(readiness::layer(readiness_latch))
                 .push(proxy::timeout::layer(config.control_connect_timeout))
                 .push(control::box_request_body::layer())
                 .push(http_metrics::layer::<_, classify::Response>(
@@ -258,12 +325,18 @@ where
             // spawn a task on an executor when `make` is called. This is done
             // lazily so that a default executor is available to spawn the
             // background buffering task.
-            future::lazy(move || match control_config {
-                None => Ok(None),
-                Some(config) => stack
-                    .make(&config)
-                    .map(Some)
-                    .map_err(|e| error!("failed to build controller: {}", e)),
+            future::lazy(move || {
+                let controller = match control_config {
+                    None => Ok(None),
+                    Some(config) => stack.make(&config).map(Some),
+                };
+                let failovers = failover_configs
+                    .iter()
+                    .map(|config| stack.make(config))
+                    .collect::<Result<Vec<_>, _>>();
+                controller
+                    .and_then(|c| failovers.map(|fs| (c, fs)))
+                    .map_err(|e| error!("failed to build controller: {}", e))
             })
         };
 
@@ -272,7 +345,7 @@ where
         let (resolver_bg_tx, resolver_bg_rx) = futures::sync::oneshot::channel();
 
         // Build the outbound and inbound proxies using the controller client.
-        let main_fut = controller_fut.and_then(move |controller| {
+        let main_fut = controller_fut.and_then(move |(controller, failovers)| {
             let (resolver, resolver_bg) = control::destination::new(
                 controller.clone(),
                 dns_resolver.clone(),
@@ -285,54 +358,122 @@ where
                 .ok()
                 .expect("admin thread must receive resolver task");
 
-            let profiles_client = ProfilesClient::new(controller, Duration::from_secs(3));
+            // Names matching the SRV suffixes are resolved via DNS rather
+            // than the controller.
+            let resolver = control::destination::srv::Resolve::new(
+                resolver,
+                dns_resolver.clone(),
+                config.destination_srv_suffixes.clone(),
+            );
+
+            // Profiles are fetched from failover controllers when the primary
+            // controller fails repeatedly. Destinations are always resolved
+            // via the primary controller.
+            let controllers = controller.iter().cloned().chain(failovers).collect();
+            let profiles_client = profiles::debounce(
+                ProfilesClient::new(
+                    controllers,
+                    ProfilesBackoff::new(
+                        Duration::from_secs(3),
+                        config.destination_profile_backoff_max,
+                    ),
+                    profile_stream_metrics,
+                ),
+                config.destination_profile_update_window,
+            );
 
             let outbound = {
                 use super::outbound::{discovery::Resolve, orig_proto_upgrade, Endpoint};
                 use proxy::{
                     canonicalize,
-                    http::{balance, header_from_target, metrics},
+                    http::{balance, header_from_target, metrics, server_id},
                     resolve,
                 };
 
                 let profiles_client = profiles_client.clone();
                 let capacity = config.outbound_router_capacity;
                 let max_idle_age = config.outbound_router_max_idle_age;
+                let router_retry_after = config.router_retry_after;
                 let endpoint_http_metrics = endpoint_http_metrics.clone();
                 let route_http_metrics = route_http_metrics.clone();
+                let router_metrics = router_metrics.clone();
+                let cancel_metrics = cancel_metrics.clone();
+                let global_limit = global_limit.clone();
+                let request_id = request_id.clone();
                 let profile_suffixes = config.destination_profile_suffixes.clone();
 
                 // Establishes connections to remote peers (for both TCP
                 // forwarding and HTTP proxying).
                 let connect = connect::Stack::new()
+                    .with_socket_options(config.connect_socket_options)
+                    .with_proxy_protocol(config.outbound_proxy_protocol)
                     .push(proxy::timeout::layer(config.outbound_connect_timeout))
                     .push(transport_metrics.connect("outbound"));
 
-                // Instantiates an HTTP client for for a `client::Config`
+                // Instantiates an HTTP client for for a `client::Config`.
+                //
+                // As requests are dispatched to the client, their deadlines are
+                // reduced by the time they have spent in the proxy.
                 let client_stack = connect
                     .clone()
-                    .push(client::layer("out"))
-                    .push(reconnect::layer())
+                    .push(
+                        client::layer("out", config.http1_pool)
+                            .with_http2_windows(config.outbound_h2_windows),
+                    )
+                    .push(
+                        reconnect::layer()
+                            .with_exponential_backoff(
+                                config.connect_backoff_min,
+                                config.connect_backoff_max,
+                                config.connect_backoff_jitter,
+                            )
+                            .with_max_attempts(config.connect_max_attempts),
+                    )
                     .push(svc::stack_per_request::layer())
-                    .push(normalize_uri::layer());
+                    .push(normalize_uri::layer())
+                    .push(deadline::layer());
 
                 // A per-`outbound::Endpoint` stack that:
                 //
                 // 1. Records http metrics  with per-endpoint labels.
                 // 2. Instruments `tap` inspection.
-                // 3. Changes request/response versions when the endpoint
+                // 3. When enabled, fails requests fast while the endpoint's
+                //    responses are failing.
+                // 4. When configured, reports the endpoint's identity and
+                //    latency in response headers.
+                // 5. Changes request/response versions when the endpoint
                 //    supports protocol upgrade (and the request may be upgraded).
-                // 4. Routes requests to the correct client (based on the
+                //    Otherwise, the endpoint isn't known to be in the mesh, so
+                //    `l5d-*` headers are stripped from requests.
+                // 6. Routes requests to the correct client (based on the
                 //    request version and headers).
+                // 7. Annotates the endpoint service with its load balancing
+                //    weight.
                 let endpoint_stack = client_stack
                     .push(buffer::layer())
-                    .push(settings::router::layer::<Endpoint, _>())
+                    .push(
+                        settings::router::layer::<Endpoint, _>()
+                            .with_rewrite_host(config.http1_rewrite_host)
+                            .with_reuse_without_authority(
+                                config.http1_reuse_without_authority,
+                            ),
+                    )
                     .push(orig_proto_upgrade::layer())
+                    .push(server_id::layer(
+                        config.response_server_id_header.clone(),
+                        config.response_latency_header.clone(),
+                    ))
+                    .push(circuit_breaker::layer::<classify::Response>(
+                        config.outbound_circuit_breaker,
+                    ))
                     .push(tap::layer(tap_next_id.clone(), taps.clone()))
+                    .push(timing::layer("tap", config.latency_breakdown))
                     .push(metrics::layer::<_, classify::Response>(
                         endpoint_http_metrics,
                     ))
-                    .push(svc::watch::layer(tls_client_config));
+                    .push(timing::layer("endpoint-metrics", config.latency_breakdown))
+                    .push(svc::watch::layer(tls_client_config))
+                    .push(balance::weight::layer());
 
                 // A per-`dst::Route` layer that uses profile data to configure
                 // a per-route layer.
@@ -351,16 +492,37 @@ where
                 //    per-route policy.
                 // 3. Creates a load balancer , configured by resolving the
                 //   `DstAddr` with a resolver.
+                // 4. Limits the number of long-lived streams to the
+                //    destination.
                 let dst_stack = endpoint_stack
                     .push(resolve::layer(Resolve::new(resolver)))
-                    .push(balance::layer())
+                    .push(balance::count::layer(balance_metrics))
+                    .push(
+                        balance::layer()
+                            .with_strategy(config.outbound_balance_strategy)
+                            .with_ejection_window(config.outbound_balance_ejection_window)
+                            .with_ejection_max_failures(
+                                config.outbound_balance_ejection_max_failures,
+                            )
+                            .with_hash_key(config.outbound_balance_hash_key.clone())
+                            .with_drain_timeout(config.outbound_balance_drain_timeout)
+                            .with_warmup(
+                                config.outbound_balance_min_ready_endpoints,
+                                config.outbound_balance_warmup_timeout,
+                            ),
+                    )
+                    .push(timing::layer("balance", config.latency_breakdown))
                     .push(buffer::layer())
-                    .push(profiles::router::layer(
-                        profile_suffixes,
-                        profiles_client,
-                        dst_route_layer,
-                    ))
-                    .push(header_from_target::layer(super::CANONICAL_DST_HEADER));
+                    .push(
+                        profiles::router::layer(profile_suffixes, profiles_client, dst_route_layer)
+                            .with_default_timeout(config.route_default_timeout),
+                    )
+                    .push(timing::layer("profile-router", config.latency_breakdown))
+                    .push(header_from_target::layer(super::CANONICAL_DST_HEADER))
+                    .push(stream_limit::layer(
+                        config.outbound_max_long_lived_streams,
+                        config.long_lived_stream_threshold,
+                    ));
 
                 // Routes request using the `DstAddr` extension.
                 //
@@ -373,25 +535,41 @@ where
                 // caching logic.
                 let dst_router = dst_stack
                     .push(buffer::layer())
-                    .push(router::layer(|req: &http::Request<_>| {
-                        let addr = req.extensions().get::<DstAddr>().cloned();
-                        debug!("outbound dst={:?}", addr);
-                        addr
-                    }))
-                    .make(&router::Config::new("out dst", capacity, max_idle_age))
+                    .push(
+                        router::layer(|req: &http::Request<_>| {
+                            let addr = req.extensions().get::<DstAddr>().cloned();
+                            debug!("outbound dst={:?}", addr);
+                            addr
+                        })
+                        .with_metrics(router_metrics.clone()),
+                    )
+                    .make(
+                        &router::Config::new("out dst", capacity, max_idle_age)
+                            .with_retry_after(router_retry_after),
+                    )
                     .map(shared::stack)
                     .expect("outbound dst router")
-                    .push(phantom_data::layer());
+                    .push(phantom_data::layer())
+                    .push(timing::layer("dst-router", config.latency_breakdown));
 
                 // Canonicalizes the request-specified `Addr` via DNS, and
                 // annotates each request with a `DstAddr` so that it may be
-                // routed by the dst_router.
+                // routed by the dst_router. When enabled, large text responses
+                // are gzipped for clients that accept it.
                 let addr_stack = dst_router
                     .push(insert_target::layer())
                     .push(map_target::layer(|addr: &Addr| {
                         DstAddr::outbound(addr.clone())
                     }))
-                    .push(canonicalize::layer(dns_resolver));
+                    .push(
+                        canonicalize::layer(
+                            dns_resolver,
+                            config.dns_canonicalize_skip_suffixes.clone(),
+                        )
+                        .with_error_ttl(config.dns_error_ttl),
+                    )
+                    .push(timing::layer("canonicalize", config.latency_breakdown))
+                    .push(compress::layer(config.outbound_gzip_min_length));
 
                 // Routes requests to an `Addr`:
                 //
@@ -409,54 +587,102 @@ where
                     .push(buffer::layer())
                     .push(timeout::layer(config.bind_timeout))
                     .push(limit::layer(MAX_IN_FLIGHT))
-                    .push(router::layer(|req: &http::Request<_>| {
-                        let addr = super::http_request_authority_addr(req)
-                            .or_else(|_| super::http_request_host_addr(req))
-                            .or_else(|_| super::http_request_orig_dst_addr(req))
-                            .ok();
-                        debug!("outbound addr={:?}", addr);
-                        addr
-                    }))
-                    .make(&router::Config::new("out addr", capacity, max_idle_age))
+                    .push(
+                        router::layer(|req: &http::Request<_>| {
+                            let addr = super::http_request_authority_addr(req)
+                                .or_else(|_| super::http_request_host_addr(req))
+                                .or_else(|_| super::http_request_orig_dst_addr(req))
+                                .ok();
+                            debug!("outbound addr={:?}", addr);
+                            addr
+                        })
+                        .with_metrics(router_metrics.clone()),
+                    )
+                    .make(
+                        &router::Config::new("out addr", capacity, max_idle_age)
+                            .with_retry_after(router_retry_after),
+                    )
                     .map(shared::stack)
                     .expect("outbound addr router")
-                    .push(phantom_data::layer());
+                    .push(phantom_data::layer())
+                    .push(timing::layer("addr-router", config.latency_breakdown));
 
                 // Instantiates an HTTP service for each `Source` using the
                 // shared `addr_router`. The `Source` is stored in the request's
                 // extensions so that it can be used by the `addr_router`.
-                let server_stack = addr_router.push(insert_target::layer());
+                //
+                // Requests are subject to the global in-flight limit, and
+                // requests canceled by the client are canceled upstream.
+                // Requests are also canceled once the deadline in their
+                // `grpc-timeout` header, if any, elapses. Streams with
+                // excessive trailers are reset. When enabled, requests lacking
+                // a request ID are given one, and each request's latency is
+                // broken down by layer. The time at which each request is
+                // received is recorded so that its deadline may be reduced
+                // before it is forwarded.
+                let server_stack = addr_router
+                    .push(global_limit)
+                    .push(grpc_timeout::layer())
+                    .push(trailer_limit)
+                    .push(request_id)
+                    .push(cancel::layer(cancel_metrics))
+                    .push(insert_target::layer())
+                    .push(deadline::received())
+                    .push(timing::root(config.latency_breakdown));
 
                 // Instantiated for each TCP connection received from the local
                 // application (including HTTP connections).
                 let accept = transport_metrics.accept("outbound").bind(());
 
-                serve(
+                let server = proxy::Server::new(
                     "out",
-                    outbound_listener,
+                    outbound_listener.local_addr(),
+                    get_original_dst.clone(),
                     accept,
                     connect,
                     server_stack,
                     config.outbound_ports_disable_protocol_detection,
-                    get_original_dst.clone(),
                     drain_rx.clone(),
+                    h2::server::Builder::default(),
+                )
+                .with_detect_protocol_timeout(
+                    config.detect_protocol_timeout,
+                    config.on_detect_protocol_timeout,
                 )
-                .map_err(|e| error!("outbound proxy background task failed: {}", e))
+                .with_tcp_shutdown(config.tcp_shutdown)
+                .with_drain_timeout(config.drain_timeout)
+                .with_h2c_upgrades(config.h2c_upgrades)
+                .with_h2_idle_timeout(config.h2_idle_timeout)
+                .with_max_connections(config.max_connections)
+                .with_tcp_taps(taps.clone());
+                // Opaque TLS connections are only routed by SNI when routes
+                // are configured.
+                let server = if config.outbound_sni_routes.is_empty() {
+                    server
+                } else {
+                    server.with_sni_route(config.outbound_sni_routes.clone())
+                };
+
+                serve(outbound_listener, server, drain_rx.clone())
+                    .map_err(|e| error!("outbound proxy background task failed: {}", e))
             };
 
             let inbound = {
                 use super::inbound::{
                     orig_proto_downgrade, rewrite_loopback_addr, Endpoint, RecognizeEndpoint,
+                    SniDetectionPort,
                 };
 
                 let capacity = config.inbound_router_capacity;
                 let max_idle_age = config.inbound_router_max_idle_age;
+                let router_retry_after = config.router_retry_after;
                 let profile_suffixes = config.destination_profile_suffixes;
                 let default_fwd_addr = config.inbound_forward.map(|a| a.into());
 
                 // Establishes connections to the local application (for both
                 // TCP forwarding and HTTP proxying).
                 let connect = connect::Stack::new()
+                    .with_socket_options(config.connect_socket_options)
                     .push(proxy::timeout::layer(config.inbound_connect_timeout))
                     .push(transport_metrics.connect("inbound"))
                     .push(rewrite_loopback_addr::layer());
@@ -464,8 +690,16 @@ where
                 // Instantiates an HTTP client for for a `client::Config`
                 let client_stack = connect
                     .clone()
-                    .push(client::layer("in"))
-                    .push(reconnect::layer())
+                    .push(client::layer("in", config.http1_pool))
+                    .push(
+                        reconnect::layer()
+                            .with_exponential_backoff(
+                                config.connect_backoff_min,
+                                config.connect_backoff_max,
+                                config.connect_backoff_jitter,
+                            )
+                            .with_max_attempts(config.connect_max_attempts),
+                    )
                     .push(svc::stack_per_request::layer())
                     .push(normalize_uri::layer());
 
@@ -476,16 +710,34 @@ where
                 // `default_fwd_addr` may be used.
                 let endpoint_router = client_stack
                     .push(buffer::layer())
-                    .push(settings::router::layer::<Endpoint, _>())
-                    .push(tap::layer(tap_next_id, taps))
+                    .push(
+                        settings::router::layer::<Endpoint, _>()
+                            .with_rewrite_host(config.http1_rewrite_host)
+                            .with_reuse_without_authority(
+                                config.http1_reuse_without_authority,
+                            ),
+                    )
+                    .push(tap::layer(tap_next_id, taps.clone()))
+                    .push(timing::layer("tap", config.latency_breakdown))
                     .push(http_metrics::layer::<_, classify::Response>(
                         endpoint_http_metrics,
                     ))
+                    .push(timing::layer("endpoint-metrics", config.latency_breakdown))
                     .push(buffer::layer())
-                    .push(router::layer(RecognizeEndpoint::new(default_fwd_addr)))
-                    .make(&router::Config::new("in endpoint", capacity, max_idle_age))
+                    .push(
+                        router::layer(
+                            RecognizeEndpoint::new(default_fwd_addr)
+                                .with_sni_ports(config.inbound_sni_ports.clone()),
+                        )
+                        .with_metrics(router_metrics.clone()),
+                    )
+                    .make(
+                        &router::Config::new("in endpoint", capacity, max_idle_age)
+                            .with_retry_after(router_retry_after),
+                    )
                     .map(shared::stack)
-                    .expect("inbound endpoint router");
+                    .expect("inbound endpoint router")
+                    .push(timing::layer("endpoint-router", config.latency_breakdown));
 
                 // A per-`dst::Route` layer that uses profile data to configure
                 // a per-route layer.
@@ -505,15 +757,18 @@ where
                 //    per-route policy.
                 // 2. Annotates the request with the `DstAddr` so that
                 //    `RecognizeEndpoint` can use the value.
+                // 3. When enabled, limits the rate of requests to the
+                //    destination.
                 let dst_stack = endpoint_router
                     .push(phantom_data::layer())
                     .push(insert_target::layer())
                     .push(buffer::layer())
-                    .push(profiles::router::layer(
-                        profile_suffixes,
-                        profiles_client,
-                        dst_route_stack,
-                    ));
+                    .push(
+                        profiles::router::layer(profile_suffixes, profiles_client, dst_route_stack)
+                            .with_default_timeout(config.route_default_timeout),
+                    )
+                    .push(timing::layer("profile-router", config.latency_breakdown))
+                    .push(ratelimit::layer(config.inbound_rate_limit));
 
                 // Routes requests to a `DstAddr`.
                 //
@@ -533,24 +788,31 @@ where
                 let dst_router = dst_stack
                     .push(buffer::layer())
                     .push(limit::layer(MAX_IN_FLIGHT))
-                    .push(router::layer(|req: &http::Request<_>| {
-                        let canonical = req
-                            .headers()
-                            .get(super::CANONICAL_DST_HEADER)
-                            .and_then(|dst| dst.to_str().ok())
-                            .and_then(|d| Addr::from_str(d).ok());
-                        info!("inbound canonical={:?}", canonical);
-
-                        let dst = canonical
-                            .or_else(|| super::http_request_authority_addr(req).ok())
-                            .or_else(|| super::http_request_host_addr(req).ok())
-                            .or_else(|| super::http_request_orig_dst_addr(req).ok());
-                        info!("inbound dst={:?}", dst);
-                        dst.map(DstAddr::inbound)
-                    }))
-                    .make(&router::Config::new("in dst", capacity, max_idle_age))
+                    .push(
+                        router::layer(|req: &http::Request<_>| {
+                            let canonical = req
+                                .headers()
+                                .get(super::CANONICAL_DST_HEADER)
+                                .and_then(|dst| dst.to_str().ok())
+                                .and_then(|d| Addr::from_str(d).ok());
+                            info!("inbound canonical={:?}", canonical);
+
+                            let dst = canonical
+                                .or_else(|| super::http_request_authority_addr(req).ok())
+                                .or_else(|| super::http_request_host_addr(req).ok())
+                                .or_else(|| super::http_request_orig_dst_addr(req).ok());
+                            info!("inbound dst={:?}", dst);
+                            dst.map(DstAddr::inbound)
+                        })
+                        .with_metrics(router_metrics.clone()),
+                    )
+                    .make(
+                        &router::Config::new("in dst", capacity, max_idle_age)
+                            .with_retry_after(router_retry_after),
+                    )
                     .map(shared::stack)
-                    .expect("inbound dst router");
+                    .expect("inbound dst router")
+                    .push(timing::layer("dst-router", config.latency_breakdown));
 
                 // As HTTP requests are accepted, the `Source` connection
                 // metadata is stored on each request's extensions.
@@ -558,25 +820,54 @@ where
                 // Furthermore, HTTP/2 requests may be downgraded to HTTP/1.1 per
                 // `orig-proto` headers. This happens in the source stack so that
                 // the router need not detect whether a request _will be_ downgraded.
+                //
+                // Requests are subject to the global in-flight limit, and
+                // requests canceled by the client are canceled upstream.
+                // Streams with excessive trailers are reset. When enabled,
+                // requests lacking a request ID are given one, each request's
+                // latency is broken down by layer, and request bodies that do
+                // not match their content-type are rejected.
                 let source_stack = dst_router
-                    .push(orig_proto_downgrade::layer())
-                    .push(insert_target::layer());
+                    .push(global_limit)
+                    .push(content_sniff::layer(
+                        config.inbound_content_sniff_ports.clone(),
+                    ))
+                    .push(trailer_limit)
+                    .push(orig_proto_downgrade::layer(downgrade_metrics))
+                    .push(request_id)
+                    .push(cancel::layer(cancel_metrics))
+                    .push(insert_target::layer())
+                    .push(timing::root(config.latency_breakdown));
 
                 // As the inbound proxy accepts connections, we don't do any
                 // special transport-level handling.
                 let accept = transport_metrics.accept("inbound").bind(());
 
-                serve(
+                let server = proxy::Server::new(
                     "in",
-                    inbound_listener,
+                    inbound_listener.local_addr(),
+                    get_original_dst.clone(),
                     accept,
                     connect,
                     source_stack,
                     config.inbound_ports_disable_protocol_detection,
-                    get_original_dst.clone(),
                     drain_rx.clone(),
+                    config.inbound_h2_settings(),
+                )
+                .with_detection_port(SniDetectionPort::new(config.inbound_sni_ports.clone()))
+                .with_detect_protocol_timeout(
+                    config.detect_protocol_timeout,
+                    config.on_detect_protocol_timeout,
                 )
-                .map_err(|e| error!("inbound proxy background task failed: {}", e))
+                .with_tcp_shutdown(config.tcp_shutdown)
+                .with_drain_timeout(config.drain_timeout)
+                .with_h2c_upgrades(config.h2c_upgrades)
+                .with_h2_idle_timeout(config.h2_idle_timeout)
+                .with_max_connections(config.max_connections)
+                .with_tcp_taps(taps);
+
+                serve(inbound_listener, server, drain_rx.clone())
+                    .map_err(|e| error!("inbound proxy background task failed: {}", e))
             };
 
             inbound.join(outbound).map(|_| {})
@@ -594,10 +885,11 @@ where
 
                     let tap = serve_tap(control_listener, TapServer::new(observe));
 
+                    let drain_readiness = readiness.drain_on(readiness_drain_rx);
                     let metrics = control::serve_http(
                         "metrics",
                         metrics_listener,
-                        metrics::Serve::new(report),
+                        readiness.serve(metrics::Serve::new(report)),
                     );
 
                     // tap is already wrapped in a logging Future.
@@ -612,6 +904,7 @@ where
                     );
 
                     rt.spawn(::logging::admin().bg("tls-config").future(tls_cfg_bg));
+                    rt.spawn(::logging::admin().bg("readiness").future(drain_readiness));
 
                     let shutdown = admin_shutdown_signal.then(|_| Ok::<(), ()>(()));
                     rt.block_on(shutdown).expect("admin");
@@ -635,13 +928,8 @@ where
 }
 
 fn serve<A, C, R, B, G>(
-    proxy_name: &'static str,
     bound_port: BoundPort,
-    accept: A,
-    connect: C,
-    router: R,
-    disable_protocol_detection_ports: IndexSet<u16>,
-    get_orig_dst: G,
+    server: proxy::Server<A, C, R, B, G>,
     drain_rx: drain::Watch,
 ) -> impl Future<Item = (), Error = io::Error> + Send + 'static
 where
@@ -664,18 +952,6 @@ where
     <B::Data as ::bytes::IntoBuf>::Buf: Send,
     G: GetOriginalDst + Send + 'static,
 {
-    let listen_addr = bound_port.local_addr();
-    let server = proxy::Server::new(
-        proxy_name,
-        listen_addr,
-        get_orig_dst,
-        accept,
-        connect,
-        router,
-        disable_protocol_detection_ports,
-        drain_rx.clone(),
-        h2::server::Builder::default(),
-    );
     let log = server.log().clone();
 
     let accept = {
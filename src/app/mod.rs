@@ -11,6 +11,7 @@ mod main;
 mod metric_labels;
 mod outbound;
 mod profiles;
+mod readiness;
 
 pub use self::main::Main;
 use addr::{self, Addr};
@@ -1,11 +1,13 @@
+use http::header::HeaderValue;
 use std::fmt;
 
 use control::destination::{Metadata, ProtocolHint};
-use proxy::http::settings;
+use proxy::http::{balance, server_id, settings};
+use proxy::resolve;
 use svc;
 use tap;
 use transport::{connect, tls};
-use NameAddr;
+use {Conditional, NameAddr};
 
 #[derive(Clone, Debug)]
 pub struct Endpoint {
@@ -31,6 +33,43 @@ impl settings::router::HasConnect for Endpoint {
     }
 }
 
+impl balance::HasWeight for Endpoint {
+    fn weight(&self) -> balance::Weight {
+        self.metadata
+            .labels()
+            .get(balance::weight::LABEL)
+            .and_then(|w| w.parse::<u32>().ok())
+            .map(balance::Weight::new)
+            .unwrap_or_default()
+    }
+}
+
+impl server_id::HasServerId for Endpoint {
+    /// Endpoints are identified by their TLS identity, if known, or else by
+    /// their address.
+    fn server_id(&self) -> Option<HeaderValue> {
+        let id = match self.metadata.tls_identity() {
+            Conditional::Some(identity) => identity.to_string(),
+            Conditional::None(_) => self.connect.addr.to_string(),
+        };
+        HeaderValue::from_str(&id).ok()
+    }
+}
+
+impl resolve::Refresh for Endpoint {
+    /// An endpoint's service is built for its TLS identity and protocol hint
+    /// and is balanced by its weight. Changes to its other labels, which only
+    /// annotate telemetry, do not tear down its connections; its telemetry
+    /// retains the labels it was built with until the endpoint is re-added.
+    fn can_refresh(&self, new: &Self) -> bool {
+        use proxy::http::balance::HasWeight;
+
+        self.metadata.tls_identity() == new.metadata.tls_identity()
+            && self.metadata.protocol_hint() == new.metadata.protocol_hint()
+            && self.weight() == new.weight()
+    }
+}
+
 impl fmt::Display for Endpoint {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.connect.addr.fmt(f)
@@ -125,21 +164,13 @@ pub mod discovery {
                         Ok(Async::Ready(resolve::Update::Remove(addr)))
                     }
                     resolve::Update::Add(addr, metadata) => {
-                        // If the endpoint does not have TLS, note the reason.
-                        // Otherwise, indicate that we don't (yet) have a TLS
-                        // config. This value may be changed by a stack layer that
-                        // provides TLS configuration.
-                        let tls = match metadata.tls_identity() {
-                            Conditional::None(reason) => reason.into(),
-                            Conditional::Some(_) => tls::ReasonForNoTls::NoConfig,
-                        };
-                        let ep = Endpoint {
-                            dst_name: Some(name.clone()),
-                            connect: connect::Target::new(addr, Conditional::None(tls)),
-                            metadata,
-                        };
+                        let ep = endpoint(name, addr, metadata);
                         Ok(Async::Ready(resolve::Update::Add(addr, ep)))
                     }
+                    resolve::Update::Update(addr, metadata) => {
+                        let ep = endpoint(name, addr, metadata);
+                        Ok(Async::Ready(resolve::Update::Update(addr, ep)))
+                    }
                 },
                 Resolution::Addr(ref mut addr) => match addr.take() {
                     Some(addr) => {
@@ -156,6 +187,21 @@ pub mod discovery {
             }
         }
     }
+
+    fn endpoint(name: &NameAddr, addr: SocketAddr, metadata: Metadata) -> Endpoint {
+        // If the endpoint does not have TLS, note the reason. Otherwise,
+        // indicate that we don't (yet) have a TLS config. This value may be
+        // changed by a stack layer that provides TLS configuration.
+        let tls = match metadata.tls_identity() {
+            Conditional::None(reason) => reason.into(),
+            Conditional::Some(_) => tls::ReasonForNoTls::NoConfig,
+        };
+        Endpoint {
+            dst_name: Some(name.clone()),
+            connect: connect::Target::new(addr, Conditional::None(tls)),
+            metadata,
+        }
+    }
 }
 
 pub mod orig_proto_upgrade {
@@ -164,7 +210,7 @@ pub mod orig_proto_upgrade {
     use http;
 
     use super::Endpoint;
-    use proxy::http::orig_proto;
+    use proxy::http::{orig_proto, strip_l5d};
     use svc;
 
     #[derive(Debug)]
@@ -219,15 +265,132 @@ pub mod orig_proto_upgrade {
         M: svc::Stack<Endpoint>,
         M::Value: svc::Service<http::Request<A>, Response = http::Response<B>>,
     {
-        type Value = svc::Either<orig_proto::Upgrade<M::Value>, M::Value>;
+        type Value = svc::Either<orig_proto::Upgrade<M::Value>, strip_l5d::Strip<M::Value>>;
         type Error = M::Error;
 
         fn make(&self, endpoint: &Endpoint) -> Result<Self::Value, Self::Error> {
             if endpoint.can_use_orig_proto() {
                 self.inner.make(&endpoint).map(|i| svc::Either::A(orig_proto::Upgrade::new(i)))
             } else {
-                self.inner.make(&endpoint).map(svc::Either::B)
+                // Endpoints that can't use orig-proto aren't known to be in
+                // the mesh, so the proxy's internal headers are not sent to
+                // them.
+                self.inner
+                    .make(&endpoint)
+                    .map(|i| svc::Either::B(strip_l5d::Strip::new(i)))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future, Poll};
+    use http;
+    use indexmap::IndexMap;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use never::Never;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+    use Conditional;
+
+    /// Records the headers of the last request it received.
+    #[derive(Clone, Debug, Default)]
+    struct Recorder(Rc<RefCell<Option<http::HeaderMap>>>);
+
+    impl svc::Stack<Endpoint> for Recorder {
+        type Value = Self;
+        type Error = Never;
+
+        fn make(&self, _: &Endpoint) -> Result<Self, Never> {
+            Ok(self.clone())
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Recorder {
+        type Response = http::Response<()>;
+        type Error = Never;
+        type Future = future::FutureResult<Self::Response, Never>;
+
+        fn poll_ready(&mut self) -> Poll<(), Never> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, req: http::Request<()>) -> Self::Future {
+            *self.0.borrow_mut() = Some(req.headers().clone());
+            let rsp = http::Response::builder()
+                .version(http::Version::HTTP_2)
+                .body(())
+                .unwrap();
+            future::ok(rsp)
+        }
+    }
+
+    fn endpoint(protocol_hint: ProtocolHint) -> Endpoint {
+        let tls = tls::ReasonForNoIdentity::NotProvidedByServiceDiscovery;
+        Endpoint {
+            dst_name: None,
+            connect: connect::Target::new(
+                ([10, 1, 1, 1], 8080).into(),
+                Conditional::None(tls.into()),
+            ),
+            metadata: Metadata::new(IndexMap::default(), protocol_hint, Conditional::None(tls)),
+        }
+    }
+
+    /// Sends a request with an `l5d-*` header to the endpoint and returns
+    /// the headers with which it was received.
+    fn send(endpoint: &Endpoint) -> http::HeaderMap {
+        let recorder = Recorder::default();
+        let mut svc = orig_proto_upgrade::layer::<(), ()>()
+            .bind(recorder.clone())
+            .make(endpoint)
+            .unwrap();
+
+        let req = http::Request::builder()
+            .uri("http://example.com/")
+            .header("l5d-dst-canonical", "example.com:80")
+            .header("x-app", "foo")
+            .body(())
+            .unwrap();
+        svc.call(req).wait().expect("response");
+
+        let headers = recorder.0.borrow_mut().take();
+        headers.expect("request must be received")
+    }
+
+    #[test]
+    fn l5d_headers_are_sent_to_mesh_endpoints() {
+        let headers = send(&endpoint(ProtocolHint::Http2));
+        assert_eq!(headers["l5d-dst-canonical"], "example.com:80");
+        assert!(headers.contains_key("l5d-orig-proto"));
+        assert_eq!(headers["x-app"], "foo");
+    }
+
+    #[test]
+    fn l5d_headers_are_stripped_for_non_mesh_endpoints() {
+        let headers = send(&endpoint(ProtocolHint::Unknown));
+        assert!(!headers.contains_key("l5d-dst-canonical"));
+        assert_eq!(headers["x-app"], "foo");
+    }
+
+    #[test]
+    fn server_id_is_the_endpoint_identity() {
+        use proxy::http::server_id::HasServerId;
+
+        let mut ep = endpoint(ProtocolHint::Unknown);
+        assert_eq!(ep.server_id().unwrap(), "10.1.1.1:8080");
+
+        let identity =
+            tls::Identity::from_sni_hostname(b"web.ns.deployment.linkerd-managed.linkerd.svc")
+                .unwrap();
+        ep.metadata =
+            Metadata::new(IndexMap::default(), ProtocolHint::Unknown, Conditional::Some(identity));
+        assert_eq!(
+            ep.server_id().unwrap(),
+            "web.ns.deployment.linkerd-managed.linkerd.svc"
+        );
+    }
+}
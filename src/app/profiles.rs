@@ -1,7 +1,10 @@
 use futures::{Async, Future, Poll, Stream};
 use http;
+use indexmap::IndexMap;
+use rand;
 use regex::Regex;
 use std::fmt;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio_timer::{clock, Delay};
 use tower_grpc::{self as grpc, Body, BoxBody};
@@ -9,13 +12,41 @@ use tower_http::HttpService;
 
 use api::destination as api;
 
+use metrics::{Counter, FmtLabels, FmtMetric, FmtMetrics, Gauge};
 use proxy::http::profiles;
+use proxy::reconnect::ExponentialBackoff;
 use NameAddr;
 
+metrics! {
+    profile_stream_state: Gauge {
+        "Whether each destination's profile stream is in a given state"
+    },
+    profile_stream_reconnects_total: Counter {
+        "Total number of times each destination's profile stream has reconnected"
+    }
+}
+
+/// Constructs a Registry/Report pair for profile stream metrics.
+pub fn metrics() -> (Registry, Report) {
+    let inner = Arc::new(Mutex::new(IndexMap::default()));
+    (Registry(inner.clone()), Report(inner))
+}
+
+/// The number of consecutive failures after which a profile is fetched from
+/// the next controller.
+const MAX_FAILURES: usize = 3;
+
+/// Each backoff is reduced by a random fraction of up to this value, so that
+/// reconnects from many proxies to a failed controller are spread out.
+const BACKOFF_JITTER: f64 = 0.5;
+
+/// Fetches profiles from an ordered list of controllers, failing over to the
+/// next controller when the current controller fails repeatedly.
 #[derive(Clone, Debug)]
 pub struct Client<T> {
-    service: Option<T>,
-    backoff: Duration,
+    services: Arc<Vec<T>>,
+    backoff: Backoff,
+    registry: Registry,
 }
 
 pub struct Rx<T>
@@ -24,11 +55,70 @@ where
     T::ResponseBody: Body,
 {
     dst: String,
-    backoff: Duration,
-    service: Option<T>,
+    backoff: Backoff,
+    failover: Failover<T>,
     state: State<T>,
+    probe: Probe<T>,
+    registry: Registry,
+}
+
+/// How long a profile stream waits before reconnecting to a controller.
+///
+/// The backoff doubles from `min` after each consecutive failure, up to `max`,
+/// and is reset once a profile is received.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    exponential: ExponentialBackoff,
+    /// The number of consecutive failures, used to grow the backoff.
+    failures: u32,
+}
+
+/// Records the health of each destination's profile stream.
+#[derive(Clone, Debug)]
+pub struct Registry(Arc<Mutex<IndexMap<String, Health>>>);
+
+/// Formats profile stream metrics for Prometheus.
+#[derive(Clone, Debug)]
+pub struct Report(Arc<Mutex<IndexMap<String, Health>>>);
+
+#[derive(Debug, Default)]
+struct Health {
+    state: StreamState,
+    reconnects: Counter,
+    /// The number of streams for the destination, so that its metrics are
+    /// retained until all of them are dropped.
+    streams: usize,
+}
+
+/// Mirrors `State`, for metrics.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum StreamState {
+    Disconnected,
+    Waiting,
+    Streaming,
+    Backoff,
+}
+
+struct Dst<'a>(&'a str);
+
+struct StateLabel(StreamState);
+
+/// Chooses the controller from which a profile is fetched.
+///
+/// Once failed over, the choice is sticky until the primary controller is
+/// found to have recovered.
+#[derive(Debug)]
+struct Failover<T> {
+    services: Arc<Vec<T>>,
+    active: usize,
+    failures: usize,
 }
 
+type ResponseFuture<T> = grpc::client::server_streaming::ResponseFuture<
+    api::DestinationProfile,
+    <T as HttpService<BoxBody>>::Future,
+>;
+
 enum State<T>
 where
     T: HttpService<BoxBody>,
@@ -36,10 +126,22 @@ where
 {
     Disconnected,
     Backoff(Delay),
-    Waiting(grpc::client::server_streaming::ResponseFuture<api::DestinationProfile, T::Future>),
+    Waiting(ResponseFuture<T>),
     Streaming(grpc::Streaming<api::DestinationProfile, T::ResponseBody>),
 }
 
+/// While failed over, periodically checks whether the primary controller has
+/// recovered.
+enum Probe<T>
+where
+    T: HttpService<BoxBody>,
+    T::ResponseBody: Body,
+{
+    Idle,
+    Backoff(Delay),
+    Waiting(ResponseFuture<T>),
+}
+
 // === impl Client ===
 
 impl<T> Client<T>
@@ -48,10 +150,14 @@ where
     T::ResponseBody: Body,
     T::Error: fmt::Debug,
 {
-    pub fn new(service: Option<T>, backoff: Duration) -> Self {
+    /// Profiles are fetched from the first of `services` that is available.
+    ///
+    /// If `services` is empty, all destinations have no routes.
+    pub fn new(services: Vec<T>, backoff: Backoff, registry: Registry) -> Self {
         Self {
-            service,
+            services: Arc::new(services),
             backoff,
+            registry,
         }
     }
 }
@@ -65,17 +171,79 @@ where
     type Stream = Rx<T>;
 
     fn get_routes(&self, dst: &NameAddr) -> Option<Self::Stream> {
+        let dst = format!("{}", dst);
+        self.registry.register(&dst);
         Some(Rx {
-            dst: format!("{}", dst),
+            dst,
             state: State::Disconnected,
-            service: self.service.clone(),
-            backoff: self.backoff,
+            probe: Probe::Idle,
+            failover: Failover::new(self.services.clone()),
+            backoff: self.backoff.clone(),
+            registry: self.registry.clone(),
         })
     }
 }
 
 // === impl Rx ===
 
+impl<T> Rx<T>
+where
+    T: HttpService<BoxBody> + Clone,
+    T::ResponseBody: Body,
+    T::Error: fmt::Debug,
+{
+    fn get_profile(service: &T, dst: &str) -> ResponseFuture<T> {
+        let mut client = api::client::Destination::new(service.clone());
+        let req = api::GetDestination {
+            scheme: "k8s".to_owned(),
+            path: dst.to_owned(),
+        };
+        debug!("disconnected; getting profile: {:?}", req);
+        client.get_profile(grpc::Request::new(req))
+    }
+
+    fn record_state(&self) {
+        let state = match self.state {
+            State::Disconnected => StreamState::Disconnected,
+            State::Waiting(_) => StreamState::Waiting,
+            State::Streaming(_) => StreamState::Streaming,
+            State::Backoff(_) => StreamState::Backoff,
+        };
+        self.registry.set_state(&self.dst, state);
+    }
+
+    /// Polls the primary controller, while failed over, until it responds.
+    fn poll_probe(
+        &mut self,
+    ) -> Option<grpc::Streaming<api::DestinationProfile, T::ResponseBody>> {
+        if self.failover.primary().is_none() {
+            self.probe = Probe::Idle;
+            return None;
+        }
+
+        loop {
+            self.probe = match self.probe {
+                Probe::Idle => Probe::Backoff(Delay::new(clock::now() + self.backoff.min())),
+                Probe::Backoff(ref mut f) => match f.poll() {
+                    Ok(Async::NotReady) => return None,
+                    Err(_) | Ok(Async::Ready(())) => {
+                        let primary = self.failover.primary().expect("must be failed over");
+                        Probe::Waiting(Self::get_profile(primary, &self.dst))
+                    }
+                },
+                Probe::Waiting(ref mut f) => match f.poll() {
+                    Ok(Async::NotReady) => return None,
+                    Ok(Async::Ready(rsp)) => return Some(rsp.into_inner()),
+                    Err(e) => {
+                        debug!("primary controller has not recovered: {:?}", e);
+                        Probe::Backoff(Delay::new(clock::now() + self.backoff.min()))
+                    }
+                },
+            };
+        }
+    }
+}
+
 impl<T> Stream for Rx<T>
 where
     T: HttpService<BoxBody> + Clone,
@@ -86,22 +254,23 @@ where
     type Error = profiles::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let service = match self.service {
-            Some(ref s) => s,
-            None => return Ok(Async::Ready(Some(Vec::new()))),
-        };
+        if self.failover.active().is_none() {
+            return Ok(Async::Ready(Some(Vec::new())));
+        }
+
+        if let Some(rsp) = self.poll_probe() {
+            info!("primary controller recovered; fetching profile for {}", self.dst);
+            self.failover.recovered();
+            self.probe = Probe::Idle;
+            self.state = State::Streaming(rsp);
+            self.record_state();
+        }
 
         loop {
             self.state = match self.state {
                 State::Disconnected => {
-                    let mut client = api::client::Destination::new(service.clone());
-                    let req = api::GetDestination {
-                        scheme: "k8s".to_owned(),
-                        path: self.dst.clone(),
-                    };
-                    debug!("disconnected; getting profile: {:?}", req);
-                    let rspf = client.get_profile(grpc::Request::new(req));
-                    State::Waiting(rspf)
+                    let service = self.failover.active().expect("must have a controller");
+                    State::Waiting(Self::get_profile(service, &self.dst))
                 }
                 State::Waiting(ref mut f) => match f.poll() {
                     Ok(Async::NotReady) => return Ok(Async::NotReady),
@@ -111,34 +280,243 @@ where
                     }
                     Err(e) => {
                         warn!("error fetching profile for {}: {:?}", self.dst, e);
-                        State::Backoff(Delay::new(clock::now() + self.backoff))
+                        self.failover.failed();
+                        let wait = self.backoff.next(rand::random());
+                        State::Backoff(Delay::new(clock::now() + wait))
                     }
                 },
                 State::Streaming(ref mut s) => match s.poll() {
                     Ok(Async::NotReady) => return Ok(Async::NotReady),
                     Ok(Async::Ready(Some(profile))) => {
                         debug!("profile received: {:?}", profile);
+                        self.failover.succeeded();
+                        self.backoff.reset();
                         let rs = profile.routes.into_iter().filter_map(convert_route);
                         return Ok(Async::Ready(Some(rs.collect())));
                     }
                     Ok(Async::Ready(None)) => {
                         debug!("profile stream ended");
-                        State::Backoff(Delay::new(clock::now() + self.backoff))
+                        self.failover.failed();
+                        let wait = self.backoff.next(rand::random());
+                        State::Backoff(Delay::new(clock::now() + wait))
                     }
                     Err(e) => {
                         warn!("profile stream failed: {:?}", e);
-                        State::Backoff(Delay::new(clock::now() + self.backoff))
+                        self.failover.failed();
+                        let wait = self.backoff.next(rand::random());
+                        State::Backoff(Delay::new(clock::now() + wait))
                     }
                 },
                 State::Backoff(ref mut f) => match f.poll() {
                     Ok(Async::NotReady) => return Ok(Async::NotReady),
-                    Err(_) | Ok(Async::Ready(())) => State::Disconnected,
+                    Err(_) | Ok(Async::Ready(())) => {
+                        self.registry.reconnected(&self.dst);
+                        State::Disconnected
+                    }
                 },
             };
+            self.record_state();
         }
     }
 }
 
+impl<T> Drop for Rx<T>
+where
+    T: HttpService<BoxBody>,
+    T::ResponseBody: Body,
+{
+    fn drop(&mut self) {
+        self.registry.remove(&self.dst);
+    }
+}
+
+// === impl Registry ===
+
+impl Registry {
+    fn register(&self, dst: &str) {
+        if let Ok(mut streams) = self.0.lock() {
+            streams
+                .entry(dst.to_owned())
+                .or_insert_with(Health::default)
+                .streams += 1;
+        }
+    }
+
+    fn set_state(&self, dst: &str, state: StreamState) {
+        if let Ok(mut streams) = self.0.lock() {
+            if let Some(health) = streams.get_mut(dst) {
+                health.state = state;
+            }
+        }
+    }
+
+    fn reconnected(&self, dst: &str) {
+        if let Ok(mut streams) = self.0.lock() {
+            if let Some(health) = streams.get_mut(dst) {
+                health.reconnects.incr();
+            }
+        }
+    }
+
+    fn remove(&self, dst: &str) {
+        if let Ok(mut streams) = self.0.lock() {
+            let unused = match streams.get_mut(dst) {
+                Some(health) => {
+                    health.streams -= 1;
+                    health.streams == 0
+                }
+                None => false,
+            };
+            if unused {
+                streams.remove(dst);
+            }
+        }
+    }
+}
+
+// === impl Report ===
+
+impl FmtMetrics for Report {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let streams = match self.0.lock() {
+            Err(_) => return Ok(()),
+            Ok(streams) => streams,
+        };
+
+        if streams.is_empty() {
+            return Ok(());
+        }
+
+        profile_stream_state.fmt_help(f)?;
+        for (dst, health) in streams.iter() {
+            for state in StreamState::ALL {
+                let value = if health.state == *state { 1 } else { 0 };
+                Gauge::from(value).fmt_metric_labeled(
+                    f,
+                    profile_stream_state.name,
+                    (Dst(dst), StateLabel(*state)),
+                )?;
+            }
+        }
+
+        profile_stream_reconnects_total.fmt_help(f)?;
+        for (dst, health) in streams.iter() {
+            health.reconnects.fmt_metric_labeled(
+                f,
+                profile_stream_reconnects_total.name,
+                Dst(dst),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// === impl StreamState ===
+
+impl StreamState {
+    const ALL: &'static [StreamState] = &[
+        StreamState::Disconnected,
+        StreamState::Waiting,
+        StreamState::Streaming,
+        StreamState::Backoff,
+    ];
+}
+
+impl Default for StreamState {
+    fn default() -> Self {
+        StreamState::Disconnected
+    }
+}
+
+impl<'a> FmtLabels for Dst<'a> {
+    fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "dst=\"{}\"", self.0)
+    }
+}
+
+impl FmtLabels for StateLabel {
+    fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self.0 {
+            StreamState::Disconnected => "disconnected",
+            StreamState::Waiting => "waiting",
+            StreamState::Streaming => "streaming",
+            StreamState::Backoff => "backoff",
+        };
+        write!(f, "state=\"{}\"", state)
+    }
+}
+
+// === impl Backoff ===
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            exponential: ExponentialBackoff::new(min, max, BACKOFF_JITTER),
+            failures: 0,
+        }
+    }
+
+    fn min(&self) -> Duration {
+        self.exponential.min()
+    }
+
+    /// Returns how long to wait after a failure, given a random number `rand`
+    /// in [0, 1), and doubles the following backoff.
+    fn next(&mut self, rand: f64) -> Duration {
+        let wait = self.exponential.duration(self.failures, rand);
+        self.failures = self.failures.saturating_add(1);
+        wait
+    }
+
+    fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+// === impl Failover ===
+
+impl<T> Failover<T> {
+    fn new(services: Arc<Vec<T>>) -> Self {
+        Self {
+            services,
+            active: 0,
+            failures: 0,
+        }
+    }
+
+    /// The controller from which profiles should be fetched.
+    fn active(&self) -> Option<&T> {
+        self.services.get(self.active)
+    }
+
+    /// The primary controller, if another controller is active.
+    fn primary(&self) -> Option<&T> {
+        if self.active == 0 {
+            return None;
+        }
+        self.services.first()
+    }
+
+    fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    fn failed(&mut self) {
+        self.failures += 1;
+        if self.failures >= MAX_FAILURES && self.services.len() > 1 {
+            self.active = (self.active + 1) % self.services.len();
+            self.failures = 0;
+            warn!("failing over to controller {}", self.active);
+        }
+    }
+
+    fn recovered(&mut self) {
+        self.active = 0;
+        self.failures = 0;
+    }
+}
+
 fn convert_route(orig: api::Route) -> Option<(profiles::RequestMatch, profiles::Route)> {
     let req_match = orig.condition.and_then(convert_req_match)?;
     let rsp_classes = orig
@@ -219,3 +597,301 @@ fn convert_rsp_match(orig: api::ResponseMatch) -> Option<profiles::ResponseMatch
 
     Some(m)
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::*;
+    use proxy::http::profiles::GetRoutes;
+    use svc;
+
+    /// A controller client whose requests always fail.
+    #[derive(Clone, Debug)]
+    struct Unavailable;
+
+    impl svc::Service<http::Request<BoxBody>> for Unavailable {
+        type Response = http::Response<BoxBody>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<BoxBody>) -> Self::Future {
+            future::err(())
+        }
+    }
+
+    /// A controller client that, while healthy, responds with an empty
+    /// profile and keeps the stream open.
+    #[derive(Clone, Debug, Default)]
+    struct Controller {
+        unhealthy: Arc<AtomicBool>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    /// A response body with a single empty profile.
+    struct EmptyProfile(Option<Bytes>);
+
+    impl svc::Service<http::Request<BoxBody>> for Controller {
+        type Response = http::Response<BoxBody>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<BoxBody>) -> Self::Future {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            if self.unhealthy.load(Ordering::SeqCst) {
+                return future::err(());
+            }
+            // A gRPC message frame: uncompressed, with an empty payload.
+            let frame = Bytes::from_static(&[0, 0, 0, 0, 0]);
+            let body = BoxBody::new(Box::new(EmptyProfile(Some(frame))));
+            future::ok(http::Response::new(body))
+        }
+    }
+
+    impl Body for EmptyProfile {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            false
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, grpc::Error> {
+            match self.0.take() {
+                Some(frame) => Ok(Async::Ready(Some(frame))),
+                None => Ok(Async::NotReady),
+            }
+        }
+
+        fn poll_metadata(&mut self) -> Poll<Option<http::HeaderMap>, grpc::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Stands in for a controller client.
+    #[derive(Debug, PartialEq)]
+    struct Stub(&'static str);
+
+    fn failover() -> Failover<Stub> {
+        Failover::new(Arc::new(vec![Stub("primary"), Stub("secondary")]))
+    }
+
+    #[test]
+    fn backoff_grows_until_capped() {
+        let backoff = Backoff {
+            exponential: ExponentialBackoff::new(
+                Duration::from_millis(100),
+                Duration::from_millis(400),
+                0.0,
+            ),
+            failures: 0,
+        };
+        let client = Client::new(vec![Unavailable], backoff, metrics().0);
+        let dst = NameAddr::from_str("web.example.com:8080").unwrap();
+        let mut rx = client.get_routes(&dst).expect("routes must be fetched");
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            for &wait in &[100, 200, 400, 400] {
+                // Each request fails, scheduling a reconnect after a growing
+                // backoff.
+                let before = clock::now();
+                assert!(rx.poll().unwrap().is_not_ready());
+                let after = clock::now();
+
+                let deadline = match rx.state {
+                    State::Backoff(ref delay) => delay.deadline(),
+                    _ => panic!("stream must back off"),
+                };
+                let wait = Duration::from_millis(wait);
+                assert!(before + wait <= deadline && deadline <= after + wait);
+
+                // Rather than waiting, the backoff is elapsed immediately.
+                rx.state = State::Disconnected;
+            }
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn stream_health_is_recorded() {
+        let (registry, report) = metrics();
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(10));
+        let client = Client::new(vec![Unavailable], backoff, registry);
+        let dst = NameAddr::from_str("web.example.com:8080").unwrap();
+        let mut rx = client.get_routes(&dst).expect("routes must be fetched");
+
+        let state = |s: &str| format!("profile_stream_state{{dst=\"{}\",state=\"{}\"}} 1", dst, s);
+        let reconnects = |n: u64| format!("profile_stream_reconnects_total{{dst=\"{}\"}} {}", dst, n);
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            // The request fails, so the stream backs off.
+            assert!(rx.poll().unwrap().is_not_ready());
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+        let rendered = format!("{}", report.as_display());
+        assert!(rendered.contains(&state("backoff")), "{}", rendered);
+        assert!(rendered.contains(&reconnects(0)), "{}", rendered);
+
+        // Once the backoff elapses, the stream reconnects and fails again.
+        rt.block_on(future::poll_fn(|| {
+            assert!(rx.poll().unwrap().is_not_ready());
+            let rendered = format!("{}", report.as_display());
+            if rendered.contains(&reconnects(1)) {
+                assert!(rendered.contains(&state("backoff")), "{}", rendered);
+                return Ok(Async::Ready(()));
+            }
+            Ok::<_, ()>(Async::NotReady)
+        }))
+        .unwrap();
+
+        // Streams are no longer reported once dropped.
+        drop(rx);
+        assert_eq!(format!("{}", report.as_display()), "");
+    }
+
+    #[test]
+    fn stream_health_is_retained_while_any_stream_remains() {
+        let (registry, report) = metrics();
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(10));
+        let client = Client::new(vec![Unavailable], backoff, registry);
+        let dst = NameAddr::from_str("web.example.com:8080").unwrap();
+        let rx0 = client.get_routes(&dst).expect("routes must be fetched");
+        let rx1 = client.get_routes(&dst).expect("routes must be fetched");
+
+        let state = format!("profile_stream_state{{dst=\"{}\",state=\"disconnected\"}} 1", dst);
+
+        drop(rx0);
+        let rendered = format!("{}", report.as_display());
+        assert!(rendered.contains(&state), "{}", rendered);
+
+        drop(rx1);
+        assert_eq!(format!("{}", report.as_display()), "");
+    }
+
+    #[test]
+    fn backoff_is_jittered_and_reset() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(10));
+        assert_eq!(backoff.next(0.0), Duration::from_millis(100));
+        assert_eq!(backoff.next(1.0), Duration::from_millis(100));
+        assert_eq!(backoff.next(0.5), Duration::from_millis(300));
+
+        backoff.reset();
+        assert_eq!(backoff.next(0.0), Duration::from_millis(100));
+
+        // The backoff never exceeds the maximum.
+        for _ in 0..64 {
+            assert!(backoff.next(0.0) <= Duration::from_secs(10));
+        }
+        assert_eq!(backoff.next(0.0), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn fails_over_after_repeated_failures() {
+        let mut f = failover();
+        assert_eq!(f.active(), Some(&Stub("primary")));
+        assert_eq!(f.primary(), None);
+
+        for _ in 1..MAX_FAILURES {
+            f.failed();
+            assert_eq!(f.active(), Some(&Stub("primary")));
+        }
+        f.failed();
+        assert_eq!(f.active(), Some(&Stub("secondary")));
+        assert_eq!(f.primary(), Some(&Stub("primary")));
+    }
+
+    #[test]
+    fn successes_reset_failures() {
+        let mut f = failover();
+        for _ in 0..(MAX_FAILURES * 2) {
+            f.failed();
+            f.succeeded();
+        }
+        assert_eq!(f.active(), Some(&Stub("primary")));
+    }
+
+    #[test]
+    fn failover_is_sticky_until_the_primary_recovers() {
+        let mut f = failover();
+        for _ in 0..MAX_FAILURES {
+            f.failed();
+        }
+        assert_eq!(f.active(), Some(&Stub("secondary")));
+
+        // Occasional failures of the secondary do not return to the primary.
+        f.failed();
+        f.succeeded();
+        assert_eq!(f.active(), Some(&Stub("secondary")));
+
+        f.recovered();
+        assert_eq!(f.active(), Some(&Stub("primary")));
+        assert_eq!(f.primary(), None);
+    }
+
+    #[test]
+    fn profiles_fail_over_and_return_to_the_recovered_primary() {
+        let (primary, secondary) = (Controller::default(), Controller::default());
+        primary.unhealthy.store(true, Ordering::SeqCst);
+        let requests = |c: &Controller| c.requests.load(Ordering::SeqCst);
+
+        // The primary is probed after the minimum backoff.
+        let backoff = Backoff::new(Duration::from_millis(50), Duration::from_millis(50));
+        let controllers = vec![primary.clone(), secondary.clone()];
+        let client = Client::new(controllers, backoff, metrics().0);
+        let dst = NameAddr::from_str("web.example.com:8080").unwrap();
+        let mut rx = client.get_routes(&dst).expect("routes must be fetched");
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            for _ in 0..MAX_FAILURES {
+                assert!(rx.poll().unwrap().is_not_ready());
+                // Rather than waiting, the backoff is elapsed immediately.
+                rx.state = State::Disconnected;
+            }
+            assert_eq!(requests(&primary), MAX_FAILURES);
+
+            // The profile is fetched from the secondary.
+            let routes = rx.poll().unwrap();
+            assert!(routes.is_ready(), "profile must be fetched from the secondary");
+            assert_eq!(requests(&secondary), 1);
+            assert_eq!(requests(&primary), MAX_FAILURES);
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+
+        // Once the primary recovers, the profile is fetched from it again.
+        primary.unhealthy.store(false, Ordering::SeqCst);
+        let routes = rt.block_on(future::poll_fn(|| rx.poll())).unwrap();
+        assert!(routes.is_some());
+        assert_eq!(requests(&primary), MAX_FAILURES + 1);
+        assert_eq!(requests(&secondary), 1);
+        assert!(rx.failover.primary().is_none(), "failover must end");
+    }
+
+    #[test]
+    fn single_controller_never_fails_over() {
+        let mut f = Failover::new(Arc::new(vec![Stub("primary")]));
+        for _ in 0..(MAX_FAILURES * 2) {
+            f.failed();
+        }
+        assert_eq!(f.active(), Some(&Stub("primary")));
+        assert_eq!(f.primary(), None);
+
+        let none = Failover::<Stub>::new(Arc::new(Vec::new()));
+        assert_eq!(none.active(), None);
+    }
+}
@@ -0,0 +1,285 @@
+use futures::future::{self, Either, FutureResult};
+use futures::{Future, Poll};
+use http::StatusCode;
+use hyper::{self, Body, Request, Response};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::config::StartupPolicy;
+use drain;
+use svc;
+
+/// Reports whether the proxy is ready to serve traffic.
+///
+/// The proxy is not ready before it has reached the Destination service (per
+/// its `StartupPolicy`), nor once it has begun to drain.
+#[derive(Clone, Debug)]
+pub struct Readiness {
+    ready: Arc<AtomicBool>,
+    draining: Arc<AtomicBool>,
+}
+
+/// Marks the proxy as ready once the Destination service has been reached.
+#[derive(Clone, Debug)]
+pub struct Latch(Arc<AtomicBool>);
+
+/// Serves the proxy's readiness on `/ready`, delegating all other requests
+/// to an inner admin service.
+#[derive(Clone, Debug)]
+pub struct Serve<S> {
+    readiness: Readiness,
+    inner: S,
+}
+
+/// A stack module that releases a `Latch` once its service is ready (i.e.
+/// once it has connected to the controller).
+#[derive(Clone, Debug)]
+pub struct Layer {
+    latch: Latch,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+    latch: Latch,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    inner: S,
+    latch: Latch,
+}
+
+/// Creates a readiness handle and the latch that marks it ready.
+///
+/// Under `StartupPolicy::ServeWithFallback`, the proxy is ready immediately.
+pub fn new(policy: StartupPolicy) -> (Readiness, Latch) {
+    let ready = Arc::new(AtomicBool::new(policy == StartupPolicy::ServeWithFallback));
+    let readiness = Readiness {
+        ready: ready.clone(),
+        draining: Arc::new(AtomicBool::new(false)),
+    };
+    (readiness, Latch(ready))
+}
+
+// === impl Readiness ===
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire) && !self.is_draining()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Returns a background future that marks the proxy as not ready once
+    /// `watch` is signaled.
+    pub fn drain_on(&self, watch: drain::Watch) -> impl Future<Item = (), Error = ()> {
+        let draining = self.draining.clone();
+        watch.signaled().map_err(|never| match never {}).map(move |()| {
+            debug!("draining; not ready");
+            draining.store(true, Ordering::Release);
+        })
+    }
+
+    pub fn serve<S>(self, inner: S) -> Serve<S> {
+        Serve {
+            readiness: self,
+            inner,
+        }
+    }
+}
+
+// === impl Latch ===
+
+impl Latch {
+    pub fn release(&self) {
+        if !self.0.swap(true, Ordering::AcqRel) {
+            debug!("controller reached; ready");
+        }
+    }
+}
+
+// === impl Serve ===
+
+impl<S> hyper::service::Service for Serve<S>
+where
+    S: hyper::service::Service<ReqBody = Body, ResBody = Body>,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = S::Error;
+    type Future = Either<FutureResult<Response<Body>, Self::Error>, S::Future>;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if req.uri().path() != "/ready" {
+            return Either::B(self.inner.call(req));
+        }
+
+        let (status, body) = if self.readiness.is_draining() {
+            (StatusCode::SERVICE_UNAVAILABLE, "draining\n")
+        } else if self.readiness.is_ready() {
+            (StatusCode::OK, "ready\n")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "not ready\n")
+        };
+        let rsp = Response::builder()
+            .status(status)
+            .body(Body::from(body))
+            .expect("builder with known status code should not fail");
+        Either::A(future::ok(rsp))
+    }
+}
+
+// === impl Layer ===
+
+pub fn layer(latch: Latch) -> Layer {
+    Layer { latch }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            inner,
+            latch: self.latch.clone(),
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service {
+            inner,
+            latch: self.latch.clone(),
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, Req> svc::Service<Req> for Service<S>
+where
+    S: svc::Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        let ready = self.inner.poll_ready()?;
+        if ready.is_ready() {
+            self.latch.release();
+        }
+        Ok(ready)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Async, Future};
+
+    use super::*;
+    use svc::Service as _Service;
+
+    /// A service that becomes ready once it has connected.
+    struct Connecting(bool);
+
+    impl svc::Service<()> for Connecting {
+        type Response = ();
+        type Error = ();
+        type Future = FutureResult<(), ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            if self.0 {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            future::ok(())
+        }
+    }
+
+    fn ready_status(readiness: &Readiness) -> StatusCode {
+        let admin = hyper::service::service_fn_ok(|_| Response::new(Body::empty()));
+        let mut serve = readiness.clone().serve(admin);
+        let req = Request::get("/ready").body(Body::empty()).unwrap();
+        hyper::service::Service::call(&mut serve, req)
+            .wait()
+            .expect("response")
+            .status()
+    }
+
+    #[test]
+    fn fail_ready_waits_for_controller() {
+        let (readiness, latch) = new(StartupPolicy::FailReady);
+        assert!(!readiness.is_ready());
+        assert_eq!(ready_status(&readiness), StatusCode::SERVICE_UNAVAILABLE);
+
+        let mut svc = Service {
+            inner: Connecting(false),
+            latch,
+        };
+        assert!(svc.poll_ready().unwrap().is_not_ready());
+        assert!(!readiness.is_ready());
+        assert_eq!(ready_status(&readiness), StatusCode::SERVICE_UNAVAILABLE);
+
+        svc.inner.0 = true;
+        assert!(svc.poll_ready().unwrap().is_ready());
+        assert!(readiness.is_ready());
+        assert_eq!(ready_status(&readiness), StatusCode::OK);
+    }
+
+    #[test]
+    fn serve_with_fallback_is_ready_immediately() {
+        let (readiness, _latch) = new(StartupPolicy::ServeWithFallback);
+        assert!(readiness.is_ready());
+        assert_eq!(ready_status(&readiness), StatusCode::OK);
+    }
+
+    #[test]
+    fn not_ready_once_draining() {
+        let (readiness, _latch) = new(StartupPolicy::ServeWithFallback);
+        let (signal, watch) = drain::channel();
+
+        future::lazy(|| {
+            let mut drain_on = readiness.drain_on(watch);
+            assert!(drain_on.poll().unwrap().is_not_ready());
+            assert_eq!(ready_status(&readiness), StatusCode::OK);
+
+            let mut drained = signal.drain();
+            assert!(drain_on.poll().unwrap().is_ready());
+            assert!(!readiness.is_ready());
+            assert_eq!(ready_status(&readiness), StatusCode::SERVICE_UNAVAILABLE);
+
+            // The readiness watch does not hold up the drain.
+            drop(drain_on);
+            assert!(drained.poll().unwrap().is_ready());
+
+            Ok::<_, ()>(())
+        }).wait().unwrap();
+    }
+}
@@ -268,7 +268,7 @@ where
             CacheChange::Removal { key } => ("remove", Update::Remove(key), key),
             CacheChange::Modification { key, new_value } => (
                 "change metadata for",
-                Update::Add(key, new_value.clone()),
+                Update::Update(key, new_value.clone()),
                 key,
             ),
         };
@@ -43,6 +43,7 @@ use transport::tls;
 use proxy::resolve::{self, Resolve, Update};
 
 pub mod background;
+pub mod srv;
 
 use app::config::Namespaces;
 use self::background::Background;
@@ -0,0 +1,336 @@
+//! Resolves destinations via DNS SRV records.
+//!
+//! Headless services may delegate port selection to DNS: each SRV target
+//! names a host and the port on which it serves. Names matching the
+//! configured suffixes are resolved via SRV lookups rather than the
+//! Destination service, so the port in the request's authority is ignored.
+
+use futures::{future, Async, Future, Poll};
+use indexmap::IndexMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_timer::{clock, Delay};
+
+use super::{Metadata, ProtocolHint};
+use dns;
+use proxy::http::balance;
+use proxy::resolve::{self, Update};
+use transport::tls;
+use {Conditional, NameAddr};
+
+/// Duration to wait before querying DNS again after an error (or a NXDOMAIN
+/// response with no TTL).
+const DNS_ERROR_TTL: Duration = Duration::from_secs(5);
+
+/// Resolves names matching `suffixes` via SRV records, and all other names
+/// via an inner resolver.
+#[derive(Clone, Debug)]
+pub struct Resolve<R> {
+    inner: R,
+    dns: dns::Resolver,
+    suffixes: Arc<Vec<dns::Suffix>>,
+}
+
+#[derive(Debug)]
+pub enum Resolution<R> {
+    Inner(R),
+    Srv(SrvResolution),
+}
+
+/// Watches a name's SRV records, updating its endpoints as the records
+/// expire.
+pub struct SrvResolution {
+    name: dns::Name,
+    dns: dns::Resolver,
+    state: State,
+    endpoints: IndexMap<SocketAddr, Metadata>,
+    updates: VecDeque<Update<Metadata>>,
+}
+
+type Query = Box<Future<Item = Endpoints, Error = dns::ResolveError> + Send>;
+
+/// The endpoints resolved from a name's SRV records, and when they expire.
+type Endpoints = (IndexMap<SocketAddr, Metadata>, Instant);
+
+enum State {
+    Querying(Query),
+    Waiting(Delay),
+}
+
+// === impl Resolve ===
+
+impl<R> Resolve<R>
+where
+    R: resolve::Resolve<NameAddr, Endpoint = Metadata>,
+{
+    pub fn new(inner: R, dns: dns::Resolver, suffixes: Vec<dns::Suffix>) -> Self {
+        Self {
+            inner,
+            dns,
+            suffixes: Arc::new(suffixes),
+        }
+    }
+}
+
+impl<R> resolve::Resolve<NameAddr> for Resolve<R>
+where
+    R: resolve::Resolve<NameAddr, Endpoint = Metadata>,
+{
+    type Endpoint = Metadata;
+    type Resolution = Resolution<R::Resolution>;
+
+    fn resolve(&self, authority: &NameAddr) -> Self::Resolution {
+        let name = authority.name();
+        if self.suffixes.iter().any(|s| s.contains(name)) {
+            trace!("resolving {} via SRV records", name);
+            Resolution::Srv(SrvResolution::new(name.clone(), self.dns.clone()))
+        } else {
+            Resolution::Inner(self.inner.resolve(authority))
+        }
+    }
+}
+
+// === impl Resolution ===
+
+impl<R> resolve::Resolution for Resolution<R>
+where
+    R: resolve::Resolution<Endpoint = Metadata>,
+{
+    type Endpoint = Metadata;
+    type Error = R::Error;
+
+    fn poll(&mut self) -> Poll<Update<Self::Endpoint>, Self::Error> {
+        match *self {
+            Resolution::Inner(ref mut res) => res.poll(),
+            Resolution::Srv(ref mut res) => Ok(res.poll()),
+        }
+    }
+}
+
+// === impl SrvResolution ===
+
+impl SrvResolution {
+    fn new(name: dns::Name, dns: dns::Resolver) -> Self {
+        let state = State::Querying(query(&dns, &name));
+        Self {
+            name,
+            dns,
+            state,
+            endpoints: IndexMap::new(),
+            updates: VecDeque::new(),
+        }
+    }
+
+    fn poll(&mut self) -> Async<Update<Metadata>> {
+        loop {
+            if let Some(update) = self.updates.pop_front() {
+                return Async::Ready(update);
+            }
+
+            let result = match self.state {
+                State::Waiting(ref mut delay) => match delay.poll() {
+                    Ok(Async::NotReady) => return Async::NotReady,
+                    // A timer error is treated as the deadline elapsing.
+                    Ok(Async::Ready(())) | Err(_) => None,
+                },
+                State::Querying(ref mut query) => match query.poll() {
+                    Ok(Async::NotReady) => return Async::NotReady,
+                    Ok(Async::Ready(endpoints)) => Some(Ok(endpoints)),
+                    Err(e) => Some(Err(e)),
+                },
+            };
+
+            self.state = match result {
+                None => State::Querying(query(&self.dns, &self.name)),
+                Some(result) => State::Waiting(Delay::new(self.update(result))),
+            };
+        }
+    }
+
+    /// Records the result of a query, returning when the name should be
+    /// queried again.
+    fn update(&mut self, result: Result<Endpoints, dns::ResolveError>) -> Instant {
+        match result {
+            Ok((endpoints, valid_until)) => {
+                trace!("SRV endpoints for {}: {:?}", self.name, endpoints.keys());
+                self.updates.extend(diff(&self.endpoints, &endpoints));
+                self.endpoints = endpoints;
+                valid_until
+            }
+            Err(e) => {
+                if let &dns::ResolveErrorKind::NoRecordsFound { valid_until, .. } = e.kind() {
+                    trace!("no SRV records for {}", self.name);
+                    let endpoints = IndexMap::new();
+                    self.updates.extend(diff(&self.endpoints, &endpoints));
+                    self.endpoints = endpoints;
+                    return valid_until.unwrap_or_else(|| clock::now() + DNS_ERROR_TTL);
+                }
+
+                // The most recent endpoints are used until a query succeeds.
+                debug!("SRV resolution failed for {}: {}", self.name, e);
+                clock::now() + DNS_ERROR_TTL
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SrvResolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SrvResolution")
+            .field("name", &self.name)
+            .field("endpoints", &self.endpoints)
+            .finish()
+    }
+}
+
+/// Looks up `name`'s SRV records and resolves the address of each of the
+/// most-preferred targets.
+///
+/// Targets whose addresses cannot be resolved are ignored.
+fn query(dns: &dns::Resolver, name: &dns::Name) -> Query {
+    let dns = dns.clone();
+    let f = dns.resolve_srv(name).and_then(move |srv| {
+        let valid_until = srv.valid_until;
+        let targets = preferred(srv.targets).into_iter().map(move |t| {
+            dns.resolve_one_ip(&t.target).then(move |ip| match ip {
+                Ok(ip) => Ok(Some((SocketAddr::from((ip, t.port)), metadata(&t)))),
+                Err(e) => {
+                    debug!("failed to resolve SRV target {}: {:?}", t.target, e);
+                    Ok(None)
+                }
+            })
+        });
+        future::join_all(targets).map(move |endpoints| {
+            let endpoints = endpoints.into_iter().filter_map(|ep| ep).collect();
+            (endpoints, valid_until)
+        })
+    });
+    Box::new(f)
+}
+
+/// Returns the targets with the lowest priority value, which clients must
+/// prefer (per RFC 2782).
+fn preferred(targets: Vec<dns::Srv>) -> Vec<dns::Srv> {
+    let min = targets.iter().map(|t| t.priority).min();
+    match min {
+        Some(min) => targets.into_iter().filter(|t| t.priority == min).collect(),
+        None => targets,
+    }
+}
+
+/// Describes a SRV target as an endpoint, weighted by its SRV weight.
+///
+/// Targets with a weight of zero are weighted as 1 so that they are not
+/// drained.
+fn metadata(target: &dns::Srv) -> Metadata {
+    let mut labels = IndexMap::new();
+    let weight = target.weight.max(1);
+    labels.insert(balance::weight::LABEL.to_owned(), weight.to_string());
+    let tls = tls::ReasonForNoIdentity::NotProvidedByServiceDiscovery;
+    Metadata::new(labels, ProtocolHint::Unknown, Conditional::None(tls))
+}
+
+/// Returns the updates that change the endpoints from `old` to `new`.
+fn diff(
+    old: &IndexMap<SocketAddr, Metadata>,
+    new: &IndexMap<SocketAddr, Metadata>,
+) -> Vec<Update<Metadata>> {
+    let removed = old
+        .keys()
+        .filter(|addr| !new.contains_key(*addr))
+        .map(|addr| Update::Remove(*addr));
+    let added = new.iter().filter_map(|(addr, meta)| match old.get(addr) {
+        None => Some(Update::Add(*addr, meta.clone())),
+        Some(old) if old != meta => Some(Update::Update(*addr, meta.clone())),
+        Some(_) => None,
+    });
+    removed.chain(added).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use convert::TryFrom;
+
+    fn srv(target: &str, port: u16, priority: u16, weight: u16) -> dns::Srv {
+        dns::Srv {
+            target: dns::Name::try_from(target.as_bytes()).unwrap(),
+            port,
+            priority,
+            weight,
+        }
+    }
+
+    #[test]
+    fn lowest_priority_targets_are_preferred() {
+        let targets = vec![
+            srv("backup.example.com.", 8080, 20, 10),
+            srv("web-0.example.com.", 8080, 10, 60),
+            srv("web-1.example.com.", 8081, 10, 0),
+        ];
+        let preferred = preferred(targets)
+            .into_iter()
+            .map(|t| (t.target.as_ref().to_owned(), t.port))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            preferred,
+            vec![
+                ("web-0.example.com.".to_owned(), 8080),
+                ("web-1.example.com.".to_owned(), 8081),
+            ]
+        );
+    }
+
+    #[test]
+    fn updates_reflect_changed_targets() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let old = vec![
+            (addr("10.1.1.1:8080"), metadata(&srv("a.example.com.", 8080, 10, 1))),
+            (addr("10.1.1.2:8080"), metadata(&srv("b.example.com.", 8080, 10, 1))),
+        ]
+        .into_iter()
+        .collect::<IndexMap<_, _>>();
+        let new = vec![
+            (addr("10.1.1.1:8080"), metadata(&srv("a.example.com.", 8080, 10, 1))),
+            (addr("10.1.1.2:8080"), metadata(&srv("b.example.com.", 8080, 10, 5))),
+            (addr("10.1.1.3:8081"), metadata(&srv("c.example.com.", 8081, 10, 1))),
+        ]
+        .into_iter()
+        .collect::<IndexMap<_, _>>();
+
+        let updates = diff(&old, &new)
+            .into_iter()
+            .map(|up| match up {
+                Update::Add(addr, meta) => {
+                    let weight = meta.labels()[balance::weight::LABEL].clone();
+                    (addr, "add", Some(weight))
+                }
+                Update::Update(addr, meta) => {
+                    let weight = meta.labels()[balance::weight::LABEL].clone();
+                    (addr, "update", Some(weight))
+                }
+                Update::Remove(addr) => (addr, "remove", None),
+            })
+            .collect::<Vec<_>>();
+        // The reweighted target is updated rather than re-added.
+        assert_eq!(
+            updates,
+            vec![
+                (addr("10.1.1.2:8080"), "update", Some("5".to_owned())),
+                (addr("10.1.1.3:8081"), "add", Some("1".to_owned())),
+            ]
+        );
+
+        let removed = diff(&new, &old)
+            .into_iter()
+            .filter_map(|up| match up {
+                Update::Remove(addr) => Some(addr),
+                Update::Add(..) | Update::Update(..) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(removed, vec![addr("10.1.1.3:8081")]);
+    }
+}
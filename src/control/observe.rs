@@ -1,14 +1,20 @@
 use futures::{future, Poll, Stream};
 use futures_mpsc_lossy;
-use http::HeaderMap;
-use indexmap::IndexMap;
+use http::header::{HeaderMap, HeaderValue};
+use indexmap::{IndexMap, IndexSet};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use tower_grpc::{self as grpc, Response};
 
 use api::tap::{server, ObserveRequest, TapEvent};
 use convert::*;
-use tap::{event, Event, Tap, Taps};
+use tap::{event, Event, InvalidMatch, ResponseMatch, Tap, Taps};
+
+const GRPC_MESSAGE: &str = "grpc-message";
+
+/// Taps only one in every N matching requests.
+const SAMPLE_STRIDE_HEADER: &str = "l5d-tap-sample-stride";
 
 #[derive(Clone, Debug)]
 pub struct Observe {
@@ -21,6 +27,16 @@ pub struct TapEvents {
     rx: futures_mpsc_lossy::Receiver<Event>,
     remaining: usize,
     current: IndexMap<usize, event::Request>,
+    /// Forwarded connections that have been tapped and not yet closed, by
+    /// their source and destination addresses.
+    connections: IndexSet<(SocketAddr, SocketAddr)>,
+    /// Only one in every `sample_stride` matching requests is tapped.
+    sample_stride: usize,
+    /// The number of matching requests seen, whether or not they were
+    /// sampled.
+    matched: usize,
+    /// Requests that were not sampled, whose events are ignored.
+    unsampled: IndexSet<usize>,
     tap_id: usize,
     taps: Arc<Mutex<Taps>>,
 }
@@ -51,17 +67,26 @@ impl server::Tap for Observe {
             ));
         }
 
+        // `ObserveRequest` cannot describe responses or sampling, so response
+        // filters and the sampling stride are read from the request's headers.
+        let response_match = match ResponseMatch::from_headers(req.headers()) {
+            Ok(m) => m,
+            Err(e) => return future::err(invalid_argument(&e)),
+        };
+        let sample_stride = match sample_stride(req.headers()) {
+            Ok(s) => s,
+            Err(e) => return future::err(invalid_argument(&e)),
+        };
+
         let req = req.into_inner();
-        let (tap, rx) = match req.match_
-            .and_then(|m| Tap::new(&m, self.tap_capacity).ok())
-        {
-            Some(m) => m,
-            None => {
-                return future::err(grpc::Error::Grpc(
-                    grpc::Status::with_code(grpc::Code::InvalidArgument),
-                    HeaderMap::new(),
-                ));
-            }
+        let capacity = self.tap_capacity;
+        let tap = req
+            .match_
+            .ok_or(InvalidMatch::Empty)
+            .and_then(|m| Tap::new(&m, capacity, response_match));
+        let (tap, rx) = match tap {
+            Ok(m) => m,
+            Err(e) => return future::err(invalid_argument(&e)),
         };
 
         let tap_id = match self.taps.lock() {
@@ -82,6 +107,10 @@ impl server::Tap for Observe {
             rx,
             tap_id,
             current: IndexMap::default(),
+            connections: IndexSet::default(),
+            sample_stride,
+            matched: 0,
+            unsampled: IndexSet::default(),
             remaining: req.limit as usize,
             taps: self.taps.clone(),
         };
@@ -90,13 +119,64 @@ impl server::Tap for Observe {
     }
 }
 
+/// Parses the sampling stride from the headers of an `ObserveRequest`.
+///
+/// If no stride is requested, every matching request is tapped.
+fn sample_stride(headers: &HeaderMap) -> Result<usize, InvalidMatch> {
+    let v = match headers.get(SAMPLE_STRIDE_HEADER) {
+        Some(v) => v,
+        None => return Ok(1),
+    };
+    v.to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .and_then(|s| if s == 0 { None } else { Some(s) })
+        .ok_or_else(|| {
+            InvalidMatch::InvalidStride(format!("{:?}", v)).in_field(SAMPLE_STRIDE_HEADER)
+        })
+}
+
+/// Fails a tap with an `InvalidArgument` status whose message describes the
+/// invalid match.
+fn invalid_argument(e: &InvalidMatch) -> grpc::Error {
+    debug!("invalid tap: {}", e);
+    let mut headers = HeaderMap::new();
+    let msg = HeaderValue::from_str(&grpc_message(&e.to_string()))
+        .expect("grpc-message must be percent-encoded");
+    headers.insert(GRPC_MESSAGE, msg);
+    grpc::Error::Grpc(grpc::Status::with_code(grpc::Code::InvalidArgument), headers)
+}
+
+/// Percent-encodes a `grpc-message` value, as required by the gRPC spec.
+fn grpc_message(msg: &str) -> String {
+    let mut encoded = String::with_capacity(msg.len());
+    for b in msg.bytes() {
+        if b < b' ' || b > b'~' || b == b'%' {
+            encoded.push_str(&format!("%{:02X}", b));
+        } else {
+            encoded.push(b as char);
+        }
+    }
+    encoded
+}
+
+impl TapEvents {
+    /// Returns true if the next matching request should be tapped.
+    fn sample(&mut self) -> bool {
+        let sampled = self.matched % self.sample_stride == 0;
+        self.matched = self.matched.wrapping_add(1);
+        sampled
+    }
+}
+
 impl Stream for TapEvents {
     type Item = TapEvent;
     type Error = grpc::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         loop {
-            if self.remaining == 0 && self.current.is_empty() {
+            let is_idle = self.current.is_empty() && self.connections.is_empty();
+            if self.remaining == 0 && is_idle {
                 trace!("tap completed");
                 return Ok(None.into());
             }
@@ -122,11 +202,19 @@ impl Stream for TapEvents {
                                 trace!("exhausted; ignoring req={}", req.id);
                                 continue;
                             }
+                            if !self.sample() {
+                                trace!("not sampled; ignoring req={}", req.id);
+                                self.unsampled.insert(req.id);
+                                continue;
+                            }
                             trace!("insert req={}", req.id);
                             self.remaining -= 1;
                             let _ = self.current.insert(req.id, req.clone());
                         }
                         Event::StreamRequestFail(ref req, _) => {
+                            if self.unsampled.remove(&req.id) {
+                                continue;
+                            }
                             trace!("fail req={}", req.id);
                             if self.current.remove(&req.id).is_none() {
                                 warn!("did not exist req={}", req.id);
@@ -134,6 +222,9 @@ impl Stream for TapEvents {
                             }
                         }
                         Event::StreamResponseOpen(ref rsp, _) => {
+                            if self.unsampled.contains(&rsp.request.id) {
+                                continue;
+                            }
                             trace!("response req={}", rsp.request.id);
                             if !self.current.contains_key(&rsp.request.id) {
                                 warn!("did not exist req={}", rsp.request.id);
@@ -142,12 +233,31 @@ impl Stream for TapEvents {
                         }
                         Event::StreamResponseFail(ref rsp, _) |
                         Event::StreamResponseEnd(ref rsp, _) => {
+                            if self.unsampled.remove(&rsp.request.id) {
+                                continue;
+                            }
                             trace!("end req={}", rsp.request.id);
                             if self.current.remove(&rsp.request.id).is_none() {
                                 warn!("did not exist req={}", rsp.request.id);
                                 continue;
                             }
                         }
+                        Event::TcpOpen(ref conn) => {
+                            if self.remaining == 0 {
+                                trace!("exhausted; ignoring conn={}", conn.source.remote);
+                                continue;
+                            }
+                            trace!("insert conn={}", conn.source.remote);
+                            self.remaining -= 1;
+                            self.connections.insert((conn.source.remote, conn.destination));
+                        }
+                        Event::TcpClose(ref conn, _) => {
+                            trace!("close conn={}", conn.source.remote);
+                            let key = (conn.source.remote, conn.destination);
+                            if !self.connections.remove(&key) {
+                                continue;
+                            }
+                        }
                         ev => {
                             trace!("ignoring event: {:?}", ev);
                             continue
@@ -176,3 +286,159 @@ impl Drop for TapEvents {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{Async, Future};
+
+    use super::*;
+
+    #[test]
+    fn taps_one_in_every_sample_stride_requests() {
+        let (tx, rx) = futures_mpsc_lossy::channel(16);
+        let mut events = TapEvents {
+            rx,
+            remaining: 8,
+            current: IndexMap::default(),
+            connections: IndexSet::default(),
+            sample_stride: 4,
+            matched: 0,
+            unsampled: IndexSet::default(),
+            tap_id: 0,
+            taps: Arc::new(Mutex::new(Taps::default())),
+        };
+
+        for id in 0..8 {
+            tx.lossy_send(Event::StreamRequestOpen(event::Request::for_test(id))).unwrap();
+        }
+        drop(tx);
+
+        let tapped = future::lazy(|| {
+            let mut tapped = Vec::new();
+            while let Async::Ready(Some(ev)) = events.poll()? {
+                tapped.push(ev);
+            }
+            Ok::<_, grpc::Error>(tapped)
+        })
+        .wait()
+        .expect("poll");
+        assert_eq!(tapped.len(), 2);
+        assert_eq!(events.current.keys().collect::<Vec<_>>(), vec![&0, &4]);
+        assert_eq!(events.unsampled.len(), 6);
+    }
+
+    #[test]
+    fn forwarded_connections_are_tapped() {
+        use api::net::TcpAddress;
+        use tokio_timer::clock;
+
+        let (tx, rx) = futures_mpsc_lossy::channel(4);
+        let mut events = TapEvents {
+            rx,
+            remaining: 1,
+            current: IndexMap::default(),
+            connections: IndexSet::default(),
+            sample_stride: 1,
+            matched: 0,
+            unsampled: IndexSet::default(),
+            tap_id: 0,
+            taps: Arc::new(Mutex::new(Taps::default())),
+        };
+
+        let dst = "10.1.1.1:8080".parse().unwrap();
+        let conn = event::Connection::for_test("10.2.2.2:50000".parse().unwrap(), dst);
+        let now = clock::now();
+        let close = event::TcpClose {
+            open_at: now,
+            close_at: now,
+            bytes_received: 5,
+            bytes_sent: 4,
+        };
+        tx.lossy_send(Event::TcpOpen(conn.clone())).unwrap();
+        tx.lossy_send(Event::TcpClose(conn, close)).unwrap();
+
+        // The sender is held, so the stream ends because the tap's limit was
+        // reached and its only connection has closed.
+        let tapped = future::lazy(|| {
+            let mut tapped = Vec::new();
+            while let Async::Ready(Some(ev)) = events.poll()? {
+                tapped.push(ev);
+            }
+            Ok::<_, grpc::Error>(tapped)
+        })
+        .wait()
+        .expect("poll");
+        assert_eq!(tapped.len(), 2);
+        assert!(events.connections.is_empty());
+
+        let labels = |ev: &TapEvent| ev.destination_meta.as_ref().unwrap().labels.clone();
+        assert_eq!(tapped[0].destination, Some(TcpAddress::from(&dst)));
+        assert_eq!(labels(&tapped[0])["tcp"], "open");
+        let close = labels(&tapped[1]);
+        assert_eq!(close["tcp"], "close");
+        assert_eq!(close["bytes_received"], "5");
+        assert_eq!(close["bytes_sent"], "4");
+        drop(tx);
+    }
+
+    #[test]
+    fn sample_stride_is_read_from_headers() {
+        use api::tap::observe_request::{self, match_};
+        use api::tap::server::Tap as _Tap;
+
+        let observe_stride = |stride: Option<&'static str>| {
+            let (_, mut observe) = Observe::new(16);
+            let mut req = grpc::Request::new(ObserveRequest {
+                limit: 1,
+                match_: Some(observe_request::Match {
+                    match_: Some(match_::Match::All(match_::Seq::default())),
+                }),
+            });
+            if let Some(stride) = stride {
+                req.headers_mut()
+                    .insert(SAMPLE_STRIDE_HEADER, HeaderValue::from_static(stride));
+            }
+            observe.observe(req).wait().map(|rsp| rsp.into_inner().sample_stride)
+        };
+
+        assert_eq!(observe_stride(None).ok(), Some(1));
+        assert_eq!(observe_stride(Some("4")).ok(), Some(4));
+        match observe_stride(Some("0")) {
+            Err(grpc::Error::Grpc(_, headers)) => assert_eq!(
+                headers[GRPC_MESSAGE],
+                "l5d-tap-sample-stride: invalid sample stride \"0\""
+            ),
+            _ => panic!("tap must fail with a gRPC error"),
+        }
+    }
+
+    #[test]
+    fn invalid_matches_name_field_in_grpc_message() {
+        use api::tap::observe_request::{self, match_::{self as pb, tcp}};
+        use api::tap::server::Tap as _Tap;
+
+        let (_, mut observe) = Observe::new(16);
+        let req = ObserveRequest {
+            limit: 1,
+            match_: Some(observe_request::Match {
+                match_: Some(pb::Match::Source(pb::Tcp {
+                    match_: Some(tcp::Match::Ports(tcp::PortRange { min: 65536, max: 0 })),
+                })),
+            }),
+        };
+
+        match observe.observe(grpc::Request::new(req)).wait() {
+            Err(grpc::Error::Grpc(_, headers)) => assert_eq!(
+                headers[GRPC_MESSAGE],
+                "source.ports.min: invalid port 65536"
+            ),
+            _ => panic!("tap must fail with a gRPC error"),
+        }
+    }
+
+    #[test]
+    fn grpc_messages_are_percent_encoded() {
+        assert_eq!(grpc_message("invalid port 0"), "invalid port 0");
+        assert_eq!(grpc_message("100%\n\u{e9}"), "100%25%0A%C3%A9");
+    }
+}
@@ -107,6 +107,27 @@ impl event::StreamRequestFail {
     }
 }
 
+impl event::Connection {
+    /// The tap API cannot describe TCP events, so a forwarded connection is
+    /// described by its endpoints, with a `tcp` label on the destination's
+    /// metadata that indicates whether it was opened or closed.
+    fn to_tap_event(&self, labels: Vec<(&str, String)>) -> tap::TapEvent {
+        let mut dst_meta = tap::tap_event::EndpointMeta::default();
+        for (k, v) in labels {
+            dst_meta.labels.insert(k.to_owned(), v);
+        }
+
+        tap::TapEvent {
+            proxy_direction: tap::tap_event::ProxyDirection::Unknown.into(),
+            source: Some((&self.source.remote).into()),
+            source_meta: Some(self.source.src_meta()),
+            destination: Some((&self.destination).into()),
+            destination_meta: Some(dst_meta),
+            event: None,
+        }
+    }
+}
+
 impl<'a> TryFrom<&'a Event> for tap::TapEvent {
     type Err = UnknownEvent;
     fn try_from(ev: &'a Event) -> Result<Self, Self::Err> {
@@ -174,6 +195,18 @@ impl<'a> TryFrom<&'a Event> for tap::TapEvent {
                 fail.to_tap_event(&ctx.request)
             }
 
+            Event::TcpOpen(ref conn) => {
+                conn.to_tap_event(vec![("tcp", "open".to_owned())])
+            }
+
+            Event::TcpClose(ref conn, ref close) => {
+                conn.to_tap_event(vec![
+                    ("tcp", "close".to_owned()),
+                    ("bytes_received", close.bytes_received.to_string()),
+                    ("bytes_sent", close.bytes_sent.to_string()),
+                ])
+            }
+
             _ => return Err(UnknownEvent),
         };
 
@@ -1,11 +1,15 @@
 use convert::TryFrom;
 use futures::prelude::*;
+use indexmap::IndexMap;
 use std::{fmt, net};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use tokio::timer::Delay;
+use tokio_timer::clock;
 use trust_dns_resolver::{
     config::{ResolverConfig, ResolverOpts},
     lookup_ip::{LookupIp},
+    proto::rr::{RData, RecordType},
     system_conf,
     AsyncResolver,
     BackgroundLookupIp,
@@ -19,6 +23,19 @@ use transport::tls;
 #[derive(Clone)]
 pub struct Resolver {
     resolver: AsyncResolver,
+    /// When set, lookups are served from the cache until they expire.
+    cache: Option<Cache>,
+    ip_family: IpFamilyPreference,
+}
+
+/// Determines which address `resolve_one_ip` selects when a name has both
+/// IPv4 and IPv6 addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpFamilyPreference {
+    /// The first address returned by the resolver is used.
+    Any,
+    V4,
+    V6,
 }
 
 #[derive(Debug)]
@@ -32,12 +49,17 @@ pub enum Response {
     DoesNotExist { retry_after: Option<Instant> },
 }
 
-pub struct IpAddrFuture(::logging::ContextualFuture<Ctx, BackgroundLookupIp>);
+pub struct IpAddrFuture {
+    lookup: Lookup,
+    ip_family: IpFamilyPreference,
+}
 
-pub struct RefineFuture(::logging::ContextualFuture<Ctx, BackgroundLookupIp>);
+pub struct RefineFuture(Lookup);
 
 pub type IpAddrListFuture = Box<Future<Item = Response, Error = ResolveError> + Send>;
 
+pub type SrvFuture = Box<Future<Item = SrvTargets, Error = ResolveError> + Send>;
+
 /// A valid DNS name.
 ///
 /// This is an alias of the strictly-validated `tls::DnsName` based on the
@@ -58,6 +80,54 @@ pub struct Refine {
     pub valid_until: Instant,
 }
 
+/// A target of a SRV record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Srv {
+    pub target: Name,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+/// The result of a SRV lookup.
+#[derive(Clone, Debug)]
+pub struct SrvTargets {
+    pub targets: Vec<Srv>,
+    pub valid_until: Instant,
+}
+
+/// The result of a successful lookup, as stored in the cache.
+#[derive(Clone, Debug)]
+struct Resolved {
+    /// The fully-qualified name that was resolved.
+    name: Name,
+    ips: Vec<net::IpAddr>,
+    valid_until: Instant,
+}
+
+/// Caches successful lookups, by the name that was looked up, until their
+/// records expire.
+///
+/// Negative results are not cached.
+#[derive(Clone, Debug)]
+struct Cache(Arc<Mutex<CacheInner>>);
+
+#[derive(Debug)]
+struct CacheInner {
+    capacity: usize,
+    entries: IndexMap<Name, Resolved>,
+}
+
+/// Either a cached result or a lookup that populates the cache on completion.
+enum Lookup<F = Box<Future<Item = Resolved, Error = ResolveError> + Send>> {
+    Cached(Option<Resolved>),
+    Pending {
+        name: Name,
+        cache: Option<Cache>,
+        future: F,
+    },
+}
+
 impl fmt::Display for Ctx {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         write!(f, "dns={}", self.0)
@@ -130,7 +200,14 @@ impl Resolver {
         let opts = env_config.configure_resolver_opts(opts);
         trace!("DNS config: {:?}", &config);
         trace!("DNS opts: {:?}", &opts);
-        Ok(Self::new(config, opts))
+        let (resolver, background) = Self::new(config, opts);
+        let resolver = if env_config.dns_cache {
+            resolver.with_cache(env_config.dns_cache_size)
+        } else {
+            resolver
+        };
+        let resolver = resolver.with_ip_family(env_config.dns_ip_family);
+        Ok((resolver, background))
     }
 
 
@@ -145,10 +222,26 @@ impl Resolver {
         let (resolver, background) = AsyncResolver::new(config, opts);
         let resolver = Resolver {
             resolver,
+            cache: None,
+            ip_family: IpFamilyPreference::default(),
         };
         (resolver, background)
     }
 
+    /// Caches up to `capacity` lookups for `resolve_one_ip` and `refine`
+    /// until their records expire.
+    pub fn with_cache(self, capacity: usize) -> Self {
+        Self {
+            cache: Some(Cache::new(capacity)),
+            ..self
+        }
+    }
+
+    /// Prefers addresses of the given family in `resolve_one_ip`.
+    pub fn with_ip_family(self, ip_family: IpFamilyPreference) -> Self {
+        Self { ip_family, ..self }
+    }
+
     pub fn resolve_all_ips(&self, deadline: Instant, name: &Name) -> IpAddrListFuture {
         let lookup = self.resolver.lookup_ip(name.as_ref());
 
@@ -172,9 +265,13 @@ impl Resolver {
         Box::new(::logging::context_future(Ctx(name.clone()), f))
     }
 
+    /// Resolves `name` to a single address, preferring the configured
+    /// address family.
     pub fn resolve_one_ip(&self, name: &Name) -> IpAddrFuture {
-        let f = self.resolver.lookup_ip(name.as_ref());
-        IpAddrFuture(::logging::context_future(Ctx(name.clone()), f))
+        IpAddrFuture {
+            lookup: self.lookup(name),
+            ip_family: self.ip_family,
+        }
     }
 
     /// Attempts to refine `name` to a fully-qualified name.
@@ -185,8 +282,31 @@ impl Resolver {
     /// For example, a name like `web` may be refined to `web.example.com.`,
     /// depending on the DNS search path.
     pub fn refine(&self, name: &Name) -> RefineFuture {
-        let f = self.resolver.lookup_ip(name.as_ref());
-        RefineFuture(::logging::context_future(Ctx(name.clone()), f))
+        RefineFuture(self.lookup(name))
+    }
+
+    /// Looks up the SRV records for `name`, returning the host, port,
+    /// priority, and weight of each target.
+    ///
+    /// Targets that are not valid DNS names (including `.`, which indicates
+    /// that the service is not available) are ignored.
+    pub fn resolve_srv(&self, name: &Name) -> SrvFuture {
+        let lookup = self.resolver.lookup(name.as_ref(), RecordType::SRV);
+        let f = ::logging::context_future(Ctx(name.clone()), lookup).map(|lookup| {
+            SrvTargets {
+                targets: srv_targets(lookup.iter()),
+                valid_until: lookup.valid_until(),
+            }
+        });
+        Box::new(f)
+    }
+
+    fn lookup(&self, name: &Name) -> Lookup {
+        Lookup::new(self.cache.as_ref(), name, clock::now(), |name| {
+            let f = self.resolver.lookup_ip(name.as_ref());
+            let f = ::logging::context_future(Ctx(name.clone()), f).map(Resolved::from);
+            Box::new(f) as Box<Future<Item = _, Error = _> + Send>
+        })
     }
 }
 
@@ -205,9 +325,9 @@ impl Future for IpAddrFuture {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let ips = try_ready!(self.0.poll().map_err(Error::ResolutionFailed));
-        ips.iter()
-            .next()
+        let resolved = try_ready!(self.lookup.poll().map_err(Error::ResolutionFailed));
+        self.ip_family
+            .select(&resolved.ips)
             .map(Async::Ready)
             .ok_or_else(|| Error::NoAddressesFound)
     }
@@ -218,22 +338,190 @@ impl Future for RefineFuture {
     type Error = ResolveError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let lookup = try_ready!(self.0.poll());
-        let valid_until = lookup.valid_until();
+        let Resolved { name, valid_until, .. } = try_ready!(self.0.poll());
+        let refine = Refine { name, valid_until };
+        Ok(Async::Ready(refine))
+    }
+}
+
+fn srv_targets<'a, I>(records: I) -> Vec<Srv>
+where
+    I: Iterator<Item = &'a RData>,
+{
+    records
+        .filter_map(|rdata| match *rdata {
+            RData::SRV(ref srv) => {
+                let target = srv.target().to_ascii();
+                match Name::try_from(target.as_bytes()) {
+                    Ok(target) => Some(Srv {
+                        target,
+                        port: srv.port(),
+                        priority: srv.priority(),
+                        weight: srv.weight(),
+                    }),
+                    Err(_) => {
+                        debug!("ignoring invalid SRV target: {}", target);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// === impl IpFamilyPreference ===
+
+impl Default for IpFamilyPreference {
+    fn default() -> Self {
+        IpFamilyPreference::Any
+    }
+}
 
+impl IpFamilyPreference {
+    /// Selects the first address of the preferred family, falling back to the
+    /// first address if there are none.
+    fn select(&self, ips: &[net::IpAddr]) -> Option<net::IpAddr> {
+        let preferred = match *self {
+            IpFamilyPreference::Any => None,
+            IpFamilyPreference::V4 => ips.iter().find(|ip| ip.is_ipv4()),
+            IpFamilyPreference::V6 => ips.iter().find(|ip| ip.is_ipv6()),
+        };
+        preferred.or_else(|| ips.first()).cloned()
+    }
+}
+
+// === impl Resolved ===
+
+impl From<LookupIp> for Resolved {
+    fn from(lookup: LookupIp) -> Self {
         let n = lookup.query().name();
         let name = Name::try_from(n.to_ascii().as_bytes())
             .expect("Name returned from resolver must be valid");
 
-        let refine = Refine { name, valid_until };
-        Ok(Async::Ready(refine))
+        Resolved {
+            name,
+            ips: lookup.iter().collect(),
+            valid_until: lookup.valid_until(),
+        }
+    }
+}
+
+// === impl Cache ===
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        let inner = CacheInner {
+            capacity,
+            entries: IndexMap::new(),
+        };
+        Cache(Arc::new(Mutex::new(inner)))
+    }
+
+    fn get(&self, name: &Name, now: Instant) -> Option<Resolved> {
+        let mut inner = self.0.lock().expect("dns cache lock poisoned");
+        let expired = match inner.entries.get(name) {
+            Some(resolved) if now < resolved.valid_until => {
+                trace!("cached: {}", name);
+                return Some(resolved.clone());
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            trace!("expired: {}", name);
+            inner.entries.swap_remove(name);
+        }
+        None
+    }
+
+    fn insert(&self, name: Name, resolved: Resolved) {
+        let mut inner = self.0.lock().expect("dns cache lock poisoned");
+        if inner.capacity == 0 {
+            return;
+        }
+
+        if !inner.entries.contains_key(&name) && inner.entries.len() >= inner.capacity {
+            // Evict the entry that expires soonest.
+            let idx = inner
+                .entries
+                .values()
+                .enumerate()
+                .min_by_key(|&(_, r)| r.valid_until)
+                .map(|(i, _)| i);
+            if let Some(idx) = idx {
+                inner.entries.swap_remove_index(idx);
+            }
+        }
+        inner.entries.insert(name, resolved);
+    }
+}
+
+// === impl Lookup ===
+
+impl<F> Lookup<F>
+where
+    F: Future<Item = Resolved, Error = ResolveError>,
+{
+    /// Serves `name` from the cache, if it has an unexpired entry at `now`;
+    /// otherwise, `lookup` is used to resolve it.
+    fn new<L>(cache: Option<&Cache>, name: &Name, now: Instant, lookup: L) -> Self
+    where
+        L: FnOnce(&Name) -> F,
+    {
+        if let Some(resolved) = cache.and_then(|c| c.get(name, now)) {
+            return Lookup::Cached(Some(resolved));
+        }
+
+        Lookup::Pending {
+            name: name.clone(),
+            cache: cache.cloned(),
+            future: lookup(name),
+        }
+    }
+}
+
+impl<F> Future for Lookup<F>
+where
+    F: Future<Item = Resolved, Error = ResolveError>,
+{
+    type Item = Resolved;
+    type Error = ResolveError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            Lookup::Cached(ref mut resolved) => {
+                let resolved = resolved.take().expect("polled after ready");
+                Ok(Async::Ready(resolved))
+            }
+            Lookup::Pending {
+                ref name,
+                ref cache,
+                ref mut future,
+            } => {
+                let resolved = try_ready!(future.poll());
+                if let Some(ref cache) = *cache {
+                    cache.insert(name.clone(), resolved.clone());
+                }
+                Ok(Async::Ready(resolved))
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Name, Suffix};
+    use super::{
+        srv_targets, Cache, IpAddrFuture, IpFamilyPreference, Lookup, Name, Resolved, Srv,
+        Suffix,
+    };
     use convert::TryFrom;
+    use futures::{future, Future};
+    use std::cell::Cell;
+    use std::net;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use trust_dns_resolver::proto::rr::{self, rdata::SRV, RData};
 
     #[test]
     fn test_dns_name_parsing() {
@@ -316,4 +604,153 @@ mod tests {
 
         assert!(Suffix::try_from("").is_err(), "suffix must not be empty");
     }
+
+    /// Resolves every name to the same address, counting queries.
+    struct StubResolver {
+        queries: Cell<usize>,
+        ttl: Duration,
+    }
+
+    impl StubResolver {
+        fn lookup(
+            &self,
+            cache: &Cache,
+            name: &Name,
+            now: Instant,
+        ) -> Lookup<future::FutureResult<Resolved, super::ResolveError>> {
+            Lookup::new(Some(cache), name, now, |name| {
+                self.queries.set(self.queries.get() + 1);
+                future::ok(Resolved {
+                    name: name.clone(),
+                    ips: vec![net::IpAddr::from([10, 1, 1, 1])],
+                    valid_until: now + self.ttl,
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn cached_lookups_are_served_within_ttl() {
+        let stub = StubResolver {
+            queries: Cell::new(0),
+            ttl: Duration::from_secs(10),
+        };
+        let cache = Cache::new(10);
+        let name = Name::try_from("web.example.com.".as_bytes()).unwrap();
+        let t0 = Instant::now();
+
+        let first = stub.lookup(&cache, &name, t0).wait().unwrap();
+        assert_eq!(stub.queries.get(), 1);
+
+        let second = stub
+            .lookup(&cache, &name, t0 + Duration::from_secs(5))
+            .wait()
+            .unwrap();
+        assert_eq!(stub.queries.get(), 1, "lookup within TTL must be cached");
+        assert_eq!(second.ips, first.ips);
+        assert_eq!(second.valid_until, first.valid_until);
+    }
+
+    #[test]
+    fn expired_lookups_are_requeried() {
+        let stub = StubResolver {
+            queries: Cell::new(0),
+            ttl: Duration::from_secs(10),
+        };
+        let cache = Cache::new(10);
+        let name = Name::try_from("web.example.com.".as_bytes()).unwrap();
+        let t0 = Instant::now();
+
+        stub.lookup(&cache, &name, t0).wait().unwrap();
+        let t1 = t0 + Duration::from_secs(10);
+        let refreshed = stub.lookup(&cache, &name, t1).wait().unwrap();
+        assert_eq!(stub.queries.get(), 2, "lookup after TTL must be requeried");
+        assert_eq!(refreshed.valid_until, t1 + Duration::from_secs(10));
+
+        // The refreshed result is cached.
+        stub.lookup(&cache, &name, t1).wait().unwrap();
+        assert_eq!(stub.queries.get(), 2);
+    }
+
+    #[test]
+    fn cache_evicts_soonest_expiring_entry() {
+        let cache = Cache::new(1);
+        let t0 = Instant::now();
+        let resolved = |n: &str, ttl: u64| {
+            let name = Name::try_from(n.as_bytes()).unwrap();
+            let r = Resolved {
+                name: name.clone(),
+                ips: vec![],
+                valid_until: t0 + Duration::from_secs(ttl),
+            };
+            (name, r)
+        };
+
+        let (a, ra) = resolved("a.example.com.", 10);
+        let (b, rb) = resolved("b.example.com.", 20);
+        cache.insert(a.clone(), ra);
+        cache.insert(b.clone(), rb);
+        assert!(cache.get(&a, t0).is_none());
+        assert!(cache.get(&b, t0).is_some());
+    }
+
+    #[test]
+    fn srv_targets_are_parsed() {
+        let srv = |priority, weight, port, target: &str| {
+            let target = rr::Name::from_str(target).unwrap();
+            RData::SRV(SRV::new(priority, weight, port, target))
+        };
+        // Records as returned for a headless service with several targets.
+        let records = vec![
+            srv(10, 60, 8080, "web-0.web.example.com."),
+            srv(10, 20, 8081, "web-1.web.example.com."),
+            srv(20, 0, 9090, "web-backup.example.com."),
+            // Not a SRV record.
+            RData::A(net::Ipv4Addr::new(10, 1, 1, 1)),
+            // The service is explicitly unavailable at this target.
+            srv(30, 0, 0, "."),
+        ];
+
+        let target = |n: &str, port, priority, weight| Srv {
+            target: Name::try_from(n.as_bytes()).unwrap(),
+            port,
+            priority,
+            weight,
+        };
+        assert_eq!(
+            srv_targets(records.iter()),
+            vec![
+                target("web-0.web.example.com.", 8080, 10, 60),
+                target("web-1.web.example.com.", 8081, 10, 20),
+                target("web-backup.example.com.", 9090, 20, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_one_ip_honors_family_preference() {
+        let v4 = net::IpAddr::from([10, 1, 1, 1]);
+        let v6 = net::IpAddr::from([0xfd00, 0, 0, 0, 0, 0, 0, 1]);
+        // A lookup that returned both A and AAAA records.
+        let resolve = |ips: Vec<net::IpAddr>, ip_family| {
+            let resolved = Resolved {
+                name: Name::try_from("web.example.com.".as_bytes()).unwrap(),
+                ips,
+                valid_until: Instant::now() + Duration::from_secs(10),
+            };
+            let lookup = Lookup::Cached(Some(resolved));
+            IpAddrFuture { lookup, ip_family }.wait().ok()
+        };
+
+        assert_eq!(resolve(vec![v4, v6], IpFamilyPreference::Any), Some(v4));
+        assert_eq!(resolve(vec![v6, v4], IpFamilyPreference::Any), Some(v6));
+        assert_eq!(resolve(vec![v4, v6], IpFamilyPreference::V6), Some(v6));
+        assert_eq!(resolve(vec![v6, v4], IpFamilyPreference::V4), Some(v4));
+
+        // When there are no addresses of the preferred family, the other
+        // family is used.
+        assert_eq!(resolve(vec![v4], IpFamilyPreference::V6), Some(v4));
+        assert_eq!(resolve(vec![v6], IpFamilyPreference::V4), Some(v6));
+        assert_eq!(resolve(vec![], IpFamilyPreference::V4), None);
+    }
 }
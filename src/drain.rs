@@ -1,8 +1,10 @@
 use std::mem;
+use std::time::Duration;
 
 use futures::{Async, Future, Poll, Stream};
 use futures::future::Shared;
 use futures::sync::{mpsc, oneshot};
+use tokio_timer::{clock, Delay};
 
 use never::Never;
 
@@ -52,6 +54,22 @@ pub struct Watching<A, F> {
     watch: Watch,
 }
 
+/// A wrapped `Future` that is aborted if it has not completed by a deadline
+/// after drain is triggered.
+#[derive(Debug)]
+pub struct Aborting<A> {
+    future: A,
+    timeout: Duration,
+    deadline: Option<Delay>,
+    watch: Watch,
+}
+
+/// A future that resolves once drain is triggered.
+#[derive(Debug)]
+pub struct Signaled {
+    watch: Watch,
+}
+
 #[derive(Debug)]
 enum State<F> {
     Watch(F),
@@ -97,6 +115,31 @@ impl Watch {
             watch: self,
         }
     }
+
+    /// Wrap a future so that, once drain is triggered, it is dropped if it has
+    /// not completed within `timeout`.
+    ///
+    /// This bounds how long a drain may be held up by a future that does not
+    /// complete when it is asked to shut down.
+    pub fn abort_after<A>(self, timeout: Duration, future: A) -> Aborting<A>
+    where
+        A: Future<Item = ()>,
+    {
+        Aborting {
+            future,
+            timeout,
+            deadline: None,
+            watch: self,
+        }
+    }
+
+    /// Returns a future that resolves once drain is triggered.
+    ///
+    /// The watch is released when the future completes, so it does not hold
+    /// up the drain.
+    pub fn signaled(self) -> Signaled {
+        Signaled { watch: self }
+    }
 }
 
 // ===== impl Watching =====
@@ -132,6 +175,59 @@ where
     }
 }
 
+// ===== impl Aborting =====
+
+impl<A> Future for Aborting<A>
+where
+    A: Future<Item = ()>,
+{
+    type Item = ();
+    type Error = A::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.deadline.is_none() {
+            match self.watch.rx.poll() {
+                Ok(Async::Ready(_)) | Err(_) => {
+                    // Drain has been triggered!
+                    self.deadline = Some(Delay::new(clock::now() + self.timeout));
+                }
+                Ok(Async::NotReady) => {}
+            }
+        }
+
+        if let Some(ref mut deadline) = self.deadline {
+            match deadline.poll() {
+                Ok(Async::NotReady) => {}
+                Ok(Async::Ready(())) => {
+                    debug!("aborting after drain deadline of {:?}", self.timeout);
+                    return Ok(Async::Ready(()));
+                }
+                Err(e) => {
+                    // A timer error is treated as the deadline elapsing.
+                    error!("drain deadline timer failed: {}", e);
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+
+        self.future.poll()
+    }
+}
+
+// ===== impl Signaled =====
+
+impl Future for Signaled {
+    type Item = ();
+    type Error = Never;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.watch.rx.poll() {
+            Ok(Async::Ready(_)) | Err(_) => Ok(Async::Ready(())),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
 // ===== impl Drained =====
 
 impl Future for Drained {
@@ -6,6 +6,7 @@
 
 extern crate bytes;
 extern crate env_logger;
+extern crate flate2;
 extern crate linkerd2_fs_watch as fs_watch;
 #[macro_use]
 extern crate futures;
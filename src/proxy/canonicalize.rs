@@ -8,9 +8,18 @@
 //!
 //! DNS TTLs are honored and, if the resolution changes, the inner stack is
 //! rebuilt with the updated value.
+//!
+//! Names that do not exist (NXDOMAIN) are cached, by all services built by a
+//! `Layer`, until the negative result expires, so that services built for a
+//! nonexistent name do not each query DNS.
+//!
+//! Names matching any of a `Layer`'s skip suffixes are never canonicalized;
+//! the inner stack is built with the original name.
 
 use futures::{future, Async, Future, Poll};
-use std::time::Duration;
+use indexmap::IndexMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{error, fmt};
 use tokio_timer::{clock, Delay, Timeout};
 
@@ -22,38 +31,60 @@ use {Addr, NameAddr};
 /// an uncanonicalized address.
 const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
 
-/// Duration to wait before polling DNS again after an error (or a NXDOMAIN
-/// response with no TTL).
-const DNS_ERROR_TTL: Duration = Duration::from_secs(3);
+/// The default duration to wait before polling DNS again after an error (or a
+/// NXDOMAIN response with no TTL).
+const DEFAULT_ERROR_TTL: Duration = Duration::from_secs(3);
+
+/// Refines a name to its fully-qualified form via DNS.
+///
+/// This is implemented by `dns::Resolver`, and is abstracted so that this
+/// module may be tested without a real DNS service.
+pub trait Refine: Clone {
+    type Future: Future<Item = dns::Refine, Error = dns::ResolveError>;
+
+    fn refine(&self, name: &dns::Name) -> Self::Future;
+}
 
 #[derive(Debug, Clone)]
-pub struct Layer {
-    resolver: dns::Resolver,
+pub struct Layer<R = dns::Resolver> {
+    resolver: R,
+    skip_suffixes: Arc<Vec<dns::Suffix>>,
     timeout: Duration,
+    error_ttl: Duration,
+    not_found: NotFound,
 }
 
 #[derive(Clone, Debug)]
-pub struct Stack<M: svc::Stack<Addr>> {
-    resolver: dns::Resolver,
+pub struct Stack<M: svc::Stack<Addr>, R = dns::Resolver> {
+    resolver: R,
+    skip_suffixes: Arc<Vec<dns::Suffix>>,
     inner: M,
     timeout: Duration,
+    error_ttl: Duration,
+    not_found: NotFound,
 }
 
-pub struct Service<M: svc::Stack<Addr>> {
+pub struct Service<M: svc::Stack<Addr>, R: Refine = dns::Resolver> {
     original: NameAddr,
     canonical: Option<NameAddr>,
-    resolver: dns::Resolver,
+    resolver: R,
     service: Option<M::Value>,
     stack: M,
-    state: State,
+    state: State<R::Future>,
     timeout: Duration,
+    error_ttl: Duration,
+    not_found: NotFound,
 }
 
-enum State {
-    Pending(Timeout<dns::RefineFuture>),
+enum State<F> {
+    Pending(Timeout<F>),
     ValidUntil(Delay),
 }
 
+/// Caches, by name, the time until which names are known not to exist.
+#[derive(Clone, Debug, Default)]
+struct NotFound(Arc<Mutex<IndexMap<NameAddr, Instant>>>);
+
 #[derive(Debug)]
 pub enum Error<M, S> {
     Stack(M),
@@ -62,50 +93,81 @@ pub enum Error<M, S> {
 
 // === Layer ===
 
-// FIXME the resolver should be abstracted to a trait so that this can be tested
-// without a real DNS service.
-pub fn layer(resolver: dns::Resolver) -> Layer {
+/// Canonicalizes names, except those matching any of `skip_suffixes`.
+pub fn layer(resolver: dns::Resolver, skip_suffixes: Vec<dns::Suffix>) -> Layer {
     Layer {
         resolver,
+        skip_suffixes: Arc::new(skip_suffixes),
         timeout: DEFAULT_TIMEOUT,
+        error_ttl: DEFAULT_ERROR_TTL,
+        not_found: NotFound::default(),
     }
 }
 
-impl<M> svc::Layer<Addr, Addr, M> for Layer
+impl<R> Layer<R> {
+    /// Sets how long to wait before querying DNS again after a lookup fails
+    /// (or a name is found not to exist without a TTL).
+    pub fn with_error_ttl(self, error_ttl: Duration) -> Self {
+        Self { error_ttl, ..self }
+    }
+}
+
+impl<M, R> svc::Layer<Addr, Addr, M> for Layer<R>
 where
     M: svc::Stack<Addr> + Clone,
+    R: Refine,
 {
-    type Value = <Stack<M> as svc::Stack<Addr>>::Value;
-    type Error = <Stack<M> as svc::Stack<Addr>>::Error;
-    type Stack = Stack<M>;
+    type Value = <Stack<M, R> as svc::Stack<Addr>>::Value;
+    type Error = <Stack<M, R> as svc::Stack<Addr>>::Error;
+    type Stack = Stack<M, R>;
 
     fn bind(&self, inner: M) -> Self::Stack {
         Stack {
             inner,
             resolver: self.resolver.clone(),
+            skip_suffixes: self.skip_suffixes.clone(),
             timeout: self.timeout,
+            error_ttl: self.error_ttl,
+            not_found: self.not_found.clone(),
         }
     }
 }
 
+// === impl Refine ===
+
+impl Refine for dns::Resolver {
+    type Future = dns::RefineFuture;
+
+    fn refine(&self, name: &dns::Name) -> Self::Future {
+        dns::Resolver::refine(self, name)
+    }
+}
+
 // === impl Stack ===
 
-impl<M> svc::Stack<Addr> for Stack<M>
+impl<M, R> svc::Stack<Addr> for Stack<M, R>
 where
     M: svc::Stack<Addr> + Clone,
+    R: Refine,
 {
-    type Value = svc::Either<Service<M>, M::Value>;
+    type Value = svc::Either<Service<M, R>, M::Value>;
     type Error = M::Error;
 
     fn make(&self, addr: &Addr) -> Result<Self::Value, Self::Error> {
         match addr {
+            Addr::Name(na) if self.skip_suffixes.iter().any(|s| s.contains(na.name())) => {
+                trace!("skipping canonicalization; name={}", na.name());
+                self.inner.make(&addr).map(svc::Either::B)
+            }
             Addr::Name(na) => {
                 let svc = Service::new(
                     na.clone(),
                     self.inner.clone(),
                     self.resolver.clone(),
                     self.timeout,
-                );
+                    self.error_ttl,
+                    self.not_found.clone(),
+                )?;
                 Ok(svc::Either::A(svc))
             }
             Addr::Socket(_) => self.inner.make(&addr).map(svc::Either::B),
@@ -115,25 +177,45 @@ where
 
 // === impl Service ===
 
-impl<M> Service<M>
+impl<M, R> Service<M, R>
 where
     M: svc::Stack<Addr>,
-    //M::Value: svc::Service,
+    R: Refine,
 {
-    fn new(original: NameAddr, stack: M, resolver: dns::Resolver, timeout: Duration) -> Self {
-        trace!("refining name={}", original.name());
-        let f = resolver.refine(original.name());
-        let state = State::Pending(Timeout::new(f, timeout));
+    fn new(
+        original: NameAddr,
+        stack: M,
+        resolver: R,
+        timeout: Duration,
+        error_ttl: Duration,
+        not_found: NotFound,
+    ) -> Result<Self, M::Error> {
+        // If the name is known not to exist, use the original name until the
+        // negative result expires.
+        let (service, state) = match not_found.get(&original) {
+            Some(valid_until) => {
+                trace!("name does not exist; name={}", original.name());
+                let service = stack.make(&original.clone().into())?;
+                (Some(service), State::ValidUntil(Delay::new(valid_until)))
+            }
+            None => {
+                trace!("refining name={}", original.name());
+                let f = resolver.refine(original.name());
+                (None, State::Pending(Timeout::new(f, timeout)))
+            }
+        };
 
-        Self {
+        Ok(Self {
             original,
             canonical: None,
             stack,
-            service: None,
+            service,
             resolver,
             state,
             timeout,
-        }
+            error_ttl,
+            not_found,
+        })
     }
 
     fn poll_state(&mut self) -> Poll<(), M::Error> {
@@ -175,38 +257,47 @@ where
                             debug_assert!(self.canonical.is_none());
                         }
 
-                        let valid_until = e
-                            .into_inner()
-                            .and_then(|e| match e.kind() {
-                                dns::ResolveErrorKind::NoRecordsFound { valid_until, .. } => {
-                                    *valid_until
-                                }
-                                _ => None,
-                            })
-                            .unwrap_or_else(|| clock::now() + DNS_ERROR_TTL);
+                        let error_ttl = self.error_ttl;
+                        let not_found_until = e.into_inner().and_then(|e| match e.kind() {
+                            dns::ResolveErrorKind::NoRecordsFound { valid_until, .. } => {
+                                Some(valid_until.unwrap_or_else(|| clock::now() + error_ttl))
+                            }
+                            _ => None,
+                        });
+                        if let Some(valid_until) = not_found_until {
+                            self.not_found.insert(self.original.clone(), valid_until);
+                        }
 
+                        let valid_until =
+                            not_found_until.unwrap_or_else(|| clock::now() + error_ttl);
                         State::ValidUntil(Delay::new(valid_until))
                     }
                 },
 
                 State::ValidUntil(ref mut f) => match f.poll().expect("timer must not fail") {
                     Async::NotReady => return Ok(Async::NotReady),
-                    Async::Ready(()) => {
-                        trace!("refresh name={}", self.original.name());
-                        // The last resolution's TTL expired, so issue a new DNS query.
-                        let f = self.resolver.refine(self.original.name());
-                        State::Pending(Timeout::new(f, self.timeout))
-                    }
+                    Async::Ready(()) => match self.not_found.get(&self.original) {
+                        // Another service has already found that the name
+                        // still does not exist.
+                        Some(valid_until) => State::ValidUntil(Delay::new(valid_until)),
+                        None => {
+                            trace!("refresh name={}", self.original.name());
+                            // The last resolution's TTL expired, so issue a new DNS query.
+                            let f = self.resolver.refine(self.original.name());
+                            State::Pending(Timeout::new(f, self.timeout))
+                        }
+                    },
                 },
             };
         }
     }
 }
 
-impl<M, Req> svc::Service<Req> for Service<M>
+impl<M, R, Req> svc::Service<Req> for Service<M, R>
 where
     M: svc::Stack<Addr>,
     M::Value: svc::Service<Req>,
+    R: Refine,
 {
     type Response = <M::Value as svc::Service<Req>>::Response;
     type Error = Error<M::Error, <M::Value as svc::Service<Req>>::Error>;
@@ -238,6 +329,30 @@ where
     }
 }
 
+// === impl NotFound ===
+
+impl NotFound {
+    /// Returns the time until which `name` is known not to exist.
+    fn get(&self, name: &NameAddr) -> Option<Instant> {
+        let mut names = self.0.lock().ok()?;
+        let now = clock::now();
+        let valid_until = *names.get(name)?;
+        if valid_until <= now {
+            names.swap_remove(name);
+            return None;
+        }
+        Some(valid_until)
+    }
+
+    fn insert(&self, name: NameAddr, valid_until: Instant) {
+        if let Ok(mut names) = self.0.lock() {
+            let now = clock::now();
+            names.retain(|_, until| *until > now);
+            names.insert(name, valid_until);
+        }
+    }
+}
+
 // === impl Error ===
 
 impl<M: fmt::Display, S: fmt::Display> fmt::Display for Error<M, S> {
@@ -257,3 +372,251 @@ impl<M: error::Error, S: error::Error> error::Error for Error<M, S> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::runtime::current_thread::Runtime;
+    use trust_dns_resolver::proto::op::Query;
+
+    use super::*;
+    use convert::TryFrom;
+    use svc::{Service as _Service, Stack as _Stack};
+
+    /// Resolves every name to NXDOMAIN, counting queries.
+    #[derive(Clone)]
+    struct NxDomain(Arc<AtomicUsize>);
+
+    /// Fails every query, counting queries.
+    #[derive(Clone)]
+    struct Fail(Arc<AtomicUsize>);
+
+    /// Refines every name to the same canonical name.
+    #[derive(Clone)]
+    struct Cname(dns::Name);
+
+    /// Builds services that are always ready.
+    #[derive(Clone)]
+    struct MakeSvc;
+
+    /// Builds services that are always ready, recording their targets.
+    #[derive(Clone, Default)]
+    struct RecordTargets(Arc<Mutex<Vec<Addr>>>);
+
+    struct Svc;
+
+    impl Refine for NxDomain {
+        type Future = future::FutureResult<dns::Refine, dns::ResolveError>;
+
+        fn refine(&self, _: &dns::Name) -> Self::Future {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            let kind = dns::ResolveErrorKind::NoRecordsFound {
+                query: Query::new(),
+                valid_until: Some(clock::now() + Duration::from_secs(60)),
+            };
+            future::err(kind.into())
+        }
+    }
+
+    impl Refine for Fail {
+        type Future = future::FutureResult<dns::Refine, dns::ResolveError>;
+
+        fn refine(&self, _: &dns::Name) -> Self::Future {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            future::err(dns::ResolveErrorKind::Message("lookup failed").into())
+        }
+    }
+
+    impl Refine for Cname {
+        type Future = future::FutureResult<dns::Refine, dns::ResolveError>;
+
+        fn refine(&self, _: &dns::Name) -> Self::Future {
+            future::ok(dns::Refine {
+                name: self.0.clone(),
+                valid_until: clock::now() + Duration::from_secs(60),
+            })
+        }
+    }
+
+    impl svc::Stack<Addr> for RecordTargets {
+        type Value = Svc;
+        type Error = ();
+
+        fn make(&self, addr: &Addr) -> Result<Svc, ()> {
+            self.0.lock().unwrap().push(addr.clone());
+            Ok(Svc)
+        }
+    }
+
+    impl svc::Stack<Addr> for MakeSvc {
+        type Value = Svc;
+        type Error = ();
+
+        fn make(&self, _: &Addr) -> Result<Svc, ()> {
+            Ok(Svc)
+        }
+    }
+
+    impl svc::Service<()> for Svc {
+        type Response = ();
+        type Error = ();
+        type Future = future::FutureResult<(), ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            future::ok(())
+        }
+    }
+
+    #[test]
+    fn nxdomain_is_cached_across_services() {
+        let refines = Arc::new(AtomicUsize::new(0));
+        let stack = Stack {
+            inner: MakeSvc,
+            resolver: NxDomain(refines.clone()),
+            skip_suffixes: Arc::new(Vec::new()),
+            timeout: DEFAULT_TIMEOUT,
+            error_ttl: DEFAULT_ERROR_TTL,
+            not_found: NotFound::default(),
+        };
+        let addr = Addr::from_str("web.example.com:8080").unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            let mut svcs = Vec::new();
+            for _ in 0..10 {
+                let mut svc = match stack.make(&addr) {
+                    Ok(svc::Either::A(svc)) => svc,
+                    _ => panic!("names must be canonicalized"),
+                };
+                // The original name is used once the name is found not to exist.
+                let ready = _Service::<()>::poll_ready(&mut svc)
+                    .ok()
+                    .expect("service must not fail");
+                assert!(ready.is_ready());
+                svcs.push(svc);
+            }
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+
+        assert_eq!(refines.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn inner_stack_is_built_with_canonical_name() {
+        let canonical = dns::Name::try_from(b"b.svc.cluster.local.".as_ref()).unwrap();
+        let targets = RecordTargets::default();
+        let stack = Stack {
+            inner: targets.clone(),
+            resolver: Cname(canonical.clone()),
+            skip_suffixes: Arc::new(Vec::new()),
+            timeout: DEFAULT_TIMEOUT,
+            error_ttl: DEFAULT_ERROR_TTL,
+            not_found: NotFound::default(),
+        };
+        let addr = Addr::from_str("a.svc:8080").unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            let mut svc = match stack.make(&addr) {
+                Ok(svc::Either::A(svc)) => svc,
+                _ => panic!("names must be canonicalized"),
+            };
+            let ready = _Service::<()>::poll_ready(&mut svc)
+                .ok()
+                .expect("service must not fail");
+            assert!(ready.is_ready());
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+
+        // Downstream targets (and so their metric labels) use the canonical
+        // name rather than the name in the request.
+        let expected: Addr = NameAddr::new(canonical, 8080).into();
+        assert_eq!(*targets.0.lock().unwrap(), vec![expected]);
+    }
+
+    #[test]
+    fn failed_queries_are_retried_after_error_ttl() {
+        let refines = Arc::new(AtomicUsize::new(0));
+        let error_ttl = Duration::from_millis(50);
+        let stack = Stack {
+            inner: MakeSvc,
+            resolver: Fail(refines.clone()),
+            skip_suffixes: Arc::new(Vec::new()),
+            timeout: DEFAULT_TIMEOUT,
+            error_ttl,
+            not_found: NotFound::default(),
+        };
+        let addr = Addr::from_str("web.example.com:8080").unwrap();
+
+        let start = Instant::now();
+        let mut rt = Runtime::new().unwrap();
+        let mut svc = rt
+            .block_on(future::lazy(|| match stack.make(&addr) {
+                Ok(svc::Either::A(svc)) => Ok::<_, ()>(svc),
+                _ => panic!("names must be canonicalized"),
+            }))
+            .unwrap();
+
+        // The original name is used after the query fails, and the name is
+        // queried again once the error TTL elapses.
+        rt.block_on(future::poll_fn(|| {
+            let ready = _Service::<()>::poll_ready(&mut svc)
+                .ok()
+                .expect("service must not fail");
+            assert!(ready.is_ready());
+            if refines.load(Ordering::SeqCst) < 2 {
+                return Ok(Async::NotReady);
+            }
+            Ok::<_, ()>(Async::Ready(()))
+        }))
+        .unwrap();
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= error_ttl, "retried after {:?}", elapsed);
+        assert!(elapsed < DEFAULT_ERROR_TTL, "retried after {:?}", elapsed);
+    }
+
+    #[test]
+    fn names_matching_skip_suffixes_are_not_canonicalized() {
+        let refines = Arc::new(AtomicUsize::new(0));
+        let stack = Stack {
+            inner: MakeSvc,
+            resolver: NxDomain(refines.clone()),
+            skip_suffixes: Arc::new(vec![dns::Suffix::try_from("svc.cluster.local.").unwrap()]),
+            timeout: DEFAULT_TIMEOUT,
+            error_ttl: DEFAULT_ERROR_TTL,
+            not_found: NotFound::default(),
+        };
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            for skipped in &["web.ns.svc.cluster.local:8080", "web.ns.svc.cluster.local.:8080"] {
+                let addr = Addr::from_str(skipped).unwrap();
+                match stack.make(&addr) {
+                    Ok(svc::Either::B(_)) => {}
+                    _ => panic!("{} must not be canonicalized", skipped),
+                }
+            }
+            assert_eq!(refines.load(Ordering::SeqCst), 0);
+
+            for refined in &["web.ns:8080", "web.example.com:8080", "cluster.local:8080"] {
+                let addr = Addr::from_str(refined).unwrap();
+                match stack.make(&addr) {
+                    Ok(svc::Either::A(_)) => {}
+                    _ => panic!("{} must be canonicalized", refined),
+                }
+            }
+            assert_eq!(refines.load(Ordering::SeqCst), 3);
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+}
@@ -0,0 +1,592 @@
+//! Upgrades HTTP/1.1 connections to cleartext HTTP/2 (h2c).
+//!
+//! A client may ask to upgrade an HTTP/1.1 connection to HTTP/2 by sending a
+//! request with `Upgrade: h2c` and `HTTP2-Settings` headers. Once the server
+//! responds with `101 Switching Protocols`, the connection is spoken as
+//! HTTP/2, and the response to the upgrade request is sent on stream 1.
+//!
+//! Since the HTTP/2 server has no way to accept a request that was not
+//! received as HTTP/2, the upgrade request is re-encoded as a HEADERS frame
+//! on stream 1 and injected into the connection immediately after the
+//! client's connection preface.
+//!
+//! The `HTTP2-Settings` header carries the client's settings as a
+//! base64url-encoded SETTINGS payload (RFC 7540 §3.2.1). Requests with a
+//! missing, repeated, or malformed `HTTP2-Settings` header are not upgraded.
+//! Otherwise, the decoded settings are prepended to the client's initial
+//! SETTINGS frame, so that they are applied before the client's own settings
+//! and are acknowledged along with them.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Future, Poll};
+use httparse;
+use std::cmp;
+use std::io::{self, Read, Write};
+use tokio::io::{read_exact, write_all, AsyncRead, AsyncWrite};
+
+const H2_PREFACE_LEN: usize = 24;
+const FRAME_HEADER_LEN: usize = 9;
+const SETTINGS_TYPE: u8 = 0x4;
+const SETTINGS_ACK: u8 = 0x1;
+
+/// The length of each identifier-value pair in a SETTINGS payload.
+const SETTING_LEN: usize = 6;
+
+/// The largest frame an HTTP/2 peer must accept before settings are exchanged.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+
+const MAX_HEADERS: usize = 64;
+
+const SWITCHING_PROTOCOLS: &[u8] = b"\
+    HTTP/1.1 101 Switching Protocols\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: h2c\r\n\
+    \r\n";
+
+/// Headers that are specific to the HTTP/1 connection and so are not
+/// included in the upgraded request.
+const CONNECTION_HEADERS: [&str; 8] = [
+    "connection",
+    "host",
+    "http2-settings",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// An HTTP/1.1 request that asks to upgrade its connection to h2c.
+#[derive(Debug)]
+pub struct Upgrade {
+    /// The length of the request head, which is consumed from the connection
+    /// before switching protocols.
+    head_len: usize,
+
+    /// The upgrade request, encoded as a HEADERS frame on stream 1.
+    headers_frame: Bytes,
+
+    /// The SETTINGS payload decoded from the `HTTP2-Settings` header.
+    settings: Bytes,
+}
+
+/// A connection that has switched protocols to HTTP/2.
+///
+/// The upgrade request's HEADERS frame is read from the connection after
+/// the client's preface and initial SETTINGS frame.
+#[derive(Debug)]
+pub struct Upgraded<T> {
+    io: T,
+    state: State,
+    /// The remainder of the upgrade request's HEADERS frame.
+    headers_frame: Bytes,
+    /// Prepended to the payload of the client's initial SETTINGS frame.
+    settings: Bytes,
+}
+
+#[derive(Debug)]
+enum State {
+    /// Reading the client's connection preface.
+    Preface { remaining: usize },
+
+    /// Buffering the header of the client's initial SETTINGS frame.
+    SettingsHeader {
+        header: [u8; FRAME_HEADER_LEN],
+        filled: usize,
+    },
+
+    /// Reading the client's SETTINGS frame header, extended to include the
+    /// upgrade's settings, followed by those settings.
+    SettingsPrefix { prefix: Bytes, remaining: usize },
+
+    /// Reading the payload of the client's initial SETTINGS frame.
+    SettingsPayload { remaining: usize },
+
+    /// Reading the injected HEADERS frame.
+    Headers,
+
+    /// Reading from the connection.
+    Done,
+}
+
+// === impl Upgrade ===
+
+impl Upgrade {
+    /// Detects an h2c upgrade request from the bytes peeked from a connection.
+    ///
+    /// Only complete, body-less HTTP/1.1 requests are upgraded. Returns
+    /// `None` if the peeked bytes don't hold such a request.
+    pub fn detect(peeked: &[u8]) -> Option<Self> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        let head_len = match req.parse(peeked) {
+            Ok(httparse::Status::Complete(len)) => len,
+            _ => return None,
+        };
+
+        if req.version != Some(1) {
+            return None;
+        }
+
+        let method = req.method?;
+        let path = req.path?;
+        if !path.starts_with('/') && path != "*" {
+            return None;
+        }
+
+        let mut upgrades_to_h2c = false;
+        let mut settings = None;
+        let mut authority = None;
+        for h in req.headers.iter() {
+            if h.name.eq_ignore_ascii_case("upgrade") {
+                upgrades_to_h2c |= h.value
+                    .split(|b| *b == b',')
+                    .any(|p| trim(p).eq_ignore_ascii_case(b"h2c"));
+            } else if h.name.eq_ignore_ascii_case("http2-settings") {
+                // Exactly one HTTP2-Settings header must be sent.
+                if settings.is_some() {
+                    return None;
+                }
+                settings = Some(decode_settings(h.value)?);
+            } else if h.name.eq_ignore_ascii_case("host") {
+                authority = Some(h.value);
+            } else if h.name.eq_ignore_ascii_case("transfer-encoding") {
+                return None;
+            } else if h.name.eq_ignore_ascii_case("content-length") {
+                if trim(h.value) != b"0" {
+                    return None;
+                }
+            }
+        }
+        let settings = settings?;
+        if !upgrades_to_h2c {
+            return None;
+        }
+
+        let mut block = Vec::new();
+        encode_header(&mut block, b":method", method.as_bytes());
+        encode_header(&mut block, b":scheme", b"http");
+        encode_header(&mut block, b":path", path.as_bytes());
+        if let Some(authority) = authority {
+            encode_header(&mut block, b":authority", authority);
+        }
+        for h in req.headers.iter() {
+            let name = h.name.to_ascii_lowercase();
+            if CONNECTION_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            encode_header(&mut block, name.as_bytes(), h.value);
+        }
+        if block.len() > DEFAULT_MAX_FRAME_SIZE {
+            return None;
+        }
+
+        // A HEADERS frame on stream 1 with END_STREAM and END_HEADERS set.
+        let mut frame = BytesMut::with_capacity(FRAME_HEADER_LEN + block.len());
+        frame.put_uint_be(block.len() as u64, 3);
+        frame.put_u8(0x1);
+        frame.put_u8(0x1 | 0x4);
+        frame.put_u32_be(1);
+        frame.put_slice(&block);
+
+        Some(Upgrade {
+            head_len,
+            headers_frame: frame.freeze(),
+            settings,
+        })
+    }
+
+    /// Consumes the upgrade request from the connection and switches
+    /// protocols, returning a connection that may be served as HTTP/2.
+    pub fn accept<T>(self, io: T) -> impl Future<Item = Upgraded<T>, Error = io::Error>
+    where
+        T: AsyncRead + AsyncWrite,
+    {
+        let Upgrade { head_len, headers_frame, settings } = self;
+        read_exact(io, vec![0; head_len])
+            .and_then(|(io, _)| write_all(io, SWITCHING_PROTOCOLS))
+            .map(move |(io, _)| Upgraded {
+                io,
+                state: State::Preface { remaining: H2_PREFACE_LEN },
+                headers_frame,
+                settings,
+            })
+    }
+}
+
+/// Decodes the value of an `HTTP2-Settings` header, a base64url-encoded
+/// SETTINGS payload without padding. Returns `None` if it is malformed.
+fn decode_settings(value: &[u8]) -> Option<Bytes> {
+    let value = trim(value);
+    let mut payload = Vec::with_capacity(value.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &b in value {
+        let sextet = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(sextet);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            payload.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    // Leftover bits must be unused padding, and the payload must consist of
+    // whole settings.
+    if bits >= 6 || acc != 0 || payload.len() % SETTING_LEN != 0 {
+        return None;
+    }
+
+    Some(payload.into())
+}
+
+/// Encodes a literal header field without indexing, per RFC 7541 §6.2.2.
+fn encode_header(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    encode_string(block, name);
+    encode_string(block, value);
+}
+
+/// Encodes a string literal without Huffman encoding, per RFC 7541 §5.2.
+fn encode_string(block: &mut Vec<u8>, s: &[u8]) {
+    // The length is encoded as an integer with a 7-bit prefix.
+    let mut len = s.len();
+    if len < 0x7f {
+        block.push(len as u8);
+    } else {
+        block.push(0x7f);
+        len -= 0x7f;
+        while len >= 0x80 {
+            block.push((len & 0x7f) as u8 | 0x80);
+            len >>= 7;
+        }
+        block.push(len as u8);
+    }
+    block.extend_from_slice(s);
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(|b| !b.is_ascii_whitespace()).map(|i| i + 1).unwrap_or(start);
+    &bytes[start..end]
+}
+
+// === impl Upgraded ===
+
+impl<T: Read> Read for Upgraded<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Reads from the connection are limited so that the HEADERS frame is
+        // injected exactly at the end of the client's SETTINGS frame.
+        loop {
+            let (n, next) = match self.state {
+                State::Preface { remaining } => {
+                    let n = self.io.read(limit(buf, remaining))?;
+                    let next = if n == remaining {
+                        State::SettingsHeader {
+                            header: [0; FRAME_HEADER_LEN],
+                            filled: 0,
+                        }
+                    } else {
+                        State::Preface { remaining: remaining - n }
+                    };
+                    (n, next)
+                }
+
+                // Nothing is read until the whole header has been buffered,
+                // since its length may be extended.
+                State::SettingsHeader { mut header, filled } => {
+                    let n = self.io.read(&mut header[filled..])?;
+                    if n == 0 {
+                        return Ok(0);
+                    }
+                    let filled = filled + n;
+                    let next = if filled < FRAME_HEADER_LEN {
+                        State::SettingsHeader { header, filled }
+                    } else {
+                        prefix_settings(&header, &self.settings)?
+                    };
+                    self.state = next;
+                    continue;
+                }
+
+                State::SettingsPrefix { ref mut prefix, remaining } => {
+                    let n = cmp::min(buf.len(), prefix.len());
+                    buf[..n].copy_from_slice(&prefix.split_to(n));
+                    let next = if !prefix.is_empty() {
+                        State::SettingsPrefix { prefix: prefix.clone(), remaining }
+                    } else if remaining == 0 {
+                        State::Headers
+                    } else {
+                        State::SettingsPayload { remaining }
+                    };
+                    (n, next)
+                }
+
+                State::SettingsPayload { remaining } => {
+                    let n = self.io.read(limit(buf, remaining))?;
+                    let next = if n == remaining {
+                        State::Headers
+                    } else {
+                        State::SettingsPayload { remaining: remaining - n }
+                    };
+                    (n, next)
+                }
+
+                State::Headers => {
+                    let n = cmp::min(buf.len(), self.headers_frame.len());
+                    buf[..n].copy_from_slice(&self.headers_frame.split_to(n));
+                    let next = if self.headers_frame.is_empty() {
+                        State::Done
+                    } else {
+                        State::Headers
+                    };
+                    (n, next)
+                }
+
+                State::Done => return self.io.read(buf),
+            };
+
+            // An EOF is passed through without advancing.
+            if n > 0 {
+                self.state = next;
+            }
+            return Ok(n);
+        }
+    }
+}
+
+/// Extends the header of the client's initial SETTINGS frame to include the
+/// upgrade's settings, which precede the client's own settings.
+///
+/// If the frame is not a SETTINGS frame, it is left unmodified so that the
+/// HTTP/2 server may reject it.
+fn prefix_settings(header: &[u8; FRAME_HEADER_LEN], settings: &Bytes) -> io::Result<State> {
+    let len = frame_len(header);
+    let is_settings = header[3] == SETTINGS_TYPE && header[4] & SETTINGS_ACK == 0;
+
+    let mut prefix = BytesMut::with_capacity(FRAME_HEADER_LEN + settings.len());
+    if is_settings && !settings.is_empty() {
+        let extended = len + settings.len();
+        if extended > DEFAULT_MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "h2c upgrade settings exceed the maximum frame size",
+            ));
+        }
+        prefix.put_uint_be(extended as u64, 3);
+        prefix.put_slice(&header[3..]);
+        prefix.put_slice(settings);
+    } else {
+        prefix.put_slice(header);
+    }
+
+    Ok(State::SettingsPrefix {
+        prefix: prefix.freeze(),
+        remaining: len,
+    })
+}
+
+fn frame_len(header: &[u8; FRAME_HEADER_LEN]) -> usize {
+    (usize::from(header[0]) << 16) | (usize::from(header[1]) << 8) | usize::from(header[2])
+}
+
+impl<T: AsyncRead> AsyncRead for Upgraded<T> {}
+
+impl<T: Write> Write for Upgraded<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for Upgraded<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+fn limit(buf: &mut [u8], max: usize) -> &mut [u8] {
+    let n = cmp::min(buf.len(), max);
+    &mut buf[..n]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPGRADE: &[u8] = b"\
+        GET /chat?x=1 HTTP/1.1\r\n\
+        Host: Example.com\r\n\
+        Connection: Upgrade, HTTP2-Settings\r\n\
+        Upgrade: h2c\r\n\
+        HTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\
+        X-Trace: abc\r\n\
+        \r\n";
+
+    /// The decoded `HTTP2-Settings` of `UPGRADE`.
+    const SETTINGS: [u8; 18] = [
+        0, 0x3, 0, 0, 0, 0x64, // MAX_CONCURRENT_STREAMS = 100
+        0, 0x4, 0x40, 0, 0, 0, // INITIAL_WINDOW_SIZE = 2^30
+        0, 0x2, 0, 0, 0, 0, // ENABLE_PUSH = 0
+    ];
+
+    #[test]
+    fn detects_complete_upgrade_requests() {
+        let upgrade = Upgrade::detect(UPGRADE).expect("upgrade");
+        assert_eq!(upgrade.head_len, UPGRADE.len());
+
+        let mut block = Vec::new();
+        encode_header(&mut block, b":method", b"GET");
+        encode_header(&mut block, b":scheme", b"http");
+        encode_header(&mut block, b":path", b"/chat?x=1");
+        encode_header(&mut block, b":authority", b"Example.com");
+        encode_header(&mut block, b"x-trace", b"abc");
+
+        let frame = &upgrade.headers_frame;
+        assert_eq!(&frame[..3], &[0, 0, block.len() as u8]);
+        assert_eq!(&frame[3..9], &[0x1, 0x5, 0, 0, 0, 1]);
+        assert_eq!(&frame[9..], &block[..]);
+
+        assert_eq!(&upgrade.settings[..], &SETTINGS[..]);
+    }
+
+    #[test]
+    fn rejects_malformed_settings() {
+        let upgrade = |settings: &str| {
+            let req = format!(
+                "GET / HTTP/1.1\r\n\
+                 Connection: Upgrade, HTTP2-Settings\r\n\
+                 Upgrade: h2c\r\n\
+                 {}\
+                 \r\n",
+                settings,
+            );
+            Upgrade::detect(req.as_bytes())
+        };
+
+        assert!(upgrade("HTTP2-Settings: AAMAAABk\r\n").is_some());
+        assert!(upgrade("").is_none(), "missing");
+        assert!(
+            upgrade("HTTP2-Settings: AAMAAABk\r\nHTTP2-Settings: AAMAAABk\r\n").is_none(),
+            "repeated"
+        );
+        assert!(upgrade("HTTP2-Settings: AAMAAABk+\r\n").is_none(), "not base64url");
+        assert!(upgrade("HTTP2-Settings: AAMAAA==\r\n").is_none(), "padded");
+        assert!(upgrade("HTTP2-Settings: AAMA\r\n").is_none(), "partial setting");
+    }
+
+    #[test]
+    fn ignores_requests_that_cannot_be_upgraded() {
+        let partial = &UPGRADE[..UPGRADE.len() - 2];
+        assert!(Upgrade::detect(partial).is_none());
+
+        let not_h2c = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nHTTP2-Settings: \r\n\r\n";
+        assert!(Upgrade::detect(not_h2c).is_none());
+
+        let with_body = b"\
+            POST / HTTP/1.1\r\n\
+            Upgrade: h2c\r\n\
+            HTTP2-Settings: \r\n\
+            Content-Length: 4\r\n\
+            \r\n";
+        assert!(Upgrade::detect(with_body).is_none());
+    }
+
+    #[test]
+    fn encodes_long_strings() {
+        let mut block = Vec::new();
+        encode_string(&mut block, &[b'a'; 1337]);
+        // 1337 - 127 = 1210 = 0b1001_0111010
+        assert_eq!(&block[..3], &[0x7f, 0xba, 0x09]);
+        assert_eq!(block.len(), 3 + 1337);
+    }
+
+    fn client() -> Vec<u8> {
+        let mut client = Vec::new();
+        client.extend_from_slice(PREFACE);
+        client.extend_from_slice(&CLIENT_SETTINGS);
+        client.extend_from_slice(&PING);
+        client
+    }
+
+    const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+    const CLIENT_SETTINGS: [u8; 15] = [0, 0, 6, 0x4, 0, 0, 0, 0, 0, 0, 0x3, 0, 0, 0, 0x1];
+    const PING: [u8; 17] = [0, 0, 8, 0x6, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn upgraded<T>(io: T) -> (Upgraded<T>, Bytes) {
+        let upgrade = Upgrade::detect(UPGRADE).expect("upgrade");
+        let headers_frame = upgrade.headers_frame.clone();
+        let io = Upgraded {
+            io,
+            state: State::Preface { remaining: H2_PREFACE_LEN },
+            headers_frame: upgrade.headers_frame,
+            settings: upgrade.settings,
+        };
+        (io, headers_frame)
+    }
+
+    #[test]
+    fn injects_headers_after_client_settings() {
+        let (mut io, headers_frame) = upgraded(io::Cursor::new(client()));
+        let mut read = Vec::new();
+        io.read_to_end(&mut read).expect("read");
+
+        // The upgrade's settings precede the client's, so that the client's
+        // own settings take precedence.
+        let mut expected = Vec::new();
+        expected.extend_from_slice(PREFACE);
+        expected.extend_from_slice(&[0, 0, 6 + 18, 0x4, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&SETTINGS);
+        expected.extend_from_slice(&CLIENT_SETTINGS[9..]);
+        expected.extend_from_slice(&headers_frame);
+        expected.extend_from_slice(&PING);
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn injects_settings_when_read_bytewise() {
+        let (mut bytewise, _) = upgraded(io::Cursor::new(client()));
+        let mut read = Vec::new();
+        let mut byte = [0; 1];
+        while bytewise.read(&mut byte).expect("read") == 1 {
+            read.push(byte[0]);
+        }
+
+        let (mut io, _) = upgraded(io::Cursor::new(client()));
+        let mut expected = Vec::new();
+        io.read_to_end(&mut expected).expect("read");
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn does_not_extend_other_frames() {
+        let mut client = Vec::new();
+        client.extend_from_slice(PREFACE);
+        client.extend_from_slice(&PING);
+
+        let (mut io, headers_frame) = upgraded(io::Cursor::new(client));
+        let mut read = Vec::new();
+        io.read_to_end(&mut read).expect("read");
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(PREFACE);
+        expected.extend_from_slice(&PING);
+        expected.extend_from_slice(&headers_frame);
+        assert_eq!(read, expected);
+    }
+}
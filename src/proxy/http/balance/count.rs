@@ -0,0 +1,182 @@
+//! Reports the number of endpoints held by each destination's balancer.
+//!
+//! A `count::Stack` wraps the discovery stream of each destination, tracking
+//! the endpoints it inserts and removes. A destination is reported only while
+//! its balancer is held.
+
+use futures::{Async, Poll};
+use indexmap::{IndexMap, IndexSet};
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, Weak};
+
+use super::tower_discover::{Change, Discover};
+use metrics::{FmtLabels, FmtMetric, FmtMetrics, Gauge};
+use svc;
+
+metrics! {
+    lb_endpoints: Gauge { "Number of endpoints held by a destination's load balancer" }
+}
+
+/// Constructs a Registry/Report pair for balancer endpoint metrics.
+pub fn metrics() -> (Registry, Report) {
+    let inner = Arc::new(Mutex::new(IndexMap::default()));
+    (Registry(inner.clone()), Report(inner))
+}
+
+type Gauges = Arc<Mutex<IndexMap<String, Weak<Mutex<Gauge>>>>>;
+
+/// Records the endpoint count of each destination's balancer.
+#[derive(Clone, Debug)]
+pub struct Registry(Gauges);
+
+/// Formats balancer endpoint metrics for Prometheus.
+#[derive(Clone, Debug)]
+pub struct Report(Gauges);
+
+#[derive(Clone, Debug)]
+pub struct Layer {
+    registry: Registry,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    registry: Registry,
+    inner: M,
+}
+
+/// Counts the endpoints discovered by the inner `Discover`.
+pub struct Counted<D: Discover> {
+    inner: D,
+    endpoints: IndexSet<D::Key>,
+    gauge: Arc<Mutex<Gauge>>,
+}
+
+struct Dst<'a>(&'a str);
+
+// === impl Layer ===
+
+pub fn layer(registry: Registry) -> Layer {
+    Layer { registry }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    T: fmt::Display,
+    M: svc::Stack<T>,
+    M::Value: Discover,
+    <M::Value as Discover>::Key: Hash + Eq + Clone,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            registry: self.registry.clone(),
+            inner,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    T: fmt::Display,
+    M: svc::Stack<T>,
+    M::Value: Discover,
+    <M::Value as Discover>::Key: Hash + Eq + Clone,
+{
+    type Value = Counted<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Counted {
+            inner,
+            endpoints: IndexSet::default(),
+            gauge: self.registry.register(target.to_string()),
+        })
+    }
+}
+
+// === impl Counted ===
+
+impl<D> Discover for Counted<D>
+where
+    D: Discover,
+    D::Key: Hash + Eq + Clone,
+{
+    type Key = D::Key;
+    type Service = D::Service;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<D::Key, D::Service>, D::Error> {
+        let change = try_ready!(self.inner.poll());
+        match change {
+            Change::Insert(ref key, _) => {
+                self.endpoints.insert(key.clone());
+            }
+            Change::Remove(ref key) => {
+                self.endpoints.remove(key);
+            }
+        }
+        if let Ok(mut gauge) = self.gauge.lock() {
+            *gauge = Gauge::from(self.endpoints.len() as u64);
+        }
+        Ok(Async::Ready(change))
+    }
+}
+
+// === impl Registry ===
+
+impl Registry {
+    /// Returns the gauge for a new balancer of `dst`, replacing that of
+    /// any prior balancer.
+    fn register(&self, dst: String) -> Arc<Mutex<Gauge>> {
+        let gauge = Arc::new(Mutex::new(Gauge::default()));
+        if let Ok(mut gauges) = self.0.lock() {
+            gauges.insert(dst, Arc::downgrade(&gauge));
+        }
+        gauge
+    }
+}
+
+// === impl Report ===
+
+impl FmtMetrics for Report {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut gauges = match self.0.lock() {
+            Err(_) => return Ok(()),
+            Ok(gauges) => gauges,
+        };
+
+        // Balancers that have been dropped are no longer reported.
+        gauges.retain(|_, gauge| gauge.upgrade().is_some());
+        if gauges.is_empty() {
+            return Ok(());
+        }
+
+        lb_endpoints.fmt_help(f)?;
+        for (dst, gauge) in gauges.iter() {
+            let gauge = match gauge.upgrade() {
+                Some(gauge) => gauge,
+                None => continue,
+            };
+            let count = match gauge.lock() {
+                Ok(count) => *count,
+                Err(_) => continue,
+            };
+            count.fmt_metric_labeled(f, lb_endpoints.name, Dst(dst))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> FmtLabels for Dst<'a> {
+    fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "dst=\"{}\"", self.0)
+    }
+}
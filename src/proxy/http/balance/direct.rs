@@ -0,0 +1,92 @@
+//! Dispatches requests directly to a destination's only endpoint.
+//!
+//! Endpoints are discovered without being balanced until more than one
+//! endpoint has been discovered. At that point, the discovered endpoints and
+//! the remaining discovery stream are handed to a balancer as `Preloaded`.
+
+use futures::{Async, Poll};
+use indexmap::IndexMap;
+use std::mem;
+
+use super::tower_discover::{Change, Discover};
+
+/// Tracks the endpoints of a destination while it has at most one.
+pub struct Direct<D: Discover> {
+    /// Taken once the endpoints are preloaded into a balancer.
+    discover: Option<D>,
+    endpoints: IndexMap<D::Key, D::Service>,
+}
+
+/// Discovers the endpoints tracked by a `Direct` before continuing
+/// discovery.
+pub struct Preloaded<D: Discover> {
+    endpoints: IndexMap<D::Key, D::Service>,
+    inner: D,
+}
+
+// === impl Direct ===
+
+impl<D: Discover> Direct<D> {
+    pub fn new(discover: D) -> Self {
+        Self {
+            discover: Some(discover),
+            endpoints: IndexMap::new(),
+        }
+    }
+
+    /// Applies all pending discovery changes, returning the number of
+    /// discovered endpoints.
+    pub fn poll_discover(&mut self) -> Result<usize, D::Error> {
+        let discover = self.discover.as_mut().expect("discovery already preloaded");
+        loop {
+            match discover.poll()? {
+                Async::Ready(Change::Insert(key, svc)) => {
+                    self.endpoints.insert(key, svc);
+                }
+                Async::Ready(Change::Remove(key)) => {
+                    self.endpoints.swap_remove(&key);
+                }
+                Async::NotReady => return Ok(self.endpoints.len()),
+            }
+        }
+    }
+
+    /// Returns the endpoint, if exactly one has been discovered.
+    pub fn endpoint_mut(&mut self) -> Option<&mut D::Service> {
+        if self.endpoints.len() == 1 {
+            self.endpoints.values_mut().next()
+        } else {
+            None
+        }
+    }
+
+    /// Discards all discovered endpoints.
+    pub fn discard(&mut self) {
+        self.endpoints.clear();
+    }
+
+    /// Hands the discovered endpoints and the discovery stream off to
+    /// be balanced.
+    pub fn preload(&mut self) -> Preloaded<D> {
+        Preloaded {
+            endpoints: mem::replace(&mut self.endpoints, IndexMap::new()),
+            inner: self.discover.take().expect("discovery already preloaded"),
+        }
+    }
+}
+
+// === impl Preloaded ===
+
+impl<D: Discover> Discover for Preloaded<D> {
+    type Key = D::Key;
+    type Service = D::Service;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        if let Some((key, svc)) = self.endpoints.pop() {
+            return Ok(Async::Ready(Change::Insert(key, svc)));
+        }
+
+        self.inner.poll()
+    }
+}
@@ -0,0 +1,228 @@
+//! Defers the removal of endpoints from the balancer so that their in-flight
+//! requests may complete.
+//!
+//! When an endpoint is removed, it is marked as draining: it is no longer
+//! ready to accept new requests, but its in-flight requests are not
+//! canceled. It is removed from the balancer once it is idle or once its
+//! drain timeout elapses, whichever happens first.
+
+use futures::task::AtomicTask;
+use futures::{Async, Future, Poll};
+use indexmap::IndexMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_timer::{clock, Delay};
+
+use super::tower_discover::{Change, Discover};
+use super::{HasWeight, Weight};
+use svc;
+
+/// Wraps each discovered service so that it may be drained when removed.
+#[derive(Debug)]
+pub struct WithDrain<D> {
+    inner: D,
+    /// When `None`, removed endpoints are not drained.
+    timeout: Option<Duration>,
+    active: IndexMap<SocketAddr, Handle>,
+    draining: IndexMap<SocketAddr, (Handle, Delay)>,
+}
+
+/// An endpoint service that is never ready once it is draining.
+#[derive(Debug)]
+pub struct Drainable<S> {
+    inner: S,
+    handle: Handle,
+}
+
+/// Tracks a request as in-flight until its response is received.
+pub struct ResponseFuture<F> {
+    inner: F,
+    in_flight: Option<InFlight>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Handle(Arc<State>);
+
+#[derive(Debug, Default)]
+struct State {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    /// Notified when a draining endpoint becomes idle.
+    task: AtomicTask,
+}
+
+struct InFlight(Handle);
+
+// === impl WithDrain ===
+
+impl<D> WithDrain<D>
+where
+    D: Discover<Key = SocketAddr>,
+{
+    pub fn new(inner: D, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            active: IndexMap::new(),
+            draining: IndexMap::new(),
+        }
+    }
+
+    /// Returns the address of a draining endpoint that is idle or whose
+    /// drain timeout has elapsed.
+    fn poll_drained(&mut self) -> Option<SocketAddr> {
+        let mut drained = None;
+        for (addr, &mut (ref handle, ref mut timeout)) in self.draining.iter_mut() {
+            // Register before checking for idleness so that the task is
+            // notified of a request that completes concurrently.
+            handle.0.task.register();
+            if handle.is_idle() {
+                trace!("{} drained", addr);
+                drained = Some(*addr);
+                break;
+            }
+
+            match timeout.poll() {
+                Ok(Async::NotReady) => {}
+                Ok(Async::Ready(())) | Err(_) => {
+                    debug!("{} drain timed out with requests in flight", addr);
+                    drained = Some(*addr);
+                    break;
+                }
+            }
+        }
+
+        let addr = drained?;
+        self.draining.remove(&addr);
+        Some(addr)
+    }
+}
+
+impl<D> Discover for WithDrain<D>
+where
+    D: Discover<Key = SocketAddr>,
+{
+    type Key = SocketAddr;
+    type Service = Drainable<D::Service>;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        if let Some(addr) = self.poll_drained() {
+            return Ok(Async::Ready(Change::Remove(addr)));
+        }
+
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Change::Insert(addr, inner) => {
+                    // A re-inserted endpoint replaces its draining service.
+                    self.draining.remove(&addr);
+                    let handle = Handle::default();
+                    self.active.insert(addr, handle.clone());
+                    let svc = Drainable { inner, handle };
+                    return Ok(Async::Ready(Change::Insert(addr, svc)));
+                }
+                Change::Remove(addr) => {
+                    let handle = match (self.active.remove(&addr), self.timeout) {
+                        (Some(handle), Some(timeout)) => {
+                            handle.0.draining.store(true, Ordering::SeqCst);
+                            if handle.is_idle() {
+                                None
+                            } else {
+                                Some((handle, Delay::new(clock::now() + timeout)))
+                            }
+                        }
+                        _ => None,
+                    };
+
+                    match handle {
+                        None => return Ok(Async::Ready(Change::Remove(addr))),
+                        Some(draining) => {
+                            debug!("draining {}", addr);
+                            self.draining.insert(addr, draining);
+                            if let Some(addr) = self.poll_drained() {
+                                return Ok(Async::Ready(Change::Remove(addr)));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// === impl Drainable ===
+
+impl<S, Req> svc::Service<Req> for Drainable<S>
+where
+    S: svc::Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        if self.handle.0.draining.load(Ordering::SeqCst) {
+            return Ok(Async::NotReady);
+        }
+
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.handle.0.in_flight.fetch_add(1, Ordering::SeqCst);
+        ResponseFuture {
+            inner: self.inner.call(req),
+            in_flight: Some(InFlight(self.handle.clone())),
+        }
+    }
+}
+
+impl<S> HasWeight for Drainable<S>
+where
+    S: HasWeight,
+{
+    fn weight(&self) -> Weight {
+        self.inner.weight()
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F: Future> Future for ResponseFuture<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let ready = self.inner.poll();
+        if let Ok(Async::NotReady) = ready {
+            return Ok(Async::NotReady);
+        }
+
+        // The request is no longer in flight once its response is
+        // received.
+        self.in_flight = None;
+        ready
+    }
+}
+
+// === impl Handle ===
+
+impl Handle {
+    fn is_idle(&self) -> bool {
+        self.0.in_flight.load(Ordering::SeqCst) == 0
+    }
+}
+
+// === impl InFlight ===
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let state = &(self.0).0;
+        let in_flight = state.in_flight.fetch_sub(1, Ordering::SeqCst) - 1;
+        if in_flight == 0 && state.draining.load(Ordering::SeqCst) {
+            state.task.notify();
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Passively ejects endpoints that fail repeatedly.
+//!
+//! `tower_balance` discards an endpoint whose `poll_ready` fails, but a
+//! flapping endpoint may be re-added by the next resolution update only to
+//! fail again. `WithEjection` tracks consecutive failures for each endpoint
+//! address, across every service discovered for that address. Once an address
+//! has failed `max_failures` consecutive times, its services are not ready
+//! until the ejection window elapses, after which the endpoint is returned to
+//! the pool.
+
+use futures::{Async, Future, Poll};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_timer::{clock, Delay};
+
+use super::tower_discover::{Change, Discover};
+use super::{HasWeight, Weight};
+use svc;
+
+/// The default number of consecutive failures after which an endpoint is
+/// ejected.
+pub const DEFAULT_MAX_FAILURES: usize = 5;
+
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Wraps each discovered service so that its failures are tracked by
+/// address.
+#[derive(Debug)]
+pub struct WithEjection<D> {
+    inner: D,
+    window: Duration,
+    max_failures: usize,
+    health: Health,
+}
+
+/// An endpoint service that is not ready while its address is ejected.
+#[derive(Debug)]
+pub struct Ejectable<S> {
+    inner: S,
+    addr: SocketAddr,
+    window: Duration,
+    max_failures: usize,
+    health: Health,
+    ejection: Option<Delay>,
+}
+
+/// Records the outcome of a response on the endpoint's health.
+pub struct ResponseFuture<F> {
+    inner: F,
+    addr: SocketAddr,
+    window: Duration,
+    max_failures: usize,
+    health: Health,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Health(Arc<Mutex<HashMap<SocketAddr, State>>>);
+
+#[derive(Debug, Default)]
+struct State {
+    failures: usize,
+    ejected_until: Option<Instant>,
+}
+
+// === impl WithEjection ===
+
+impl<D> WithEjection<D>
+where
+    D: Discover<Key = SocketAddr>,
+{
+    pub fn new(inner: D, window: Duration, max_failures: usize) -> Self {
+        Self {
+            inner,
+            window,
+            max_failures,
+            health: Health::default(),
+        }
+    }
+}
+
+impl<D> Discover for WithEjection<D>
+where
+    D: Discover<Key = SocketAddr>,
+{
+    type Key = SocketAddr;
+    type Service = Ejectable<D::Service>;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        let change = match try_ready!(self.inner.poll()) {
+            Change::Insert(addr, inner) => {
+                // If the address is already ejected, the new service
+                // remains ejected until the window elapses.
+                let svc = Ejectable {
+                    inner,
+                    addr,
+                    window: self.window,
+                    max_failures: self.max_failures,
+                    health: self.health.clone(),
+                    ejection: None,
+                };
+                Change::Insert(addr, svc)
+            }
+            Change::Remove(addr) => {
+                self.health.forget(&addr);
+                Change::Remove(addr)
+            }
+        };
+
+        Ok(Async::Ready(change))
+    }
+}
+
+// === impl Ejectable ===
+
+impl<S> Ejectable<S> {
+    /// Returns true while this endpoint's address is ejected, ensuring
+    /// that the task is notified when the ejection ends.
+    fn poll_ejected(&mut self) -> bool {
+        let until = match self.health.ejected_until(&self.addr) {
+            Some(until) => until,
+            None => {
+                self.ejection = None;
+                return false;
+            }
+        };
+
+        let delay = self.ejection.get_or_insert_with(|| Delay::new(until));
+        if delay.deadline() != until {
+            delay.reset(until);
+        }
+
+        match delay.poll() {
+            Ok(Async::NotReady) => true,
+            Ok(Async::Ready(())) | Err(_) => {
+                debug!("returning {} to the balancer", self.addr);
+                self.ejection = None;
+                self.health.restore(&self.addr);
+                false
+            }
+        }
+    }
+}
+
+impl<S, Req> svc::Service<Req> for Ejectable<S>
+where
+    S: svc::Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        if self.poll_ejected() {
+            return Ok(Async::NotReady);
+        }
+
+        match self.inner.poll_ready() {
+            Err(e) => {
+                let ejected = self.health.failed(self.addr, self.window, self.max_failures);
+                if ejected && self.poll_ejected() {
+                    return Ok(Async::NotReady);
+                }
+                Err(e)
+            }
+            ready => ready,
+        }
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        ResponseFuture {
+            inner: self.inner.call(req),
+            addr: self.addr,
+            window: self.window,
+            max_failures: self.max_failures,
+            health: self.health.clone(),
+        }
+    }
+}
+
+impl<S> HasWeight for Ejectable<S>
+where
+    S: HasWeight,
+{
+    fn weight(&self) -> Weight {
+        self.inner.weight()
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F: Future> Future for ResponseFuture<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(rsp)) => {
+                self.health.succeeded(&self.addr);
+                Ok(Async::Ready(rsp))
+            }
+            Err(e) => {
+                self.health.failed(self.addr, self.window, self.max_failures);
+                Err(e)
+            }
+        }
+    }
+}
+
+// === impl Health ===
+
+impl Health {
+    fn ejected_until(&self, addr: &SocketAddr) -> Option<Instant> {
+        let states = self.0.lock().expect("lock endpoint health");
+        states.get(addr).and_then(|s| s.ejected_until)
+    }
+
+    /// Records a failure, returning true if the failure causes the
+    /// endpoint to be ejected.
+    fn failed(&self, addr: SocketAddr, window: Duration, max_failures: usize) -> bool {
+        let mut states = self.0.lock().expect("lock endpoint health");
+        let state = states.entry(addr).or_insert_with(State::default);
+        if state.ejected_until.is_some() {
+            return false;
+        }
+
+        state.failures += 1;
+        if state.failures < max_failures {
+            return false;
+        }
+
+        debug!(
+            "ejecting {} for {:?} after {} consecutive failures",
+            addr, window, state.failures
+        );
+        state.failures = 0;
+        state.ejected_until = Some(clock::now() + window);
+        true
+    }
+
+    fn succeeded(&self, addr: &SocketAddr) {
+        let mut states = self.0.lock().expect("lock endpoint health");
+        if let Some(state) = states.get_mut(addr) {
+            state.failures = 0;
+        }
+    }
+
+    /// Ends an elapsed ejection.
+    fn restore(&self, addr: &SocketAddr) {
+        let mut states = self.0.lock().expect("lock endpoint health");
+        let elapsed = states
+            .get(addr)
+            .and_then(|s| s.ejected_until)
+            .map(|until| until <= clock::now())
+            .unwrap_or(false);
+        if elapsed {
+            states.remove(addr);
+        }
+    }
+
+    /// Discards the state for an address that is no longer resolved.
+    fn forget(&self, addr: &SocketAddr) {
+        let mut states = self.0.lock().expect("lock endpoint health");
+        states.remove(addr);
+    }
+}
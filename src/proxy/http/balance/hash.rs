@@ -0,0 +1,242 @@
+//! Routes each request to an endpoint chosen by consistent hashing on a
+//! request attribute, so that requests with the same key are served by the
+//! same endpoint.
+//!
+//! Each endpoint is placed at a number of points on a hash ring in
+//! proportion to its weight. A request is dispatched to the first ready
+//! endpoint at or after its key's hash. When an endpoint is added or removed,
+//! only the keys that hash nearest to its points are remapped.
+
+use futures::{future, Async, Future, Poll};
+use http::{self, header};
+use indexmap::{IndexMap, IndexSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+use super::tower_discover::{Change, Discover};
+use super::{Error, HasWeight, Weight};
+use proxy::server::Source;
+use svc;
+
+/// The number of points placed on the ring for each unit of weight.
+const POINTS_PER_WEIGHT: u32 = 40;
+
+/// Weights are capped so that heavily-weighted endpoints do not bloat
+/// the ring.
+const MAX_WEIGHT: u32 = 100;
+
+/// Identifies the request attribute on which requests are hashed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Key {
+    Header(header::HeaderName),
+    Cookie(String),
+    /// The IP address of the client that sent the request.
+    SourceIp,
+}
+
+/// Balances requests over endpoints by consistent hashing.
+pub struct Balance<D: Discover> {
+    discover: D,
+    key: Key,
+    endpoints: IndexMap<D::Key, D::Service>,
+    ring: Ring<D::Key>,
+    /// The endpoints that were ready when the balancer was last polled.
+    ready: IndexSet<D::Key>,
+    /// Hashed in place of the key of requests that have none, so that
+    /// such requests are spread across endpoints.
+    unkeyed: u64,
+}
+
+/// Maps points on the ring to endpoint keys.
+#[derive(Debug)]
+struct Ring<K> {
+    points: BTreeMap<u64, K>,
+}
+
+// === impl Key ===
+
+impl Key {
+    /// Hashes the value of this key in `req`, if it has one.
+    fn hash_of<B>(&self, req: &http::Request<B>) -> Option<u64> {
+        match *self {
+            Key::Header(ref name) => req.headers().get(name).map(|v| hash(v.as_bytes())),
+            Key::Cookie(ref name) => req
+                .headers()
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|cookie| {
+                    let mut kv = cookie.trim().splitn(2, '=');
+                    match (kv.next(), kv.next()) {
+                        (Some(k), Some(v)) if k == name => Some(hash(v)),
+                        _ => None,
+                    }
+                })
+                .next(),
+            Key::SourceIp => req
+                .extensions()
+                .get::<Source>()
+                .map(|src| hash(&src.remote.ip())),
+        }
+    }
+}
+
+impl Default for Key {
+    fn default() -> Self {
+        Key::SourceIp
+    }
+}
+
+// === impl Balance ===
+
+impl<D> Balance<D>
+where
+    D: Discover,
+    D::Key: Clone + HasWeight,
+{
+    pub fn new(discover: D, key: Key) -> Self {
+        Self {
+            discover,
+            key,
+            endpoints: IndexMap::new(),
+            ring: Ring {
+                points: BTreeMap::new(),
+            },
+            ready: IndexSet::new(),
+            unkeyed: 0,
+        }
+    }
+
+    fn poll_discover(&mut self) -> Result<(), D::Error> {
+        loop {
+            match self.discover.poll()? {
+                Async::Ready(Change::Insert(key, svc)) => {
+                    // A re-inserted endpoint may have a new weight.
+                    self.ring.remove(&key);
+                    self.ring.insert(&key);
+                    self.endpoints.insert(key, svc);
+                }
+                Async::Ready(Change::Remove(key)) => self.remove(&key),
+                Async::NotReady => return Ok(()),
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &D::Key) {
+        self.ring.remove(key);
+        self.endpoints.swap_remove(key);
+        self.ready.swap_remove(key);
+    }
+}
+
+impl<D, A> svc::Service<http::Request<A>> for Balance<D>
+where
+    D: Discover,
+    D::Key: Clone + HasWeight,
+    D::Service: svc::Service<http::Request<A>>,
+{
+    type Response = <D::Service as svc::Service<http::Request<A>>>::Response;
+    type Error = Error<<D::Service as svc::Service<http::Request<A>>>::Error, D::Error>;
+    type Future = future::MapErr<
+        <D::Service as svc::Service<http::Request<A>>>::Future,
+        fn(<D::Service as svc::Service<http::Request<A>>>::Error) -> Self::Error,
+    >;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.poll_discover().map_err(Error::Balance)?;
+
+        // Every endpoint is polled, since the endpoint that serves the
+        // next request is not known until it is called.
+        self.ready.clear();
+        let mut failed = Vec::new();
+        for (key, svc) in self.endpoints.iter_mut() {
+            match svc.poll_ready() {
+                Ok(Async::Ready(())) => {
+                    self.ready.insert(key.clone());
+                }
+                Ok(Async::NotReady) => {}
+                Err(_) => failed.push(key.clone()),
+            }
+        }
+        for key in failed {
+            debug!("discarding failed endpoint");
+            self.remove(&key);
+        }
+
+        if self.ready.is_empty() {
+            Ok(Async::NotReady)
+        } else {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let point = match self.key.hash_of(&req) {
+            Some(point) => point,
+            None => {
+                self.unkeyed = self.unkeyed.wrapping_add(1);
+                hash(&self.unkeyed)
+            }
+        };
+
+        // If the key's endpoint is not ready, the request is served by
+        // the next ready endpoint on the ring.
+        let key = {
+            let ready = &self.ready;
+            self.ring
+                .find(point, |k| ready.contains(k))
+                .cloned()
+                .expect("called before ready")
+        };
+        self.ready.swap_remove(&key);
+
+        let svc = self.endpoints.get_mut(&key).expect("ready endpoint must exist");
+        svc.call(req).map_err(Error::Inner as fn(_) -> _)
+    }
+}
+
+// === impl Ring ===
+
+impl<K: Hash + Eq + Clone + HasWeight> Ring<K> {
+    fn insert(&mut self, key: &K) {
+        for i in 0..points(key.weight()) {
+            self.points.insert(hash(&(key, i)), key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        let points = self
+            .points
+            .iter()
+            .filter(|&(_, k)| k == key)
+            .map(|(p, _)| *p)
+            .collect::<Vec<_>>();
+        for p in points {
+            self.points.remove(&p);
+        }
+    }
+
+    /// Returns the first key at or after `point`, wrapping around the
+    /// ring, that satisfies `f`.
+    fn find<F: Fn(&K) -> bool>(&self, point: u64, f: F) -> Option<&K> {
+        self.points
+            .range(point..)
+            .chain(self.points.range(..point))
+            .map(|(_, k)| k)
+            .find(|k| f(k))
+    }
+}
+
+fn points(weight: Weight) -> u32 {
+    weight.get().min(MAX_WEIGHT) * POINTS_PER_WEIGHT
+}
+
+/// Hashes `value` with fixed keys so that points are stable across
+/// balancers.
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,1378 @@
+extern crate tower_balance;
+extern crate tower_discover;
+extern crate tower_h2_balance;
+
+use futures::{future, Async, Future, Poll};
+use h2;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_timer::{clock, Delay};
+use self::tower_discover::Discover;
+
+pub use self::tower_balance::{
+    choose::{PowerOfTwoChoices, RoundRobin},
+    load::{WithPeakEwma, WithPendingRequests},
+    Balance,
+    Error,
+};
+pub use self::tower_h2_balance::{PendingUntilFirstData, PendingUntilFirstDataBody};
+pub use self::weight::{HasWeight, Weight};
+
+use http;
+use svc;
+use tower_h2::Body;
+
+pub mod count;
+pub mod direct;
+pub mod drain;
+pub mod eject;
+pub mod hash;
+pub mod weight;
+
+/// Determines how a balancer chooses an endpoint for each request.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Strategy {
+    /// Chooses the less loaded of two random endpoints, where load is
+    /// measured as a peak-sensitive EWMA of response latency.
+    P2cPeakEwma { decay: Duration },
+
+    /// Chooses endpoints in turn, regardless of load.
+    ///
+    /// Suitable for homogeneous endpoints where the cost of measuring load is
+    /// not worthwhile. Endpoint weights are not considered, though endpoints
+    /// with a weight of zero are still drained.
+    RoundRobin,
+
+    /// Chooses the less loaded of two random endpoints, where load is
+    /// measured as the number of pending requests.
+    LeastLoaded,
+
+    /// Chooses endpoints by consistent hashing on a request attribute, so
+    /// that requests with the same `hash::Key` are served by the same
+    /// endpoint while it remains available.
+    ConsistentHash,
+}
+
+/// Configures a stack to resolve `T` typed targets to balance requests over
+/// `M`-typed endpoint stacks.
+#[derive(Debug)]
+pub struct Layer<A, B> {
+    strategy: Strategy,
+    hash_key: hash::Key,
+    ejection_window: Duration,
+    ejection_max_failures: usize,
+    drain_timeout: Option<Duration>,
+    min_ready: usize,
+    warmup_timeout: Duration,
+    _marker: PhantomData<fn(A) -> B>,
+}
+
+/// Resolves `T` typed targets to balance requests over `M`-typed endpoint stacks.
+#[derive(Debug)]
+pub struct Stack<M, A, B> {
+    strategy: Strategy,
+    hash_key: hash::Key,
+    ejection_window: Duration,
+    ejection_max_failures: usize,
+    drain_timeout: Option<Duration>,
+    min_ready: usize,
+    warmup_timeout: Duration,
+    inner: M,
+    _marker: PhantomData<fn(A) -> B>,
+}
+
+/// Balances requests over endpoints according to a `Strategy`.
+///
+/// While only a single endpoint has been discovered, requests are dispatched
+/// to it directly, avoiding the balancer's bookkeeping. Once more endpoints
+/// are discovered, requests are balanced according to the `Strategy`.
+pub enum Service<D>
+where
+    D: Discover<Key = SocketAddr>,
+    D::Service: HasWeight,
+{
+    Direct(direct::Direct<Resolved<D>>, Strategy, hash::Key, Option<Warmup>),
+    P2cPeakEwma(PeakEwmaBalance<D>),
+    RoundRobin(RoundRobinBalance<D>),
+    LeastLoaded(LeastLoadedBalance<D>),
+    ConsistentHash(ConsistentHashBalance<D>),
+}
+
+/// Holds a new balancer not-ready until `min_ready` endpoints have been
+/// discovered or its deadline elapses, whichever happens first.
+pub struct Warmup {
+    min_ready: usize,
+    deadline: Delay,
+}
+
+pub enum ResponseFuture<P, R, L, S> {
+    P2cPeakEwma(P),
+    RoundRobin(R),
+    LeastLoaded(L),
+    Direct(S),
+    /// The service was called before it was ready.
+    NotReady,
+}
+
+/// Each strategy instruments response bodies differently, so the response
+/// body type varies by strategy.
+#[derive(Debug)]
+pub enum ResponseBody<P, R, L, S> {
+    P2cPeakEwma(P),
+    RoundRobin(R),
+    LeastLoaded(L),
+    Direct(S),
+}
+
+type Resolved<D> = weight::WithWeight<eject::WithEjection<drain::WithDrain<D>>>;
+
+type Discovered<D> = direct::Preloaded<Resolved<D>>;
+
+type Endpoint<D> = <Resolved<D> as Discover>::Service;
+
+type ServiceError<D, Req> =
+    Error<<Endpoint<D> as svc::Service<Req>>::Error, <Resolved<D> as Discover>::Error>;
+
+type DirectFuture<D, Req> = future::MapErr<
+    <Endpoint<D> as svc::Service<Req>>::Future,
+    fn(<Endpoint<D> as svc::Service<Req>>::Error) -> ServiceError<D, Req>,
+>;
+
+type PeakEwmaBalance<D> = Balance<
+    weight::WithWeightedLoad<WithPeakEwma<Discovered<D>, PendingUntilFirstData>>,
+    PowerOfTwoChoices,
+>;
+
+type RoundRobinBalance<D> = Balance<Discovered<D>, RoundRobin>;
+
+type LeastLoadedBalance<D> = Balance<
+    weight::WithWeightedLoad<WithPendingRequests<Discovered<D>, PendingUntilFirstData>>,
+    PowerOfTwoChoices,
+>;
+
+type ConsistentHashBalance<D> = hash::Balance<Discovered<D>>;
+
+// === impl Strategy ===
+
+impl Strategy {
+    const DEFAULT_DECAY: Duration = Duration::from_secs(10);
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Strategy::P2cPeakEwma {
+            decay: Strategy::DEFAULT_DECAY,
+        }
+    }
+}
+
+// === impl Layer ===
+
+pub fn layer<A, B>() -> Layer<A, B> {
+    Layer {
+        strategy: Strategy::default(),
+        hash_key: hash::Key::default(),
+        ejection_window: eject::DEFAULT_WINDOW,
+        ejection_max_failures: eject::DEFAULT_MAX_FAILURES,
+        drain_timeout: None,
+        min_ready: 1,
+        warmup_timeout: Duration::from_secs(0),
+        _marker: PhantomData,
+    }
+}
+
+impl<A, B> Layer<A, B> {
+    pub fn with_strategy(self, strategy: Strategy) -> Self {
+        Self {
+            strategy,
+            .. self
+        }
+    }
+
+    /// Sets the request attribute on which requests are hashed when the
+    /// strategy is `ConsistentHash`.
+    pub fn with_hash_key(self, hash_key: hash::Key) -> Self {
+        Self {
+            hash_key,
+            .. self
+        }
+    }
+
+    /// Sets how long an endpoint is ejected from the balancer after it fails
+    /// repeatedly.
+    pub fn with_ejection_window(self, ejection_window: Duration) -> Self {
+        Self {
+            ejection_window,
+            .. self
+        }
+    }
+
+    /// Sets the number of consecutive failures after which an endpoint is
+    /// ejected from the balancer.
+    pub fn with_ejection_max_failures(self, ejection_max_failures: usize) -> Self {
+        Self {
+            ejection_max_failures,
+            .. self
+        }
+    }
+
+    /// Sets how long a removed endpoint may continue to serve its in-flight
+    /// requests before it is dropped from the balancer.
+    ///
+    /// When `None`, removed endpoints are dropped immediately.
+    pub fn with_drain_timeout(self, drain_timeout: Option<Duration>) -> Self {
+        Self {
+            drain_timeout,
+            .. self
+        }
+    }
+
+    /// Holds each new balancer not-ready until at least `min_ready`
+    /// endpoints have been discovered, or until `warmup_timeout` elapses, so
+    /// that requests are not all dispatched to the first endpoint discovered.
+    pub fn with_warmup(self, min_ready: usize, warmup_timeout: Duration) -> Self {
+        Self {
+            min_ready,
+            warmup_timeout,
+            .. self
+        }
+    }
+}
+
+impl<A, B> Clone for Layer<A, B> {
+    fn clone(&self) -> Self {
+        Layer {
+            strategy: self.strategy,
+            hash_key: self.hash_key.clone(),
+            ejection_window: self.ejection_window,
+            ejection_max_failures: self.ejection_max_failures,
+            drain_timeout: self.drain_timeout,
+            min_ready: self.min_ready,
+            warmup_timeout: self.warmup_timeout,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, M, A, B> svc::Layer<T, T, M> for Layer<A, B>
+where
+    M: svc::Stack<T> + Clone,
+    M::Value: Discover<Key = SocketAddr>,
+    <M::Value as Discover>::Service: svc::Service<http::Request<A>, Response = http::Response<B>>,
+    <M::Value as Discover>::Service: HasWeight,
+    A: Body,
+    B: Body,
+{
+    type Value = <Stack<M, A, B> as svc::Stack<T>>::Value;
+    type Error = <Stack<M, A, B> as svc::Stack<T>>::Error;
+    type Stack = Stack<M, A, B>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            strategy: self.strategy,
+            hash_key: self.hash_key.clone(),
+            ejection_window: self.ejection_window,
+            ejection_max_failures: self.ejection_max_failures,
+            drain_timeout: self.drain_timeout,
+            min_ready: self.min_ready,
+            warmup_timeout: self.warmup_timeout,
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<M: Clone, A, B> Clone for Stack<M, A, B> {
+    fn clone(&self) -> Self {
+        Stack {
+            strategy: self.strategy,
+            hash_key: self.hash_key.clone(),
+            ejection_window: self.ejection_window,
+            ejection_max_failures: self.ejection_max_failures,
+            drain_timeout: self.drain_timeout,
+            min_ready: self.min_ready,
+            warmup_timeout: self.warmup_timeout,
+            inner: self.inner.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, M, A, B> svc::Stack<T> for Stack<M, A, B>
+where
+    M: svc::Stack<T> + Clone,
+    M::Value: Discover<Key = SocketAddr>,
+    <M::Value as Discover>::Service: svc::Service<http::Request<A>, Response = http::Response<B>>,
+    <M::Value as Discover>::Service: HasWeight,
+    A: Body,
+    B: Body,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let discover = drain::WithDrain::new(self.inner.make(target)?, self.drain_timeout);
+        let discover = eject::WithEjection::new(
+            discover,
+            self.ejection_window,
+            self.ejection_max_failures,
+        );
+        let discover = weight::WithWeight::new(discover);
+        let direct = direct::Direct::new(discover);
+        let warmup = if self.min_ready > 1 {
+            Some(Warmup {
+                min_ready: self.min_ready,
+                deadline: Delay::new(clock::now() + self.warmup_timeout),
+            })
+        } else {
+            None
+        };
+        Ok(Service::Direct(direct, self.strategy, self.hash_key.clone(), warmup))
+    }
+}
+
+// === impl Service ===
+
+impl<D> Service<D>
+where
+    D: Discover<Key = SocketAddr>,
+    D::Service: HasWeight,
+{
+    fn balance(strategy: Strategy, hash_key: hash::Key, discover: Discovered<D>) -> Self {
+        let instrument = PendingUntilFirstData::default();
+        match strategy {
+            Strategy::P2cPeakEwma { decay } => {
+                let loaded = WithPeakEwma::new(discover, decay, instrument);
+                Service::P2cPeakEwma(Balance::p2c(weight::WithWeightedLoad::new(loaded)))
+            }
+            Strategy::RoundRobin => Service::RoundRobin(Balance::round_robin(discover)),
+            Strategy::LeastLoaded => {
+                let loaded = WithPendingRequests::new(discover, instrument);
+                Service::LeastLoaded(Balance::p2c(weight::WithWeightedLoad::new(loaded)))
+            }
+            Strategy::ConsistentHash => {
+                Service::ConsistentHash(hash::Balance::new(discover, hash_key))
+            }
+        }
+    }
+}
+
+impl<D, Req, P, R, L, S> svc::Service<Req> for Service<D>
+where
+    D: Discover<Key = SocketAddr>,
+    D::Service: HasWeight,
+    Endpoint<D>: svc::Service<Req, Response = http::Response<S>>,
+    PeakEwmaBalance<D>: svc::Service<
+        Req,
+        Response = http::Response<P>,
+        Error = ServiceError<D, Req>,
+    >,
+    RoundRobinBalance<D>: svc::Service<
+        Req,
+        Response = http::Response<R>,
+        Error = ServiceError<D, Req>,
+    >,
+    LeastLoadedBalance<D>: svc::Service<
+        Req,
+        Response = http::Response<L>,
+        Error = ServiceError<D, Req>,
+    >,
+    ConsistentHashBalance<D>: svc::Service<
+        Req,
+        Response = http::Response<S>,
+        Error = ServiceError<D, Req>,
+        Future = DirectFuture<D, Req>,
+    >,
+{
+    type Response = http::Response<ResponseBody<P, R, L, S>>;
+    type Error = ServiceError<D, Req>;
+    type Future = ResponseFuture<
+        <PeakEwmaBalance<D> as svc::Service<Req>>::Future,
+        <RoundRobinBalance<D> as svc::Service<Req>>::Future,
+        <LeastLoadedBalance<D> as svc::Service<Req>>::Future,
+        DirectFuture<D, Req>,
+    >;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        let grown = match *self {
+            Service::Direct(ref mut direct, strategy, ref hash_key, ref mut warmup) => {
+                let endpoints = direct.poll_discover().map_err(Error::Balance)?;
+
+                let warm = warmup
+                    .as_mut()
+                    .map(|w| w.is_warm(endpoints))
+                    .unwrap_or(true);
+                if !warm {
+                    trace!("{} endpoints discovered; warming up", endpoints);
+                    return Ok(Async::NotReady);
+                }
+                *warmup = None;
+
+                if endpoints <= 1 {
+                    let ready = match direct.endpoint_mut() {
+                        Some(ep) => ep.poll_ready(),
+                        None => return Ok(Async::NotReady),
+                    };
+                    return match ready {
+                        Ok(ready) => Ok(ready),
+                        Err(_) => {
+                            // As the balancer would, discard the failed
+                            // endpoint until it is discovered again.
+                            debug!("discarding failed endpoint");
+                            direct.discard();
+                            Ok(Async::NotReady)
+                        }
+                    };
+                }
+
+                debug!("{} endpoints discovered; balancing requests", endpoints);
+                Some((strategy, hash_key.clone(), direct.preload()))
+            }
+            _ => None,
+        };
+        if let Some((strategy, hash_key, discover)) = grown {
+            *self = Service::balance(strategy, hash_key, discover);
+        }
+
+        match *self {
+            Service::Direct(..) => unreachable!("must be balanced"),
+            Service::P2cPeakEwma(ref mut b) => b.poll_ready(),
+            Service::RoundRobin(ref mut b) => b.poll_ready(),
+            Service::LeastLoaded(ref mut b) => b.poll_ready(),
+            Service::ConsistentHash(ref mut b) => b.poll_ready(),
+        }
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        match *self {
+            Service::Direct(ref mut direct, ..) => {
+                match direct.endpoint_mut() {
+                    Some(ep) => {
+                        ResponseFuture::Direct(ep.call(req).map_err(Error::Inner as fn(_) -> _))
+                    }
+                    None => ResponseFuture::NotReady,
+                }
+            }
+            Service::P2cPeakEwma(ref mut b) => ResponseFuture::P2cPeakEwma(b.call(req)),
+            Service::RoundRobin(ref mut b) => ResponseFuture::RoundRobin(b.call(req)),
+            Service::LeastLoaded(ref mut b) => ResponseFuture::LeastLoaded(b.call(req)),
+            // Consistently-hashed requests are dispatched to an endpoint
+            // without instrumentation, just as a single endpoint is.
+            Service::ConsistentHash(ref mut b) => ResponseFuture::Direct(b.call(req)),
+        }
+    }
+}
+
+// === impl Warmup ===
+
+impl Warmup {
+    /// Returns true once enough endpoints have been discovered or the
+    /// deadline has elapsed.
+    fn is_warm(&mut self, endpoints: usize) -> bool {
+        if endpoints >= self.min_ready {
+            return true;
+        }
+
+        match self.deadline.poll() {
+            Ok(Async::NotReady) => false,
+            Ok(Async::Ready(())) => {
+                debug!("warmup timed out with {} endpoints", endpoints);
+                true
+            }
+            Err(e) => {
+                // A timer error is treated as the warmup elapsing.
+                error!("balancer warmup timer failed: {}", e);
+                true
+            }
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<P, R, L, S, PB, RB, LB, SB, EI, EB> Future for ResponseFuture<P, R, L, S>
+where
+    P: Future<Item = http::Response<PB>, Error = Error<EI, EB>>,
+    R: Future<Item = http::Response<RB>, Error = P::Error>,
+    L: Future<Item = http::Response<LB>, Error = P::Error>,
+    S: Future<Item = http::Response<SB>, Error = P::Error>,
+{
+    type Item = http::Response<ResponseBody<PB, RB, LB, SB>>;
+    type Error = P::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rsp = match self {
+            ResponseFuture::P2cPeakEwma(ref mut f) => {
+                try_ready!(f.poll()).map(ResponseBody::P2cPeakEwma)
+            }
+            ResponseFuture::RoundRobin(ref mut f) => {
+                try_ready!(f.poll()).map(ResponseBody::RoundRobin)
+            }
+            ResponseFuture::LeastLoaded(ref mut f) => {
+                try_ready!(f.poll()).map(ResponseBody::LeastLoaded)
+            }
+            ResponseFuture::Direct(ref mut f) => {
+                try_ready!(f.poll()).map(ResponseBody::Direct)
+            }
+            ResponseFuture::NotReady => return Err(Error::NotReady),
+        };
+
+        Ok(rsp.into())
+    }
+}
+
+// === impl ResponseBody ===
+
+impl<P, R, L, S> Body for ResponseBody<P, R, L, S>
+where
+    P: Body,
+    R: Body<Data = P::Data>,
+    L: Body<Data = P::Data>,
+    S: Body<Data = P::Data>,
+{
+    type Data = P::Data;
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            ResponseBody::P2cPeakEwma(ref b) => b.is_end_stream(),
+            ResponseBody::RoundRobin(ref b) => b.is_end_stream(),
+            ResponseBody::LeastLoaded(ref b) => b.is_end_stream(),
+            ResponseBody::Direct(ref b) => b.is_end_stream(),
+        }
+    }
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, h2::Error> {
+        match self {
+            ResponseBody::P2cPeakEwma(ref mut b) => b.poll_data(),
+            ResponseBody::RoundRobin(ref mut b) => b.poll_data(),
+            ResponseBody::LeastLoaded(ref mut b) => b.poll_data(),
+            ResponseBody::Direct(ref mut b) => b.poll_data(),
+        }
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+        match self {
+            ResponseBody::P2cPeakEwma(ref mut b) => b.poll_trailers(),
+            ResponseBody::RoundRobin(ref mut b) => b.poll_trailers(),
+            ResponseBody::LeastLoaded(ref mut b) => b.poll_trailers(),
+            ResponseBody::Direct(ref mut b) => b.poll_trailers(),
+        }
+    }
+}
+
+impl<P: Default, R, L, S> Default for ResponseBody<P, R, L, S> {
+    fn default() -> Self {
+        ResponseBody::P2cPeakEwma(P::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::sync::oneshot;
+    use futures::{future, Async, Poll};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::eject::{self, WithEjection};
+    use super::tower_balance::load::Load;
+    use super::tower_discover::Change;
+    use super::weight::{WithWeight, WithWeightedLoad};
+    use super::*;
+    use never::Never;
+    use proxy::resolve::{self, Update};
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    const ENDPOINT_ID: &str = "x-endpoint-id";
+    const SESSION: &str = "x-session";
+
+    #[derive(Clone, Debug)]
+    struct Endpoint {
+        id: usize,
+        weight: Weight,
+    }
+
+    #[derive(Clone, Debug)]
+    struct Resolve(Vec<Endpoint>);
+
+    struct Resolution(VecDeque<Update<Endpoint>>);
+
+    #[derive(Clone, Debug)]
+    struct MakeSvc;
+
+    /// An endpoint service with a constant load.
+    struct Svc(Endpoint);
+
+    #[derive(Debug, Default)]
+    struct EmptyBody;
+
+    /// Discovers a fixed sequence of endpoint services.
+    struct Endpoints<S>(VecDeque<(SocketAddr, S)>);
+
+    /// Discovers endpoint changes as they are pushed by a test.
+    #[derive(Clone, Default)]
+    struct Changes(Rc<RefCell<VecDeque<Change<SocketAddr, Svc>>>>);
+
+    /// An endpoint service that always fails, counting how often it is
+    /// polled for readiness.
+    #[derive(Clone, Debug, Default)]
+    struct Failing(Arc<AtomicUsize>);
+
+    impl resolve::Refresh for Endpoint {
+        fn can_refresh(&self, new: &Self) -> bool {
+            self.weight == new.weight
+        }
+    }
+
+    impl resolve::Resolve<()> for Resolve {
+        type Endpoint = Endpoint;
+        type Resolution = Resolution;
+
+        fn resolve(&self, _: &()) -> Self::Resolution {
+            let updates = self.0.iter().map(|ep| {
+                let addr = SocketAddr::from(([10, 0, 0, ep.id as u8], 8080));
+                Update::Add(addr, ep.clone())
+            });
+            Resolution(updates.collect())
+        }
+    }
+
+    impl resolve::Resolution for Resolution {
+        type Endpoint = Endpoint;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Update<Endpoint>, ()> {
+            match self.0.pop_front() {
+                Some(up) => Ok(up.into()),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl svc::Stack<Endpoint> for MakeSvc {
+        type Value = Svc;
+        type Error = Never;
+
+        fn make(&self, ep: &Endpoint) -> Result<Svc, Never> {
+            Ok(Svc(ep.clone()))
+        }
+    }
+
+    impl svc::Service<http::Request<EmptyBody>> for Svc {
+        type Response = http::Response<EmptyBody>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, _: http::Request<EmptyBody>) -> Self::Future {
+            let rsp = http::Response::builder()
+                .header(ENDPOINT_ID, self.0.id.to_string().as_str())
+                .body(EmptyBody)
+                .unwrap();
+            future::ok(rsp)
+        }
+    }
+
+    impl Load for Svc {
+        type Metric = f64;
+
+        fn load(&self) -> f64 {
+            1.0
+        }
+    }
+
+    impl HasWeight for Svc {
+        fn weight(&self) -> Weight {
+            self.0.weight
+        }
+    }
+
+    impl<S> Discover for Endpoints<S> {
+        type Key = SocketAddr;
+        type Service = S;
+        type Error = Never;
+
+        fn poll(&mut self) -> Poll<Change<SocketAddr, S>, Never> {
+            match self.0.pop_front() {
+                Some((addr, svc)) => Ok(Change::Insert(addr, svc).into()),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl Discover for Changes {
+        type Key = SocketAddr;
+        type Service = Svc;
+        type Error = Never;
+
+        fn poll(&mut self) -> Poll<Change<SocketAddr, Svc>, Never> {
+            match self.0.borrow_mut().pop_front() {
+                Some(change) => Ok(change.into()),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl svc::Stack<()> for Changes {
+        type Value = Self;
+        type Error = Never;
+
+        fn make(&self, _: &()) -> Result<Self, Never> {
+            Ok(self.clone())
+        }
+    }
+
+    impl Changes {
+        fn insert(&self, ep: Endpoint) {
+            let change = Change::Insert(addr(ep.id), Svc(ep));
+            self.0.borrow_mut().push_back(change);
+        }
+
+        fn remove(&self, id: usize) {
+            self.0.borrow_mut().push_back(Change::Remove(addr(id)));
+        }
+    }
+
+    impl svc::Service<()> for Failing {
+        type Response = ();
+        type Error = ();
+        type Future = future::FutureResult<(), ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(())
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            future::err(())
+        }
+    }
+
+    impl Failing {
+        fn polls(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Body for EmptyBody {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            true
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+    }
+
+    fn endpoint(id: usize, weight: u32) -> Endpoint {
+        Endpoint {
+            id,
+            weight: Weight::new(weight),
+        }
+    }
+
+    fn addr(id: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, id as u8], 8080))
+    }
+
+    fn discover(endpoints: Vec<Endpoint>) -> resolve::Stack<Resolve, MakeSvc> {
+        resolve::layer::<(), _>(Resolve(endpoints)).bind(MakeSvc)
+    }
+
+    /// Sends `n` requests through `svc`, returning the number of requests
+    /// served by each endpoint.
+    fn send<S, B>(svc: &mut S, n: usize) -> Vec<usize>
+    where
+        S: svc::Service<http::Request<EmptyBody>, Response = http::Response<B>>,
+        S::Error: ::std::fmt::Debug,
+    {
+        let mut rt = Runtime::new().unwrap();
+        let mut counts = vec![0; 4];
+        for _ in 0..n {
+            rt.block_on(future::poll_fn(|| svc.poll_ready()))
+                .expect("ready");
+            let rsp = rt.block_on(svc.call(http::Request::new(EmptyBody)))
+                .expect("call");
+            let id = rsp.headers()[ENDPOINT_ID]
+                .to_str()
+                .expect("id")
+                .parse::<usize>()
+                .expect("id");
+            counts[id] += 1;
+        }
+        counts
+    }
+
+    /// Sends a request with the given session key through `svc`, returning
+    /// the endpoint that served it.
+    fn route<S, B>(rt: &mut Runtime, svc: &mut S, session: &str) -> usize
+    where
+        S: svc::Service<http::Request<EmptyBody>, Response = http::Response<B>>,
+        S::Error: ::std::fmt::Debug,
+    {
+        rt.block_on(future::poll_fn(|| svc.poll_ready()))
+            .expect("ready");
+        let req = http::Request::builder()
+            .header(SESSION, session)
+            .body(EmptyBody)
+            .unwrap();
+        let rsp = rt.block_on(svc.call(req)).expect("call");
+        rsp.headers()[ENDPOINT_ID]
+            .to_str()
+            .expect("id")
+            .parse::<usize>()
+            .expect("id")
+    }
+
+    #[test]
+    fn weighted_load_is_scaled_by_weight() {
+        let ep = endpoint(0, 4);
+        assert_eq!(weight::Weighted::new(Svc(ep.clone()), Weight::new(4)).load(), 0.25);
+        assert_eq!(weight::Weighted::new(Svc(ep.clone()), Weight::default()).load(), 1.0);
+        assert!(weight::Weighted::new(Svc(ep), Weight::DRAINED).load().is_infinite());
+    }
+
+    #[test]
+    fn selection_skews_toward_heavier_endpoints() {
+        const REQUESTS: usize = 300;
+
+        let endpoints = vec![endpoint(0, 1), endpoint(1, 1), endpoint(2, 8), endpoint(3, 0)];
+        let discover = discover(endpoints).make(&()).expect("discover");
+        let mut balance = Balance::p2c(WithWeightedLoad::new(WithWeight::new(discover)));
+
+        let counts = send(&mut balance, REQUESTS);
+        assert_eq!(counts[3], 0, "drained endpoint must not be chosen");
+        assert!(counts[2] > counts[0], "counts={:?}", counts);
+        assert!(counts[2] > counts[1], "counts={:?}", counts);
+        assert_eq!(counts.iter().sum::<usize>(), REQUESTS);
+    }
+
+    #[test]
+    fn each_strategy_routes_requests() {
+        const REQUESTS: usize = 12;
+
+        let strategies = vec![
+            Strategy::default(),
+            Strategy::RoundRobin,
+            Strategy::LeastLoaded,
+        ];
+        for strategy in strategies {
+            let endpoints = vec![endpoint(0, 1), endpoint(1, 1), endpoint(2, 1), endpoint(3, 0)];
+            let mut balance = layer::<EmptyBody, EmptyBody>()
+                .with_strategy(strategy)
+                .bind(discover(endpoints))
+                .make(&())
+                .expect("balance");
+
+            let counts = send(&mut balance, REQUESTS);
+            assert_eq!(counts[3], 0, "strategy={:?}", strategy);
+            assert_eq!(counts.iter().sum::<usize>(), REQUESTS, "strategy={:?}", strategy);
+
+            if strategy == Strategy::RoundRobin {
+                assert_eq!(&counts[..3], &[4, 4, 4], "strategy={:?}", strategy);
+            }
+        }
+    }
+
+    #[test]
+    fn single_endpoint_is_dispatched_directly() {
+        const REQUESTS: usize = 4;
+
+        for strategy in vec![Strategy::default(), Strategy::RoundRobin, Strategy::LeastLoaded] {
+            let mut svc = layer::<EmptyBody, EmptyBody>()
+                .with_strategy(strategy)
+                .bind(discover(vec![endpoint(0, 1)]))
+                .make(&())
+                .expect("balance");
+
+            let counts = send(&mut svc, REQUESTS);
+            assert_eq!(counts[0], REQUESTS, "strategy={:?}", strategy);
+            match svc {
+                Service::Direct(..) => {}
+                _ => panic!("a single endpoint must not be balanced; strategy={:?}", strategy),
+            }
+        }
+    }
+
+    #[test]
+    fn direct_calls_before_ready_fail() {
+        let mut svc = layer::<EmptyBody, EmptyBody>()
+            .bind(discover(vec![endpoint(0, 1)]))
+            .make(&())
+            .expect("balance");
+
+        let mut rt = Runtime::new().unwrap();
+        match rt.block_on(svc.call(http::Request::new(EmptyBody))) {
+            Err(Error::NotReady) => {}
+            Err(_) => panic!("call must fail as not ready"),
+            Ok(_) => panic!("call must not be dispatched before the service is ready"),
+        }
+    }
+
+    #[test]
+    fn multiple_endpoints_are_balanced() {
+        const REQUESTS: usize = 4;
+
+        let endpoints = vec![endpoint(0, 1), endpoint(1, 1)];
+        let mut svc = layer::<EmptyBody, EmptyBody>()
+            .with_strategy(Strategy::RoundRobin)
+            .bind(discover(endpoints))
+            .make(&())
+            .expect("balance");
+        match svc {
+            Service::Direct(..) => {}
+            _ => panic!("endpoints must not be balanced before they are discovered"),
+        }
+
+        let counts = send(&mut svc, REQUESTS);
+        assert_eq!(&counts[..2], &[2, 2]);
+        match svc {
+            Service::RoundRobin(..) => {}
+            _ => panic!("multiple endpoints must be balanced"),
+        }
+    }
+
+    #[test]
+    fn consistent_hashing_routes_keys_to_the_same_endpoint() {
+        const SESSIONS: usize = 64;
+
+        let endpoints = vec![endpoint(0, 1), endpoint(1, 1), endpoint(2, 1), endpoint(3, 0)];
+        let mut svc = layer::<EmptyBody, EmptyBody>()
+            .with_strategy(Strategy::ConsistentHash)
+            .with_hash_key(hash::Key::Header(http::header::HeaderName::from_static(SESSION)))
+            .bind(discover(endpoints))
+            .make(&())
+            .expect("balance");
+
+        let mut rt = Runtime::new().unwrap();
+        let sessions = (0..SESSIONS).map(|i| format!("session-{}", i)).collect::<Vec<_>>();
+        let routed = sessions
+            .iter()
+            .map(|s| route(&mut rt, &mut svc, s))
+            .collect::<Vec<_>>();
+        match svc {
+            Service::ConsistentHash(..) => {}
+            _ => panic!("multiple endpoints must be balanced"),
+        }
+
+        assert!(!routed.contains(&3), "drained endpoint must not be chosen");
+        for id in 0..3 {
+            assert!(routed.contains(&id), "endpoint {} must be chosen; routed={:?}", id, routed);
+        }
+        for _ in 0..2 {
+            for (session, id) in sessions.iter().zip(&routed) {
+                assert_eq!(route(&mut rt, &mut svc, session), *id, "session={}", session);
+            }
+        }
+    }
+
+    #[test]
+    fn consistent_hashing_remaps_only_removed_endpoint_keys() {
+        const SESSIONS: usize = 200;
+
+        let changes = Changes::default();
+        for id in 0..4 {
+            changes.insert(endpoint(id, 1));
+        }
+        let header = http::header::HeaderName::from_static(SESSION);
+        let mut balance = hash::Balance::new(WithWeight::new(changes.clone()), hash::Key::Header(header));
+
+        let mut rt = Runtime::new().unwrap();
+        let sessions = (0..SESSIONS).map(|i| format!("session-{}", i)).collect::<Vec<_>>();
+        let before = sessions
+            .iter()
+            .map(|s| route(&mut rt, &mut balance, s))
+            .collect::<Vec<_>>();
+        assert!(before.contains(&3), "removed endpoint must have been chosen");
+
+        changes.remove(3);
+        let after = sessions
+            .iter()
+            .map(|s| route(&mut rt, &mut balance, s))
+            .collect::<Vec<_>>();
+        for ((session, old), new) in sessions.iter().zip(&before).zip(&after) {
+            assert_ne!(*new, 3, "session={}", session);
+            if *old != 3 {
+                assert_eq!(new, old, "session={} must not be remapped", session);
+            }
+        }
+    }
+
+    #[test]
+    fn repeatedly_failing_endpoints_are_ejected() {
+        const WINDOW: Duration = Duration::from_millis(100);
+
+        let addr = SocketAddr::from(([10, 0, 0, 1], 8080));
+        let failing = Failing::default();
+        let endpoints = vec![(addr, failing.clone()), (addr, failing.clone())];
+        let mut discover = WithEjection::new(
+            Endpoints(endpoints.into_iter().collect()),
+            WINDOW,
+            eject::DEFAULT_MAX_FAILURES,
+        );
+
+        let mut rt = Runtime::new().unwrap();
+        let start = Instant::now();
+        let mut readded = rt
+            .block_on(future::lazy(|| {
+                let mut svc = match discover.poll() {
+                    Ok(Async::Ready(Change::Insert(_, svc))) => svc,
+                    _ => panic!("endpoint must be discovered"),
+                };
+                for _ in 1..eject::DEFAULT_MAX_FAILURES {
+                    assert_eq!(svc.poll_ready(), Err(()));
+                }
+
+                // The last consecutive failure ejects the endpoint.
+                assert_eq!(svc.poll_ready(), Ok(Async::NotReady));
+                assert_eq!(failing.polls(), eject::DEFAULT_MAX_FAILURES);
+
+                // Neither the ejected service nor a service re-added for the
+                // same address is polled during the ejection window.
+                assert_eq!(svc.poll_ready(), Ok(Async::NotReady));
+                let mut readded = match discover.poll() {
+                    Ok(Async::Ready(Change::Insert(_, svc))) => svc,
+                    _ => panic!("endpoint must be re-discovered"),
+                };
+                assert_eq!(readded.poll_ready(), Ok(Async::NotReady));
+                assert_eq!(failing.polls(), eject::DEFAULT_MAX_FAILURES);
+
+                Ok::<_, ()>(readded)
+            }))
+            .unwrap();
+
+        // Once the window elapses, the endpoint is polled again.
+        let rsp = rt.block_on(future::poll_fn(|| readded.poll_ready()));
+        assert_eq!(rsp, Err(()));
+        assert!(start.elapsed() >= WINDOW);
+        assert_eq!(failing.polls(), eject::DEFAULT_MAX_FAILURES + 1);
+    }
+
+    #[test]
+    fn ejection_max_failures_is_configurable() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 8080));
+        let failing = Failing::default();
+        let endpoints = vec![(addr, failing.clone())];
+        let mut discover = WithEjection::new(
+            Endpoints(endpoints.into_iter().collect()),
+            Duration::from_secs(10),
+            2,
+        );
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            let mut svc = match discover.poll() {
+                Ok(Async::Ready(Change::Insert(_, svc))) => svc,
+                _ => panic!("endpoint must be discovered"),
+            };
+            assert_eq!(svc.poll_ready(), Err(()));
+            assert_eq!(svc.poll_ready(), Ok(Async::NotReady));
+            assert_eq!(failing.polls(), 2);
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn removed_endpoints_are_not_ejected_when_readded() {
+        struct Readd(VecDeque<Change<SocketAddr, Failing>>);
+
+        impl Discover for Readd {
+            type Key = SocketAddr;
+            type Service = Failing;
+            type Error = ();
+
+            fn poll(&mut self) -> Poll<Change<SocketAddr, Failing>, ()> {
+                match self.0.pop_front() {
+                    Some(change) => Ok(change.into()),
+                    None => Ok(Async::NotReady),
+                }
+            }
+        }
+
+        let addr = SocketAddr::from(([10, 0, 0, 1], 8080));
+        let failing = Failing::default();
+        let changes = vec![
+            Change::Insert(addr, failing.clone()),
+            Change::Remove(addr),
+            Change::Insert(addr, failing.clone()),
+        ];
+        let window = Duration::from_secs(60);
+        let mut discover = WithEjection::new(
+            Readd(changes.into_iter().collect()),
+            window,
+            eject::DEFAULT_MAX_FAILURES,
+        );
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            let mut svc = match discover.poll() {
+                Ok(Async::Ready(Change::Insert(_, svc))) => svc,
+                _ => panic!("endpoint must be discovered"),
+            };
+            for _ in 1..eject::DEFAULT_MAX_FAILURES {
+                assert_eq!(svc.poll_ready(), Err(()));
+            }
+            assert_eq!(svc.poll_ready(), Ok(Async::NotReady));
+
+            match discover.poll() {
+                Ok(Async::Ready(Change::Remove(a))) => assert_eq!(a, addr),
+                _ => panic!("endpoint must be removed"),
+            }
+
+            // The re-added endpoint is polled immediately.
+            let mut readded = match discover.poll() {
+                Ok(Async::Ready(Change::Insert(_, svc))) => svc,
+                _ => panic!("endpoint must be re-discovered"),
+            };
+            assert_eq!(readded.poll_ready(), Err(()));
+            assert_eq!(failing.polls(), eject::DEFAULT_MAX_FAILURES + 1);
+
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    /// Discovers changes to endpoints whose responses are completed by a test.
+    #[derive(Clone, Default)]
+    struct Pending {
+        changes: Rc<RefCell<VecDeque<Change<SocketAddr, Pending>>>>,
+        responses: Rc<RefCell<VecDeque<oneshot::Sender<()>>>>,
+    }
+
+    impl Discover for Pending {
+        type Key = SocketAddr;
+        type Service = Pending;
+        type Error = Never;
+
+        fn poll(&mut self) -> Poll<Change<SocketAddr, Pending>, Never> {
+            match self.changes.borrow_mut().pop_front() {
+                Some(change) => Ok(change.into()),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl svc::Service<()> for Pending {
+        type Response = ();
+        type Error = ();
+        type Future = Box<Future<Item = (), Error = ()>>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            let (tx, rx) = oneshot::channel();
+            self.responses.borrow_mut().push_back(tx);
+            Box::new(rx.map_err(|_| ()))
+        }
+    }
+
+    impl Pending {
+        fn respond(&self) {
+            let tx = self.responses.borrow_mut().pop_front().expect("in flight");
+            tx.send(()).expect("respond");
+        }
+    }
+
+    #[test]
+    fn removed_endpoints_drain_in_flight_requests() {
+        let pending = Pending::default();
+        pending
+            .changes
+            .borrow_mut()
+            .push_back(Change::Insert(addr(0), pending.clone()));
+        let mut discover = drain::WithDrain::new(pending.clone(), Some(Duration::from_secs(60)));
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            let mut svc = match discover.poll() {
+                Ok(Async::Ready(Change::Insert(_, svc))) => svc,
+                _ => panic!("endpoint must be inserted"),
+            };
+            assert!(svc.poll_ready().unwrap().is_ready());
+            let mut first = svc.call(());
+            let mut second = svc.call(());
+
+            // The endpoint is not removed while requests are in flight, but
+            // it no longer accepts new requests.
+            pending
+                .changes
+                .borrow_mut()
+                .push_back(Change::Remove(addr(0)));
+            assert!(discover.poll().unwrap().is_not_ready());
+            assert!(svc.poll_ready().unwrap().is_not_ready());
+
+            pending.respond();
+            assert!(first.poll().unwrap().is_ready());
+            assert!(discover.poll().unwrap().is_not_ready());
+
+            // Once its last request completes, the endpoint is removed.
+            pending.respond();
+            assert!(second.poll().unwrap().is_ready());
+            match discover.poll() {
+                Ok(Async::Ready(Change::Remove(a))) => assert_eq!(a, addr(0)),
+                _ => panic!("drained endpoint must be removed"),
+            }
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn removed_endpoints_are_dropped_when_drain_times_out() {
+        const TIMEOUT: Duration = Duration::from_millis(20);
+
+        for timeout in vec![None, Some(TIMEOUT)] {
+            let pending = Pending::default();
+            pending
+                .changes
+                .borrow_mut()
+                .push_back(Change::Insert(addr(0), pending.clone()));
+            let mut discover = drain::WithDrain::new(pending.clone(), timeout);
+
+            let mut rt = Runtime::new().unwrap();
+            let mut svc = match rt.block_on(future::poll_fn(|| discover.poll())) {
+                Ok(Change::Insert(_, svc)) => svc,
+                _ => panic!("endpoint must be inserted"),
+            };
+            let _in_flight = svc.call(());
+
+            pending
+                .changes
+                .borrow_mut()
+                .push_back(Change::Remove(addr(0)));
+            let start = Instant::now();
+            match rt.block_on(future::poll_fn(|| discover.poll())) {
+                Ok(Change::Remove(a)) => assert_eq!(a, addr(0)),
+                _ => panic!("endpoint must be removed"),
+            }
+            if timeout.is_some() {
+                assert!(start.elapsed() >= TIMEOUT);
+            }
+        }
+    }
+
+    #[test]
+    fn readiness_waits_for_min_ready_endpoints() {
+        let changes = Changes::default();
+        let mut svc = layer::<EmptyBody, EmptyBody>()
+            .with_strategy(Strategy::RoundRobin)
+            .with_warmup(3, Duration::from_secs(60))
+            .bind(changes.clone())
+            .make(&())
+            .expect("balance");
+
+        let mut rt = Runtime::new().unwrap();
+        let mut poll_ready = || {
+            rt.block_on(future::lazy(|| Ok::<_, ()>(svc.poll_ready().expect("ready"))))
+                .unwrap()
+        };
+
+        // Endpoints trickle in, but the balancer is not ready until the
+        // third is discovered.
+        assert!(poll_ready().is_not_ready());
+        changes.insert(endpoint(0, 1));
+        assert!(poll_ready().is_not_ready());
+        changes.insert(endpoint(1, 1));
+        assert!(poll_ready().is_not_ready());
+        changes.insert(endpoint(2, 1));
+        assert!(poll_ready().is_ready());
+    }
+
+    #[test]
+    fn readiness_is_not_delayed_past_warmup_timeout() {
+        const TIMEOUT: Duration = Duration::from_millis(20);
+
+        let changes = Changes::default();
+        changes.insert(endpoint(0, 1));
+        let mut svc = layer::<EmptyBody, EmptyBody>()
+            .with_warmup(3, TIMEOUT)
+            .bind(changes.clone())
+            .make(&())
+            .expect("balance");
+
+        let start = Instant::now();
+        let counts = send(&mut svc, 1);
+        assert!(start.elapsed() >= TIMEOUT);
+        assert_eq!(counts[0], 1);
+
+        // Once warmed up, the balancer does not wait again.
+        changes.remove(0);
+        changes.insert(endpoint(1, 1));
+        let start = Instant::now();
+        let counts = send(&mut svc, 1);
+        assert!(start.elapsed() < TIMEOUT);
+        assert_eq!(counts[1], 1);
+    }
+
+    #[test]
+    fn endpoint_count_tracks_discovery() {
+        use metrics::FmtMetrics;
+
+        /// Discovers `Changes` for every destination.
+        struct MakeChanges(Changes);
+
+        impl svc::Stack<&'static str> for MakeChanges {
+            type Value = Changes;
+            type Error = Never;
+
+            fn make(&self, _: &&'static str) -> Result<Changes, Never> {
+                Ok(self.0.clone())
+            }
+        }
+
+        /// Drains all pending changes and renders the report.
+        fn poll_all(discover: &mut count::Counted<Changes>, report: &count::Report) -> String {
+            while let Ok(Async::Ready(_)) = discover.poll() {}
+            format!("{}", report.as_display())
+        }
+
+        let gauge = |n: usize| {
+            format!("lb_endpoints{{dst=\"web.ns.svc.cluster.local:8080\"}} {}\n", n)
+        };
+
+        let (registry, report) = count::metrics();
+        let changes = Changes::default();
+        let stack = count::layer(registry).bind(MakeChanges(changes.clone()));
+        let mut discover = stack.make(&"web.ns.svc.cluster.local:8080").unwrap();
+        assert!(poll_all(&mut discover, &report).contains(&gauge(0)));
+
+        changes.insert(endpoint(1, 1));
+        changes.insert(endpoint(2, 1));
+        changes.insert(endpoint(3, 1));
+        assert!(poll_all(&mut discover, &report).contains(&gauge(3)));
+
+        // Re-inserting an endpoint replaces it.
+        changes.insert(endpoint(2, 2));
+        changes.remove(1);
+        assert!(poll_all(&mut discover, &report).contains(&gauge(2)));
+
+        changes.remove(2);
+        changes.remove(3);
+        assert!(poll_all(&mut discover, &report).contains(&gauge(0)));
+
+        // Once the balancer is dropped, its destination is no longer reported.
+        drop(discover);
+        assert_eq!(format!("{}", report.as_display()), "");
+    }
+}
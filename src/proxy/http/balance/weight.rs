@@ -0,0 +1,271 @@
+//! Biases endpoint selection by a per-endpoint `Weight`.
+//!
+//! Endpoint services are annotated with their target's weight by
+//! `weight::layer`. Because the peak-EWMA load wrapper hides the endpoint
+//! service, the weight is carried through the balancer's discovery keys:
+//! `WithWeight` moves each endpoint's weight into its key and
+//! `WithWeightedLoad` uses the key's weight to scale the load of the
+//! instrumented service by `1/weight`.
+//!
+//! Endpoints with a weight of zero are drained: they are removed from the
+//! balancer and are never chosen.
+
+use futures::{Async, Poll};
+use std::hash;
+use std::marker::PhantomData;
+
+use super::tower_balance::load::Load;
+use super::tower_discover::{Change, Discover};
+use svc;
+
+/// Endpoint labels are used to carry weights until the destination
+/// service exposes weights explicitly.
+pub const LABEL: &str = "lb_weight";
+
+/// A relative weight used to bias load balancing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Weight(u32);
+
+/// Describes a target or service that has an associated `Weight`.
+pub trait HasWeight {
+    fn weight(&self) -> Weight;
+}
+
+/// Annotates each endpoint service with its target's weight.
+#[derive(Debug)]
+pub struct Layer<T>(PhantomData<fn(T)>);
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+}
+
+/// Wraps a value with a `Weight`.
+///
+/// When used as a discovery key, a `Weighted` is hashed and compared
+/// without regard to its weight so that endpoints may be removed by key
+/// alone.
+#[derive(Clone, Debug)]
+pub struct Weighted<T> {
+    inner: T,
+    weight: Weight,
+}
+
+/// Moves the weight of each discovered endpoint service into its key.
+///
+/// Endpoints with a weight of zero are removed instead of inserted.
+#[derive(Debug)]
+pub struct WithWeight<D>(D);
+
+/// Wraps each discovered service so that its load is scaled by the
+/// weight carried in its key.
+#[derive(Debug)]
+pub struct WithWeightedLoad<D>(D);
+
+// === impl Weight ===
+
+impl Weight {
+    /// Indicates that an endpoint should not receive requests.
+    pub const DRAINED: Weight = Weight(0);
+
+    pub fn new(weight: u32) -> Self {
+        Weight(weight)
+    }
+
+    pub fn is_drained(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+
+    fn scale(&self, load: f64) -> f64 {
+        if self.is_drained() {
+            return ::std::f64::INFINITY;
+        }
+
+        load / f64::from(self.0)
+    }
+}
+
+impl Default for Weight {
+    fn default() -> Self {
+        Weight(1)
+    }
+}
+
+// === impl Layer ===
+
+pub fn layer<T: HasWeight>() -> Layer<T> {
+    Layer(PhantomData)
+}
+
+impl<T> Clone for Layer<T> {
+    fn clone(&self) -> Self {
+        Layer(PhantomData)
+    }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer<T>
+where
+    T: HasWeight,
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack { inner }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    T: HasWeight,
+    M: svc::Stack<T>,
+{
+    type Value = Weighted<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Weighted::new(inner, target.weight()))
+    }
+}
+
+// === impl Weighted ===
+
+impl<T> Weighted<T> {
+    pub fn new(inner: T, weight: Weight) -> Self {
+        Self { inner, weight }
+    }
+}
+
+impl<T> HasWeight for Weighted<T> {
+    fn weight(&self) -> Weight {
+        self.weight
+    }
+}
+
+impl<T: hash::Hash> hash::Hash for Weighted<T> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.inner.hash(state);
+    }
+}
+
+impl<T: PartialEq> PartialEq for Weighted<T> {
+    fn eq(&self, other: &Weighted<T>) -> bool {
+        self.inner.eq(&other.inner)
+    }
+}
+
+impl<T: Eq> Eq for Weighted<T> {}
+
+impl<S, Req> svc::Service<Req> for Weighted<S>
+where
+    S: svc::Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.inner.call(req)
+    }
+}
+
+impl<L> Load for Weighted<L>
+where
+    L: Load,
+    L::Metric: Into<f64>,
+{
+    type Metric = f64;
+
+    fn load(&self) -> Self::Metric {
+        self.weight.scale(self.inner.load().into())
+    }
+}
+
+// === impl WithWeight ===
+
+impl<D> WithWeight<D>
+where
+    D: Discover,
+    D::Service: HasWeight,
+{
+    pub fn new(discover: D) -> Self {
+        WithWeight(discover)
+    }
+}
+
+impl<D> Discover for WithWeight<D>
+where
+    D: Discover,
+    D::Service: HasWeight,
+{
+    type Key = Weighted<D::Key>;
+    type Service = D::Service;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        let change = match try_ready!(self.0.poll()) {
+            Change::Insert(key, svc) => {
+                let weight = svc.weight();
+                if weight.is_drained() {
+                    // Removing the key ensures that an endpoint that was
+                    // previously inserted with a non-zero weight is no
+                    // longer chosen.
+                    trace!("draining endpoint with zero weight");
+                    Change::Remove(Weighted::new(key, weight))
+                } else {
+                    Change::Insert(Weighted::new(key, weight), svc)
+                }
+            }
+            Change::Remove(key) => Change::Remove(Weighted::new(key, Weight::DRAINED)),
+        };
+
+        Ok(Async::Ready(change))
+    }
+}
+
+// === impl WithWeightedLoad ===
+
+impl<D, K> WithWeightedLoad<D>
+where
+    D: Discover<Key = Weighted<K>>,
+    K: hash::Hash + Eq,
+{
+    pub fn new(discover: D) -> Self {
+        WithWeightedLoad(discover)
+    }
+}
+
+impl<D, K> Discover for WithWeightedLoad<D>
+where
+    D: Discover<Key = Weighted<K>>,
+    K: hash::Hash + Eq,
+{
+    type Key = Weighted<K>;
+    type Service = Weighted<D::Service>;
+    type Error = D::Error;
+
+    fn poll(&mut self) -> Poll<Change<Self::Key, Self::Service>, Self::Error> {
+        let change = match try_ready!(self.0.poll()) {
+            Change::Insert(key, svc) => {
+                let weight = key.weight();
+                Change::Insert(key, Weighted::new(svc, weight))
+            }
+            Change::Remove(key) => Change::Remove(key),
+        };
+
+        Ok(Async::Ready(change))
+    }
+}
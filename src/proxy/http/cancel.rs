@@ -0,0 +1,347 @@
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+use h2;
+use http;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tower_h2::Body;
+
+use metrics::{Counter, FmtMetrics};
+use svc;
+
+metrics! {
+    client_canceled_total: Counter {
+        "Total number of requests canceled by the client before a response was received"
+    }
+}
+
+/// Constructs a Registry/Report pair for client cancelation metrics.
+pub fn metrics() -> (Registry, Report) {
+    let inner = Arc::new(Mutex::new(Counter::default()));
+    (Registry(inner.clone()), Report(inner))
+}
+
+/// A stack module that cancels in-flight upstream requests when the client
+/// cancels its request.
+///
+/// A client's cancelation is only observed while its request body is being
+/// streamed: when the request body fails (i.e. because the client reset its
+/// stream), the upstream response future is dropped, so that the upstream
+/// request is reset rather than being allowed to run to completion.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    registry: Registry,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+    registry: Registry,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    inner: S,
+    registry: Registry,
+}
+
+/// Counts requests canceled by clients.
+#[derive(Clone, Debug)]
+pub struct Registry(Arc<Mutex<Counter>>);
+
+/// Formats client cancelation metrics for Prometheus.
+#[derive(Clone, Debug)]
+pub struct Report(Arc<Mutex<Counter>>);
+
+/// Notifies the `ResponseFuture` when the request body fails.
+pub struct RequestBody<B> {
+    inner: B,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+/// Drops the inner future once the request's client has canceled.
+pub struct ResponseFuture<F> {
+    inner: Option<F>,
+    canceled: Option<oneshot::Receiver<()>>,
+    registry: Registry,
+}
+
+// === impl Layer ===
+
+pub fn layer(registry: Registry) -> Layer {
+    Layer { registry }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            inner,
+            registry: self.registry.clone(),
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service {
+            inner,
+            registry: self.registry.clone(),
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, A, B> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<RequestBody<A>>, Response = http::Response<B>>,
+    S::Error: From<h2::Reason>,
+    A: Body,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        // A request without a body cannot be observed to be canceled.
+        let (cancel, canceled) = if req.body().is_end_stream() {
+            (None, None)
+        } else {
+            let (tx, rx) = oneshot::channel();
+            (Some(tx), Some(rx))
+        };
+
+        let req = {
+            let (head, inner) = req.into_parts();
+            http::Request::from_parts(head, RequestBody { inner, cancel })
+        };
+
+        ResponseFuture {
+            inner: Some(self.inner.call(req)),
+            canceled,
+            registry: self.registry.clone(),
+        }
+    }
+}
+
+// === impl Registry ===
+
+impl Registry {
+    fn incr(&self) {
+        if let Ok(mut canceled) = self.0.lock() {
+            canceled.incr();
+        }
+    }
+}
+
+// === impl Report ===
+
+impl FmtMetrics for Report {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let canceled = match self.0.lock() {
+            Err(_) => return Ok(()),
+            Ok(canceled) => *canceled,
+        };
+
+        client_canceled_total.fmt_help(f)?;
+        client_canceled_total.fmt_metric(f, canceled)
+    }
+}
+
+// === impl RequestBody ===
+
+impl<B: Body> RequestBody<B> {
+    fn cancel(&mut self) {
+        if let Some(tx) = self.cancel.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+impl<B: Body> Body for RequestBody<B> {
+    type Data = B::Data;
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, h2::Error> {
+        self.inner.poll_data().map_err(|e| {
+            self.cancel();
+            e
+        })
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+        self.inner.poll_trailers().map_err(|e| {
+            self.cancel();
+            e
+        })
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: Future<Item = http::Response<B>>,
+    F::Error: From<h2::Reason>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let inner = self.inner.as_mut().expect("polled after cancelation");
+            if let Async::Ready(rsp) = inner.poll()? {
+                return Ok(Async::Ready(rsp));
+            }
+        }
+
+        let canceled = match self.canceled.as_mut().map(|c| c.poll()) {
+            None | Some(Ok(Async::NotReady)) => return Ok(Async::NotReady),
+            Some(Ok(Async::Ready(()))) => true,
+            // The request body completed without being canceled.
+            Some(Err(oneshot::Canceled)) => false,
+        };
+        self.canceled = None;
+
+        if !canceled {
+            return Ok(Async::NotReady);
+        }
+
+        debug!("client canceled request; canceling upstream request");
+        self.inner = None;
+        self.registry.incr();
+        Err(h2::Reason::CANCEL.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use svc::Service as _Service;
+
+    /// A request body that is either empty or reset by the client.
+    struct TestBody(bool);
+
+    /// Streams the request body upstream, never responding.
+    struct Upstream;
+
+    struct UpstreamFuture {
+        body: RequestBody<TestBody>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Body for TestBody {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            false
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            if self.0 {
+                return Err(h2::Reason::CANCEL.into());
+            }
+            Ok(Async::Ready(None))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+    }
+
+    impl svc::Service<http::Request<RequestBody<TestBody>>> for Upstream {
+        type Response = http::Response<()>;
+        type Error = h2::Error;
+        type Future = UpstreamFuture;
+
+        fn poll_ready(&mut self) -> Poll<(), h2::Error> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, req: http::Request<RequestBody<TestBody>>) -> Self::Future {
+            UpstreamFuture {
+                body: req.into_body(),
+                dropped: Arc::new(AtomicBool::new(false)),
+            }
+        }
+    }
+
+    impl Future for UpstreamFuture {
+        type Item = http::Response<()>;
+        type Error = h2::Error;
+
+        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+            // Errors are reported to the client, not the upstream.
+            let _ = self.body.poll_data();
+            Ok(Async::NotReady)
+        }
+    }
+
+    impl Drop for UpstreamFuture {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn send(reset: bool) -> (ResponseFuture<UpstreamFuture>, Arc<AtomicBool>, Report) {
+        let (registry, report) = metrics();
+        let mut svc = Service {
+            inner: Upstream,
+            registry,
+        };
+        let rsp = svc.call(http::Request::new(TestBody(reset)));
+        let dropped = rsp.inner.as_ref().expect("upstream").dropped.clone();
+        (rsp, dropped, report)
+    }
+
+    fn canceled(report: &Report) -> u64 {
+        report.0.lock().unwrap().value()
+    }
+
+    #[test]
+    fn client_cancelation_drops_upstream_future() {
+        let (mut rsp, dropped, report) = send(true);
+
+        let err = future::lazy(|| Ok::<_, ()>(rsp.poll())).wait().unwrap();
+        assert_eq!(err.unwrap_err().reason(), Some(h2::Reason::CANCEL));
+        assert!(dropped.load(Ordering::SeqCst), "upstream must be dropped");
+        assert_eq!(canceled(&report), 1);
+    }
+
+    #[test]
+    fn completed_request_body_is_not_canceled() {
+        let (mut rsp, dropped, report) = send(false);
+
+        let poll = future::lazy(|| Ok::<_, ()>(rsp.poll())).wait().unwrap();
+        assert!(poll.expect("poll").is_not_ready());
+        assert!(!dropped.load(Ordering::SeqCst), "upstream must not be dropped");
+        assert_eq!(canceled(&report), 0);
+    }
+}
@@ -0,0 +1,671 @@
+//! Stops sending requests to an endpoint whose responses are failing.
+//!
+//! Each service tracks the classifications of its most recent responses.
+//! When the ratio of failures among them reaches the configured threshold,
+//! the circuit opens and requests fail immediately with `Error::Open`. Once
+//! the cooldown elapses, a single probe request is sent: if it succeeds the
+//! circuit closes, and otherwise it remains open for another cooldown.
+//!
+//! Responses are classified by the `ClassifyResponse` request extension, so
+//! only classes that are failures count against the endpoint.
+
+use futures::{Async, Future, Poll};
+use h2;
+use http;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{error, fmt};
+use tokio_timer::clock;
+use tower_h2::Body;
+
+use super::metrics::classify::{ClassifyEos, ClassifyResponse};
+use svc;
+
+/// Implemented by response classes that may indicate a failed response.
+pub trait IsFailure {
+    fn is_failure(&self) -> bool;
+}
+
+/// Determines when a circuit is opened and how long it stays open.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Config {
+    window: usize,
+    failure_ratio: f64,
+    cooldown: Duration,
+}
+
+#[derive(Debug)]
+pub struct Layer<C> {
+    /// When `None`, circuits are never opened.
+    config: Option<Config>,
+    _p: PhantomData<fn() -> C>,
+}
+
+#[derive(Debug)]
+pub struct Stack<M, C> {
+    config: Option<Config>,
+    inner: M,
+    _p: PhantomData<fn() -> C>,
+}
+
+#[derive(Debug)]
+pub struct Service<S, C> {
+    breaker: Option<Breaker>,
+    inner: S,
+    _p: PhantomData<fn() -> C>,
+}
+
+pub struct ResponseFuture<F, C> {
+    /// When `None`, the circuit was open.
+    inner: Option<F>,
+    classify: Option<C>,
+    outcome: Option<Outcome>,
+}
+
+pub struct ResponseBody<B, C>
+where
+    C: ClassifyEos<Error = h2::Error>,
+    C::Class: IsFailure,
+{
+    inner: B,
+    classify: Option<C>,
+    outcome: Option<Outcome>,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The inner service failed.
+    Service(E),
+
+    /// The circuit is open, so the request was not sent.
+    Open,
+}
+
+/// A circuit's state, shared by all clones of a service.
+#[derive(Clone, Debug)]
+struct Breaker {
+    config: Config,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed {
+        /// Whether each of the most recent responses failed.
+        recent: VecDeque<bool>,
+        failures: usize,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe request has been sent and has not yet been classified.
+    HalfOpen,
+}
+
+/// Records a response's classification on its circuit.
+///
+/// If a probe's outcome is dropped before the response is classified, the
+/// probe is treated as having failed so that another may be sent.
+struct Outcome {
+    breaker: Breaker,
+    is_probe: bool,
+}
+
+// === impl Config ===
+
+impl Config {
+    /// Opens a circuit when at least `failure_ratio` of the last `window`
+    /// responses failed, for `cooldown`.
+    pub fn new(window: usize, failure_ratio: f64, cooldown: Duration) -> Self {
+        Self {
+            window: window.max(1),
+            failure_ratio,
+            cooldown,
+        }
+    }
+}
+
+// === impl Layer ===
+
+pub fn layer<C>(config: Option<Config>) -> Layer<C>
+where
+    C: ClassifyResponse<Error = h2::Error> + Clone + Default + Send + Sync + 'static,
+    C::Class: IsFailure,
+{
+    Layer {
+        config,
+        _p: PhantomData,
+    }
+}
+
+impl<C> Clone for Layer<C> {
+    fn clone(&self) -> Self {
+        Layer {
+            config: self.config,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<T, M, C> svc::Layer<T, T, M> for Layer<C>
+where
+    M: svc::Stack<T>,
+    C: ClassifyResponse<Error = h2::Error> + Clone + Default + Send + Sync + 'static,
+    C::Class: IsFailure,
+{
+    type Value = <Stack<M, C> as svc::Stack<T>>::Value;
+    type Error = <Stack<M, C> as svc::Stack<T>>::Error;
+    type Stack = Stack<M, C>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            config: self.config,
+            inner,
+            _p: PhantomData,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<M: Clone, C> Clone for Stack<M, C> {
+    fn clone(&self) -> Self {
+        Stack {
+            config: self.config,
+            inner: self.inner.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<T, M, C> svc::Stack<T> for Stack<M, C>
+where
+    M: svc::Stack<T>,
+    C: ClassifyResponse<Error = h2::Error> + Clone + Default + Send + Sync + 'static,
+    C::Class: IsFailure,
+{
+    type Value = Service<M::Value, C>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service {
+            breaker: self.config.map(Breaker::new),
+            inner,
+            _p: PhantomData,
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S: Clone, C> Clone for Service<S, C> {
+    fn clone(&self) -> Self {
+        Service {
+            breaker: self.breaker.clone(),
+            inner: self.inner.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<S, C, A, B> svc::Service<http::Request<A>> for Service<S, C>
+where
+    S: svc::Service<http::Request<A>, Response = http::Response<B>>,
+    C: ClassifyResponse<Error = h2::Error> + Clone + Default + Send + Sync + 'static,
+    C::Class: IsFailure,
+{
+    type Response = http::Response<ResponseBody<B, C::ClassifyEos>>;
+    type Error = Error<S::Error>;
+    type Future = ResponseFuture<S::Future, C>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready().map_err(Error::Service)
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let outcome = match self.breaker {
+            None => None,
+            Some(ref breaker) => match breaker.permit() {
+                Some(outcome) => Some(outcome),
+                None => {
+                    return ResponseFuture {
+                        inner: None,
+                        classify: None,
+                        outcome: None,
+                    };
+                }
+            },
+        };
+
+        let classify = req.extensions().get::<C>().cloned().unwrap_or_default();
+        ResponseFuture {
+            inner: Some(self.inner.call(req)),
+            classify: Some(classify),
+            outcome,
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, C, B> Future for ResponseFuture<F, C>
+where
+    F: Future<Item = http::Response<B>>,
+    C: ClassifyResponse<Error = h2::Error>,
+    C::Class: IsFailure,
+{
+    type Item = http::Response<ResponseBody<B, C::ClassifyEos>>;
+    type Error = Error<F::Error>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let poll = match self.inner {
+            None => return Err(Error::Open),
+            Some(ref mut f) => f.poll(),
+        };
+        let rsp = match poll {
+            Ok(Async::Ready(rsp)) => rsp,
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => {
+                if let Some(outcome) = self.outcome.take() {
+                    outcome.record(true);
+                }
+                return Err(Error::Service(e));
+            }
+        };
+
+        let classify = self.classify.take().map(|c| c.start(&rsp));
+        let outcome = self.outcome.take();
+        Ok(rsp
+            .map(|inner| ResponseBody {
+                inner,
+                classify,
+                outcome,
+            })
+            .into())
+    }
+}
+
+// === impl ResponseBody ===
+
+impl<B, C> ResponseBody<B, C>
+where
+    C: ClassifyEos<Error = h2::Error>,
+    C::Class: IsFailure,
+{
+    fn record(&mut self, class: C::Class) {
+        if let Some(outcome) = self.outcome.take() {
+            outcome.record(class.is_failure());
+        }
+    }
+
+    fn measure_err(&mut self, err: h2::Error) -> h2::Error {
+        if let Some(class) = self.classify.take().map(|c| c.error(&err)) {
+            self.record(class);
+        }
+        err
+    }
+}
+
+impl<B, C> Default for ResponseBody<B, C>
+where
+    B: Default,
+    C: ClassifyEos<Error = h2::Error>,
+    C::Class: IsFailure,
+{
+    fn default() -> Self {
+        ResponseBody {
+            inner: B::default(),
+            classify: None,
+            outcome: None,
+        }
+    }
+}
+
+impl<B, C> Body for ResponseBody<B, C>
+where
+    B: Body,
+    C: ClassifyEos<Error = h2::Error>,
+    C::Class: IsFailure,
+{
+    type Data = B::Data;
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, h2::Error> {
+        self.inner.poll_data().map_err(|e| self.measure_err(e))
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+        let trls = try_ready!(self.inner.poll_trailers().map_err(|e| self.measure_err(e)));
+
+        if let Some(class) = self.classify.take().map(|c| c.eos(trls.as_ref())) {
+            self.record(class);
+        }
+
+        Ok(trls.into())
+    }
+}
+
+impl<B, C> Drop for ResponseBody<B, C>
+where
+    C: ClassifyEos<Error = h2::Error>,
+    C::Class: IsFailure,
+{
+    fn drop(&mut self) {
+        if let Some(class) = self.classify.take().map(|c| c.eos(None)) {
+            self.record(class);
+        }
+    }
+}
+
+// === impl Breaker ===
+
+impl Breaker {
+    fn new(config: Config) -> Self {
+        let state = State::Closed {
+            recent: VecDeque::with_capacity(config.window),
+            failures: 0,
+        };
+        Self {
+            config,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Returns an `Outcome` if a request may be sent, or `None` if the circuit
+    /// is open.
+    fn permit(&self) -> Option<Outcome> {
+        let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+        let is_probe = match *state {
+            State::Closed { .. } => false,
+            State::HalfOpen => return None,
+            State::Open { until } if clock::now() < until => return None,
+            State::Open { .. } => {
+                debug!("circuit half-open; probing");
+                true
+            }
+        };
+        if is_probe {
+            *state = State::HalfOpen;
+        }
+
+        Some(Outcome {
+            breaker: self.clone(),
+            is_probe,
+        })
+    }
+
+    fn open(&self, state: &mut State) {
+        debug!("circuit opened for {:?}", self.config.cooldown);
+        *state = State::Open {
+            until: clock::now() + self.config.cooldown,
+        };
+    }
+}
+
+// === impl Outcome ===
+
+impl Outcome {
+    fn record(mut self, failed: bool) {
+        self.record_mut(failed);
+    }
+
+    fn record_mut(&mut self, failed: bool) {
+        let breaker = self.breaker.clone();
+        let mut state = match breaker.state.lock() {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+
+        if self.is_probe {
+            // Only the first record is effective.
+            self.is_probe = false;
+            if failed {
+                breaker.open(&mut *state);
+            } else {
+                debug!("circuit closed");
+                *state = State::Closed {
+                    recent: VecDeque::with_capacity(breaker.config.window),
+                    failures: 0,
+                };
+            }
+            return;
+        }
+
+        let should_open = match *state {
+            // Responses to requests sent before the circuit opened are
+            // ignored.
+            State::Open { .. } | State::HalfOpen => return,
+            State::Closed {
+                ref mut recent,
+                ref mut failures,
+            } => {
+                recent.push_back(failed);
+                if failed {
+                    *failures += 1;
+                }
+                if recent.len() > breaker.config.window {
+                    if recent.pop_front() == Some(true) {
+                        *failures -= 1;
+                    }
+                }
+
+                recent.len() == breaker.config.window
+                    && *failures as f64 >= breaker.config.failure_ratio * recent.len() as f64
+            }
+        };
+
+        if should_open {
+            breaker.open(&mut *state);
+        }
+    }
+}
+
+impl Drop for Outcome {
+    fn drop(&mut self) {
+        if self.is_probe {
+            self.record_mut(true);
+        }
+    }
+}
+
+// === impl Error ===
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Service(e) => fmt::Display::fmt(e, f),
+            Error::Open => f.pad("circuit breaker is open"),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for Error<E> {
+    fn cause(&self) -> Option<&error::Error> {
+        match self {
+            Error::Service(e) => e.cause(),
+            Error::Open => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+    use std::thread;
+
+    use super::*;
+    use app::classify::{self, Class};
+    use never::Never;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    #[derive(Clone, Debug)]
+    struct MakeSvc(Svc);
+
+    /// Responds with the current status and counts the requests it receives.
+    #[derive(Clone, Debug, Default)]
+    struct Svc {
+        status: Arc<Mutex<http::StatusCode>>,
+        requests: Arc<Mutex<usize>>,
+    }
+
+    #[derive(Debug, Default)]
+    struct EmptyBody;
+
+    impl svc::Stack<()> for MakeSvc {
+        type Value = Svc;
+        type Error = Never;
+
+        fn make(&self, _: &()) -> Result<Svc, Never> {
+            Ok(self.0.clone())
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Svc {
+        type Response = http::Response<EmptyBody>;
+        type Error = Never;
+        type Future = future::FutureResult<Self::Response, Never>;
+
+        fn poll_ready(&mut self) -> Poll<(), Never> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            *self.requests.lock().unwrap() += 1;
+            let rsp = http::Response::builder()
+                .status(*self.status.lock().unwrap())
+                .body(EmptyBody)
+                .unwrap();
+            future::ok(rsp)
+        }
+    }
+
+    impl Body for EmptyBody {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            true
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+    }
+
+    impl Svc {
+        fn respond_with(&self, status: http::StatusCode) {
+            *self.status.lock().unwrap() = status;
+        }
+
+        fn requests(&self) -> usize {
+            *self.requests.lock().unwrap()
+        }
+    }
+
+    /// Sends a request, reading its response to completion. Returns `None` if
+    /// the circuit was open.
+    fn send<S>(svc: &mut S) -> Option<http::StatusCode>
+    where
+        S: svc::Service<
+            http::Request<()>,
+            Response = http::Response<ResponseBody<EmptyBody, classify::Eos>>,
+            Error = Error<Never>,
+        >,
+    {
+        match svc.call(http::Request::new(())).wait() {
+            Ok(rsp) => {
+                let status = rsp.status();
+                let mut body = rsp.into_body();
+                assert!(body.poll_trailers().expect("trailers").is_ready());
+                Some(status)
+            }
+            Err(Error::Open) => None,
+            Err(Error::Service(e)) => match e {},
+        }
+    }
+
+    #[test]
+    fn circuit_opens_on_failures_and_closes_after_a_successful_probe() {
+        let cooldown = Duration::from_millis(50);
+        let config = Config::new(4, 0.5, cooldown);
+        let backend = Svc::default();
+        let stack = layer::<classify::Response>(Some(config)).bind(MakeSvc(backend.clone()));
+        let mut svc = stack.make(&()).unwrap();
+
+        // Failures only open the circuit once the window is full.
+        backend.respond_with(http::StatusCode::OK);
+        assert_eq!(send(&mut svc), Some(http::StatusCode::OK));
+        assert_eq!(send(&mut svc), Some(http::StatusCode::OK));
+        backend.respond_with(http::StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(send(&mut svc).is_some());
+        assert!(send(&mut svc).is_some());
+        assert_eq!(backend.requests(), 4);
+
+        // While the circuit is open, requests fail without being sent.
+        assert_eq!(send(&mut svc), None);
+        assert_eq!(send(&mut svc.clone()), None);
+        assert_eq!(backend.requests(), 4);
+
+        // After the cooldown, a failed probe reopens the circuit.
+        thread::sleep(cooldown + Duration::from_millis(10));
+        assert!(send(&mut svc).is_some());
+        assert_eq!(send(&mut svc), None);
+        assert_eq!(backend.requests(), 5);
+
+        // A successful probe closes it.
+        thread::sleep(cooldown + Duration::from_millis(10));
+        backend.respond_with(http::StatusCode::OK);
+        assert_eq!(send(&mut svc), Some(http::StatusCode::OK));
+        assert_eq!(send(&mut svc), Some(http::StatusCode::OK));
+        assert_eq!(backend.requests(), 7);
+    }
+
+    #[test]
+    fn only_failure_classes_are_counted() {
+        let config = Config::new(2, 0.5, Duration::from_secs(60));
+        let backend = Svc::default();
+        let stack = layer::<classify::Response>(Some(config)).bind(MakeSvc(backend.clone()));
+        let mut svc = stack.make(&()).unwrap();
+
+        // Client errors are not failures.
+        backend.respond_with(http::StatusCode::NOT_FOUND);
+        for _ in 0..4 {
+            assert_eq!(send(&mut svc), Some(http::StatusCode::NOT_FOUND));
+        }
+
+        assert!(Class::Default(classify::SuccessOrFailure::Failure).is_failure());
+    }
+
+    #[test]
+    fn probes_are_not_sent_concurrently() {
+        let cooldown = Duration::from_millis(10);
+        let config = Config::new(1, 1.0, cooldown);
+        let backend = Svc::default();
+        let stack = layer::<classify::Response>(Some(config)).bind(MakeSvc(backend.clone()));
+        let mut svc = stack.make(&()).unwrap();
+
+        backend.respond_with(http::StatusCode::BAD_GATEWAY);
+        assert!(send(&mut svc).is_some());
+        thread::sleep(cooldown + Duration::from_millis(10));
+
+        // The probe's response has not yet completed, so other requests fail.
+        let probe = svc.call(http::Request::new(())).wait().expect("probe");
+        assert_eq!(send(&mut svc), None);
+
+        // Once the probe's response ends, it is classified as a failure and
+        // the circuit is reopened.
+        drop(probe);
+        assert_eq!(send(&mut svc), None);
+        assert_eq!(backend.requests(), 2);
+    }
+}
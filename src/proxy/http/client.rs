@@ -5,6 +5,7 @@ use http;
 use hyper;
 use std::{error, fmt, net};
 use std::marker::PhantomData;
+use std::time::Duration;
 use tokio::executor::Executor;
 use tower_h2;
 
@@ -26,12 +27,36 @@ pub struct Config {
     _p: (),
 }
 
+/// Configures how HTTP/1 clients retain idle connections for reuse.
+///
+/// Concurrent requests to an endpoint are dispatched on separate
+/// connections. Once a connection is idle, it may be reused by later requests
+/// until it has been idle for `idle_timeout`; connections in excess of
+/// `max_idle` are closed as soon as they are idle.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Http1Pool {
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+/// Configures the initial flow control windows of HTTP/2 clients.
+///
+/// Windows that are not set use the protocol's default (64KB). Larger windows
+/// allow more data in flight on links with a high bandwidth-delay product.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Http2Windows {
+    stream: Option<u32>,
+    connection: Option<u32>,
+}
+
 /// Configurs an HTTP client that uses a `C`-typed connector
 ///
 /// The `proxy_name` is used for diagnostics (logging, mostly).
 #[derive(Debug)]
 pub struct Layer<B> {
     proxy_name: &'static str,
+    http1_pool: Http1Pool,
+    http2_windows: Http2Windows,
     _p: PhantomData<fn() -> B>,
 }
 
@@ -47,6 +72,8 @@ where
 {
     connect: C,
     proxy_name: &'static str,
+    http1_pool: Http1Pool,
+    http2_windows: Http2Windows,
     _p: PhantomData<fn() -> B>,
 }
 
@@ -157,6 +184,10 @@ impl ShouldNormalizeUri for Config {
     fn should_normalize_uri(&self) -> bool {
         !self.settings.is_http2() && !self.settings.was_absolute_form()
     }
+
+    fn should_rewrite_host(&self) -> bool {
+        self.settings.rewrite_host()
+    }
 }
 
 impl ShouldStackPerRequest for Config {
@@ -172,19 +203,68 @@ impl fmt::Display for Config {
 }
 
 
+// === impl Http1Pool ===
+
+impl Http1Pool {
+    /// Retains up to `max_idle` idle connections to each endpoint, each for up
+    /// to `idle_timeout`.
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        Self {
+            max_idle,
+            idle_timeout,
+        }
+    }
+}
+
+// === impl Http2Windows ===
+
+impl Http2Windows {
+    /// Sets the initial window size, in bytes, of each stream and of each
+    /// connection, if not the protocol default.
+    pub fn new(stream: Option<u32>, connection: Option<u32>) -> Self {
+        Self { stream, connection }
+    }
+
+    fn builder(&self) -> h2::client::Builder {
+        let mut h2 = h2::client::Builder::default();
+        // h2 currently doesn't handle PUSH_PROMISE that well, so we just
+        // disable it for now.
+        h2.enable_push(false);
+        if let Some(sz) = self.stream {
+            h2.initial_window_size(sz);
+        }
+        if let Some(sz) = self.connection {
+            h2.initial_connection_window_size(sz);
+        }
+        h2
+    }
+}
+
 // === impl Layer ===
 
-pub fn layer<B>(proxy_name: &'static str) -> Layer<B>
+pub fn layer<B>(proxy_name: &'static str, http1_pool: Http1Pool) -> Layer<B>
 where
     B: tower_h2::Body + Send + 'static,
     <B::Data as IntoBuf>::Buf: Send + 'static,
 {
     Layer {
         proxy_name,
+        http1_pool,
+        http2_windows: Http2Windows::default(),
         _p: PhantomData,
     }
 }
 
+impl<B> Layer<B> {
+    /// Sets the initial flow control windows of HTTP/2 clients.
+    pub fn with_http2_windows(self, http2_windows: Http2Windows) -> Self {
+        Self {
+            http2_windows,
+            ..self
+        }
+    }
+}
+
 impl<B> Clone for Layer<B>
 where
     B: tower_h2::Body + 'static,
@@ -193,6 +273,8 @@ where
     fn clone(&self) -> Self {
         Self {
             proxy_name: self.proxy_name,
+            http1_pool: self.http1_pool,
+            http2_windows: self.http2_windows,
             _p: PhantomData,
         }
     }
@@ -216,6 +298,8 @@ where
         Stack {
             connect,
             proxy_name: self.proxy_name,
+            http1_pool: self.http1_pool,
+            http2_windows: self.http2_windows,
             _p: PhantomData,
          }
     }
@@ -234,6 +318,8 @@ where
         Self {
             proxy_name: self.proxy_name,
             connect: self.connect.clone(),
+            http1_pool: self.http1_pool,
+            http2_windows: self.http2_windows,
             _p: PhantomData,
         }
     }
@@ -258,7 +344,13 @@ where
         let executor = ::logging::Client::proxy(self.proxy_name, config.target.addr)
             .with_settings(config.settings.clone())
             .executor();
-        Ok(Client::new(&config.settings, connect, executor))
+        Ok(Client::new(
+            &config.settings,
+            self.http1_pool,
+            self.http2_windows,
+            connect,
+            executor,
+        ))
     }
 }
 
@@ -276,7 +368,13 @@ where
     <B::Data as IntoBuf>::Buf: Send + 'static,
 {
     /// Create a new `Client`, bound to a specific protocol (HTTP/1 or HTTP/2).
-    pub fn new(settings: &Settings, connect: C, executor: E) -> Self {
+    pub fn new(
+        settings: &Settings,
+        http1_pool: Http1Pool,
+        http2_windows: Http2Windows,
+        connect: C,
+        executor: E,
+    ) -> Self {
         match settings {
             Settings::Http1 { was_absolute_form, .. } => {
                 let h1 = hyper::Client::builder()
@@ -284,16 +382,18 @@ where
                     // hyper should never try to automatically set the Host
                     // header, instead always just passing whatever we received.
                     .set_host(false)
+                    // Connections are only kept alive if the request's
+                    // version and `Connection` header allow it.
+                    .keep_alive(settings.keep_alive())
+                    .keep_alive_timeout(http1_pool.idle_timeout)
+                    .max_idle_per_host(http1_pool.max_idle)
                     .build(HyperConnect::new(connect, *was_absolute_form));
                 Client {
                     inner: ClientInner::Http1(h1),
                 }
             },
             Settings::Http2 => {
-                let mut h2_builder = h2::client::Builder::default();
-                // h2 currently doesn't handle PUSH_PROMISE that well, so we just
-                // disable it for now.
-                h2_builder.enable_push(false);
+                let h2_builder = http2_windows.builder();
                 let h2 = tower_h2::client::Connect::new(connect, h2_builder, BoxExecutor::new(executor));
 
                 Client {
@@ -451,7 +551,7 @@ impl Future for ClientServiceFuture {
                 if h1::is_upgrade(&res) {
                     trace!("client response is HTTP/1.1 upgrade");
                 } else {
-                    h1::strip_connection_headers(res.headers_mut());
+                    h1::strip_response_connection_headers(&mut res);
                 }
                 Ok(Async::Ready(res))
             },
@@ -504,3 +604,104 @@ impl super::HasH2Reason for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::io::{self, Read};
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio::net::TcpStream;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_timer::{clock, Delay};
+
+    use super::*;
+
+    const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+    const DEFAULT_WINDOW: u32 = 65_535;
+
+    /// A frame's type, stream ID, and payload.
+    type Frame = (u8, u32, Vec<u8>);
+
+    /// Returns the frames that a client built with `windows` writes when it
+    /// establishes a connection.
+    fn handshake_frames(windows: Http2Windows) -> Vec<Frame> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut io, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            io.read_to_end(&mut buf).unwrap();
+            buf
+        });
+
+        let mut rt = Runtime::new().unwrap();
+        let (client, conn) = rt
+            .block_on(TcpStream::connect(&addr).and_then(move |io| {
+                windows
+                    .builder()
+                    .handshake::<_, Bytes>(io)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            }))
+            .unwrap();
+        rt.spawn(conn.map_err(|_| ()));
+        // Give the connection a chance to write its frames.
+        rt.block_on(Delay::new(clock::now() + Duration::from_millis(100)))
+            .unwrap();
+        drop(client);
+        drop(rt);
+
+        let buf = server.join().unwrap();
+        assert!(buf.starts_with(PREFACE), "client must write the preface");
+        parse_frames(&buf[PREFACE.len()..])
+    }
+
+    fn parse_frames(mut buf: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while buf.len() >= 9 {
+            let len = (buf[0] as usize) << 16 | (buf[1] as usize) << 8 | buf[2] as usize;
+            let kind = buf[3];
+            let stream = u32_at(&buf[5..9]) & 0x7fff_ffff;
+            frames.push((kind, stream, buf[9..9 + len].to_vec()));
+            buf = &buf[9 + len..];
+        }
+        frames
+    }
+
+    fn u32_at(b: &[u8]) -> u32 {
+        (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+    }
+
+    /// Returns the value of the SETTINGS_INITIAL_WINDOW_SIZE setting, if any.
+    fn initial_window_setting(frames: &[Frame]) -> Option<u32> {
+        let &(_, _, ref payload) = frames.iter().find(|f| f.0 == 0x4)?;
+        payload
+            .chunks(6)
+            .find(|s| s[0] == 0 && s[1] == 0x4)
+            .map(|s| u32_at(&s[2..6]))
+    }
+
+    /// Returns the connection's window size, as advertised by WINDOW_UPDATEs
+    /// on stream 0.
+    fn connection_window(frames: &[Frame]) -> u32 {
+        frames
+            .iter()
+            .filter(|f| f.0 == 0x8 && f.1 == 0)
+            .fold(DEFAULT_WINDOW, |w, f| w + (u32_at(&f.2) & 0x7fff_ffff))
+    }
+
+    #[test]
+    fn http2_windows_are_defaults_when_unset() {
+        let frames = handshake_frames(Http2Windows::default());
+        assert_eq!(initial_window_setting(&frames), None);
+        assert_eq!(connection_window(&frames), DEFAULT_WINDOW);
+    }
+
+    #[test]
+    fn http2_windows_are_advertised() {
+        let windows = Http2Windows::new(Some(1_048_576), Some(4_194_304));
+        let frames = handshake_frames(windows);
+        assert_eq!(initial_window_setting(&frames), Some(1_048_576));
+        assert_eq!(connection_window(&frames), 4_194_304);
+    }
+}
@@ -0,0 +1,495 @@
+use bytes::{Buf, Bytes, IntoBuf};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{Async, Future, Poll};
+use h2;
+use http;
+use http::header::{self, HeaderValue};
+use std::io::{self, Cursor, Write};
+use std::mem;
+use tower_h2::Body;
+
+use metrics::accepts_gzip;
+use svc;
+
+/// Media types, other than `text/*`, whose bodies compress well.
+const COMPRESSIBLE_TYPES: [&str; 3] = [
+    "application/javascript",
+    "application/json",
+    "application/xml",
+];
+
+/// Compresses large, uncompressed text responses for clients that accept
+/// gzip.
+///
+/// A response is compressed when its `Content-Length` is at least
+/// `min_length` bytes. If `min_length` is `None`, responses are never
+/// compressed.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    min_length: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    min_length: Option<u64>,
+    inner: M,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    min_length: Option<u64>,
+    inner: S,
+}
+
+pub struct ResponseFuture<F> {
+    /// Set only if the request accepts gzip-encoded responses.
+    min_length: Option<u64>,
+    inner: F,
+}
+
+/// A response body that is compressed as it is streamed, or passed through
+/// unmodified.
+pub enum ResponseBody<B> {
+    Passthru(B),
+    Gzip {
+        inner: B,
+        /// Taken once the inner body completes.
+        encoder: Option<GzEncoder<Vec<u8>>>,
+    },
+}
+
+/// A chunk of a `ResponseBody`, either as read from the inner body or as
+/// compressed.
+#[derive(Debug)]
+pub enum Data<D> {
+    Passthru(D),
+    Gzip(Cursor<Bytes>),
+}
+
+// === impl Layer ===
+
+pub fn layer(min_length: Option<u64>) -> Layer {
+    Layer { min_length }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            min_length: self.min_length,
+            inner,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service {
+            min_length: self.min_length,
+            inner,
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, A, B> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<A>, Response = http::Response<B>>,
+    B: Body,
+{
+    type Response = http::Response<ResponseBody<B>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let min_length = self.min_length.filter(|_| {
+            req.method() != http::Method::HEAD && accepts_gzip(req.headers())
+        });
+        let inner = self.inner.call(req);
+        ResponseFuture { min_length, inner }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: Future<Item = http::Response<B>>,
+    B: Body,
+{
+    type Item = http::Response<ResponseBody<B>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rsp = try_ready!(self.inner.poll());
+
+        let compress = self.min_length
+            .map(|min| should_compress(&rsp, min))
+            .unwrap_or(false);
+        if !compress {
+            return Ok(Async::Ready(rsp.map(ResponseBody::Passthru)));
+        }
+
+        let (mut parts, inner) = rsp.into_parts();
+        // The length of the compressed body isn't known until it's been
+        // compressed.
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        parts.headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+        let body = ResponseBody::Gzip {
+            inner,
+            encoder: Some(GzEncoder::new(Vec::new(), Compression::default())),
+        };
+        Ok(Async::Ready(http::Response::from_parts(parts, body)))
+    }
+}
+
+// === impl ResponseBody ===
+
+impl<B: Default> Default for ResponseBody<B> {
+    fn default() -> Self {
+        ResponseBody::Passthru(B::default())
+    }
+}
+
+impl<B: Body> Body for ResponseBody<B> {
+    type Data = Data<<B::Data as IntoBuf>::Buf>;
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            ResponseBody::Passthru(inner) => inner.is_end_stream(),
+            ResponseBody::Gzip { encoder, .. } => encoder.is_none(),
+        }
+    }
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, h2::Error> {
+        let (inner, encoder) = match self {
+            ResponseBody::Passthru(inner) => {
+                let data = try_ready!(inner.poll_data());
+                return Ok(Async::Ready(data.map(|d| Data::Passthru(d.into_buf()))));
+            }
+            ResponseBody::Gzip { inner, encoder } => (inner, encoder),
+        };
+
+        loop {
+            let chunk = match encoder {
+                None => return Ok(Async::Ready(None)),
+                Some(_) => try_ready!(inner.poll_data()),
+            };
+
+            let compressed = match chunk {
+                Some(chunk) => {
+                    let encoder = encoder.as_mut().expect("encoder must be set");
+                    let mut chunk = chunk.into_buf();
+                    while chunk.has_remaining() {
+                        let n = {
+                            let bytes = chunk.bytes();
+                            encoder.write_all(bytes).map_err(compress_error)?;
+                            bytes.len()
+                        };
+                        chunk.advance(n);
+                    }
+                    mem::replace(encoder.get_mut(), Vec::new())
+                }
+                None => {
+                    let encoder = encoder.take().expect("encoder must be set");
+                    encoder.finish().map_err(compress_error)?
+                }
+            };
+
+            // The encoder buffers its output, so a chunk of input may not
+            // produce any compressed data yet.
+            if !compressed.is_empty() {
+                let data = Data::Gzip(Cursor::new(compressed.into()));
+                return Ok(Async::Ready(Some(data)));
+            }
+        }
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+        match self {
+            ResponseBody::Passthru(inner) => inner.poll_trailers(),
+            ResponseBody::Gzip { inner, .. } => inner.poll_trailers(),
+        }
+    }
+}
+
+// === impl Data ===
+
+impl<D: Buf> Buf for Data<D> {
+    fn remaining(&self) -> usize {
+        match self {
+            Data::Passthru(d) => d.remaining(),
+            Data::Gzip(d) => d.remaining(),
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Data::Passthru(d) => d.bytes(),
+            Data::Gzip(d) => d.bytes(),
+        }
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        match self {
+            Data::Passthru(d) => d.advance(cnt),
+            Data::Gzip(d) => d.advance(cnt),
+        }
+    }
+}
+
+fn compress_error(e: io::Error) -> h2::Error {
+    debug!("failed to compress response body: {}", e);
+    h2::Reason::INTERNAL_ERROR.into()
+}
+
+fn should_compress<B>(rsp: &http::Response<B>, min_length: u64) -> bool {
+    // Don't compress a body that's already encoded.
+    if rsp.headers().contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+
+    // Don't compress a partial body, since its range refers to the
+    // uncompressed representation.
+    if rsp.headers().contains_key(header::CONTENT_RANGE) {
+        return false;
+    }
+
+    let status = rsp.status();
+    if status.is_informational()
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::PARTIAL_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED
+    {
+        return false;
+    }
+
+    let is_large = rsp
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|len| len >= min_length)
+        .unwrap_or(false);
+
+    is_large && rsp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(is_compressible)
+        .unwrap_or(false)
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || COMPRESSIBLE_TYPES.contains(&media_type.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::GzDecoder;
+    use futures::future;
+    use std::collections::VecDeque;
+    use std::io::Read;
+
+    use super::*;
+    use never::Never;
+    use svc::Service as _Service;
+
+    const MIN_LENGTH: u64 = 1024;
+
+    /// Responds with a fixed set of headers and body chunks.
+    struct Svc {
+        headers: http::HeaderMap,
+        chunks: Vec<&'static str>,
+    }
+
+    #[derive(Default)]
+    struct Chunks(VecDeque<Bytes>);
+
+    impl svc::Service<http::Request<()>> for Svc {
+        type Response = http::Response<Chunks>;
+        type Error = Never;
+        type Future = future::FutureResult<Self::Response, Never>;
+
+        fn poll_ready(&mut self) -> Poll<(), Never> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            let chunks = self.chunks.iter().map(|c| Bytes::from(*c)).collect();
+            let mut rsp = http::Response::new(Chunks(chunks));
+            *rsp.headers_mut() = self.headers.clone();
+            future::ok(rsp)
+        }
+    }
+
+    impl Body for Chunks {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            Ok(Async::Ready(self.0.pop_front()))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+    }
+
+    fn text(content_type: &'static str, chunks: Vec<&'static str>) -> Svc {
+        let len = chunks.iter().map(|c| c.len()).sum::<usize>();
+        let mut headers = http::HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+        Svc { headers, chunks }
+    }
+
+    fn call(svc: Svc, accept_encoding: Option<&'static str>) -> http::Response<ResponseBody<Chunks>> {
+        let mut service = Service {
+            min_length: Some(MIN_LENGTH),
+            inner: svc,
+        };
+        let mut req = http::Request::new(());
+        if let Some(ae) = accept_encoding {
+            req.headers_mut().insert(header::ACCEPT_ENCODING, HeaderValue::from_static(ae));
+        }
+        service.call(req).wait().expect("response")
+    }
+
+    fn read_body(body: &mut ResponseBody<Chunks>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        while let Async::Ready(Some(chunk)) = body.poll_data().expect("data") {
+            buf.extend_from_slice(&chunk.collect::<Vec<u8>>());
+        }
+        assert!(body.is_end_stream());
+        buf
+    }
+
+    fn large_text() -> Vec<&'static str> {
+        vec!["hello, world! "; 200]
+    }
+
+    #[test]
+    fn large_text_response_is_gzipped_for_accepting_client() {
+        let chunks = large_text();
+        let expected = chunks.concat();
+
+        let svc = text("text/plain; charset=utf-8", chunks);
+        let rsp = call(svc, Some("deflate, gzip;q=0.8"));
+
+        assert_eq!(rsp.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(rsp.headers()[header::VARY], "accept-encoding");
+        assert!(!rsp.headers().contains_key(header::CONTENT_LENGTH));
+
+        let compressed = read_body(&mut rsp.into_body());
+        assert!(compressed.len() < expected.len());
+
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .expect("decompress");
+        assert_eq!(decompressed, expected);
+    }
+
+    #[test]
+    fn response_is_left_alone_unless_client_accepts_gzip() {
+        for &ae in &[None, Some("deflate"), Some("gzip;q=0")] {
+            let chunks = large_text();
+            let expected = chunks.concat();
+
+            let rsp = call(text("text/plain", chunks), ae);
+
+            assert!(!rsp.headers().contains_key(header::CONTENT_ENCODING), "{:?}", ae);
+            assert_eq!(
+                rsp.headers()[header::CONTENT_LENGTH],
+                HeaderValue::from(expected.len()),
+            );
+            assert_eq!(read_body(&mut rsp.into_body()), expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn response_is_left_alone_unless_compressible() {
+        let already_encoded = {
+            let mut svc = text("text/html", large_text());
+            svc.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("br"));
+            svc
+        };
+        let partial = {
+            let mut svc = text("text/html", large_text());
+            svc.headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_static("bytes 0-2799/5000"),
+            );
+            svc
+        };
+        let small = text("text/html", vec!["hello, world!"]);
+        let binary = text("image/png", large_text());
+
+        for svc in vec![already_encoded, partial, small, binary] {
+            let expected_encoding = svc.headers.get(header::CONTENT_ENCODING).cloned();
+            let expected = svc.chunks.concat();
+
+            let rsp = call(svc, Some("gzip"));
+
+            assert_eq!(rsp.headers().get(header::CONTENT_ENCODING).cloned(), expected_encoding);
+            assert_eq!(read_body(&mut rsp.into_body()), expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn partial_content_is_not_compressed() {
+        let rsp = http::Response::builder()
+            .status(http::StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(header::CONTENT_LENGTH, HeaderValue::from(MIN_LENGTH))
+            .body(())
+            .unwrap();
+        assert!(!should_compress(&rsp, MIN_LENGTH));
+    }
+
+    #[test]
+    fn compressible_types() {
+        assert!(is_compressible("text/html"));
+        assert!(is_compressible("Application/JSON; charset=utf-8"));
+        assert!(is_compressible("application/vnd.api+json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/octet-stream"));
+    }
+}
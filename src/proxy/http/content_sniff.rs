@@ -0,0 +1,543 @@
+use futures::{Async, Future, Poll};
+use h2;
+use http;
+use http::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use indexmap::IndexSet;
+use std::sync::Arc;
+use std::{error, fmt, mem};
+use tower_h2::Body;
+
+use proxy::server::Source;
+use svc;
+
+/// The maximum number of leading body bytes that are sniffed.
+const SNIFF_WINDOW: usize = 512;
+
+/// Known file signatures and the media types that may declare them.
+const SIGNATURES: &[(&[u8], &[&str])] = &[
+    (
+        b"MZ",
+        &["application/x-msdownload", "application/vnd.microsoft.portable-executable"],
+    ),
+    (b"\x7fELF", &["application/x-elf", "application/x-executable"]),
+    (b"\xfe\xed\xfa\xce", &["application/x-mach-binary"]),
+    (b"\xfe\xed\xfa\xcf", &["application/x-mach-binary"]),
+    (b"\xcf\xfa\xed\xfe", &["application/x-mach-binary"]),
+    (b"\xca\xfe\xba\xbe", &["application/java-vm", "application/x-mach-binary"]),
+    (b"PK\x03\x04", &["application/zip", "application/java-archive"]),
+    (b"\x1f\x8b", &["application/gzip", "application/x-gzip"]),
+    (b"%PDF-", &["application/pdf"]),
+    (b"\x89PNG\r\n\x1a\n", &["image/png"]),
+    (b"\xff\xd8\xff", &["image/jpeg"]),
+    (b"GIF87a", &["image/gif"]),
+    (b"GIF89a", &["image/gif"]),
+];
+
+/// Signatures that may also begin ordinary text (e.g. a name starting with
+/// "MZ"), so they are only sniffed for non-textual types.
+const AMBIGUOUS_SIGNATURES: &[&[u8]] = &[b"MZ"];
+
+/// A stack module that, for requests to configured inbound ports, rejects
+/// requests whose declared `Content-Type` does not match the leading bytes of
+/// their body.
+///
+/// The first body frame is read before the request is dispatched, and at
+/// most `SNIFF_WINDOW` bytes of it are compared against known file
+/// signatures. Textual types (e.g. `application/json`) must not begin with
+/// any known signature, and types with a known signature must begin with it.
+/// Mismatched requests are refused with a 415 Unsupported Media Type
+/// response.
+///
+/// Requests without a `Content-Type`, with a `Content-Encoding`, or without a
+/// body are not sniffed. If a sniffed body cannot be read, the request fails.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    ports: Arc<IndexSet<u16>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+    ports: Arc<IndexSet<u16>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    inner: S,
+    ports: Arc<IndexSet<u16>>,
+}
+
+/// A request's body could not be read for sniffing, or the inner service
+/// failed.
+#[derive(Debug)]
+pub enum Error<E> {
+    Service(E),
+    Body(h2::Error),
+}
+
+pub struct ResponseFuture<S, A>
+where
+    S: svc::Service<http::Request<SniffBody<A>>>,
+    A: Body,
+{
+    state: State<S, A>,
+}
+
+/// Replays a sniffed frame before the rest of the body.
+pub struct SniffBody<B: Body> {
+    inner: B,
+    sniffed: Option<B::Data>,
+}
+
+enum State<S, A>
+where
+    S: svc::Service<http::Request<SniffBody<A>>>,
+    A: Body,
+{
+    /// Waiting for the first body frame. The service has already been made
+    /// ready.
+    Sniffing(Option<(S, http::Request<A>)>),
+    Responding(S::Future),
+    Rejected,
+}
+
+// === impl Layer ===
+
+/// Sniffs the bodies of inbound requests to the given original destination
+/// ports. If `ports` is empty, no requests are sniffed.
+pub fn layer(ports: IndexSet<u16>) -> Layer {
+    Layer {
+        ports: Arc::new(ports),
+    }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            inner,
+            ports: self.ports.clone(),
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service {
+            inner,
+            ports: self.ports.clone(),
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, A, B> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<SniffBody<A>>, Response = http::Response<B>> + Clone,
+    A: Body,
+    A::Data: AsRef<[u8]>,
+    B: Default,
+{
+    type Response = http::Response<B>;
+    type Error = Error<S::Error>;
+    type Future = ResponseFuture<S, A>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready().map_err(Error::Service)
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        if !self.should_sniff(&req) {
+            let req = req.map(|inner| SniffBody {
+                inner,
+                sniffed: None,
+            });
+            return ResponseFuture {
+                state: State::Responding(self.inner.call(req)),
+            };
+        }
+
+        // The ready service is called once the body has been sniffed, and a
+        // clone takes its place.
+        let ready = mem::replace(&mut self.inner, self.inner.clone());
+        ResponseFuture {
+            state: State::Sniffing(Some((ready, req))),
+        }
+    }
+}
+
+impl<S> Service<S> {
+    fn should_sniff<A: Body>(&self, req: &http::Request<A>) -> bool {
+        if self.ports.is_empty() || req.body().is_end_stream() {
+            return false;
+        }
+
+        let port = req
+            .extensions()
+            .get::<Source>()
+            .and_then(|s| s.orig_dst)
+            .map(|a| a.port());
+        let port_matches = port.map(|p| self.ports.contains(&p)).unwrap_or(false);
+
+        port_matches
+            && req.headers().contains_key(CONTENT_TYPE)
+            && !req.headers().contains_key(CONTENT_ENCODING)
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<S, A, B> Future for ResponseFuture<S, A>
+where
+    S: svc::Service<http::Request<SniffBody<A>>, Response = http::Response<B>>,
+    A: Body,
+    A::Data: AsRef<[u8]>,
+    B: Default,
+{
+    type Item = http::Response<B>;
+    type Error = Error<S::Error>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            self.state = match self.state {
+                State::Sniffing(ref mut sniffing) => {
+                    let sniffed = {
+                        let &mut (_, ref mut req) =
+                            sniffing.as_mut().expect("polled after ready");
+                        try_ready!(req.body_mut().poll_data().map_err(|e| {
+                            debug!("failed to read body for sniffing: {:?}", e);
+                            Error::Body(e)
+                        }))
+                    };
+
+                    let (mut svc, req) = sniffing.take().expect("polled after ready");
+                    let matches = match (declared(&req), sniffed.as_ref()) {
+                        (Some(ct), Some(data)) => content_type_matches(&ct, data.as_ref()),
+                        _ => true,
+                    };
+                    if matches {
+                        let req = req.map(|inner| SniffBody { inner, sniffed });
+                        State::Responding(svc.call(req))
+                    } else {
+                        State::Rejected
+                    }
+                }
+                State::Responding(ref mut f) => return f.poll().map_err(Error::Service),
+                State::Rejected => {
+                    warn!("request body does not match its content-type; rejecting");
+                    let rsp = http::Response::builder()
+                        .status(http::StatusCode::UNSUPPORTED_MEDIA_TYPE)
+                        .body(B::default())
+                        .expect("rejected response must be valid");
+                    return Ok(Async::Ready(rsp));
+                }
+            };
+        }
+    }
+}
+
+// === impl Error ===
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Service(e) => fmt::Display::fmt(e, f),
+            Error::Body(e) => write!(f, "failed to read request body: {}", e),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for Error<E> {
+    fn cause(&self) -> Option<&error::Error> {
+        match self {
+            Error::Service(e) => e.cause(),
+            Error::Body(e) => Some(e),
+        }
+    }
+}
+
+// === impl SniffBody ===
+
+impl<B: Body + Default> Default for SniffBody<B> {
+    fn default() -> Self {
+        SniffBody {
+            inner: B::default(),
+            sniffed: None,
+        }
+    }
+}
+
+impl<B: Body> Body for SniffBody<B> {
+    type Data = B::Data;
+
+    fn is_end_stream(&self) -> bool {
+        self.sniffed.is_none() && self.inner.is_end_stream()
+    }
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, h2::Error> {
+        if let Some(data) = self.sniffed.take() {
+            return Ok(Async::Ready(Some(data)));
+        }
+        self.inner.poll_data()
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+        self.inner.poll_trailers()
+    }
+}
+
+// === sniffing ===
+
+/// Returns the essence of the request's declared media type (i.e. without
+/// parameters), in lowercase.
+fn declared<A>(req: &http::Request<A>) -> Option<String> {
+    let ct = req.headers().get(CONTENT_TYPE)?.to_str().ok()?;
+    let essence = ct.split(';').next().unwrap_or("").trim();
+    Some(essence.to_ascii_lowercase())
+}
+
+fn is_textual(ct: &str) -> bool {
+    ct.starts_with("text/")
+        || ct.ends_with("/json")
+        || ct.ends_with("+json")
+        || ct.ends_with("/xml")
+        || ct.ends_with("+xml")
+        || ct == "application/javascript"
+        || ct == "application/x-www-form-urlencoded"
+}
+
+/// Returns false if `body` is known to contain something other than the
+/// declared content type, `ct`.
+fn content_type_matches(ct: &str, body: &[u8]) -> bool {
+    let window = &body[..body.len().min(SNIFF_WINDOW)];
+    let textual = is_textual(ct);
+    let sniffed = SIGNATURES
+        .iter()
+        .filter(|&&(magic, _)| !textual || !AMBIGUOUS_SIGNATURES.contains(&magic))
+        .find(|&&(magic, _)| window.starts_with(magic))
+        .map(|&(_, types)| types);
+
+    if textual {
+        return sniffed.is_none();
+    }
+
+    let has_signature = SIGNATURES.iter().any(|&(_, types)| types.contains(&ct));
+    match sniffed {
+        Some(types) => !has_signature || types.contains(&ct),
+        None => !has_signature,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+    use std::net::SocketAddr;
+
+    use super::*;
+    use svc::Service as _Service;
+    use transport::tls;
+    use Conditional;
+
+    /// A body with a single frame.
+    #[derive(Default)]
+    struct Frame(Option<Bytes>);
+
+    impl Body for Frame {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            self.0.is_none()
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            Ok(Async::Ready(self.0.take()))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+    }
+
+    /// Responds with the request's body.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl svc::Service<http::Request<SniffBody<Frame>>> for Echo {
+        type Response = http::Response<Frame>;
+        type Error = h2::Error;
+        type Future = future::FutureResult<Self::Response, h2::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), h2::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, req: http::Request<SniffBody<Frame>>) -> Self::Future {
+            let mut body = req.into_body();
+            match body.poll_data() {
+                Ok(Async::Ready(data)) => future::ok(http::Response::new(Frame(data))),
+                Ok(Async::NotReady) => panic!("body must be ready"),
+                Err(e) => future::err(e),
+            }
+        }
+    }
+
+    /// A body that fails to be read.
+    struct Broken;
+
+    impl Body for Broken {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            false
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            Err(h2::Reason::INTERNAL_ERROR.into())
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+    }
+
+    /// Must be polled ready before each call. Clones are not ready.
+    #[derive(Default)]
+    struct ReadyOnce(bool);
+
+    impl Clone for ReadyOnce {
+        fn clone(&self) -> Self {
+            ReadyOnce(false)
+        }
+    }
+
+    impl<B: Body> svc::Service<http::Request<SniffBody<B>>> for ReadyOnce {
+        type Response = http::Response<Frame>;
+        type Error = h2::Error;
+        type Future = future::FutureResult<Self::Response, h2::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), h2::Error> {
+            self.0 = true;
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<SniffBody<B>>) -> Self::Future {
+            assert!(self.0, "called before ready");
+            self.0 = false;
+            future::ok(http::Response::new(Frame::default()))
+        }
+    }
+
+    fn request<B>(content_type: &str, body: B) -> http::Request<B> {
+        let mut req = http::Request::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap();
+        let addr = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
+        let tls = Conditional::None(tls::ReasonForNoTls::Disabled);
+        req.extensions_mut()
+            .insert(Source::for_test(addr, addr, Some(addr), tls));
+        req
+    }
+
+    fn frame(body: &'static [u8]) -> Frame {
+        Frame(Some(Bytes::from_static(body)))
+    }
+
+    fn svc() -> Service<Echo> {
+        let ports = vec![8080].into_iter().collect();
+        Service {
+            inner: Echo,
+            ports: Arc::new(ports),
+        }
+    }
+
+    #[test]
+    fn matching_content_type_passes() {
+        let json = br#"{"hello": "world"}"#;
+        let req = request("application/json; charset=utf-8", frame(json));
+        let rsp = svc().call(req).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+        // The sniffed frame is replayed to the inner service.
+        assert_eq!(rsp.into_body().0, Some(Bytes::from_static(json)));
+
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let rsp = svc().call(request("image/png", frame(png))).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+    }
+
+    #[test]
+    fn mismatched_content_type_is_rejected() {
+        let elf = b"\x7fELF\x02\x01\x01\0";
+        let rsp = svc().call(request("application/json", frame(elf))).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let jpeg = b"\xff\xd8\xff\xe0\0\x10JFIF";
+        let rsp = svc().call(request("image/png", frame(jpeg))).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn ambiguous_signatures_are_not_sniffed_in_text() {
+        let text = b"MZ Holdings quarterly report";
+        let rsp = svc().call(request("text/plain", frame(text))).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+
+        let rsp = svc().call(request("image/png", frame(text))).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn sniffed_requests_are_dispatched_to_the_ready_service() {
+        let mut svc = Service {
+            inner: ReadyOnce::default(),
+            ports: Arc::new(vec![8080].into_iter().collect()),
+        };
+        for _ in 0..2 {
+            assert!(svc.poll_ready().unwrap().is_ready());
+            let rsp = svc.call(request("text/plain", frame(b"hello"))).wait().unwrap();
+            assert_eq!(rsp.status(), http::StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn body_errors_fail_sniffed_requests() {
+        let mut svc = Service {
+            inner: ReadyOnce::default(),
+            ports: Arc::new(vec![8080].into_iter().collect()),
+        };
+        assert!(svc.poll_ready().unwrap().is_ready());
+        match svc.call(request("application/json", Broken)).wait() {
+            Err(Error::Body(_)) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("request must fail"),
+        }
+    }
+
+    #[test]
+    fn unconfigured_ports_are_not_sniffed() {
+        let elf = b"\x7fELF\x02\x01\x01\0";
+        let mut svc = Service {
+            inner: Echo,
+            ports: Arc::new(IndexSet::new()),
+        };
+        let rsp = svc.call(request("application/json", frame(elf))).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+    }
+}
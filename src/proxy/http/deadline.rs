@@ -0,0 +1,241 @@
+//! Propagates the remaining time budget of requests with deadlines.
+//!
+//! A request's deadline is carried, relative to when it was sent, in its
+//! `grpc-timeout` or `l5d-deadline` header (both of which use the
+//! `grpc-timeout` format). The `received` layer records when each request was
+//! received by the proxy; before the request is forwarded, `layer` reduces
+//! its deadline headers by the time elapsed since then, so that downstream
+//! proxies observe an accurate remaining budget.
+
+use futures::Poll;
+use http;
+use http::header::HeaderName;
+use std::time::{Duration, Instant};
+use tokio_timer::clock;
+
+use super::grpc_timeout::{encode_timeout, parse_timeout, GRPC_TIMEOUT};
+use svc;
+
+pub const L5D_DEADLINE: &str = "l5d-deadline";
+
+/// Records when a request was received by the proxy.
+///
+/// Stored in each request's extensions.
+#[derive(Clone, Copy, Debug)]
+pub struct Received(Instant);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Layer {
+    kind: Kind,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+    kind: Kind,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    inner: S,
+    kind: Kind,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Receive,
+    Forward,
+}
+
+// === impl Layer ===
+
+/// Annotates each request with the time at which it was received.
+pub fn received() -> Layer {
+    Layer {
+        kind: Kind::Receive,
+    }
+}
+
+/// Reduces the deadlines of requests by the time elapsed since they were
+/// received.
+pub fn layer() -> Layer {
+    Layer {
+        kind: Kind::Forward,
+    }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            inner,
+            kind: self.kind,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service {
+            inner,
+            kind: self.kind,
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, A> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<A>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, mut req: http::Request<A>) -> Self::Future {
+        match self.kind {
+            Kind::Receive => {
+                if req.extensions().get::<Received>().is_none() {
+                    req.extensions_mut().insert(Received(clock::now()));
+                }
+            }
+            Kind::Forward => {
+                if let Some(Received(at)) = req.extensions().get::<Received>().cloned() {
+                    let elapsed = clock::now() - at;
+                    for &name in &[GRPC_TIMEOUT, L5D_DEADLINE] {
+                        reduce(&mut req, HeaderName::from_static(name), elapsed);
+                    }
+                }
+            }
+        }
+
+        self.inner.call(req)
+    }
+}
+
+/// Rewrites the `name` header, if it holds a valid timeout, with the time that
+/// remains once `elapsed` has passed.
+fn reduce<A>(req: &mut http::Request<A>, name: HeaderName, elapsed: Duration) {
+    let remaining = match req.headers().get(&name).and_then(parse_timeout) {
+        Some(timeout) => timeout.checked_sub(elapsed).unwrap_or_default(),
+        None => return,
+    };
+
+    trace!("{}: {:?} remains after {:?}", name, remaining, elapsed);
+    req.headers_mut().insert(name, encode_timeout(remaining));
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future};
+
+    use super::*;
+    use never::Never;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    /// Responds with the request's headers.
+    #[derive(Clone, Debug)]
+    struct Echo;
+
+    impl svc::Stack<()> for Echo {
+        type Value = Self;
+        type Error = Never;
+
+        fn make(&self, _: &()) -> Result<Self, Never> {
+            Ok(Echo)
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Echo {
+        type Response = http::HeaderMap;
+        type Error = Never;
+        type Future = future::FutureResult<http::HeaderMap, Never>;
+
+        fn poll_ready(&mut self) -> Poll<(), Never> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, req: http::Request<()>) -> Self::Future {
+            future::ok(req.headers().clone())
+        }
+    }
+
+    fn forward(req: http::Request<()>) -> http::HeaderMap {
+        let mut svc = layer().bind(Echo).make(&()).unwrap();
+        svc.call(req).wait().unwrap()
+    }
+
+    fn request(name: &'static str, timeout: &'static str, age: Duration) -> http::Request<()> {
+        let mut req = http::Request::builder()
+            .header(name, timeout)
+            .body(())
+            .unwrap();
+        req.extensions_mut().insert(Received(clock::now() - age));
+        req
+    }
+
+    fn timeout(headers: &http::HeaderMap, name: &str) -> Duration {
+        parse_timeout(&headers[name]).expect("forwarded timeout must be valid")
+    }
+
+    #[test]
+    fn forwarded_deadlines_are_reduced_by_elapsed_time() {
+        let age = Duration::from_millis(250);
+        for &name in &[GRPC_TIMEOUT, L5D_DEADLINE] {
+            let headers = forward(request(name, "10S", age));
+            let remaining = timeout(&headers, name);
+            assert!(remaining <= Duration::from_millis(9_750), "{}={:?}", name, remaining);
+            assert!(remaining > Duration::from_millis(9_000), "{}={:?}", name, remaining);
+        }
+    }
+
+    #[test]
+    fn expired_deadlines_are_forwarded_as_zero() {
+        let headers = forward(request(GRPC_TIMEOUT, "100m", Duration::from_secs(1)));
+        assert_eq!(timeout(&headers, GRPC_TIMEOUT), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn received_requests_are_annotated_before_forwarding() {
+        let req = http::Request::builder()
+            .header(GRPC_TIMEOUT, "10S")
+            .body(())
+            .unwrap();
+        let mut svc = received().bind(layer().bind(Echo)).make(&()).unwrap();
+        let headers = svc.call(req).wait().unwrap();
+        assert_ne!(headers[GRPC_TIMEOUT], "10S", "the deadline must be rewritten");
+        assert!(timeout(&headers, GRPC_TIMEOUT) <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn unknown_or_invalid_deadlines_are_unmodified() {
+        let req = http::Request::builder()
+            .header(GRPC_TIMEOUT, "10S")
+            .body(())
+            .unwrap();
+        assert_eq!(forward(req)[GRPC_TIMEOUT], "10S");
+
+        let headers = forward(request(L5D_DEADLINE, "bogus", Duration::from_secs(1)));
+        assert_eq!(headers[L5D_DEADLINE], "bogus");
+    }
+}
@@ -0,0 +1,458 @@
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll};
+use http;
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use svc;
+
+/// A stack module that limits the number of requests that may be in flight
+/// across all services it builds.
+///
+/// This is intended to be a process-wide backstop: all services built by
+/// clones of a `Layer` share a single limit. When the limit is reached,
+/// requests wait for capacity in the order in which they were received. Once
+/// the queue of waiting requests is full, additional requests are shed with
+/// a 503 Service Unavailable response.
+///
+/// Capacity is acquired when a request is called, rather than when the
+/// service is polled for readiness, since hyper's server does not poll for
+/// readiness. Queued requests are dispatched on clones of the inner service,
+/// so this layer should be placed above a router, whose services are always
+/// ready.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    limit: Limit,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+    limit: Limit,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    inner: S,
+    limit: Limit,
+}
+
+pub struct ResponseFuture<S, A>
+where
+    S: svc::Service<http::Request<A>>,
+{
+    limit: Limit,
+    state: State<S, A>,
+}
+
+enum State<S, A>
+where
+    S: svc::Service<http::Request<A>>,
+{
+    /// The request is waiting for capacity.
+    Queued {
+        id: usize,
+        inner: S,
+        request: http::Request<A>,
+    },
+    /// Capacity has been acquired, but the inner service is not yet ready.
+    Acquired {
+        permit: Permit,
+        inner: S,
+        request: http::Request<A>,
+    },
+    Dispatched {
+        future: S::Future,
+        permit: Option<Permit>,
+    },
+    Shed,
+    Done,
+}
+
+#[derive(Clone, Debug)]
+struct Limit(Arc<Mutex<Shared>>);
+
+#[derive(Debug)]
+struct Shared {
+    max_in_flight: usize,
+    max_queued: usize,
+    in_flight: usize,
+    next_id: usize,
+    /// Requests waiting for capacity, in the order they were received, with
+    /// the task to notify once each may proceed.
+    waiters: VecDeque<(usize, Option<Task>)>,
+}
+
+/// Releases a unit of capacity when dropped.
+#[derive(Debug)]
+struct Permit(Limit);
+
+enum Acquire {
+    Ready(Permit),
+    Queued(usize),
+    Shed,
+}
+
+// === impl Layer ===
+
+pub fn layer(max_in_flight: usize, max_queued: usize) -> Layer {
+    let shared = Shared {
+        max_in_flight,
+        max_queued,
+        in_flight: 0,
+        next_id: 0,
+        waiters: VecDeque::new(),
+    };
+    Layer {
+        limit: Limit(Arc::new(Mutex::new(shared))),
+    }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            inner,
+            limit: self.limit.clone(),
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service {
+            inner,
+            limit: self.limit.clone(),
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, A, B> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<A>, Response = http::Response<B>> + Clone,
+    B: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S, A>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let state = match self.limit.acquire() {
+            Acquire::Ready(permit) => State::Dispatched {
+                future: self.inner.call(req),
+                permit: Some(permit),
+            },
+            Acquire::Queued(id) => State::Queued {
+                id,
+                inner: self.inner.clone(),
+                request: req,
+            },
+            Acquire::Shed => {
+                debug!("global request limit reached; shedding request");
+                State::Shed
+            }
+        };
+
+        ResponseFuture {
+            limit: self.limit.clone(),
+            state,
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<S, A, B> Future for ResponseFuture<S, A>
+where
+    S: svc::Service<http::Request<A>, Response = http::Response<B>>,
+    B: Default,
+{
+    type Item = S::Response;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let State::Dispatched {
+                ref mut future,
+                ref mut permit,
+            } = self.state
+            {
+                let rsp = try_ready!(future.poll());
+                // Capacity is released as soon as the response is available.
+                permit.take();
+                return Ok(Async::Ready(rsp));
+            }
+
+            self.state = match mem::replace(&mut self.state, State::Done) {
+                State::Queued { id, inner, request } => match self.limit.poll_acquire(id) {
+                    Some(permit) => State::Acquired {
+                        permit,
+                        inner,
+                        request,
+                    },
+                    None => {
+                        self.state = State::Queued { id, inner, request };
+                        return Ok(Async::NotReady);
+                    }
+                },
+                State::Acquired {
+                    permit,
+                    mut inner,
+                    request,
+                } => match inner.poll_ready()? {
+                    Async::Ready(()) => State::Dispatched {
+                        future: inner.call(request),
+                        permit: Some(permit),
+                    },
+                    Async::NotReady => {
+                        self.state = State::Acquired {
+                            permit,
+                            inner,
+                            request,
+                        };
+                        return Ok(Async::NotReady);
+                    }
+                },
+                State::Shed => {
+                    let rsp = http::Response::builder()
+                        .status(http::StatusCode::SERVICE_UNAVAILABLE)
+                        .body(B::default())
+                        .expect("shed response must be valid");
+                    return Ok(Async::Ready(rsp));
+                }
+                State::Dispatched { .. } => unreachable!("dispatched requests are polled above"),
+                State::Done => panic!("polled after complete"),
+            };
+        }
+    }
+}
+
+impl<S, A> Drop for ResponseFuture<S, A>
+where
+    S: svc::Service<http::Request<A>>,
+{
+    fn drop(&mut self) {
+        if let State::Queued { id, .. } = self.state {
+            self.limit.cancel(id);
+        }
+    }
+}
+
+// === impl Limit ===
+
+impl Limit {
+    /// Acquires capacity for a new request, or queues it if capacity is not
+    /// available.
+    fn acquire(&self) -> Acquire {
+        let mut shared = self.0.lock().expect("global limit lock poisoned");
+
+        if shared.waiters.is_empty() && shared.in_flight < shared.max_in_flight {
+            shared.in_flight += 1;
+            return Acquire::Ready(Permit(self.clone()));
+        }
+
+        if shared.waiters.len() >= shared.max_queued {
+            return Acquire::Shed;
+        }
+
+        let id = shared.next_id;
+        shared.next_id = shared.next_id.wrapping_add(1);
+        shared.waiters.push_back((id, None));
+        Acquire::Queued(id)
+    }
+
+    /// Acquires capacity for a queued request once it is at the front of the
+    /// queue. Otherwise, the current task is notified when it may proceed.
+    fn poll_acquire(&self, id: usize) -> Option<Permit> {
+        let mut shared = self.0.lock().expect("global limit lock poisoned");
+
+        let is_next = shared.waiters.front().map(|&(i, _)| i == id).unwrap_or(false);
+        if is_next && shared.in_flight < shared.max_in_flight {
+            shared.waiters.pop_front();
+            shared.in_flight += 1;
+            // The next waiter may be able to use remaining capacity.
+            shared.notify_next();
+            return Some(Permit(self.clone()));
+        }
+
+        if let Some(w) = shared.waiters.iter_mut().find(|w| w.0 == id) {
+            w.1 = Some(task::current());
+        }
+        None
+    }
+
+    /// Removes a waiter that no longer needs capacity.
+    fn cancel(&self, id: usize) {
+        if let Ok(mut shared) = self.0.lock() {
+            shared.waiters.retain(|&(i, _)| i != id);
+            shared.notify_next();
+        }
+    }
+}
+
+// === impl Shared ===
+
+impl Shared {
+    fn notify_next(&self) {
+        if self.in_flight < self.max_in_flight {
+            if let Some(&(_, Some(ref task))) = self.waiters.front() {
+                task.notify();
+            }
+        }
+    }
+}
+
+// === impl Permit ===
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = (self.0).0.lock() {
+            shared.in_flight -= 1;
+            shared.notify_next();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use futures::sync::oneshot;
+
+    use super::*;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    /// Builds services that respond to each request when signaled.
+    #[derive(Clone, Debug)]
+    struct MakeSvc(Svc);
+
+    #[derive(Clone, Debug)]
+    struct Svc(Arc<Mutex<VecDeque<oneshot::Receiver<()>>>>);
+
+    struct Respond(oneshot::Receiver<()>);
+
+    impl svc::Stack<()> for MakeSvc {
+        type Value = Svc;
+        type Error = ();
+
+        fn make(&self, _: &()) -> Result<Svc, ()> {
+            Ok(self.0.clone())
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Svc {
+        type Response = http::Response<()>;
+        type Error = ();
+        type Future = Respond;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            let rx = self.0.lock().unwrap().pop_front();
+            Respond(rx.expect("unexpected request"))
+        }
+    }
+
+    impl Future for Respond {
+        type Item = http::Response<()>;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Self::Item, ()> {
+            try_ready!(self.0.poll().map_err(|_| ()));
+            Ok(Async::Ready(http::Response::new(())))
+        }
+    }
+
+    fn status<F>(rsp: &mut F) -> Option<http::StatusCode>
+    where
+        F: Future<Item = http::Response<()>, Error = ()>,
+    {
+        match rsp.poll().expect("response") {
+            Async::Ready(rsp) => Some(rsp.status()),
+            Async::NotReady => None,
+        }
+    }
+
+    #[test]
+    fn excess_requests_queue_and_then_shed() {
+        let (tx0, rx0) = oneshot::channel();
+        let (_tx1, rx1) = oneshot::channel();
+        let rxs = vec![rx0, rx1].into_iter().collect();
+        let stack = layer(1, 1).bind(MakeSvc(Svc(Arc::new(Mutex::new(rxs)))));
+
+        future::lazy(|| {
+            // Requests are called without their services being polled for
+            // readiness, as hyper's server does.
+            let mut svc = stack.make(&()).unwrap();
+
+            // The first request holds the only unit of capacity.
+            let mut rsp0 = svc.call(http::Request::new(()));
+            assert_eq!(status(&mut rsp0), None);
+
+            // The second request is queued.
+            let mut rsp1 = svc.call(http::Request::new(()));
+            assert_eq!(status(&mut rsp1), None);
+
+            // The queue is full, so the third request is shed.
+            let mut rsp2 = svc.call(http::Request::new(()));
+            assert_eq!(status(&mut rsp2), Some(http::StatusCode::SERVICE_UNAVAILABLE));
+
+            // Once the first request completes, the queued request is
+            // dispatched.
+            tx0.send(()).unwrap();
+            assert_eq!(status(&mut rsp0), Some(http::StatusCode::OK));
+            assert_eq!(status(&mut rsp1), None);
+
+            // And the queue has room again.
+            let mut rsp3 = svc.call(http::Request::new(()));
+            assert_eq!(status(&mut rsp3), None);
+
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn canceled_requests_leave_the_queue() {
+        let stack = layer(0, 1).bind(MakeSvc(Svc(Arc::new(Mutex::new(VecDeque::new())))));
+
+        future::lazy(|| {
+            let mut svc = stack.make(&()).unwrap();
+            let mut queued = svc.call(http::Request::new(()));
+            assert_eq!(status(&mut queued), None);
+            drop(queued);
+
+            // The canceled request no longer occupies the queue.
+            let mut rsp = svc.call(http::Request::new(()));
+            assert_eq!(status(&mut rsp), None);
+
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
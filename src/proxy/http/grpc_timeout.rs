@@ -0,0 +1,325 @@
+//! Bounds requests by the deadline carried in their `grpc-timeout` header.
+//!
+//! When a request's deadline elapses before a response is received, the
+//! request is canceled and a trailers-only gRPC response is returned with a
+//! `DEADLINE_EXCEEDED` status.
+
+use futures::{Async, Future, Poll};
+use http;
+use http::header::{HeaderValue, CONTENT_TYPE};
+use std::time::Duration;
+use tokio_timer::{clock, Delay};
+
+use svc;
+
+pub const GRPC_TIMEOUT: &str = "grpc-timeout";
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+
+/// The `DEADLINE_EXCEEDED` gRPC status code.
+const DEADLINE_EXCEEDED: &str = "4";
+
+/// A timeout value has at most 8 digits.
+const MAX_DIGITS: usize = 8;
+
+/// The largest value that may be encoded in a timeout.
+const MAX_VALUE: u64 = 99_999_999;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Clone, Debug)]
+pub struct Layer(());
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    inner: S,
+}
+
+pub struct ResponseFuture<F> {
+    inner: F,
+    /// When `None`, the request has no deadline.
+    deadline: Option<Delay>,
+}
+
+// === impl Layer ===
+
+pub fn layer() -> Layer {
+    Layer(())
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack { inner }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service { inner })
+    }
+}
+
+// === impl Service ===
+
+impl<S, A, B> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<A>, Response = http::Response<B>>,
+    B: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let timeout = req.headers().get(GRPC_TIMEOUT).and_then(|v| {
+            let timeout = parse_timeout(v);
+            if timeout.is_none() {
+                debug!("ignoring invalid grpc-timeout: {:?}", v);
+            }
+            timeout
+        });
+
+        ResponseFuture {
+            inner: self.inner.call(req),
+            deadline: timeout.map(|t| Delay::new(clock::now() + t)),
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: Future<Item = http::Response<B>>,
+    B: Default,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(rsp) = self.inner.poll()? {
+            return Ok(Async::Ready(rsp));
+        }
+
+        if let Some(ref mut deadline) = self.deadline {
+            match deadline.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => {}
+                Err(e) => error!("grpc-timeout timer failed: {}", e),
+            }
+        } else {
+            return Ok(Async::NotReady);
+        }
+
+        debug!("grpc-timeout elapsed");
+        Ok(Async::Ready(deadline_exceeded()))
+    }
+}
+
+/// Builds a trailers-only gRPC response with a `DEADLINE_EXCEEDED` status.
+fn deadline_exceeded<B: Default>() -> http::Response<B> {
+    http::Response::builder()
+        .header(CONTENT_TYPE, "application/grpc")
+        .header(GRPC_STATUS, DEADLINE_EXCEEDED)
+        .header(GRPC_MESSAGE, "Deadline Exceeded")
+        .body(B::default())
+        .expect("deadline response must be valid")
+}
+
+/// Parses a `grpc-timeout` value, i.e. up to 8 digits followed by a unit:
+/// `H` (hours), `M` (minutes), `S` (seconds), `m` (milliseconds), `u`
+/// (microseconds), or `n` (nanoseconds).
+pub fn parse_timeout(value: &HeaderValue) -> Option<Duration> {
+    let s = value.to_str().ok()?;
+    if s.len() < 2 || s.len() > MAX_DIGITS + 1 {
+        return None;
+    }
+
+    let (digits, unit) = s.split_at(s.len() - 1);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n = digits.parse::<u64>().ok()?;
+
+    match unit {
+        "H" => Some(Duration::from_secs(n * 60 * 60)),
+        "M" => Some(Duration::from_secs(n * 60)),
+        "S" => Some(Duration::from_secs(n)),
+        "m" => Some(Duration::from_millis(n)),
+        "u" => Some(Duration::from_nanos(n * 1_000)),
+        "n" => Some(Duration::from_nanos(n)),
+        _ => None,
+    }
+}
+
+/// Encodes a `grpc-timeout` value in the finest unit that can represent it
+/// with at most 8 digits. Precision that cannot be represented is truncated.
+pub fn encode_timeout(timeout: Duration) -> HeaderValue {
+    let nanos = timeout
+        .as_secs()
+        .saturating_mul(NANOS_PER_SEC)
+        .saturating_add(u64::from(timeout.subsec_nanos()));
+    let units = [
+        (1, 'n'),
+        (1_000, 'u'),
+        (1_000_000, 'm'),
+        (NANOS_PER_SEC, 'S'),
+        (60 * NANOS_PER_SEC, 'M'),
+        (60 * 60 * NANOS_PER_SEC, 'H'),
+    ];
+    let (n, unit) = units
+        .iter()
+        .map(|&(per, unit)| (nanos / per, unit))
+        .find(|&(n, _)| n <= MAX_VALUE)
+        .unwrap_or((MAX_VALUE, 'H'));
+
+    HeaderValue::from_str(&format!("{}{}", n, unit))
+        .expect("timeout must be a valid header value")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::time::Instant;
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::*;
+    use never::Never;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    /// Responds after a delay, or never.
+    #[derive(Clone, Debug)]
+    struct Slow(Option<Duration>);
+
+    impl svc::Stack<()> for Slow {
+        type Value = Self;
+        type Error = Never;
+
+        fn make(&self, _: &()) -> Result<Self, Never> {
+            Ok(self.clone())
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Slow {
+        type Response = http::Response<()>;
+        type Error = ();
+        type Future = Box<Future<Item = http::Response<()>, Error = ()>>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            match self.0 {
+                Some(d) => Box::new(
+                    Delay::new(clock::now() + d)
+                        .map(|_| http::Response::new(()))
+                        .map_err(|_| ()),
+                ),
+                None => Box::new(future::empty()),
+            }
+        }
+    }
+
+    fn send(delay: Option<Duration>, timeout: Option<&'static str>) -> http::Response<()> {
+        let mut svc = layer().bind(Slow(delay)).make(&()).unwrap();
+        let mut req = http::Request::new(());
+        if let Some(t) = timeout {
+            req.headers_mut()
+                .insert(GRPC_TIMEOUT, HeaderValue::from_static(t));
+        }
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| svc.call(req)))
+            .expect("response")
+    }
+
+    fn parse(s: &'static str) -> Option<Duration> {
+        parse_timeout(&HeaderValue::from_static(s))
+    }
+
+    #[test]
+    fn parses_timeouts() {
+        assert_eq!(parse("2H"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse("3M"), Some(Duration::from_secs(3 * 60)));
+        assert_eq!(parse("10S"), Some(Duration::from_secs(10)));
+        assert_eq!(parse("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse("1500u"), Some(Duration::from_micros(1_500)));
+        assert_eq!(parse("99999999n"), Some(Duration::from_nanos(99_999_999)));
+        assert_eq!(parse("0S"), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn rejects_invalid_timeouts() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("S"), None, "digits are required");
+        assert_eq!(parse("10"), None, "a unit is required");
+        assert_eq!(parse("10s"), None, "units are case-sensitive");
+        assert_eq!(parse("123456789S"), None, "at most 8 digits");
+        assert_eq!(parse("-1S"), None);
+        assert_eq!(parse("+1S"), None);
+        assert_eq!(parse("1.5S"), None);
+    }
+
+    #[test]
+    fn encodes_timeouts() {
+        let encode = |d| encode_timeout(d).to_str().unwrap().to_owned();
+        assert_eq!(encode(Duration::from_nanos(99_999_999)), "99999999n");
+        assert_eq!(encode(Duration::from_millis(250)), "250000u");
+        assert_eq!(encode(Duration::from_secs(10)), "10000000u");
+        assert_eq!(encode(Duration::from_secs(2 * 60 * 60)), "7200000m");
+        assert_eq!(encode(Duration::from_secs(1_000_000)), "1000000S");
+        assert_eq!(encode(Duration::from_secs(0)), "0n");
+
+        let d = Duration::new(12, 345_678_901);
+        let truncated = Duration::from_micros(12_345_678);
+        assert_eq!(parse_timeout(&encode_timeout(d)), Some(truncated));
+    }
+
+    #[test]
+    fn deadline_exceeded_when_timeout_elapses() {
+        let start = Instant::now();
+        let rsp = send(None, Some("20m"));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+        assert_eq!(rsp.headers()[GRPC_STATUS], DEADLINE_EXCEEDED);
+        assert_eq!(rsp.headers()[CONTENT_TYPE], "application/grpc");
+    }
+
+    #[test]
+    fn responses_within_timeout_are_unmodified() {
+        let rsp = send(Some(Duration::from_millis(1)), Some("10S"));
+        assert!(rsp.headers().get(GRPC_STATUS).is_none());
+
+        let rsp = send(Some(Duration::from_millis(1)), None);
+        assert!(rsp.headers().get(GRPC_STATUS).is_none());
+
+        let rsp = send(Some(Duration::from_millis(1)), Some("bogus"));
+        assert!(rsp.headers().get(GRPC_STATUS).is_none());
+    }
+}
@@ -1,6 +1,6 @@
 use bytes::BytesMut;
 use http;
-use http::header::{CONNECTION, HOST, UPGRADE};
+use http::header::{HeaderName, HeaderValue, CONNECTION, HOST, UPGRADE};
 use http::uri::{Authority, Parts, Scheme, Uri};
 use std::fmt::Write;
 use std::mem;
@@ -10,7 +10,11 @@ use super::super::server::Source;
 
 /// Tries to make sure the `Uri` of the request is in a form needed by
 /// hyper's Client.
-pub fn normalize_our_view_of_uri<B>(req: &mut http::Request<B>) {
+///
+/// A valid `Host` header is never modified. Only if `rewrite_host` is enabled
+/// and the request has no `Host` header is one synthesized from the original
+/// destination; otherwise, the request's headers are left untouched.
+pub fn normalize_our_view_of_uri<B>(req: &mut http::Request<B>, rewrite_host: bool) {
     debug_assert!(
         req.uri().scheme_part().is_none(),
         "normalize_uri shouldn't be called with absolute URIs: {:?}",
@@ -34,6 +38,11 @@ pub fn normalize_our_view_of_uri<B>(req: &mut http::Request<B>) {
             .expect("socket address display is under 31 bytes");
         let auth = Authority::from_shared(bytes.freeze())
             .expect("socket address is valid authority");
+        if rewrite_host && !req.headers().contains_key(HOST) {
+            let host = HeaderValue::from_str(auth.as_str())
+                .expect("authority is valid header value");
+            req.headers_mut().insert(HOST, host);
+        }
         set_authority(req.uri_mut(), auth);
     }
 }
@@ -83,27 +92,81 @@ fn set_authority(uri: &mut http::Uri, auth: Authority) {
     *uri = new;
 }
 
-pub fn strip_connection_headers(headers: &mut http::HeaderMap) {
-    if let Some(val) = headers.remove(CONNECTION) {
-        if let Ok(conn_header) = val.to_str() {
-            // A `Connection` header may have a comma-separated list of
-            // names of other headers that are meant for only this specific
-            // connection.
-            //
-            // Iterate these names and remove them as headers.
-            for name in conn_header.split(',') {
-                let name = name.trim();
-                headers.remove(name);
-            }
+/// Headers that only apply to a single HTTP/1 connection and so must not be
+/// forwarded, regardless of whether they are named by `Connection`.
+const HOP_BY_HOP_HEADERS: [&str; 3] = ["keep-alive", "proxy-connection", "upgrade"];
 
-        }
+/// Whether a request's `Connection` header explicitly asks for its
+/// connection to be kept alive or closed.
+///
+/// Since the header is stripped before requests are forwarded, the
+/// disposition is recorded in the request's extensions so that it may be
+/// honored by the far side of an upgraded connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Disposition {
+    KeepAlive,
+    Close,
+}
+
+/// Removes hop-by-hop headers from a request so that they are not forwarded.
+///
+/// If the request explicitly asks for its connection to be kept alive or
+/// closed, a `Disposition` is inserted into its extensions.
+pub fn strip_connection_headers<B>(req: &mut http::Request<B>) {
+    if let Some(disposition) = connection_disposition(req.headers()) {
+        req.extensions_mut().insert(disposition);
     }
+    strip_hop_by_hop_headers(req.headers_mut());
+}
+
+/// Removes hop-by-hop headers from a response so that they are not forwarded.
+pub fn strip_response_connection_headers<B>(res: &mut http::Response<B>) {
+    strip_hop_by_hop_headers(res.headers_mut());
+}
+
+fn strip_hop_by_hop_headers(headers: &mut http::HeaderMap) {
+    // A `Connection` header may have a comma-separated list of names of
+    // other headers that are meant for only this specific connection. The
+    // header may also be repeated.
+    //
+    // Collect these names so that they may be removed as headers.
+    let named = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in named {
+        headers.remove(name);
+    }
+    headers.remove(CONNECTION);
 
     // Additionally, strip these "connection-level" headers always, since
     // they are otherwise illegal if upgraded to HTTP2.
-    headers.remove(UPGRADE);
-    headers.remove("proxy-connection");
-    headers.remove("keep-alive");
+    for name in &HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
+
+fn connection_disposition(headers: &http::HeaderMap) -> Option<Disposition> {
+    let mut disposition = None;
+    let tokens = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','));
+    for token in tokens {
+        let token = token.trim();
+        if token.eq_ignore_ascii_case("close") {
+            // `close` takes precedence over `keep-alive`.
+            return Some(Disposition::Close);
+        }
+        if token.eq_ignore_ascii_case("keep-alive") {
+            disposition = Some(Disposition::KeepAlive);
+        }
+    }
+    disposition
 }
 
 /// Checks requests to determine if they want to perform an HTTP upgrade.
@@ -122,7 +185,8 @@ pub fn wants_upgrade<B>(req: &http::Request<B>) -> bool {
         // requests going over that connection. Instead of that confusion,
         // the proxy strips h2 upgrade headers.
         //
-        // Eventually, the proxy will support h2 upgrades directly.
+        // When h2c upgrades are enabled, the server upgrades these
+        // connections itself, before requests are dispatched.
         return upgrade != "h2c";
     }
 
@@ -212,3 +276,114 @@ pub fn is_bad_request<B>(req: &http::Request<B>) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use http;
+    use std::net::SocketAddr;
+
+    use super::*;
+    use transport::tls;
+    use Conditional;
+
+    fn with_orig_dst<B>(req: &mut http::Request<B>, orig_dst: &str) {
+        let local = "127.0.0.1:4143".parse::<SocketAddr>().unwrap();
+        let src = Source::for_test(
+            "10.1.1.1:40000".parse().unwrap(),
+            local,
+            Some(orig_dst.parse().unwrap()),
+            Conditional::None(tls::ReasonForNoTls::Disabled),
+        );
+        req.extensions_mut().insert(src);
+    }
+
+    #[test]
+    fn normalize_preserves_host_header_verbatim() {
+        for &rewrite_host in &[false, true] {
+            let mut req = http::Request::builder()
+                .uri("/docs")
+                .header("host", "Legacy.Example.COM:8080")
+                .body(())
+                .unwrap();
+            with_orig_dst(&mut req, "10.2.2.2:8080");
+
+            normalize_our_view_of_uri(&mut req, rewrite_host);
+
+            assert_eq!(req.headers().get_all(HOST).iter().count(), 1);
+            assert_eq!(req.headers()[HOST].as_bytes(), b"Legacy.Example.COM:8080");
+            assert_eq!(req.uri().authority_part().unwrap(), "Legacy.Example.COM:8080");
+        }
+    }
+
+    #[test]
+    fn normalize_synthesizes_missing_host_when_rewriting() {
+        let mut req = http::Request::builder()
+            .uri("/docs")
+            .body(())
+            .unwrap();
+        with_orig_dst(&mut req, "10.2.2.2:8080");
+
+        normalize_our_view_of_uri(&mut req, true);
+
+        assert_eq!(req.headers()[HOST], "10.2.2.2:8080");
+        assert_eq!(req.uri(), "http://10.2.2.2:8080/docs");
+    }
+
+    #[test]
+    fn normalize_does_not_synthesize_host_when_not_rewriting() {
+        let mut req = http::Request::builder()
+            .uri("/docs")
+            .body(())
+            .unwrap();
+        with_orig_dst(&mut req, "10.2.2.2:8080");
+
+        normalize_our_view_of_uri(&mut req, false);
+
+        assert!(!req.headers().contains_key(HOST));
+        assert_eq!(req.uri(), "http://10.2.2.2:8080/docs");
+    }
+
+    #[test]
+    fn strips_headers_named_by_connection() {
+        let mut req = http::Request::builder()
+            .header("connection", "close, X-Foo")
+            .header("x-foo", "bar")
+            .header("keep-alive", "timeout=5")
+            .header("proxy-connection", "keep-alive")
+            .header("x-bar", "baz")
+            .header("content-type", "text/plain")
+            .body(())
+            .unwrap();
+
+        strip_connection_headers(&mut req);
+
+        assert_eq!(req.extensions().get(), Some(&Disposition::Close));
+        let headers = req.headers();
+        assert!(!headers.contains_key("connection"));
+        assert!(!headers.contains_key("x-foo"));
+        assert!(!headers.contains_key("keep-alive"));
+        assert!(!headers.contains_key("proxy-connection"));
+        assert_eq!(headers["x-bar"], "baz");
+        assert_eq!(headers["content-type"], "text/plain");
+    }
+
+    #[test]
+    fn strips_headers_named_by_repeated_connection_headers() {
+        let mut res = http::Response::builder()
+            .header("connection", "x-foo")
+            .header("connection", " X-Bar ,,")
+            .header("x-foo", "1")
+            .header("x-bar", "2")
+            .header("x-baz", "3")
+            .body(())
+            .unwrap();
+
+        strip_response_connection_headers(&mut res);
+
+        let headers = res.headers();
+        assert!(!headers.contains_key("connection"));
+        assert!(!headers.contains_key("x-foo"));
+        assert!(!headers.contains_key("x-bar"));
+        assert_eq!(headers["x-baz"], "3");
+    }
+}
@@ -5,7 +5,7 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio_timer::clock;
 
-use metrics::{latency, Counter, FmtLabels, Histogram};
+use metrics::{latency, Bounds, Bucket, Counter, FmtLabels, Gauge, Histogram};
 
 pub mod classify;
 mod report;
@@ -14,12 +14,33 @@ mod service;
 pub use self::report::Report;
 pub use self::service::layer;
 
-pub fn new<T, C>(retain_idle: Duration) -> (Arc<Mutex<Registry<T, C>>>, Report<T, C>)
+/// The maximum size (inclusive) of each body size bucket, in bytes.
+const BYTES_BOUNDS: &Bounds = &Bounds(&[
+    Bucket::Le(64),
+    Bucket::Le(256),
+    Bucket::Le(1_024),
+    Bucket::Le(4_096),
+    Bucket::Le(16_384),
+    Bucket::Le(65_536),
+    Bucket::Le(262_144),
+    Bucket::Le(1_048_576),
+    Bucket::Le(4_194_304),
+    Bucket::Le(16_777_216),
+    // A final upper bound.
+    Bucket::Inf,
+]);
+
+/// Creates a registry whose response latencies are recorded into histograms
+/// with the given `latency_bounds`.
+pub fn new<T, C>(
+    retain_idle: Duration,
+    latency_bounds: &'static Bounds,
+) -> (Arc<Mutex<Registry<T, C>>>, Report<T, C>)
 where
     T: FmtLabels + Clone + Hash + Eq,
     C: FmtLabels + Hash + Eq,
 {
-    let registry = Arc::new(Mutex::new(Registry::default()));
+    let registry = Arc::new(Mutex::new(Registry::new(latency_bounds)));
     (registry.clone(), Report::new(retain_idle, registry))
 }
 
@@ -30,6 +51,7 @@ where
     C: Hash + Eq,
 {
     by_target: IndexMap<T, Arc<Mutex<Metrics<C>>>>,
+    latency_bounds: &'static Bounds,
 }
 
 #[derive(Debug)]
@@ -39,7 +61,12 @@ where
 {
     last_update: Instant,
     total: Counter,
+    /// The number of requests whose responses have not yet completed.
+    inflight: Gauge,
+    /// The sizes of request bodies, in bytes.
+    request_bytes: Histogram<u64>,
     by_status: IndexMap<http::StatusCode, StatusMetrics<C>>,
+    latency_bounds: &'static Bounds,
 }
 
 #[derive(Debug)]
@@ -51,28 +78,31 @@ where
     by_class: IndexMap<C, ClassMetrics>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct ClassMetrics {
     total: Counter,
+    /// Elapsed times between a request being received and the first frame of
+    /// its response body.
+    first_byte_latency: Histogram<latency::Ms>,
+    /// Elapsed times between a request being received and its response
+    /// stream completing.
+    duration: Histogram<latency::Ms>,
+    /// The sizes of response bodies, in bytes.
+    response_bytes: Histogram<u64>,
 }
 
-impl<T, C> Default for Registry<T, C>
+impl<T, C> Registry<T, C>
 where
     T: Hash + Eq,
     C: Hash + Eq,
 {
-    fn default() -> Self {
+    fn new(latency_bounds: &'static Bounds) -> Self {
         Self {
             by_target: IndexMap::default(),
+            latency_bounds,
         }
     }
-}
 
-impl<T, C> Registry<T, C>
-where
-    T: Hash + Eq,
-    C: Hash + Eq,
-{
     /// Retains metrics for all targets that (1) no longer have an active
     /// reference to the `Metrics` structure and (2) have not been updated since `epoch`.
     fn retain_since(&mut self, epoch: Instant) {
@@ -82,31 +112,54 @@ where
     }
 }
 
-impl<C> Default for Metrics<C>
+impl<C> Metrics<C>
 where
     C: Hash + Eq,
 {
-    fn default() -> Self {
+    fn new(latency_bounds: &'static Bounds) -> Self {
         Self {
             last_update: clock::now(),
             total: Counter::default(),
+            inflight: Gauge::default(),
+            request_bytes: Histogram::new(BYTES_BOUNDS),
             by_status: IndexMap::default(),
+            latency_bounds,
         }
     }
 }
 
-impl<C> Default for StatusMetrics<C>
+impl<C> Default for Metrics<C>
 where
     C: Hash + Eq,
 {
     fn default() -> Self {
+        Self::new(latency::BOUNDS)
+    }
+}
+
+impl<C> StatusMetrics<C>
+where
+    C: Hash + Eq,
+{
+    fn new(latency_bounds: &'static Bounds) -> Self {
         Self {
-            latency: Histogram::default(),
+            latency: Histogram::new(latency_bounds),
             by_class: IndexMap::default(),
         }
     }
 }
 
+impl ClassMetrics {
+    fn new(latency_bounds: &'static Bounds) -> Self {
+        Self {
+            total: Counter::default(),
+            first_byte_latency: Histogram::new(latency_bounds),
+            duration: Histogram::new(latency_bounds),
+            response_bytes: Histogram::new(BYTES_BOUNDS),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -115,7 +168,7 @@ mod tests {
         use std::time::Duration;
         use tokio_timer::clock;
 
-        use metrics::FmtLabels;
+        use metrics::{latency, FmtLabels};
 
         #[derive(Clone, Debug, Hash, Eq, PartialEq)]
         struct Target(usize);
@@ -142,7 +195,7 @@ mod tests {
         }
 
         let retain_idle_for = Duration::from_secs(1);
-        let (r, report) = super::new::<Target, Class>(retain_idle_for);
+        let (r, report) = super::new::<Target, Class>(retain_idle_for, latency::BOUNDS);
         let mut registry = r.lock().unwrap();
 
         let before_update = clock::now();
@@ -178,4 +231,60 @@ mod tests {
 
         drop((registry, report));
     }
+
+    #[test]
+    fn latencies_are_recorded_in_configured_buckets() {
+        use http;
+        use std::fmt;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        use metrics::{Bounds, Bucket, FmtLabels, FmtMetrics};
+        use super::{Metrics, StatusMetrics};
+
+        #[derive(Clone, Debug, Hash, Eq, PartialEq)]
+        struct Target;
+        impl FmtLabels for Target {
+            fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "target=\"test\"")
+            }
+        }
+
+        #[derive(Clone, Debug, Hash, Eq, PartialEq)]
+        struct Class;
+        impl FmtLabels for Class {
+            fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "class=\"test\"")
+            }
+        }
+
+        let bounds = Bounds::leak(vec![Bucket::Le(5), Bucket::Le(50), Bucket::Inf]);
+        let (r, report) = super::new::<Target, Class>(Duration::from_secs(60), bounds);
+        {
+            let mut registry = r.lock().unwrap();
+            let bounds = registry.latency_bounds;
+            let metrics = registry
+                .by_target
+                .entry(Target)
+                .or_insert_with(|| Arc::new(Mutex::new(Metrics::new(bounds))));
+            let mut metrics = metrics.lock().unwrap();
+            let status = metrics
+                .by_status
+                .entry(http::StatusCode::OK)
+                .or_insert_with(|| StatusMetrics::new(bounds));
+            for ms in &[1, 3, 20, 70] {
+                status.latency.add(Duration::from_millis(*ms));
+            }
+        }
+
+        let rendered = format!("{}", report.as_display());
+        for line in &[
+            "response_latency_ms_bucket{target=\"test\",status_code=\"200\",le=\"5\"} 2",
+            "response_latency_ms_bucket{target=\"test\",status_code=\"200\",le=\"50\"} 3",
+            "response_latency_ms_bucket{target=\"test\",status_code=\"200\",le=\"+Inf\"} 4",
+        ] {
+            assert!(rendered.contains(line), "missing {:?} in:\n{}", line, rendered);
+        }
+        assert!(!rendered.contains("le=\"10\""), "default buckets used:\n{}", rendered);
+    }
 }
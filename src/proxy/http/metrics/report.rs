@@ -5,7 +5,7 @@ use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio_timer::clock;
 
-use metrics::{latency, Counter, FmtLabels, FmtMetric, FmtMetrics, Histogram, Metric};
+use metrics::{latency, Counter, FmtLabels, FmtMetric, FmtMetrics, Gauge, Histogram, Metric};
 
 use super::{ClassMetrics, Metrics, Registry, StatusMetrics};
 
@@ -26,8 +26,13 @@ struct Status(http::StatusCode);
 #[derive(Clone, Debug)]
 struct Scope {
     request_total_key: String,
+    requests_inflight_key: String,
     response_total_key: String,
     response_latency_ms_key: String,
+    response_first_byte_latency_ms_key: String,
+    response_duration_ms_key: String,
+    request_bytes_key: String,
+    response_bytes_key: String,
 }
 
 // ===== impl Report =====
@@ -83,12 +88,31 @@ where
         self.scope.request_total().fmt_help(f)?;
         registry.fmt_by_target(f, self.scope.request_total(), |s| &s.total)?;
 
+        self.scope.requests_inflight().fmt_help(f)?;
+        registry.fmt_by_target(f, self.scope.requests_inflight(), |s| &s.inflight)?;
+
         self.scope.response_latency_ms().fmt_help(f)?;
         registry.fmt_by_status(f, self.scope.response_latency_ms(), |s| &s.latency)?;
 
         self.scope.response_total().fmt_help(f)?;
         registry.fmt_by_class(f, self.scope.response_total(), |s| &s.total)?;
 
+        self.scope.response_first_byte_latency_ms().fmt_help(f)?;
+        registry.fmt_by_class(
+            f,
+            self.scope.response_first_byte_latency_ms(),
+            |s| &s.first_byte_latency,
+        )?;
+
+        self.scope.response_duration_ms().fmt_help(f)?;
+        registry.fmt_by_class(f, self.scope.response_duration_ms(), |s| &s.duration)?;
+
+        self.scope.request_bytes().fmt_help(f)?;
+        registry.fmt_by_target(f, self.scope.request_bytes(), |s| &s.request_bytes)?;
+
+        self.scope.response_bytes().fmt_help(f)?;
+        registry.fmt_by_class(f, self.scope.response_bytes(), |s| &s.response_bytes)?;
+
         Ok(())
     }
 }
@@ -170,8 +194,13 @@ impl Default for Scope {
     fn default() -> Self {
         Self {
             request_total_key: "request_total".to_owned(),
+            requests_inflight_key: "requests_inflight".to_owned(),
             response_total_key: "response_total".to_owned(),
             response_latency_ms_key: "response_latency_ms".to_owned(),
+            response_first_byte_latency_ms_key: "response_first_byte_latency_ms".to_owned(),
+            response_duration_ms_key: "response_duration_ms".to_owned(),
+            request_bytes_key: "request_bytes".to_owned(),
+            response_bytes_key: "response_bytes".to_owned(),
         }
     }
 }
@@ -184,8 +213,16 @@ impl Scope {
 
         Self {
             request_total_key: format!("{}_request_total", prefix),
+            requests_inflight_key: format!("{}_requests_inflight", prefix),
             response_total_key: format!("{}_response_total", prefix),
             response_latency_ms_key: format!("{}_response_latency_ms", prefix),
+            response_first_byte_latency_ms_key: format!(
+                "{}_response_first_byte_latency_ms",
+                prefix
+            ),
+            response_duration_ms_key: format!("{}_response_duration_ms", prefix),
+            request_bytes_key: format!("{}_request_bytes", prefix),
+            response_bytes_key: format!("{}_response_bytes", prefix),
         }
     }
 
@@ -193,6 +230,10 @@ impl Scope {
         Metric::new(&self.request_total_key, &Self::REQUEST_TOTAL_HELP)
     }
 
+    fn requests_inflight(&self) -> Metric<Gauge> {
+        Metric::new(&self.requests_inflight_key, &Self::REQUESTS_INFLIGHT_HELP)
+    }
+
     fn response_total(&self) -> Metric<Counter> {
         Metric::new(&self.response_total_key, &Self::RESPONSE_TOTAL_HELP)
     }
@@ -201,13 +242,47 @@ impl Scope {
         Metric::new(&self.response_latency_ms_key, &Self::RESPONSE_LATENCY_MS_HELP)
     }
 
+    fn response_first_byte_latency_ms(&self) -> Metric<Histogram<latency::Ms>> {
+        Metric::new(
+            &self.response_first_byte_latency_ms_key,
+            &Self::RESPONSE_FIRST_BYTE_LATENCY_MS_HELP,
+        )
+    }
+
+    fn response_duration_ms(&self) -> Metric<Histogram<latency::Ms>> {
+        Metric::new(&self.response_duration_ms_key, &Self::RESPONSE_DURATION_MS_HELP)
+    }
+
+    fn request_bytes(&self) -> Metric<Histogram<u64>> {
+        Metric::new(&self.request_bytes_key, &Self::REQUEST_BYTES_HELP)
+    }
+
+    fn response_bytes(&self) -> Metric<Histogram<u64>> {
+        Metric::new(&self.response_bytes_key, &Self::RESPONSE_BYTES_HELP)
+    }
+
     const REQUEST_TOTAL_HELP: &'static str = "Total count of HTTP requests.";
 
+    const REQUESTS_INFLIGHT_HELP: &'static str =
+        "Number of HTTP requests whose responses have not completed.";
+
     const RESPONSE_TOTAL_HELP: &'static str = "Total count of HTTP responses.";
 
     const RESPONSE_LATENCY_MS_HELP: &'static str =
         "Elapsed times between a request's headers being received \
         and its response stream completing";
+
+    const RESPONSE_FIRST_BYTE_LATENCY_MS_HELP: &'static str =
+        "Elapsed times between a request's headers being received \
+        and the first frame of its response body";
+
+    const RESPONSE_DURATION_MS_HELP: &'static str =
+        "Elapsed times between a request's headers being received \
+        and its response body ending";
+
+    const REQUEST_BYTES_HELP: &'static str = "Sizes of HTTP request bodies, in bytes.";
+
+    const RESPONSE_BYTES_HELP: &'static str = "Sizes of HTTP response bodies, in bytes.";
 }
 
 impl FmtLabels for Status {
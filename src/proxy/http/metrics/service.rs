@@ -1,3 +1,4 @@
+use bytes::{Buf, IntoBuf};
 use futures::{Async, Future, Poll};
 use h2;
 use http;
@@ -59,6 +60,7 @@ where
     classify: Option<C>,
     metrics: Option<Arc<Mutex<Metrics<C::Class>>>>,
     stream_open_at: Instant,
+    inflight: Option<Inflight<C::Class>>,
     inner: F,
 }
 
@@ -69,6 +71,10 @@ where
     C: Hash + Eq,
 {
     metrics: Option<Arc<Mutex<Metrics<C>>>>,
+    /// Records the size of the body once it ends.
+    bytes_metrics: Option<Arc<Mutex<Metrics<C>>>>,
+    /// The number of bytes read from the body.
+    bytes: usize,
     inner: B,
 }
 
@@ -83,10 +89,17 @@ where
     classify: Option<C>,
     metrics: Option<Arc<Mutex<Metrics<C::Class>>>>,
     stream_open_at: Instant,
-    latency_recorded: bool,
+    /// Set when the first frame of the response body is received.
+    first_byte_at: Option<Instant>,
+    response_bytes: usize,
+    inflight: Option<Inflight<C::Class>>,
     inner: B,
 }
 
+/// Counts a request as in flight until it is dropped.
+#[derive(Debug)]
+struct Inflight<C: Hash + Eq>(Arc<Mutex<Metrics<C>>>);
+
 // === impl Layer ===
 
 pub fn layer<K, C>(registry: Arc<Mutex<Registry<K, C::Class>>>) -> Layer<K, C>
@@ -170,12 +183,14 @@ where
         let inner = self.inner.make(target)?;
 
         let metrics = match self.registry.lock() {
-            Ok(mut r) => Some(
-                r.by_target
+            Ok(mut r) => {
+                let latency_bounds = r.latency_bounds;
+                let metrics = r
+                    .by_target
                     .entry(target.clone().into())
-                    .or_insert_with(|| Arc::new(Mutex::new(Metrics::default())))
-                    .clone(),
-            ),
+                    .or_insert_with(|| Arc::new(Mutex::new(Metrics::new(latency_bounds))));
+                Some(metrics.clone())
+            }
             Err(_) => None,
         };
 
@@ -226,13 +241,16 @@ where
 
     fn call(&mut self, req: http::Request<A>) -> Self::Future {
         let mut req_metrics = self.metrics.clone();
+        let mut bytes_metrics = self.metrics.clone();
 
         if req.body().is_end_stream() {
+            bytes_metrics = None;
             if let Some(lock) = req_metrics.take() {
                 let now = clock::now();
                 if let Ok(mut metrics) = lock.lock() {
                     (*metrics).last_update = now;
                     (*metrics).total.incr();
+                    (*metrics).request_bytes.add(0);
                 }
             }
         }
@@ -241,17 +259,21 @@ where
             let (head, inner) = req.into_parts();
             let body = RequestBody {
                 metrics: req_metrics,
+                bytes_metrics,
+                bytes: 0,
                 inner,
             };
             http::Request::from_parts(head, body)
         };
 
         let classify = req.extensions().get::<C>().cloned().unwrap_or_default();
+        let inflight = self.metrics.clone().map(Inflight::new);
 
         ResponseFuture {
             classify: Some(classify),
             metrics: self.metrics.clone(),
             stream_open_at: clock::now(),
+            inflight,
             inner: self.inner.call(req),
         }
     }
@@ -279,7 +301,9 @@ where
                 classify,
                 metrics: self.metrics.clone(),
                 stream_open_at: self.stream_open_at,
-                latency_recorded: false,
+                first_byte_at: None,
+                response_bytes: 0,
+                inflight: self.inflight.take(),
                 inner,
             };
             http::Response::from_parts(head, body)
@@ -292,6 +316,7 @@ where
 impl<B, C> tower_h2::Body for RequestBody<B, C>
 where
     B: tower_h2::Body,
+    B::Data: Clone,
     C: Hash + Eq,
 {
     type Data = B::Data;
@@ -303,6 +328,10 @@ where
     fn poll_data(&mut self) -> Poll<Option<Self::Data>, h2::Error> {
         let frame = try_ready!(self.inner.poll_data());
 
+        if let Some(ref data) = frame {
+            self.bytes += len(data);
+        }
+
         if let Some(lock) = self.metrics.take() {
             let now = clock::now();
             if let Ok(mut metrics) = lock.lock() {
@@ -311,17 +340,42 @@ where
             }
         }
 
+        if frame.is_none() || self.inner.is_end_stream() {
+            self.record_bytes();
+        }
+
         Ok(Async::Ready(frame))
     }
 
     fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
-        self.inner.poll_trailers()
+        let trailers = try_ready!(self.inner.poll_trailers());
+        self.record_bytes();
+        Ok(Async::Ready(trailers))
+    }
+}
+
+impl<B, C> RequestBody<B, C>
+where
+    B: tower_h2::Body,
+    C: Hash + Eq,
+{
+    /// Records the size of the body, once it has ended.
+    fn record_bytes(&mut self) {
+        let lock = match self.bytes_metrics.take() {
+            Some(lock) => lock,
+            None => return,
+        };
+        if let Ok(mut metrics) = lock.lock() {
+            (*metrics).last_update = clock::now();
+            (*metrics).request_bytes.add(self.bytes as u64);
+        }
     }
 }
 
 impl<B, C> tower_grpc::Body for RequestBody<B, C>
 where
     B: tower_h2::Body,
+    B::Data: Clone,
     C: Hash + Eq,
 {
     type Data = B::Data;
@@ -352,7 +406,9 @@ where
             stream_open_at: clock::now(),
             classify: None,
             metrics: None,
-            latency_recorded: false,
+            first_byte_at: None,
+            response_bytes: 0,
+            inflight: None,
         }
     }
 }
@@ -377,14 +433,15 @@ where
 
         (*metrics).last_update = now;
 
+        let latency_bounds = metrics.latency_bounds;
         let status_metrics = metrics
             .by_status
             .entry(self.status)
-            .or_insert_with(|| StatusMetrics::default());
+            .or_insert_with(|| StatusMetrics::new(latency_bounds));
 
         status_metrics.latency.add(now - self.stream_open_at);
 
-        self.latency_recorded = true;
+        self.first_byte_at = Some(now);
     }
 
     fn record_class(&mut self, class: C::Class) {
@@ -400,17 +457,22 @@ where
 
         (*metrics).last_update = now;
 
+        let latency_bounds = metrics.latency_bounds;
         let status_metrics = metrics
             .by_status
             .entry(self.status)
-            .or_insert_with(|| StatusMetrics::default());
+            .or_insert_with(|| StatusMetrics::new(latency_bounds));
 
         let class_metrics = status_metrics
             .by_class
             .entry(class)
-            .or_insert_with(|| ClassMetrics::default());
+            .or_insert_with(|| ClassMetrics::new(latency_bounds));
 
         class_metrics.total.incr();
+        let first_byte_at = self.first_byte_at.unwrap_or(now);
+        class_metrics.first_byte_latency.add(first_byte_at - self.stream_open_at);
+        class_metrics.duration.add(now - self.stream_open_at);
+        class_metrics.response_bytes.add(self.response_bytes as u64);
     }
 
     fn measure_err(&mut self, err: C::Error) -> C::Error {
@@ -424,6 +486,7 @@ where
 impl<B, C> tower_h2::Body for ResponseBody<B, C>
 where
     B: tower_h2::Body,
+    B::Data: Clone,
     C: ClassifyEos<Error = h2::Error>,
     C::Class: Hash + Eq,
 {
@@ -436,7 +499,11 @@ where
     fn poll_data(&mut self) -> Poll<Option<Self::Data>, h2::Error> {
         let frame = try_ready!(self.inner.poll_data().map_err(|e| self.measure_err(e)));
 
-        if !self.latency_recorded {
+        if let Some(ref data) = frame {
+            self.response_bytes += len(data);
+        }
+
+        if self.first_byte_at.is_none() {
             self.record_latency();
         }
 
@@ -449,6 +516,7 @@ where
         if let Some(c) = self.classify.take().map(|c| c.eos(trls.as_ref())) {
             self.record_class(c);
         }
+        self.inflight = None;
 
         Ok(Async::Ready(trls))
     }
@@ -457,6 +525,7 @@ where
 impl<B, C> tower_grpc::Body for ResponseBody<B, C>
 where
     B: tower_h2::Body,
+    B::Data: Clone,
     C: ClassifyEos<Error = h2::Error>,
     C::Class: Hash + Eq,
 {
@@ -482,7 +551,7 @@ where
     C::Class: Hash + Eq,
 {
     fn drop(&mut self) {
-        if !self.latency_recorded {
+        if self.first_byte_at.is_none() {
             self.record_latency();
         }
 
@@ -491,3 +560,233 @@ where
         }
     }
 }
+
+// === impl Inflight ===
+
+impl<C: Hash + Eq> Inflight<C> {
+    fn new(lock: Arc<Mutex<Metrics<C>>>) -> Self {
+        if let Ok(mut metrics) = lock.lock() {
+            metrics.inflight.incr();
+        }
+        Inflight(lock)
+    }
+}
+
+impl<C: Hash + Eq> Drop for Inflight<C> {
+    fn drop(&mut self) {
+        if let Ok(mut metrics) = self.0.lock() {
+            metrics.inflight.decr();
+        }
+    }
+}
+
+/// Returns the number of bytes in a data frame.
+///
+/// Frames are cheaply cloned (i.e. they are reference-counted buffers), so
+/// that their lengths may be determined without consuming them.
+fn len<D: IntoBuf + Clone>(data: &D) -> usize {
+    data.clone().into_buf().remaining()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+    use std::collections::VecDeque;
+    use std::fmt;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use app::classify::{self, Class, SuccessOrFailure};
+    use metrics::{latency, Bounds, Bucket, FmtLabels, FmtMetrics};
+    use never::Never;
+    use proxy::http::metrics as http_metrics;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+    use tower_h2::Body;
+
+    #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+    struct Target;
+
+    /// Makes services that respond with frames of the given sizes.
+    #[derive(Clone, Debug)]
+    struct MakeSvc(Vec<usize>);
+
+    /// Reads each request's body and responds with frames of the given sizes.
+    struct Svc(Vec<usize>);
+
+    /// A body that yields each of its frames.
+    #[derive(Debug, Default)]
+    struct Frames(VecDeque<Bytes>);
+
+    impl FmtLabels for Target {
+        fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "target=\"test\"")
+        }
+    }
+
+    impl svc::Stack<Target> for MakeSvc {
+        type Value = Svc;
+        type Error = Never;
+
+        fn make(&self, _: &Target) -> Result<Svc, Never> {
+            Ok(Svc(self.0.clone()))
+        }
+    }
+
+    impl<B: Body> svc::Service<http::Request<B>> for Svc {
+        type Response = http::Response<Frames>;
+        type Error = Never;
+        type Future = future::FutureResult<Self::Response, Never>;
+
+        fn poll_ready(&mut self) -> Poll<(), Never> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let mut body = req.into_body();
+            while let Ok(Async::Ready(Some(_))) = body.poll_data() {}
+            future::ok(http::Response::new(frames(&self.0)))
+        }
+    }
+
+    impl Body for Frames {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            Ok(Async::Ready(self.0.pop_front()))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+    }
+
+    fn frames(sizes: &[usize]) -> Frames {
+        Frames(sizes.iter().map(|&n| Bytes::from(vec![0u8; n])).collect())
+    }
+
+    #[test]
+    fn body_sizes_are_recorded() {
+        let (registry, report) = http_metrics::new::<Target, Class>(
+            Default::default(),
+            latency::BOUNDS,
+        );
+        let stack = layer::<Target, classify::Response>(registry.clone())
+            .bind(MakeSvc(vec![5_000]));
+
+        let mut svc = stack.make(&Target).expect("make");
+        let req = http::Request::new(frames(&[100, 200]));
+        let mut rsp = svc.call(req).wait().expect("call").into_body();
+
+        // The request's size is recorded once its body ends, before the
+        // response completes.
+        {
+            let registry = registry.lock().unwrap();
+            let metrics = registry.by_target[&Target].lock().unwrap();
+            metrics.request_bytes.assert_bucket_exactly(300, 1);
+        }
+
+        while let Async::Ready(Some(_)) = rsp.poll_data().expect("data") {}
+        drop(rsp);
+
+        {
+            let registry = registry.lock().unwrap();
+            let metrics = registry.by_target[&Target].lock().unwrap();
+            let success = Class::Default(SuccessOrFailure::Success);
+            let class = &metrics.by_status[&http::StatusCode::OK].by_class[&success];
+            class.response_bytes.assert_bucket_exactly(5_000, 1);
+        }
+
+        let rendered = format!("{}", report.as_display());
+        let has_sum = |name: &str, sum: &str| {
+            rendered.lines().any(|l| l.starts_with(name) && l.ends_with(sum))
+        };
+        assert!(has_sum("request_bytes_sum{", " 300"), "{}", rendered);
+        assert!(has_sum("response_bytes_sum{", " 5000"), "{}", rendered);
+    }
+
+    #[test]
+    fn first_byte_latency_is_recorded_separately_from_duration() {
+        let bounds = Bounds::leak(vec![Bucket::Le(50), Bucket::Le(10_000), Bucket::Inf]);
+        let (registry, _) = http_metrics::new::<Target, Class>(Default::default(), bounds);
+        let stack = layer::<Target, classify::Response>(registry.clone())
+            .bind(MakeSvc(vec![10, 10]));
+
+        let mut svc = stack.make(&Target).expect("make");
+        let req = http::Request::new(frames(&[]));
+        let mut rsp = svc.call(req).wait().expect("call").into_body();
+
+        // The response's first frame is delayed only briefly, but its body
+        // takes much longer to complete.
+        thread::sleep(Duration::from_millis(5));
+        assert!(rsp.poll_data().expect("data").is_ready());
+        thread::sleep(Duration::from_millis(100));
+        while let Async::Ready(Some(_)) = rsp.poll_data().expect("data") {}
+        assert!(rsp.poll_trailers().expect("trailers").is_ready());
+
+        let registry = registry.lock().unwrap();
+        let metrics = registry.by_target[&Target].lock().unwrap();
+        let success = Class::Default(SuccessOrFailure::Success);
+        let class = &metrics.by_status[&http::StatusCode::OK].by_class[&success];
+        class
+            .first_byte_latency
+            .assert_bucket_exactly(50, 1)
+            .assert_bucket_exactly(10_000, 0);
+        class
+            .duration
+            .assert_bucket_exactly(50, 0)
+            .assert_bucket_exactly(10_000, 1);
+    }
+
+    #[test]
+    fn inflight_requests_are_gauged() {
+        let (registry, report) = http_metrics::new::<Target, Class>(
+            Default::default(),
+            latency::BOUNDS,
+        );
+        let stack = layer::<Target, classify::Response>(registry.clone())
+            .bind(MakeSvc(vec![10]));
+        let mut svc = stack.make(&Target).expect("make");
+        let inflight = || -> u64 {
+            let registry = registry.lock().unwrap();
+            let metrics = registry.by_target[&Target].lock().unwrap();
+            metrics.inflight.into()
+        };
+
+        let pending = svc.call(http::Request::new(frames(&[])));
+        let mut rsps = (0..2)
+            .map(|_| {
+                let req = http::Request::new(frames(&[]));
+                svc.call(req).wait().expect("call").into_body()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(inflight(), 3);
+
+        drop(pending);
+        assert_eq!(inflight(), 2);
+
+        // A response completes once its trailers have been read, even while
+        // its body is still held.
+        {
+            let rsp = &mut rsps[0];
+            while let Async::Ready(Some(_)) = rsp.poll_data().expect("data") {}
+            assert!(rsp.poll_trailers().expect("trailers").is_ready());
+        }
+        assert_eq!(inflight(), 1);
+
+        drop(rsps);
+        assert_eq!(inflight(), 0);
+
+        let rendered = format!("{}", report.as_display());
+        assert!(
+            rendered.contains("requests_inflight{target=\"test\"} 0"),
+            "{}",
+            rendered
+        );
+    }
+}
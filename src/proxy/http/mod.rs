@@ -1,6 +1,13 @@
 pub mod balance;
+pub mod cancel;
+pub mod circuit_breaker;
 pub mod client;
+pub mod compress;
+pub mod content_sniff;
+pub mod deadline;
 pub(super) mod glue;
+pub mod global_limit;
+pub mod grpc_timeout;
 pub mod h1;
 pub mod header_from_target;
 pub mod insert_target;
@@ -8,16 +15,34 @@ pub mod metrics;
 pub mod normalize_uri;
 pub mod orig_proto;
 pub mod profiles;
+pub mod ratelimit;
+pub mod request_id;
 pub mod router;
+pub mod server_id;
 pub mod settings;
+pub mod stream_limit;
+pub mod strip_l5d;
+pub mod timing;
+pub mod trailer_limit;
 pub mod upgrade;
 
 pub use self::client::{Client, Error as ClientError};
 pub use self::glue::HttpBody as Body;
 pub use self::settings::Settings;
 
+use http;
+use http::header::CONTENT_TYPE;
 use svc::Either;
 
+/// Returns true if the headers describe a gRPC message.
+pub fn is_grpc(headers: &http::HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .map(|ct| ct.starts_with("application/grpc"))
+        .unwrap_or(false)
+}
+
 pub trait HasH2Reason {
     fn h2_reason(&self) -> Option<::h2::Reason>;
 }
@@ -31,6 +56,24 @@ impl<E: HasH2Reason> HasH2Reason for super::buffer::ServiceError<E> {
     }
 }
 
+impl<E: HasH2Reason> HasH2Reason for super::reconnect::Error<E> {
+    fn h2_reason(&self) -> Option<::h2::Reason> {
+        match self {
+            super::reconnect::Error::Service(e) => e.h2_reason(),
+            super::reconnect::Error::ConnectFailed { .. } => None,
+        }
+    }
+}
+
+impl<E: HasH2Reason> HasH2Reason for circuit_breaker::Error<E> {
+    fn h2_reason(&self) -> Option<::h2::Reason> {
+        match self {
+            circuit_breaker::Error::Service(e) => e.h2_reason(),
+            circuit_breaker::Error::Open => None,
+        }
+    }
+}
+
 impl<A: HasH2Reason, B: HasH2Reason> HasH2Reason for Either<A, B> {
     fn h2_reason(&self) -> Option<::h2::Reason> {
         match self {
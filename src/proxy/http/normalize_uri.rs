@@ -6,6 +6,10 @@ use svc;
 
 pub trait ShouldNormalizeUri {
     fn should_normalize_uri(&self) -> bool;
+
+    /// Whether a `Host` header may be synthesized when normalizing a request
+    /// that lacks one.
+    fn should_rewrite_host(&self) -> bool;
 }
 
 #[derive(Clone, Debug)]
@@ -19,6 +23,7 @@ pub struct Stack<N> {
 #[derive(Clone, Debug)]
 pub struct Service<S> {
     inner: S,
+    rewrite_host: bool,
 }
 
 // === impl Layer ===
@@ -54,7 +59,8 @@ where
     fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
         let inner = self.inner.make(&target)?;
         if target.should_normalize_uri() {
-            Ok(svc::Either::A(Service { inner }))
+            let rewrite_host = target.should_rewrite_host();
+            Ok(svc::Either::A(Service { inner, rewrite_host }))
         } else {
             Ok(svc::Either::B(inner))
         }
@@ -80,7 +86,7 @@ where
             request.version() != http::Version::HTTP_2,
             "normalize_uri must only be applied to HTTP/1"
         );
-        h1::normalize_our_view_of_uri(&mut request);
+        h1::normalize_our_view_of_uri(&mut request, self.rewrite_host);
         self.inner.call(request)
     }
 }
@@ -1,10 +1,19 @@
 use futures::{future, Future, Poll};
 use http;
-use http::header::{TRANSFER_ENCODING, HeaderValue};
+use http::header::{CONNECTION, COOKIE, HOST, TRAILER, TRANSFER_ENCODING, HeaderValue};
+use std::fmt;
+use std::sync::{Arc, Mutex};
 
 use super::h1;
+use metrics::{Counter, FmtMetrics};
 use svc;
 
+metrics! {
+    orig_proto_lossy_downgrade_total: Counter {
+        "Total number of HTTP/2 requests that could not be faithfully downgraded to their original protocol"
+    }
+}
+
 const L5D_ORIG_PROTO: &str = "l5d-orig-proto";
 
 /// Upgrades HTTP requests from their original protocol to HTTP2.
@@ -18,6 +27,21 @@ pub struct Upgrade<S> {
 #[derive(Clone, Debug)]
 pub struct Downgrade<S> {
     inner: S,
+    registry: Option<Registry>,
+}
+
+/// Counts requests that are downgraded lossily.
+#[derive(Clone, Debug)]
+pub struct Registry(Arc<Mutex<Counter>>);
+
+/// Formats lossy downgrade metrics for Prometheus.
+#[derive(Clone, Debug)]
+pub struct Report(Arc<Mutex<Counter>>);
+
+/// Constructs a Registry/Report pair for lossy downgrade metrics.
+pub fn metrics() -> (Registry, Report) {
+    let inner = Arc::new(Mutex::new(Counter::default()));
+    (Registry(inner.clone()), Report(inner))
 }
 
 // ==== impl Upgrade =====
@@ -62,19 +86,35 @@ where
             // Since the version is going to set to HTTP_2, the NormalizeUri
             // middleware won't normalize the URI automatically, so it
             // needs to be done now.
-            h1::normalize_our_view_of_uri(&mut req);
+            h1::normalize_our_view_of_uri(&mut req, true);
         }
 
-        let val = match (req.version(), was_absolute_form) {
-            (http::Version::HTTP_11, false) => "HTTP/1.1",
-            (http::Version::HTTP_11, true) => "HTTP/1.1; absolute-form",
-            (http::Version::HTTP_10, false) => "HTTP/1.0",
-            (http::Version::HTTP_10, true) => "HTTP/1.0; absolute-form",
-            (v, _) => unreachable!("bad orig-proto version: {:?}", v),
+        let mut val = match req.version() {
+            http::Version::HTTP_11 => String::from("HTTP/1.1"),
+            http::Version::HTTP_10 => String::from("HTTP/1.0"),
+            v => unreachable!("bad orig-proto version: {:?}", v),
         };
+        if was_absolute_form {
+            val.push_str("; absolute-form");
+        }
+
+        // HTTP/1.0 connections are closed after each request and HTTP/1.1
+        // connections are kept alive, unless the client explicitly asked
+        // otherwise. The far side needs to know when it did.
+        let disposition = req.extensions().get::<h1::Disposition>().cloned();
+        match (req.version(), disposition) {
+            (http::Version::HTTP_10, Some(h1::Disposition::KeepAlive)) => {
+                val.push_str("; keep-alive");
+            }
+            (http::Version::HTTP_11, Some(h1::Disposition::Close)) => {
+                val.push_str("; close");
+            }
+            _ => {}
+        }
+
         req.headers_mut().insert(
             L5D_ORIG_PROTO,
-            HeaderValue::from_static(val)
+            HeaderValue::from_str(&val).expect("orig-proto must be a valid header value")
         );
 
         // transfer-encoding is illegal in HTTP2
@@ -110,10 +150,21 @@ impl<S> Downgrade<S> {
     where
         S: svc::Service<http::Request<A>, Response = http::Response<B>>,
     {
-        Self { inner }
+        Self {
+            inner,
+            registry: None,
+        }
     }
-}
 
+    /// Counts requests that cannot be faithfully represented in their
+    /// original protocol.
+    pub fn with_metrics(self, registry: Registry) -> Self {
+        Self {
+            registry: Some(registry),
+            ..self
+        }
+    }
+}
 
 impl<S, A, B> svc::Service<http::Request<A>> for Downgrade<S>
 where
@@ -151,6 +202,29 @@ where
                     );
                 }
 
+                // Restore the original connection disposition. HTTP/1.0
+                // requests are closed explicitly so that the client's
+                // expectation is honored regardless of the server's defaults.
+                let connection = if has_param(val, "close") {
+                    Some("close")
+                } else if has_param(val, "keep-alive") {
+                    Some("keep-alive")
+                } else if req.version() == http::Version::HTTP_10 {
+                    Some("close")
+                } else {
+                    None
+                };
+                if let Some(connection) = connection {
+                    req.headers_mut()
+                        .insert(CONNECTION, HeaderValue::from_static(connection));
+                }
+
+                if let Some(ref registry) = self.registry {
+                    if is_lossy_in_h1(&req) {
+                        registry.incr();
+                    }
+                }
+
                 if !was_absolute_form(val) {
                     h1::set_origin_form(req.uri_mut());
                 }
@@ -187,8 +261,251 @@ where
     }
 }
 
+/// Determines whether an HTTP2 request includes constructs that cannot be
+/// faithfully represented in HTTP/1.
+fn is_lossy_in_h1<B>(req: &http::Request<B>) -> bool {
+    // HTTP2 permits cookies to be split across fields, but HTTP/1 clients
+    // must send a single `cookie` header.
+    if req.headers().get_all(COOKIE).iter().count() > 1 {
+        debug!("downgrade is lossy: multiple cookie fields");
+        return true;
+    }
+
+    // Only one of the `:authority` and `host` may be sent over HTTP/1.
+    if let (Some(authority), Some(host)) = (req.uri().authority_part(), req.headers().get(HOST)) {
+        if authority.as_str().as_bytes() != host.as_bytes() {
+            debug!("downgrade is lossy: authority differs from host");
+            return true;
+        }
+    }
+
+    // Request trailers are not sent over HTTP/1.
+    if req.headers().contains_key(TRAILER) {
+        debug!("downgrade is lossy: request declares trailers");
+        return true;
+    }
+
+    false
+}
+
+// === impl Registry ===
+
+impl Registry {
+    fn incr(&self) {
+        if let Ok(mut lossy) = self.0.lock() {
+            lossy.incr();
+        }
+    }
+}
+
+// === impl Report ===
+
+impl FmtMetrics for Report {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lossy = match self.0.lock() {
+            Err(_) => return Ok(()),
+            Ok(lossy) => *lossy,
+        };
+
+        orig_proto_lossy_downgrade_total.fmt_help(f)?;
+        orig_proto_lossy_downgrade_total.fmt_metric(f, lossy)
+    }
+}
+
 fn was_absolute_form(val: &[u8]) -> bool {
-    val.len() >= "HTTP/1.1; absolute-form".len()
-        && &val[10..23] == b"absolute-form"
+    has_param(val, "absolute-form")
+}
+
+/// Determines whether an orig-proto header value, like
+/// `HTTP/1.1; absolute-form; close`, includes the given parameter.
+fn has_param(val: &[u8], param: &str) -> bool {
+    val.split(|b| *b == b';')
+        .skip(1)
+        .any(|p| trim(p) == param.as_bytes())
 }
 
+fn trim(mut bytes: &[u8]) -> &[u8] {
+    while let Some((b, rest)) = bytes.split_first() {
+        if *b != b' ' {
+            break;
+        }
+        bytes = rest;
+    }
+    while let Some((b, rest)) = bytes.split_last() {
+        if *b != b' ' {
+            break;
+        }
+        bytes = rest;
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{Async, Future};
+    use h2;
+    use tower_h2::Body;
+
+    use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+
+    use super::*;
+    use svc::Service as _Service;
+
+    /// A gRPC server that only speaks HTTP/1.
+    struct Server;
+
+    /// A response body that has trailers.
+    struct GrpcBody(Option<http::HeaderMap>);
+
+    impl svc::Service<http::Request<()>> for Server {
+        type Response = http::Response<GrpcBody>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, req: http::Request<()>) -> Self::Future {
+            assert_eq!(req.version(), http::Version::HTTP_11);
+
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+
+            let rsp = http::Response::builder()
+                .version(http::Version::HTTP_11)
+                .header(CONTENT_TYPE, "application/grpc+proto")
+                .header(CONTENT_LENGTH, "0")
+                .body(GrpcBody(Some(trailers)))
+                .unwrap();
+            future::ok(rsp)
+        }
+    }
+
+    impl Body for GrpcBody {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            self.0.is_none()
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::Ready(self.0.take()))
+        }
+    }
+
+    fn downgrade(mut req: http::request::Builder) -> u64 {
+        let (registry, report) = metrics();
+        let mut svc = Downgrade::new(Server).with_metrics(registry);
+
+        let req = req
+            .version(http::Version::HTTP_2)
+            .uri("http://svc.ns.svc.cluster.local/pkg.Service/Method")
+            .header(L5D_ORIG_PROTO, "HTTP/1.1")
+            .body(())
+            .unwrap();
+        svc.call(req).wait().expect("response");
+
+        let lossy = report.0.lock().unwrap().value();
+        lossy
+    }
+
+    #[test]
+    fn lossy_downgrades_are_counted() {
+        let mut cookies = http::Request::builder();
+        cookies.header(COOKIE, "a=1").header(COOKIE, "b=2");
+        assert_eq!(downgrade(cookies), 1);
+
+        let mut host = http::Request::builder();
+        host.header(HOST, "other.ns.svc.cluster.local");
+        assert_eq!(downgrade(host), 1);
+
+        let mut trailers = http::Request::builder();
+        trailers.header(TRAILER, "checksum");
+        assert_eq!(downgrade(trailers), 1);
+    }
+
+    #[test]
+    fn faithful_downgrades_are_not_counted() {
+        let mut req = http::Request::builder();
+        req.header(COOKIE, "a=1; b=2")
+            .header(HOST, "svc.ns.svc.cluster.local");
+        assert_eq!(downgrade(req), 0);
+    }
+
+    /// An HTTP/1 server that responds with the version and `connection`
+    /// header of each request it receives.
+    struct Http1Server;
+
+    impl svc::Service<http::Request<()>> for Http1Server {
+        type Response = http::Response<(http::Version, Option<HeaderValue>)>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, req: http::Request<()>) -> Self::Future {
+            let rsp = http::Response::builder()
+                .version(req.version())
+                .body((req.version(), req.headers().get(CONNECTION).cloned()))
+                .unwrap();
+            future::ok(rsp)
+        }
+    }
+
+    fn round_trip(
+        version: http::Version,
+        connection: Option<&'static str>,
+    ) -> (http::Version, Option<HeaderValue>) {
+        let mut svc = Upgrade::new(Downgrade::new(Http1Server));
+
+        let mut req = http::Request::builder()
+            .version(version)
+            .uri("/")
+            .body(())
+            .unwrap();
+        if let Some(connection) = connection {
+            req.headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static(connection));
+        }
+        // As the server does before requests are proxied.
+        h1::strip_connection_headers(&mut req);
+
+        let rsp = svc.call(req).wait().expect("response");
+        assert_eq!(rsp.version(), version);
+        rsp.into_body()
+    }
+
+    #[test]
+    fn h1_connection_disposition_is_preserved() {
+        let close = Some(HeaderValue::from_static("close"));
+        let keep_alive = Some(HeaderValue::from_static("keep-alive"));
+
+        let http10 = http::Version::HTTP_10;
+        assert_eq!(round_trip(http10, None), (http10, close.clone()));
+        assert_eq!(round_trip(http10, Some("close")), (http10, close.clone()));
+        assert_eq!(round_trip(http10, Some("keep-alive")), (http10, keep_alive));
+
+        let http11 = http::Version::HTTP_11;
+        assert_eq!(round_trip(http11, None), (http11, None));
+        assert_eq!(round_trip(http11, Some("keep-alive")), (http11, None));
+        assert_eq!(round_trip(http11, Some("close")), (http11, close));
+    }
+
+    #[test]
+    fn orig_proto_params() {
+        let val = b"HTTP/1.1; absolute-form; close";
+        assert!(was_absolute_form(val));
+        assert!(has_param(val, "close"));
+        assert!(!has_param(val, "keep-alive"));
+        assert!(!was_absolute_form(b"HTTP/1.0; keep-alive"));
+        assert!(!has_param(b"HTTP/1.0", "HTTP/1.0"));
+    }
+}
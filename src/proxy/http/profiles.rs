@@ -2,13 +2,15 @@
 
 extern crate tower_discover;
 
-use futures::Stream;
+use futures::{stream, Async, Future, Poll, Stream};
 use http;
 use indexmap::IndexMap;
 use regex::Regex;
 use std::iter::FromIterator;
 use std::sync::Arc;
-use std::{error, fmt};
+use std::time::{Duration, Instant};
+use std::{cmp, error, fmt};
+use tokio_timer::{clock, Delay};
 
 use NameAddr;
 
@@ -24,6 +26,37 @@ pub trait GetRoutes {
     fn get_routes(&self, dst: &NameAddr) -> Option<Self::Stream>;
 }
 
+/// Coalesces route updates that are received in quick succession.
+///
+/// An update is only published once no further update has been received for
+/// the debounce window, so that a flapping controller doesn't cause all of a
+/// destination's routes to be rebuilt for each update. An update is never
+/// delayed for more than `MAX_DEBOUNCE_WINDOWS` windows, so that constant
+/// flapping can't starve updates.
+#[derive(Clone, Debug)]
+pub struct Debounce<G> {
+    inner: G,
+    window: Duration,
+}
+
+pub struct DebounceStream<S> {
+    inner: stream::Fuse<S>,
+    window: Duration,
+    pending: Option<Pending>,
+}
+
+/// The latest unpublished update.
+struct Pending {
+    routes: Routes,
+    delay: Delay,
+    /// The time by which the update must be published, regardless of
+    /// further updates.
+    deadline: Instant,
+}
+
+/// The most debounce windows for which an update may be delayed.
+const MAX_DEBOUNCE_WINDOWS: u32 = 4;
+
 /// Implemented by target types that may be combined with a Route.
 pub trait WithRoute {
     type Output;
@@ -74,6 +107,89 @@ pub enum ResponseMatch {
     },
 }
 
+// === impl Debounce ===
+
+pub fn debounce<G: GetRoutes>(inner: G, window: Duration) -> Debounce<G> {
+    Debounce { inner, window }
+}
+
+impl<G: GetRoutes> GetRoutes for Debounce<G> {
+    type Stream = DebounceStream<G::Stream>;
+
+    fn get_routes(&self, dst: &NameAddr) -> Option<Self::Stream> {
+        let window = self.window;
+        self.inner
+            .get_routes(dst)
+            .map(|inner| DebounceStream::new(inner, window))
+    }
+}
+
+impl<S: Stream> DebounceStream<S> {
+    fn new(inner: S, window: Duration) -> Self {
+        Self {
+            inner: inner.fuse(),
+            window,
+            pending: None,
+        }
+    }
+}
+
+impl<S> Stream for DebounceStream<S>
+where
+    S: Stream<Item = Routes, Error = Error>,
+{
+    type Item = Routes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Routes>, Error> {
+        loop {
+            match self.inner.poll()? {
+                Async::Ready(Some(routes)) => {
+                    // Each update restarts the window, up to the deadline of
+                    // the first unpublished update.
+                    let now = clock::now();
+                    let deadline = self
+                        .pending
+                        .take()
+                        .map(|p| p.deadline)
+                        .unwrap_or_else(|| now + self.window * MAX_DEBOUNCE_WINDOWS);
+                    let delay = Delay::new(cmp::min(now + self.window, deadline));
+                    self.pending = Some(Pending {
+                        routes,
+                        delay,
+                        deadline,
+                    });
+                }
+                Async::Ready(None) => {
+                    // The inner stream is fused, so subsequent polls end
+                    // the stream once the pending update is published.
+                    let routes = self.pending.take().map(|p| p.routes);
+                    return Ok(Async::Ready(routes));
+                }
+                Async::NotReady => break,
+            }
+        }
+
+        let settled = match self.pending {
+            None => return Ok(Async::NotReady),
+            Some(Pending { ref mut delay, .. }) => match delay.poll() {
+                Ok(Async::NotReady) => false,
+                Ok(Async::Ready(())) => true,
+                Err(e) => {
+                    error!("route update timer failed: {}", e);
+                    true
+                }
+            },
+        };
+        if !settled {
+            return Ok(Async::NotReady);
+        }
+
+        let routes = self.pending.take().map(|p| p.routes);
+        Ok(Async::Ready(routes))
+    }
+}
+
 // === impl Route ===
 
 impl Route {
@@ -167,10 +283,16 @@ impl error::Error for Error {}
 /// underlying stack is buffered, and so `poll_ready` is NOT called on the routes
 /// before requests are dispatched. If an individual route wishes to apply
 /// backpressure, it must implement its own buffer/limit strategy.
+///
+/// Requests on the default route may be given a timeout, after which they are
+/// answered with a 504 Gateway Timeout. Destination profiles cannot configure
+/// timeouts, so requests on configured routes never time out.
 pub mod router {
-    use futures::{Async, Poll, Stream};
+    use futures::{Async, Future, Poll, Stream};
     use http;
+    use std::time::Duration;
     use std::{error, fmt};
+    use tokio_timer::{clock, Delay};
 
     use dns;
     use svc;
@@ -195,6 +317,7 @@ pub mod router {
             get_routes,
             route_layer,
             default_route: Route::default(),
+            default_timeout: None,
             _p: ::std::marker::PhantomData,
         }
     }
@@ -204,6 +327,7 @@ pub mod router {
         get_routes: G,
         route_layer: R,
         default_route: Route,
+        default_timeout: Option<Duration>,
         suffixes: Vec<dns::Suffix>,
         _p: ::std::marker::PhantomData<fn() -> M>,
     }
@@ -214,6 +338,7 @@ pub mod router {
         get_routes: G,
         route_layer: R,
         default_route: Route,
+        default_timeout: Option<Duration>,
         suffixes: Vec<dns::Suffix>,
     }
 
@@ -233,6 +358,12 @@ pub mod router {
         route_stream: Option<G>,
         routes: Vec<(RequestMatch, R::Value)>,
         default_route: R::Value,
+        default_timeout: Option<Duration>,
+    }
+
+    pub struct ResponseFuture<F> {
+        inner: F,
+        timeout: Option<Delay>,
     }
 
     impl<D: fmt::Display, R: fmt::Display> fmt::Display for Error<D, R> {
@@ -246,6 +377,17 @@ pub mod router {
 
     impl<D: error::Error, R: error::Error> error::Error for Error<D, R> {}
 
+    impl<G, M, R> Layer<G, M, R> {
+        /// Sets the timeout, if any, of requests that don't match a configured
+        /// route.
+        pub fn with_default_timeout(self, default_timeout: Option<Duration>) -> Self {
+            Self {
+                default_timeout,
+                ..self
+            }
+        }
+    }
+
     impl<T, G, M, R> svc::Layer<T, T, M> for Layer<G, M, R>
     where
         T: CanGetDestination + WithRoute + Clone,
@@ -268,6 +410,7 @@ pub mod router {
                 get_routes: self.get_routes.clone(),
                 route_layer: self.route_layer.clone(),
                 default_route: self.default_route.clone(),
+                default_timeout: self.default_timeout,
                 suffixes: self.suffixes.clone(),
             }
         }
@@ -318,6 +461,7 @@ pub mod router {
                 stack,
                 route_stream,
                 default_route,
+                default_timeout: self.default_timeout,
                 routes: Vec::new(),
             })
         }
@@ -347,16 +491,17 @@ pub mod router {
         }
     }
 
-    impl<G, T, R, B> svc::Service<http::Request<B>> for Service<G, T, R>
+    impl<G, T, R, B, RB> svc::Service<http::Request<B>> for Service<G, T, R>
     where
         G: Stream<Item = Routes, Error = super::Error>,
         T: WithRoute + Clone,
         R: svc::Stack<T::Output> + Clone,
-        R::Value: svc::Service<http::Request<B>>,
+        R::Value: svc::Service<http::Request<B>, Response = http::Response<RB>>,
+        RB: Default,
     {
-        type Response = <R::Value as svc::Service<http::Request<B>>>::Response;
+        type Response = http::Response<RB>;
         type Error = <R::Value as svc::Service<http::Request<B>>>::Error;
-        type Future = <R::Value as svc::Service<http::Request<B>>>::Future;
+        type Future = ResponseFuture<<R::Value as svc::Service<http::Request<B>>>::Future>;
 
         fn poll_ready(&mut self) -> Poll<(), Self::Error> {
             while let Some(Async::Ready(Some(routes))) = self.poll_route_stream() {
@@ -370,12 +515,262 @@ pub mod router {
             for (ref condition, ref mut service) in &mut self.routes {
                 if condition.is_match(&req) {
                     trace!("using configured route: {:?}", condition);
-                    return service.call(req);
+                    return ResponseFuture::new(service.call(req), None);
                 }
             }
 
             trace!("using default route");
-            self.default_route.call(req)
+            ResponseFuture::new(self.default_route.call(req), self.default_timeout)
+        }
+    }
+
+    // === impl ResponseFuture ===
+
+    impl<F> ResponseFuture<F> {
+        fn new(inner: F, timeout: Option<Duration>) -> Self {
+            Self {
+                inner,
+                timeout: timeout.map(|t| Delay::new(clock::now() + t)),
+            }
         }
     }
+
+    impl<F, B> Future for ResponseFuture<F>
+    where
+        F: Future<Item = http::Response<B>>,
+        B: Default,
+    {
+        type Item = F::Item;
+        type Error = F::Error;
+
+        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+            if let Async::Ready(rsp) = self.inner.poll()? {
+                return Ok(Async::Ready(rsp));
+            }
+
+            let expired = match self.timeout {
+                None => false,
+                Some(ref mut delay) => match delay.poll() {
+                    Ok(Async::NotReady) => false,
+                    Ok(Async::Ready(())) => true,
+                    Err(e) => {
+                        error!("route timer failed: {}", e);
+                        true
+                    }
+                },
+            };
+            if !expired {
+                return Ok(Async::NotReady);
+            }
+
+            debug!("route timed out");
+            let rsp = http::Response::builder()
+                .status(http::StatusCode::GATEWAY_TIMEOUT)
+                .body(B::default())
+                .expect("timeout response must be valid");
+            Ok(Async::Ready(rsp))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::time::Instant;
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::*;
+    use dns;
+    use never::Never;
+    use svc::{self, Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    /// A stream of route updates that are pushed by the test.
+    struct Updates(Rc<RefCell<VecDeque<Routes>>>);
+
+    impl Stream for Updates {
+        type Item = Routes;
+        type Error = Error;
+
+        fn poll(&mut self) -> Poll<Option<Routes>, Error> {
+            match self.0.borrow_mut().pop_front() {
+                Some(routes) => Ok(Async::Ready(Some(routes))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    /// Builds an update with `n` routes.
+    fn routes(n: usize) -> Routes {
+        (0..n)
+            .map(|_| (RequestMatch::Method(http::Method::GET), Route::default()))
+            .collect()
+    }
+
+    #[test]
+    fn updates_within_the_debounce_window_are_coalesced() {
+        const WINDOW: Duration = Duration::from_millis(50);
+
+        let updates = Rc::new(RefCell::new(VecDeque::new()));
+        let mut stream = DebounceStream::new(Updates(updates.clone()), WINDOW);
+        let mut rt = Runtime::new().unwrap();
+
+        for n in 1..4 {
+            updates.borrow_mut().push_back(routes(n));
+        }
+        let start = Instant::now();
+        let update = rt.block_on(future::poll_fn(|| stream.poll())).unwrap();
+        assert!(start.elapsed() >= WINDOW);
+        assert_eq!(update.map(|rs| rs.len()), Some(3), "only the last update is published");
+
+        let idle = rt
+            .block_on(future::lazy(|| Ok::<_, Error>(stream.poll())))
+            .unwrap()
+            .unwrap();
+        assert!(idle.is_not_ready(), "coalesced updates must be published once");
+
+        // Once settled, a new update is published after the window.
+        updates.borrow_mut().push_back(routes(1));
+        let update = rt.block_on(future::poll_fn(|| stream.poll())).unwrap();
+        assert_eq!(update.map(|rs| rs.len()), Some(1));
+    }
+
+    #[test]
+    fn constant_updates_are_published_after_the_max_delay() {
+        const WINDOW: Duration = Duration::from_millis(50);
+
+        /// Publishes an update every 10ms, more often than the window.
+        struct Flapping(Delay);
+
+        impl Stream for Flapping {
+            type Item = Routes;
+            type Error = Error;
+
+            fn poll(&mut self) -> Poll<Option<Routes>, Error> {
+                match self.0.poll() {
+                    Ok(Async::Ready(())) => {}
+                    _ => return Ok(Async::NotReady),
+                }
+                self.0 = Delay::new(clock::now() + Duration::from_millis(10));
+                Ok(Async::Ready(Some(routes(1))))
+            }
+        }
+
+        let mut rt = Runtime::new().unwrap();
+        let start = Instant::now();
+        let update = rt
+            .block_on(future::lazy(|| {
+                let mut stream = DebounceStream::new(Flapping(Delay::new(clock::now())), WINDOW);
+                future::poll_fn(move || stream.poll())
+            }))
+            .unwrap();
+        assert_eq!(update.map(|rs| rs.len()), Some(1));
+        assert!(start.elapsed() >= WINDOW * MAX_DEBOUNCE_WINDOWS);
+    }
+
+    #[test]
+    fn ended_streams_are_fused() {
+        struct Ended(bool);
+
+        impl Stream for Ended {
+            type Item = Routes;
+            type Error = Error;
+
+            fn poll(&mut self) -> Poll<Option<Routes>, Error> {
+                assert!(!self.0, "ended stream must not be polled");
+                self.0 = true;
+                Ok(Async::Ready(None))
+            }
+        }
+
+        let mut stream = DebounceStream::new(Ended(false), Duration::from_millis(50));
+        for _ in 0..2 {
+            match stream.poll() {
+                Ok(Async::Ready(None)) => {}
+                _ => panic!("stream must end"),
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct Target(NameAddr);
+
+    impl CanGetDestination for Target {
+        fn get_destination(&self) -> Option<&NameAddr> {
+            Some(&self.0)
+        }
+    }
+
+    impl WithRoute for Target {
+        type Output = Self;
+
+        fn with_route(self, _: Route) -> Self {
+            self
+        }
+    }
+
+    /// Serves routes that are pushed by the test.
+    #[derive(Clone)]
+    struct GetUpdates(Rc<RefCell<VecDeque<Routes>>>);
+
+    impl GetRoutes for GetUpdates {
+        type Stream = Updates;
+
+        fn get_routes(&self, _: &NameAddr) -> Option<Updates> {
+            Some(Updates(self.0.clone()))
+        }
+    }
+
+    /// Never responds.
+    #[derive(Clone, Debug)]
+    struct Pending;
+
+    impl svc::Stack<Target> for Pending {
+        type Value = Self;
+        type Error = Never;
+
+        fn make(&self, _: &Target) -> Result<Self, Never> {
+            Ok(Pending)
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Pending {
+        type Response = http::Response<()>;
+        type Error = Never;
+        type Future = future::Empty<Self::Response, Never>;
+
+        fn poll_ready(&mut self) -> Poll<(), Never> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            future::empty()
+        }
+    }
+
+    #[test]
+    fn default_route_times_out() {
+        let updates = Rc::new(RefCell::new(VecDeque::new()));
+        let stack = router::layer::<Target, _, Pending, _>(
+            vec![dns::Suffix::Root],
+            GetUpdates(updates),
+            (),
+        )
+        .with_default_timeout(Some(Duration::from_millis(10)))
+        .bind(Pending);
+        let target = Target(NameAddr::from_str("web.example.com:8080").unwrap());
+        let mut svc = stack.make(&target).ok().expect("router must be built");
+
+        let mut rt = Runtime::new().unwrap();
+        let rsp = rt
+            .block_on(future::lazy(move || {
+                assert!(svc.poll_ready().unwrap().is_ready());
+                svc.call(http::Request::new(()))
+            }))
+            .ok()
+            .expect("response");
+        assert_eq!(rsp.status(), http::StatusCode::GATEWAY_TIMEOUT);
+    }
 }
@@ -0,0 +1,397 @@
+//! Limits the rate at which HTTP requests are sent to each target.
+//!
+//! Each target is given a token bucket that holds up to `Limit::requests`
+//! tokens and is refilled at a rate of `requests` tokens per `interval`.
+//! Every request takes a token; requests for which no token is available
+//! fail with a 429 Too Many Requests response.
+//!
+//! Targets are identified by their metric labels, so services built for
+//! targets with the same labels (and all clones of those services) share a
+//! bucket.
+
+use futures::{Async, Future, Poll};
+use http;
+use indexmap::IndexMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_timer::clock;
+
+use metrics::FmtLabels;
+use svc;
+
+/// Provides the current time within the module. Useful for testing.
+pub trait Now {
+    fn now(&self) -> Instant;
+}
+
+/// The rate at which requests are permitted to each target.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Limit {
+    requests: u32,
+    interval: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Layer<N = ()> {
+    /// When `None`, requests are not limited.
+    limit: Option<Limit>,
+    now: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M, N = ()> {
+    limit: Option<Limit>,
+    now: N,
+    buckets: Arc<Mutex<IndexMap<String, Arc<Mutex<Bucket>>>>>,
+    inner: M,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S, N = ()> {
+    limit: Option<(Limit, Arc<Mutex<Bucket>>)>,
+    now: N,
+    inner: S,
+}
+
+pub struct ResponseFuture<F> {
+    /// When `None`, the request was rate limited.
+    inner: Option<F>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: u32,
+    /// The time at which tokens were last added to the bucket.
+    refilled_at: Instant,
+}
+
+/// Formats a target's labels so that they may be used as a bucket's key.
+struct Key<'t, T: 't>(&'t T);
+
+// === impl Limit ===
+
+impl Limit {
+    /// Permits up to `requests` requests to each target per `interval`.
+    pub fn new(requests: u32, interval: Duration) -> Self {
+        Self { requests, interval }
+    }
+
+    /// Returns how long it takes to add a single token to a bucket.
+    fn refill_period(&self) -> Duration {
+        self.interval / self.requests.max(1)
+    }
+}
+
+// === impl Layer ===
+
+pub fn layer(limit: Option<Limit>) -> Layer {
+    Layer { limit, now: () }
+}
+
+impl<T, M, N> svc::Layer<T, T, M> for Layer<N>
+where
+    T: FmtLabels,
+    M: svc::Stack<T>,
+    N: Now + Clone,
+{
+    type Value = <Stack<M, N> as svc::Stack<T>>::Value;
+    type Error = <Stack<M, N> as svc::Stack<T>>::Error;
+    type Stack = Stack<M, N>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            limit: self.limit,
+            now: self.now.clone(),
+            buckets: Arc::new(Mutex::new(IndexMap::new())),
+            inner,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M, N> svc::Stack<T> for Stack<M, N>
+where
+    T: FmtLabels,
+    M: svc::Stack<T>,
+    N: Now + Clone,
+{
+    type Value = Service<M::Value, N>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+
+        let limit = match (self.limit, self.buckets.lock()) {
+            (Some(limit), Ok(mut buckets)) => {
+                // Buckets are discarded once no services use them.
+                buckets.retain(|_, b| Arc::strong_count(b) > 1);
+
+                let now = self.now.now();
+                let bucket = buckets
+                    .entry(Key(target).to_string())
+                    .or_insert_with(|| {
+                        Arc::new(Mutex::new(Bucket {
+                            tokens: limit.requests,
+                            refilled_at: now,
+                        }))
+                    })
+                    .clone();
+                Some((limit, bucket))
+            }
+            _ => None,
+        };
+
+        Ok(Service {
+            limit,
+            now: self.now.clone(),
+            inner,
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, N, A, B> svc::Service<http::Request<A>> for Service<S, N>
+where
+    S: svc::Service<http::Request<A>, Response = http::Response<B>>,
+    B: Default,
+    N: Now,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let permitted = match self.limit {
+            Some((ref limit, ref bucket)) => bucket
+                .lock()
+                .map(|mut b| b.acquire(limit, self.now.now()))
+                .unwrap_or(true),
+            None => true,
+        };
+
+        if !permitted {
+            return ResponseFuture { inner: None };
+        }
+
+        ResponseFuture {
+            inner: Some(self.inner.call(req)),
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: Future<Item = http::Response<B>>,
+    B: Default,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner {
+            Some(ref mut f) => f.poll(),
+            None => {
+                debug!("rate limit exceeded");
+                let rsp = http::Response::builder()
+                    .status(http::StatusCode::TOO_MANY_REQUESTS)
+                    .body(B::default())
+                    .expect("rate limited response must be valid");
+                Ok(Async::Ready(rsp))
+            }
+        }
+    }
+}
+
+// === impl Bucket ===
+
+impl Bucket {
+    /// Takes a token from the bucket, if one is available.
+    fn acquire(&mut self, limit: &Limit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens == 0 {
+            return false;
+        }
+
+        self.tokens -= 1;
+        true
+    }
+
+    /// Adds the tokens that have accrued since the bucket was last refilled.
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        if self.tokens >= limit.requests {
+            self.refilled_at = now;
+            return;
+        }
+        if now <= self.refilled_at {
+            return;
+        }
+
+        let period = nanos(limit.refill_period());
+        let accrued = if period == 0 {
+            u64::from(limit.requests)
+        } else {
+            nanos(now - self.refilled_at) / period
+        };
+
+        let missing = limit.requests - self.tokens;
+        if accrued >= u64::from(missing) {
+            self.tokens = limit.requests;
+            self.refilled_at = now;
+        } else {
+            // Time that has not yet accrued a whole token is carried over.
+            let accrued = accrued as u32;
+            self.tokens += accrued;
+            self.refilled_at += limit.refill_period() * accrued;
+        }
+    }
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(u64::from(d.subsec_nanos()))
+}
+
+// === impl Key ===
+
+impl<'t, T: FmtLabels> fmt::Display for Key<'t, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_labels(f)
+    }
+}
+
+// === impl Now ===
+
+/// Default source of time.
+impl Now for () {
+    fn now(&self) -> Instant {
+        clock::now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use never::Never;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    /// A mocked instance of `Now` to drive tests.
+    #[derive(Clone)]
+    struct Clock(Rc<RefCell<Instant>>);
+
+    #[derive(Clone, Debug)]
+    struct Target(&'static str);
+
+    #[derive(Clone, Debug)]
+    struct MakeSvc;
+
+    #[derive(Clone, Debug)]
+    struct Svc;
+
+    impl Clock {
+        fn advance(&self, d: Duration) {
+            *self.0.borrow_mut() += d;
+        }
+    }
+
+    impl Now for Clock {
+        fn now(&self) -> Instant {
+            *self.0.borrow()
+        }
+    }
+
+    impl FmtLabels for Target {
+        fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "target=\"{}\"", self.0)
+        }
+    }
+
+    impl svc::Stack<Target> for MakeSvc {
+        type Value = Svc;
+        type Error = Never;
+
+        fn make(&self, _: &Target) -> Result<Svc, Never> {
+            Ok(Svc)
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Svc {
+        type Response = http::Response<()>;
+        type Error = Never;
+        type Future = future::FutureResult<Self::Response, Never>;
+
+        fn poll_ready(&mut self) -> Poll<(), Never> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            future::ok(http::Response::new(()))
+        }
+    }
+
+    fn status<S>(svc: &mut S) -> http::StatusCode
+    where
+        S: svc::Service<http::Request<()>, Response = http::Response<()>, Error = Never>,
+    {
+        svc.call(http::Request::new(())).wait().expect("response").status()
+    }
+
+    #[test]
+    fn excess_requests_are_limited_until_refilled() {
+        let clock = Clock(Rc::new(RefCell::new(Instant::now())));
+        let layer = Layer {
+            limit: Some(Limit::new(2, Duration::from_secs(1))),
+            now: clock.clone(),
+        };
+        let stack = layer.bind(MakeSvc);
+
+        // Clones of a service, and services for the same target, share a
+        // bucket.
+        let mut svc0 = stack.make(&Target("a")).unwrap();
+        let mut svc1 = svc0.clone();
+        let mut svc2 = stack.make(&Target("a")).unwrap();
+        assert_eq!(status(&mut svc0), http::StatusCode::OK);
+        assert_eq!(status(&mut svc1), http::StatusCode::OK);
+        assert_eq!(status(&mut svc2), http::StatusCode::TOO_MANY_REQUESTS);
+
+        // Other targets are limited independently.
+        let mut other = stack.make(&Target("b")).unwrap();
+        assert_eq!(status(&mut other), http::StatusCode::OK);
+
+        // A single token is added every half second.
+        clock.advance(Duration::from_millis(499));
+        assert_eq!(status(&mut svc0), http::StatusCode::TOO_MANY_REQUESTS);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(status(&mut svc0), http::StatusCode::OK);
+        assert_eq!(status(&mut svc1), http::StatusCode::TOO_MANY_REQUESTS);
+
+        // Once the full interval has elapsed, the bucket is full again, but
+        // tokens do not accrue beyond its capacity.
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(status(&mut svc0), http::StatusCode::OK);
+        assert_eq!(status(&mut svc1), http::StatusCode::OK);
+        assert_eq!(status(&mut svc2), http::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn requests_are_not_limited_without_a_limit() {
+        let stack = layer(None).bind(MakeSvc);
+        let mut svc = stack.make(&Target("a")).unwrap();
+        for _ in 0..100 {
+            assert_eq!(status(&mut svc), http::StatusCode::OK);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Ensures that each request carries an ID with which it may be correlated
+//! across hops.
+//!
+//! When a request lacks the configured header, a random (version 4) UUID is
+//! generated and inserted; otherwise the request's existing ID is propagated.
+
+use futures::Poll;
+use http;
+use http::header::{HeaderName, HeaderValue};
+use rand::{self, Rng};
+use std::fmt::Write;
+
+use svc;
+
+#[derive(Clone, Debug)]
+pub struct Layer {
+    /// When `None`, requests are not modified.
+    header: Option<HeaderName>,
+    overwrite: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    header: Option<HeaderName>,
+    overwrite: bool,
+    inner: M,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    header: Option<HeaderName>,
+    overwrite: bool,
+    inner: S,
+}
+
+// === impl Layer ===
+
+/// Ensures that each request has an ID in the `header` header, if one is
+/// configured.
+pub fn layer(header: Option<HeaderName>) -> Layer {
+    Layer {
+        header,
+        overwrite: false,
+    }
+}
+
+impl Layer {
+    /// Sets whether existing request IDs are replaced with generated IDs.
+    pub fn with_overwrite(self, overwrite: bool) -> Self {
+        Self { overwrite, ..self }
+    }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            header: self.header.clone(),
+            overwrite: self.overwrite,
+            inner,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service {
+            header: self.header.clone(),
+            overwrite: self.overwrite,
+            inner,
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, B> svc::Service<http::Request<B>> for Service<S>
+where
+    S: svc::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        if let Some(ref header) = self.header {
+            if self.overwrite || !req.headers().contains_key(header) {
+                let id = generate_id();
+                trace!("generated request id {:?}", id);
+                req.headers_mut().insert(header.clone(), id);
+            }
+        }
+
+        self.inner.call(req)
+    }
+}
+
+/// Generates a random (version 4) UUID.
+fn generate_id() -> HeaderValue {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let mut id = String::with_capacity(36);
+    for (i, b) in bytes.iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            id.push('-');
+        }
+        write!(id, "{:02x}", b).expect("writing to a string must succeed");
+    }
+
+    HeaderValue::from_str(&id).expect("UUID must be a valid header value")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future};
+
+    use super::*;
+    use never::Never;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    /// Responds with the request's ID header.
+    #[derive(Clone, Debug)]
+    struct Echo;
+
+    impl svc::Stack<()> for Echo {
+        type Value = Self;
+        type Error = Never;
+
+        fn make(&self, _: &()) -> Result<Self, Never> {
+            Ok(Echo)
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Echo {
+        type Response = Option<HeaderValue>;
+        type Error = Never;
+        type Future = future::FutureResult<Self::Response, Never>;
+
+        fn poll_ready(&mut self) -> Poll<(), Never> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, req: http::Request<()>) -> Self::Future {
+            future::ok(req.headers().get("l5d-request-id").cloned())
+        }
+    }
+
+    fn call(layer: Layer, id: Option<&'static str>) -> Option<HeaderValue> {
+        let mut svc = layer.bind(Echo).make(&()).unwrap();
+        let mut req = http::Request::new(());
+        if let Some(id) = id {
+            req.headers_mut().insert("l5d-request-id", HeaderValue::from_static(id));
+        }
+        svc.call(req).wait().unwrap()
+    }
+
+    fn header() -> Option<HeaderName> {
+        Some(HeaderName::from_static("l5d-request-id"))
+    }
+
+    #[test]
+    fn id_is_generated_when_absent() {
+        let value = call(layer(header()), None).expect("header must be set");
+        let uuid = value.to_str().unwrap();
+        assert_eq!(uuid.len(), 36, "{}", uuid);
+        assert_eq!(&uuid[14..15], "4", "{}", uuid);
+
+        let other = call(layer(header()), None);
+        assert_ne!(other.unwrap(), value, "ids must be unique");
+    }
+
+    #[test]
+    fn existing_id_is_preserved() {
+        let value = call(layer(header()), Some("abc123"));
+        assert_eq!(value.unwrap(), "abc123");
+    }
+
+    #[test]
+    fn existing_id_is_replaced_when_overwriting() {
+        let value = call(layer(header()).with_overwrite(true), Some("abc123"));
+        assert_ne!(value.unwrap(), "abc123");
+    }
+
+    #[test]
+    fn requests_are_unmodified_without_a_header() {
+        assert!(call(layer(None), None).is_none());
+    }
+}
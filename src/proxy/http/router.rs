@@ -1,24 +1,53 @@
 use futures::{Future, Poll};
 use h2;
 use http;
-use http::header::CONTENT_LENGTH;
+use http::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use indexmap::IndexMap;
 use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{error, fmt};
 
+use super::is_grpc;
+use metrics::{FmtLabels, FmtMetric, FmtMetrics, Gauge};
 use never::Never;
 use svc;
 
 extern crate linkerd2_router;
 
-use self::linkerd2_router::Error;
-pub use self::linkerd2_router::{Recognize, Router};
+use self::linkerd2_router::{Error, MakeRetry};
+pub use self::linkerd2_router::{Occupancy, Recognize, Router};
+
+metrics! {
+    router_cache_occupancy: Gauge { "Number of routes held in a router's cache" },
+    router_cache_capacity: Gauge { "Maximum number of routes a router's cache may hold" }
+}
+
+/// Constructs a Registry/Report pair for router cache metrics.
+pub fn metrics() -> (Registry, Report) {
+    let inner = Arc::new(Mutex::new(IndexMap::default()));
+    (Registry(inner.clone()), Report(inner))
+}
+
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+
+/// The `INTERNAL` gRPC status code.
+const GRPC_INTERNAL: &str = "13";
+
+/// The `UNAVAILABLE` gRPC status code.
+const GRPC_UNAVAILABLE: &str = "14";
+
+/// The default time that clients are asked to wait before retrying requests
+/// that were rejected because a router was at capacity.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Debug)]
 pub struct Config {
     capacity: usize,
     max_idle_age: Duration,
     proxy_name: &'static str,
+    retry_after: Duration,
 }
 
 /// A layer that that builds a routing service.
@@ -29,16 +58,28 @@ pub struct Config {
 #[derive(Clone, Debug)]
 pub struct Layer<Req, Rec: Recognize<Req>> {
     recognize: Rec,
+    registry: Option<Registry>,
     _p: PhantomData<fn() -> Req>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Stack<Req, Rec: Recognize<Req>, Stk> {
     recognize: Rec,
+    registry: Option<Registry>,
     inner: Stk,
     _p: PhantomData<fn() -> Req>,
 }
 
+/// Records the cache occupancy of each router built by a `Layer`.
+#[derive(Clone, Debug)]
+pub struct Registry(Arc<Mutex<IndexMap<&'static str, Occupancy>>>);
+
+/// Formats router cache metrics for Prometheus.
+#[derive(Clone, Debug)]
+pub struct Report(Arc<Mutex<IndexMap<&'static str, Occupancy>>>);
+
+struct Name(&'static str);
+
 pub struct Service<Req, Rec, Stk>
 where
     Rec: Recognize<Req>,
@@ -46,11 +87,15 @@ where
     Stk::Value: svc::Service<Req>,
 {
     inner: Router<Req, Rec, Stk>,
+    retry_after: HeaderValue,
 }
 
-/// Catches errors from the inner future and maps them to 500 responses.
+/// Catches errors from the inner future and maps them to 5XX responses or,
+/// for gRPC requests, to trailers-only responses with a gRPC status.
 pub struct ResponseFuture<F> {
     inner: F,
+    retry_after: HeaderValue,
+    grpc: bool,
 }
 
 // === impl Config ===
@@ -61,8 +106,28 @@ impl Config {
             proxy_name,
             capacity,
             max_idle_age,
+            retry_after: DEFAULT_RETRY_AFTER,
+        }
+    }
+
+    /// Sets the `Retry-After` delay advertised on responses to requests that
+    /// are rejected because the router is at capacity.
+    pub fn with_retry_after(self, retry_after: Duration) -> Self {
+        Self {
+            retry_after,
+            ..self
         }
     }
+
+    /// Formats the retry delay as a `Retry-After` value, in whole seconds
+    /// (rounded up).
+    fn retry_after_header(&self) -> HeaderValue {
+        let mut secs = self.retry_after.as_secs();
+        if self.retry_after.subsec_nanos() > 0 {
+            secs += 1;
+        }
+        HeaderValue::from(secs)
+    }
 }
 
 // Used for logging contexts
@@ -80,10 +145,24 @@ where
 {
     Layer {
         recognize,
+        registry: None,
         _p: PhantomData,
     }
 }
 
+impl<Req, Rec> Layer<Req, Rec>
+where
+    Rec: Recognize<Req>,
+{
+    /// Reports the cache occupancy of routers built by this layer.
+    pub fn with_metrics(self, registry: Registry) -> Self {
+        Self {
+            registry: Some(registry),
+            ..self
+        }
+    }
+}
+
 impl<Req, Rec, Stk, B> svc::Layer<Config, Rec::Target, Stk> for Layer<Req, Rec>
 where
     Rec: Recognize<Req> + Clone + Send + Sync + 'static,
@@ -101,6 +180,7 @@ where
         Stack {
             inner,
             recognize: self.recognize.clone(),
+            registry: self.registry.clone(),
             _p: PhantomData,
         }
     }
@@ -126,11 +206,74 @@ where
             self.inner.clone(),
             config.capacity,
             config.max_idle_age,
+            MakeRetry::disabled(),
         );
-        Ok(Service { inner })
+        if let Some(ref registry) = self.registry {
+            registry.register(config.proxy_name, inner.occupancy());
+        }
+        Ok(Service {
+            inner,
+            retry_after: config.retry_after_header(),
+        })
+    }
+}
+
+// === impl Registry ===
+
+impl Registry {
+    fn register(&self, name: &'static str, occupancy: Occupancy) {
+        if let Ok(mut routers) = self.0.lock() {
+            routers.insert(name, occupancy);
+        }
     }
 }
 
+// === impl Report ===
+
+impl FmtMetrics for Report {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let routers = match self.0.lock() {
+            Err(_) => return Ok(()),
+            Ok(routers) => routers,
+        };
+
+        if routers.is_empty() {
+            return Ok(());
+        }
+
+        router_cache_occupancy.fmt_help(f)?;
+        for (name, occupancy) in routers.iter() {
+            Gauge::from(occupancy.len() as u64).fmt_metric_labeled(
+                f,
+                router_cache_occupancy.name,
+                Name(*name),
+            )?;
+        }
+
+        router_cache_capacity.fmt_help(f)?;
+        for (name, occupancy) in routers.iter() {
+            Gauge::from(occupancy.capacity() as u64).fmt_metric_labeled(
+                f,
+                router_cache_capacity.name,
+                Name(*name),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FmtLabels for Name {
+    fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "router=\"{}\"", self.0)
+    }
+}
+
+/// Maps router errors to the status of the response returned in their place.
+///
+/// Requests that can't be recognized have no upstream to which they may be
+/// routed, so they fail with a 502; failures to build or call a route are
+/// internal errors.
 fn route_err_to_5xx<E, F>(e: Error<E, F>) -> http::StatusCode
 where
     E: error::Error,
@@ -147,31 +290,46 @@ where
         }
         Error::NotRecognized => {
             error!("could not recognize request");
-            http::StatusCode::INTERNAL_SERVER_ERROR
+            http::StatusCode::BAD_GATEWAY
         }
         Error::NoCapacity(capacity) => {
-            // TODO For H2 streams, we should probably signal a protocol-level
-            // capacity change.
+            // This is signaled with an HTTP-level 503 (with a `Retry-After`
+            // header) rather than an H2 protocol-level error, since requests
+            // may have been upgraded from (or will be downgraded to) HTTP/1,
+            // and stream resets do not survive that translation.
             error!("router at capacity ({})", capacity);
             http::StatusCode::SERVICE_UNAVAILABLE
         }
     }
 }
 
+/// Maps the status of an error response to the gRPC status returned in its
+/// place.
+///
+/// Requests that can't be routed may succeed if retried, so they are
+/// `UNAVAILABLE`; all other failures are `INTERNAL`.
+fn status_to_grpc(status: http::StatusCode) -> &'static str {
+    match status {
+        http::StatusCode::BAD_GATEWAY | http::StatusCode::SERVICE_UNAVAILABLE => GRPC_UNAVAILABLE,
+        _ => GRPC_INTERNAL,
+    }
+}
+
 // === impl Service ===
 
-impl<Req, Rec, Stk, B> svc::Service<Req> for Service<Req, Rec, Stk>
+impl<A, Rec, Stk, B> svc::Service<http::Request<A>> for Service<http::Request<A>, Rec, Stk>
 where
-    Rec: Recognize<Req> + Send + Sync + 'static,
+    Rec: Recognize<http::Request<A>> + Send + Sync + 'static,
     Stk: svc::Stack<Rec::Target> + Send + Sync + 'static,
-    Stk::Value: svc::Service<Req, Response = http::Response<B>>,
-    <Stk::Value as svc::Service<Req>>::Error: error::Error,
+    Stk::Value: svc::Service<http::Request<A>, Response = http::Response<B>>,
+    <Stk::Value as svc::Service<http::Request<A>>>::Error: error::Error,
     Stk::Error: fmt::Debug,
     B: Default + Send + 'static,
 {
-    type Response = <Router<Req, Rec, Stk> as svc::Service<Req>>::Response;
+    type Response = <Router<http::Request<A>, Rec, Stk> as svc::Service<http::Request<A>>>::Response;
     type Error = h2::Error;
-    type Future = ResponseFuture<<Router<Req, Rec, Stk> as svc::Service<Req>>::Future>;
+    type Future =
+        ResponseFuture<<Router<http::Request<A>, Rec, Stk> as svc::Service<http::Request<A>>>::Future>;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
         self.inner.poll_ready().map_err(|e| {
@@ -180,10 +338,15 @@ where
         })
     }
 
-    fn call(&mut self, request: Req) -> Self::Future {
+    fn call(&mut self, request: http::Request<A>) -> Self::Future {
         trace!("routing...");
+        let grpc = is_grpc(request.headers());
         let inner = self.inner.call(request);
-        ResponseFuture { inner }
+        ResponseFuture {
+            inner,
+            retry_after: self.retry_after.clone(),
+            grpc,
+        }
     }
 }
 
@@ -197,6 +360,7 @@ where
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            retry_after: self.retry_after.clone(),
         }
     }
 }
@@ -214,14 +378,185 @@ where
     type Error = h2::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let retry_after = &self.retry_after;
+        let grpc = self.grpc;
         self.inner.poll().or_else(|e| {
-            let response = http::Response::builder()
-                .status(route_err_to_5xx(e))
-                .header(CONTENT_LENGTH, "0")
-                .body(B::default())
-                .unwrap();
+            let at_capacity = match e {
+                Error::NoCapacity(_) => true,
+                _ => false,
+            };
+
+            let status = route_err_to_5xx(e);
+            let mut response = if grpc {
+                // gRPC clients expect failures to be signaled by a
+                // `grpc-status`, so a trailers-only response is returned.
+                http::Response::builder()
+                    .header(CONTENT_TYPE, "application/grpc")
+                    .header(GRPC_STATUS, status_to_grpc(status))
+                    .header(GRPC_MESSAGE, status.canonical_reason().unwrap_or(""))
+                    .body(B::default())
+                    .unwrap()
+            } else {
+                http::Response::builder()
+                    .status(status)
+                    .header(CONTENT_LENGTH, "0")
+                    .body(B::default())
+                    .unwrap()
+            };
+            if at_capacity {
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, retry_after.clone());
+            }
 
             Ok(response.into())
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::io;
+
+    use super::*;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    /// Responds with an empty response.
+    #[derive(Clone, Debug)]
+    struct Ok200;
+
+    impl svc::Stack<()> for Ok200 {
+        type Value = Self;
+        type Error = Never;
+
+        fn make(&self, _: &()) -> Result<Self, Never> {
+            Ok(Ok200)
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Ok200 {
+        type Response = http::Response<()>;
+        type Error = Never;
+        type Future = future::FutureResult<Self::Response, Never>;
+
+        fn poll_ready(&mut self) -> Poll<(), Never> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            future::ok(http::Response::new(()))
+        }
+    }
+
+    fn recognize(_: &http::Request<()>) -> Option<()> {
+        Some(())
+    }
+
+    type RecognizeFn = fn(&http::Request<()>) -> Option<()>;
+
+    fn router(config: &Config) -> Service<http::Request<()>, RecognizeFn, Ok200> {
+        layer::<_, http::Request<()>>(recognize as RecognizeFn)
+            .bind(Ok200)
+            .make(config)
+            .unwrap()
+    }
+
+    #[test]
+    fn no_capacity_is_unavailable_with_retry_after() {
+        let config = Config::new("test", 0, Duration::from_secs(60))
+            .with_retry_after(Duration::from_millis(2_500));
+        let mut router = router(&config);
+
+        let rsp = router.call(http::Request::new(())).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rsp.headers()[RETRY_AFTER], "3");
+    }
+
+    #[test]
+    fn routed_responses_do_not_retry_after() {
+        let config = Config::new("test", 1, Duration::from_secs(60));
+        let mut router = router(&config);
+
+        let rsp = router.call(http::Request::new(())).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+        assert!(rsp.headers().get(RETRY_AFTER).is_none());
+    }
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        type E = Error<io::Error, ()>;
+
+        let inner: E = Error::Inner(io::Error::new(io::ErrorKind::Other, "boom"));
+        assert_eq!(route_err_to_5xx(inner), http::StatusCode::INTERNAL_SERVER_ERROR);
+
+        let route: E = Error::Route(());
+        assert_eq!(route_err_to_5xx(route), http::StatusCode::INTERNAL_SERVER_ERROR);
+
+        let not_recognized: E = Error::NotRecognized;
+        assert_eq!(route_err_to_5xx(not_recognized), http::StatusCode::BAD_GATEWAY);
+
+        let no_capacity: E = Error::NoCapacity(10);
+        assert_eq!(route_err_to_5xx(no_capacity), http::StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    fn grpc_request() -> http::Request<()> {
+        http::Request::builder()
+            .header(CONTENT_TYPE, "application/grpc+proto")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn grpc_requests_at_capacity_are_unavailable() {
+        let config = Config::new("test", 0, Duration::from_secs(60));
+        let mut router = router(&config);
+
+        let rsp = router.call(grpc_request()).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+        assert_eq!(rsp.headers()[CONTENT_TYPE], "application/grpc");
+        assert_eq!(rsp.headers()[GRPC_STATUS], GRPC_UNAVAILABLE);
+        assert_eq!(rsp.headers()[RETRY_AFTER], "1");
+    }
+
+    #[test]
+    fn unrecognized_grpc_requests_are_unavailable() {
+        fn unrecognized(_: &http::Request<()>) -> Option<()> {
+            None
+        }
+
+        let config = Config::new("test", 1, Duration::from_secs(60));
+        let mut router = layer::<_, http::Request<()>>(unrecognized as RecognizeFn)
+            .bind(Ok200)
+            .make(&config)
+            .unwrap();
+
+        let rsp = router.call(grpc_request()).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+        assert_eq!(rsp.headers()[GRPC_STATUS], GRPC_UNAVAILABLE);
+    }
+
+    #[test]
+    fn errors_map_to_grpc_statuses() {
+        assert_eq!(status_to_grpc(http::StatusCode::BAD_GATEWAY), GRPC_UNAVAILABLE);
+        assert_eq!(status_to_grpc(http::StatusCode::SERVICE_UNAVAILABLE), GRPC_UNAVAILABLE);
+        assert_eq!(status_to_grpc(http::StatusCode::INTERNAL_SERVER_ERROR), GRPC_INTERNAL);
+    }
+
+    #[test]
+    fn unrecognized_requests_are_bad_gateway() {
+        fn unrecognized(_: &http::Request<()>) -> Option<()> {
+            None
+        }
+
+        let config = Config::new("test", 1, Duration::from_secs(60));
+        let mut router = layer::<_, http::Request<()>>(unrecognized as RecognizeFn)
+            .bind(Ok200)
+            .make(&config)
+            .unwrap();
+
+        let rsp = router.call(http::Request::new(())).wait().unwrap();
+        assert_eq!(rsp.status(), http::StatusCode::BAD_GATEWAY);
+        assert!(rsp.headers().get(RETRY_AFTER).is_none());
+    }
+}
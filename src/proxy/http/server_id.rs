@@ -0,0 +1,245 @@
+//! Annotates responses with the identity of the endpoint that served them
+//! and, optionally, the time the endpoint took to respond.
+//!
+//! The latency is reported in milliseconds, e.g. `12.345`, and includes the
+//! time spent in all inner layers (i.e. connecting to the endpoint).
+
+use futures::{Async, Future, Poll};
+use http;
+use http::header::{HeaderName, HeaderValue};
+use std::time::Instant;
+use tokio_timer::clock;
+
+use svc;
+
+/// Implemented by targets that identify the server that handles requests.
+pub trait HasServerId {
+    fn server_id(&self) -> Option<HeaderValue>;
+}
+
+#[derive(Clone, Debug)]
+pub struct Layer {
+    /// When `None`, the server ID is not reported.
+    server_id: Option<HeaderName>,
+    /// When `None`, latency is not reported.
+    latency: Option<HeaderName>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    server_id: Option<HeaderName>,
+    latency: Option<HeaderName>,
+    inner: M,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    server_id: Option<(HeaderName, HeaderValue)>,
+    latency: Option<HeaderName>,
+    inner: S,
+}
+
+pub struct ResponseFuture<F> {
+    inner: F,
+    server_id: Option<(HeaderName, HeaderValue)>,
+    latency: Option<(HeaderName, Instant)>,
+}
+
+// === impl Layer ===
+
+/// Adds the `server_id` and `latency` headers, when configured, to responses.
+pub fn layer(server_id: Option<HeaderName>, latency: Option<HeaderName>) -> Layer {
+    Layer { server_id, latency }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    T: HasServerId,
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            server_id: self.server_id.clone(),
+            latency: self.latency.clone(),
+            inner,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    T: HasServerId,
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        let server_id = self
+            .server_id
+            .as_ref()
+            .and_then(|name| target.server_id().map(|id| (name.clone(), id)));
+        Ok(Service {
+            server_id,
+            latency: self.latency.clone(),
+            inner,
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, A, B> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<A>, Response = http::Response<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let latency = self.latency.clone().map(|name| (name, clock::now()));
+        ResponseFuture {
+            inner: self.inner.call(req),
+            server_id: self.server_id.clone(),
+            latency,
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: Future<Item = http::Response<B>>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut rsp = try_ready!(self.inner.poll());
+
+        if let Some((name, id)) = self.server_id.take() {
+            rsp.headers_mut().insert(name, id);
+        }
+
+        if let Some((name, start)) = self.latency.take() {
+            let d = clock::now() - start;
+            let ms = d.as_secs() as f64 * 1_000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0;
+            let value = HeaderValue::from_str(&format!("{:.3}", ms))
+                .expect("latency must be a valid header value");
+            rsp.headers_mut().insert(name, value);
+        }
+
+        Ok(Async::Ready(rsp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_timer::Delay;
+
+    use super::*;
+    use never::Never;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    /// Identifies the server by name.
+    struct Target(&'static str);
+
+    impl HasServerId for Target {
+        fn server_id(&self) -> Option<HeaderValue> {
+            Some(HeaderValue::from_static(self.0))
+        }
+    }
+
+    /// Responds after a delay.
+    #[derive(Clone, Debug)]
+    struct Slow(Duration);
+
+    impl svc::Stack<Target> for Slow {
+        type Value = Self;
+        type Error = Never;
+
+        fn make(&self, _: &Target) -> Result<Self, Never> {
+            Ok(self.clone())
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Slow {
+        type Response = http::Response<()>;
+        type Error = ();
+        type Future = Box<Future<Item = http::Response<()>, Error = ()>>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            let rsp = Delay::new(clock::now() + self.0)
+                .map(|_| http::Response::new(()))
+                .map_err(|_| ());
+            Box::new(rsp)
+        }
+    }
+
+    fn send(layer: Layer, target: &'static str, delay: Duration) -> http::HeaderMap {
+        let mut svc = layer.bind(Slow(delay)).make(&Target(target)).unwrap();
+        let mut rt = Runtime::new().unwrap();
+        let rsp = rt
+            .block_on(future::lazy(|| svc.call(http::Request::new(()))))
+            .expect("response");
+        rsp.headers().clone()
+    }
+
+    fn name(n: &'static str) -> Option<HeaderName> {
+        Some(HeaderName::from_static(n))
+    }
+
+    #[test]
+    fn server_id_and_latency_are_reported() {
+        let delay = Duration::from_millis(50);
+        let layer = layer(name("l5d-server-id"), name("l5d-server-latency"));
+        let headers = send(layer, "web-0.web.ns.serviceaccount.identity", delay);
+
+        assert_eq!(
+            headers["l5d-server-id"],
+            "web-0.web.ns.serviceaccount.identity"
+        );
+
+        let ms = headers["l5d-server-latency"]
+            .to_str()
+            .unwrap()
+            .parse::<f64>()
+            .expect("latency must be a number");
+        assert!(ms >= 50.0, "latency={}", ms);
+        assert!(ms < 5_000.0, "latency={}", ms);
+    }
+
+    #[test]
+    fn latency_is_not_reported_unless_configured() {
+        let layer = layer(name("l5d-server-id"), None);
+        let headers = send(layer, "web-0", Duration::from_millis(1));
+        assert_eq!(headers["l5d-server-id"], "web-0");
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn responses_are_unmodified_without_headers() {
+        let headers = send(layer(None, None), "web-0", Duration::from_millis(1));
+        assert!(headers.is_empty());
+    }
+}
@@ -8,6 +8,13 @@ use http::{self, header::HOST};
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Settings {
     Http1 {
+        /// Whether the client's connections are kept alive between requests.
+        ///
+        /// HTTP/1.0 connections are closed after each response unless the
+        /// request explicitly asks for keep-alive; HTTP/1.1 connections are
+        /// kept alive unless the request explicitly asks for them to be
+        /// closed.
+        keep_alive: bool,
         /// Indicates whether a new service must be created for each request.
         stack_per_request: bool,
         /// Whether or not the request URI was in absolute form.
@@ -17,6 +24,12 @@ pub enum Settings {
         /// absolute URIs be bound to separate service stacks. It is also
         /// used to determine what URI normalization will be necessary.
         was_absolute_form: bool,
+        /// Whether the proxy may synthesize a `Host` header for requests
+        /// that lack one.
+        ///
+        /// A valid `Host` header is always forwarded verbatim; when this is
+        /// false, the proxy never modifies the `Host` header at all.
+        rewrite_host: bool,
     },
     Http2,
 }
@@ -27,7 +40,17 @@ impl Settings {
     // The router need only have enough capacity for each `Settings` variant.
     const ROUTER_CAPACITY: usize = 5;
 
-    pub fn from_request<B>(req: &http::Request<B>) -> Self {
+    /// Determines the settings for a request.
+    ///
+    /// HTTP/1 requests without an authority may be normalized to different
+    /// hosts, so each is bound to a new client stack unless
+    /// `reuse_without_authority` is set. In that case, such requests share the
+    /// client for their original destination.
+    pub fn from_request<B>(
+        req: &http::Request<B>,
+        rewrite_host: bool,
+        reuse_without_authority: bool,
+    ) -> Self {
         if req.version() == http::Version::HTTP_2 {
             return Settings::Http2;
         }
@@ -44,9 +67,18 @@ impl Settings {
             })
             .unwrap_or(true);
 
+        let disposition = req.extensions().get::<super::h1::Disposition>();
+        let keep_alive = match (req.version(), disposition) {
+            (_, Some(super::h1::Disposition::Close)) => false,
+            (_, Some(super::h1::Disposition::KeepAlive)) => true,
+            (version, None) => version != http::Version::HTTP_10,
+        };
+
         Settings::Http1 {
-            stack_per_request: is_missing_authority,
+            keep_alive,
+            stack_per_request: is_missing_authority && !reuse_without_authority,
             was_absolute_form: super::h1::is_absolute_form(req.uri()),
+            rewrite_host,
         }
     }
 
@@ -60,6 +92,22 @@ impl Settings {
         }
     }
 
+    /// Returns true if the client's connections should be kept alive.
+    pub fn keep_alive(&self) -> bool {
+        match self {
+            Settings::Http1 { keep_alive, .. } => *keep_alive,
+            Settings::Http2 => true,
+        }
+    }
+
+    /// Returns true if a `Host` header may be synthesized for the request.
+    pub fn rewrite_host(&self) -> bool {
+        match self {
+            Settings::Http1 { rewrite_host, .. } => *rewrite_host,
+            Settings::Http2 => false,
+        }
+    }
+
     pub fn can_reuse_clients(&self) -> bool {
         match self {
             Settings::Http1 {
@@ -96,10 +144,19 @@ pub mod router {
     }
 
     #[derive(Debug)]
-    pub struct Layer<T, B>(PhantomData<(T, fn(B))>);
+    pub struct Layer<T, B> {
+        rewrite_host: bool,
+        reuse_without_authority: bool,
+        _p: PhantomData<(T, fn(B))>,
+    }
 
     #[derive(Debug)]
-    pub struct Stack<B, M>(M, PhantomData<fn(B)>);
+    pub struct Stack<B, M> {
+        inner: M,
+        rewrite_host: bool,
+        reuse_without_authority: bool,
+        _p: PhantomData<fn(B)>,
+    }
 
     pub struct Service<B, M>
     where
@@ -123,17 +180,48 @@ pub mod router {
         Stack(M),
     }
 
-    pub struct Recognize(connect::Target);
+    pub struct Recognize {
+        target: connect::Target,
+        rewrite_host: bool,
+        reuse_without_authority: bool,
+    }
 
     type Router<B, M> = rt::Router<http::Request<B>, Recognize, M>;
 
     pub fn layer<T: HasConnect, B>() -> Layer<T, B> {
-        Layer(PhantomData)
+        Layer {
+            rewrite_host: false,
+            reuse_without_authority: false,
+            _p: PhantomData,
+        }
+    }
+
+    impl<T, B> Layer<T, B> {
+        /// Controls whether HTTP/1 clients may synthesize a `Host` header for
+        /// requests that lack one. By default, the `Host` header is never
+        /// modified.
+        pub fn with_rewrite_host(self, rewrite_host: bool) -> Self {
+            Self { rewrite_host, ..self }
+        }
+
+        /// Controls whether HTTP/1 requests without an authority share a
+        /// client for their original destination, rather than each being
+        /// bound to a new client.
+        pub fn with_reuse_without_authority(self, reuse_without_authority: bool) -> Self {
+            Self {
+                reuse_without_authority,
+                ..self
+            }
+        }
     }
 
     impl<T, B> Clone for Layer<T, B> {
         fn clone(&self) -> Self {
-            Layer(PhantomData)
+            Layer {
+                rewrite_host: self.rewrite_host,
+                reuse_without_authority: self.reuse_without_authority,
+                _p: PhantomData,
+            }
         }
     }
 
@@ -148,13 +236,23 @@ pub mod router {
         type Stack = Stack<B, M>;
 
         fn bind(&self, inner: M) -> Self::Stack {
-            Stack(inner, PhantomData)
+            Stack {
+                inner,
+                rewrite_host: self.rewrite_host,
+                reuse_without_authority: self.reuse_without_authority,
+                _p: PhantomData,
+            }
         }
     }
 
     impl<B, M: Clone> Clone for Stack<B, M> {
         fn clone(&self) -> Self {
-            Stack(self.0.clone(), PhantomData)
+            Stack {
+                inner: self.inner.clone(),
+                rewrite_host: self.rewrite_host,
+                reuse_without_authority: self.reuse_without_authority,
+                _p: PhantomData,
+            }
         }
     }
 
@@ -170,12 +268,18 @@ pub mod router {
         fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
             use std::time::Duration;
 
+            let recognize = Recognize {
+                target: target.connect(),
+                rewrite_host: self.rewrite_host,
+                reuse_without_authority: self.reuse_without_authority,
+            };
             let router = Router::new(
-                Recognize(target.connect()),
-                self.0.clone(),
+                recognize,
+                self.inner.clone(),
                 Settings::ROUTER_CAPACITY,
                 // Doesn't matter, since we are guaranteed to have enough capacity.
                 Duration::from_secs(0),
+                rt::MakeRetry::disabled(),
             );
 
             Ok(Service { router })
@@ -186,8 +290,9 @@ pub mod router {
         type Target = Config;
 
         fn recognize(&self, req: &http::Request<B>) -> Option<Self::Target> {
-            let settings = Settings::from_request(req);
-            Some(Config::new(self.0.clone(), settings))
+            let settings =
+                Settings::from_request(req, self.rewrite_host, self.reuse_without_authority);
+            Some(Config::new(self.target.clone(), settings))
         }
     }
 
@@ -262,4 +367,75 @@ pub mod router {
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::rt::Recognize as _Recognize;
+        use super::*;
+        use proxy::http::h1;
+        use transport::tls;
+        use Conditional;
+
+        fn recognize(reuse_without_authority: bool) -> Recognize {
+            let addr = ([10, 1, 2, 3], 8080).into();
+            let tls = Conditional::None(tls::ReasonForNoTls::Disabled);
+            Recognize {
+                target: connect::Target::new(addr, tls),
+                rewrite_host: true,
+                reuse_without_authority,
+            }
+        }
+
+        fn no_authority(path: &'static str) -> http::Request<()> {
+            http::Request::get(path).body(()).unwrap()
+        }
+
+        #[test]
+        fn requests_without_authority_share_a_client_when_enabled() {
+            let rec = recognize(true);
+            let a = rec.recognize(&no_authority("/a")).unwrap();
+            let b = rec.recognize(&no_authority("/b")).unwrap();
+            assert!(a.settings.can_reuse_clients());
+            assert_eq!(a, b);
+        }
+
+        #[test]
+        fn http10_is_distinct_from_http11() {
+            let rec = recognize(false);
+            let mut req = http::Request::get("http://example.com/").body(()).unwrap();
+
+            *req.version_mut() = http::Version::HTTP_10;
+            let http10 = rec.recognize(&req).unwrap();
+            assert!(!http10.settings.keep_alive());
+
+            *req.version_mut() = http::Version::HTTP_11;
+            let http11 = rec.recognize(&req).unwrap();
+            assert!(http11.settings.keep_alive());
+
+            assert_ne!(http10, http11);
+        }
+
+        #[test]
+        fn explicit_connection_disposition_is_honored() {
+            let rec = recognize(false);
+            let mut req = http::Request::get("http://example.com/").body(()).unwrap();
+
+            *req.version_mut() = http::Version::HTTP_10;
+            req.extensions_mut().insert(h1::Disposition::KeepAlive);
+            let http10 = rec.recognize(&req).unwrap();
+            assert!(http10.settings.keep_alive());
+
+            *req.version_mut() = http::Version::HTTP_11;
+            req.extensions_mut().insert(h1::Disposition::Close);
+            let http11 = rec.recognize(&req).unwrap();
+            assert!(!http11.settings.keep_alive());
+        }
+
+        #[test]
+        fn requests_without_authority_are_bound_per_request_by_default() {
+            let rec = recognize(false);
+            let a = rec.recognize(&no_authority("/a")).unwrap();
+            assert!(!a.settings.can_reuse_clients());
+        }
+    }
 }
@@ -0,0 +1,355 @@
+use futures::{Async, Future, Poll};
+use h2;
+use http;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_timer::clock;
+use tower_h2::Body;
+
+use svc;
+
+/// A stack module that limits the number of long-lived streams that may be
+/// open concurrently on each service it builds (i.e. per destination).
+///
+/// A stream is long-lived once it has been open for at least `threshold`.
+/// While `max_long_lived` streams are long-lived, new requests are refused
+/// with a 503 Service Unavailable response; streams that are already open
+/// are unaffected.
+#[derive(Clone, Copy, Debug)]
+pub struct Layer {
+    max_long_lived: usize,
+    threshold: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+    max_long_lived: usize,
+    threshold: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    inner: S,
+    streams: Streams,
+}
+
+pub struct ResponseFuture<F> {
+    /// When `None`, the stream was refused.
+    inner: Option<F>,
+    stream: Option<OpenStream>,
+}
+
+/// Holds a stream open until the response body completes.
+pub struct ResponseBody<B> {
+    inner: B,
+    stream: Option<OpenStream>,
+}
+
+#[derive(Clone, Debug)]
+struct Streams(Arc<Mutex<Shared>>);
+
+#[derive(Debug)]
+struct Shared {
+    max_long_lived: usize,
+    threshold: Duration,
+    next_id: usize,
+    /// The number of open streams that are known to be long-lived.
+    long_lived: usize,
+    /// The time at which each other open stream was opened.
+    ///
+    /// Because ids are allocated in order, streams are ordered from oldest to
+    /// newest, so streams that have become long-lived are always first.
+    young: BTreeMap<usize, Instant>,
+}
+
+/// Closes a stream when dropped.
+#[derive(Debug)]
+struct OpenStream {
+    id: usize,
+    streams: Streams,
+}
+
+// === impl Layer ===
+
+pub fn layer(max_long_lived: usize, threshold: Duration) -> Layer {
+    Layer {
+        max_long_lived,
+        threshold,
+    }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            inner,
+            max_long_lived: self.max_long_lived,
+            threshold: self.threshold,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        let shared = Shared {
+            max_long_lived: self.max_long_lived,
+            threshold: self.threshold,
+            next_id: 0,
+            long_lived: 0,
+            young: BTreeMap::new(),
+        };
+        Ok(Service {
+            inner,
+            streams: Streams(Arc::new(Mutex::new(shared))),
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, A, B> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<A>, Response = http::Response<B>>,
+    B: Body + Default,
+{
+    type Response = http::Response<ResponseBody<B>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        match self.streams.open() {
+            Some(stream) => ResponseFuture {
+                inner: Some(self.inner.call(req)),
+                stream: Some(stream),
+            },
+            None => ResponseFuture {
+                inner: None,
+                stream: None,
+            },
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: Future<Item = http::Response<B>>,
+    B: Body + Default,
+{
+    type Item = http::Response<ResponseBody<B>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rsp = match self.inner {
+            Some(ref mut f) => try_ready!(f.poll()),
+            None => {
+                warn!("long-lived stream limit reached; refusing stream");
+                http::Response::builder()
+                    .status(http::StatusCode::SERVICE_UNAVAILABLE)
+                    .body(B::default())
+                    .expect("refused response must be valid")
+            }
+        };
+
+        let stream = self.stream.take();
+        Ok(Async::Ready(rsp.map(|inner| ResponseBody { inner, stream })))
+    }
+}
+
+// === impl ResponseBody ===
+
+impl<B: Default> Default for ResponseBody<B> {
+    fn default() -> Self {
+        ResponseBody {
+            inner: B::default(),
+            stream: None,
+        }
+    }
+}
+
+impl<B: Body> Body for ResponseBody<B> {
+    type Data = B::Data;
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, h2::Error> {
+        let data = try_ready!(self.inner.poll_data());
+        if self.inner.is_end_stream() {
+            self.stream = None;
+        }
+        Ok(Async::Ready(data))
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+        let trailers = try_ready!(self.inner.poll_trailers());
+        self.stream = None;
+        Ok(Async::Ready(trailers))
+    }
+}
+
+// === impl Streams ===
+
+impl Streams {
+    /// Opens a new stream, unless too many streams are long-lived.
+    fn open(&self) -> Option<OpenStream> {
+        let mut shared = self.0.lock().expect("stream limit lock poisoned");
+
+        let now = clock::now();
+        shared.promote_long_lived(now);
+        if shared.long_lived >= shared.max_long_lived {
+            return None;
+        }
+
+        let id = shared.next_id;
+        shared.next_id = shared.next_id.wrapping_add(1);
+        shared.young.insert(id, now);
+        Some(OpenStream {
+            id,
+            streams: self.clone(),
+        })
+    }
+}
+
+// === impl Shared ===
+
+impl Shared {
+    /// Counts the streams that have become long-lived since the last call.
+    ///
+    /// Each stream is promoted at most once, so this is amortized constant
+    /// time per stream.
+    fn promote_long_lived(&mut self, now: Instant) {
+        loop {
+            let oldest = match self.young.iter().next() {
+                Some((&id, &opened_at)) if opened_at + self.threshold <= now => id,
+                _ => return,
+            };
+            self.young.remove(&oldest);
+            self.long_lived += 1;
+        }
+    }
+}
+
+// === impl OpenStream ===
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = (self.streams).0.lock() {
+            if shared.young.remove(&self.id).is_none() {
+                shared.long_lived -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+
+    use super::*;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    /// Serves streams that never end.
+    struct Streaming;
+
+    /// A response body that is never complete.
+    #[derive(Default)]
+    struct Open;
+
+    impl Body for Open {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            false
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            Ok(Async::NotReady)
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    impl svc::Stack<()> for Streaming {
+        type Value = Streaming;
+        type Error = ();
+
+        fn make(&self, _: &()) -> Result<Streaming, ()> {
+            Ok(Streaming)
+        }
+    }
+
+    impl svc::Service<http::Request<()>> for Streaming {
+        type Response = http::Response<Open>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            future::ok(http::Response::new(Open))
+        }
+    }
+
+    fn open(svc: &mut Service<Streaming>) -> http::Response<ResponseBody<Open>> {
+        svc.call(http::Request::new(())).wait().expect("response")
+    }
+
+    #[test]
+    fn long_lived_streams_are_limited() {
+        // Every stream is immediately long-lived.
+        let stack = layer(2, Duration::from_secs(0)).bind(Streaming);
+        let mut svc = stack.make(&()).unwrap();
+
+        let rsp0 = open(&mut svc);
+        let rsp1 = open(&mut svc);
+        assert_eq!(rsp0.status(), http::StatusCode::OK);
+        assert_eq!(rsp1.status(), http::StatusCode::OK);
+
+        // Once the limit is reached, new streams are refused.
+        assert_eq!(open(&mut svc).status(), http::StatusCode::SERVICE_UNAVAILABLE);
+
+        // Closing a long-lived stream frees capacity.
+        drop(rsp0);
+        assert_eq!(open(&mut svc).status(), http::StatusCode::OK);
+    }
+
+    #[test]
+    fn short_lived_streams_are_not_limited() {
+        let stack = layer(1, Duration::from_secs(60)).bind(Streaming);
+        let mut svc = stack.make(&()).unwrap();
+
+        let rsps = (0..4).map(|_| open(&mut svc)).collect::<Vec<_>>();
+        for rsp in &rsps {
+            assert_eq!(rsp.status(), http::StatusCode::OK);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Removes the proxy's internal `l5d-*` headers from requests.
+//!
+//! Endpoints outside of the mesh have no use for these headers, and they
+//! should not leak to them.
+
+use futures::Poll;
+use http;
+use http::header::HeaderName;
+
+use svc;
+
+const L5D_PREFIX: &str = "l5d-";
+
+/// Strips `l5d-*` headers from requests before they are sent to the inner
+/// service.
+#[derive(Clone, Debug)]
+pub struct Strip<S> {
+    inner: S,
+}
+
+// === impl Strip ===
+
+impl<S> Strip<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, B> svc::Service<http::Request<B>> for Strip<S>
+where
+    S: svc::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        let names = req
+            .headers()
+            .keys()
+            .filter(|n| is_l5d(n))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in names {
+            trace!("stripping {} header", name);
+            req.headers_mut().remove(name);
+        }
+
+        self.inner.call(req)
+    }
+}
+
+fn is_l5d(name: &HeaderName) -> bool {
+    // Header names are always lowercase.
+    name.as_str().starts_with(L5D_PREFIX)
+}
@@ -0,0 +1,377 @@
+use futures::{Async, Future, Poll};
+use http;
+use http::header::HeaderValue;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_timer::clock;
+
+use svc;
+
+/// The response header in which a request's latency breakdown is reported.
+pub const BREAKDOWN_HEADER: &str = "l5d-latency-breakdown";
+
+/// The name under which time not attributed to any layer is reported.
+const UNATTRIBUTED: &str = "proxy";
+
+/// A stack module that records the time each request spends within a layer of
+/// the proxy.
+///
+/// A `root` layer, when enabled, annotates each request with a `Breakdown`.
+/// Each layer built with `layer` then records when the request entered it and
+/// when its response left it. Once the response is available to the root, the
+/// time spent exclusively in each layer (i.e. excluding the time spent in
+/// inner layers) is reported in the `BREAKDOWN_HEADER` response header, e.g.:
+///
+/// ```text
+/// l5d-latency-breakdown: proxy;dur=0.051, balance;dur=0.012, tap;dur=3.208
+/// ```
+///
+/// Durations are in milliseconds and sum to the total time the root waited
+/// for the response. Requests without a `Breakdown` are not instrumented.
+///
+/// When breakdowns are disabled, each layer returns its inner stack's values
+/// unchanged.
+#[derive(Clone, Copy, Debug)]
+pub struct Layer {
+    kind: Kind,
+    enabled: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+    kind: Kind,
+    enabled: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    inner: S,
+    kind: Kind,
+}
+
+pub struct ResponseFuture<F> {
+    inner: F,
+    /// Set when this layer created the request's breakdown.
+    breakdown: Option<Breakdown>,
+    /// Set when this layer participates in an existing breakdown.
+    span: Option<Span>,
+}
+
+/// Records when a request entered and left each instrumented layer.
+///
+/// Stored in each instrumented request's extensions.
+#[derive(Clone, Debug)]
+pub struct Breakdown(Arc<Mutex<Spans>>);
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Root,
+    Layer(&'static str),
+}
+
+#[derive(Debug)]
+struct Spans {
+    start: Instant,
+    /// Spans in the order in which the request entered each layer.
+    ///
+    /// Because layers are nested, each span contains all subsequent spans.
+    spans: Vec<(&'static str, Instant, Option<Instant>)>,
+}
+
+/// Marks the end of a layer's span when the response leaves the layer.
+struct Span {
+    breakdown: Breakdown,
+    index: usize,
+}
+
+// === impl Layer ===
+
+/// Instruments the time requests spend within the layers below this one,
+/// when `enabled`.
+pub fn layer(name: &'static str, enabled: bool) -> Layer {
+    Layer {
+        kind: Kind::Layer(name),
+        enabled,
+    }
+}
+
+/// Creates a latency breakdown for each request, when `enabled`.
+pub fn root(enabled: bool) -> Layer {
+    Layer {
+        kind: Kind::Root,
+        enabled,
+    }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            inner,
+            kind: self.kind,
+            enabled: self.enabled,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = svc::Either<Service<M::Value>, M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        if self.enabled {
+            Ok(svc::Either::A(Service {
+                inner,
+                kind: self.kind,
+            }))
+        } else {
+            Ok(svc::Either::B(inner))
+        }
+    }
+}
+
+// === impl Service ===
+
+impl<S, A, B> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<A>, Response = http::Response<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, mut req: http::Request<A>) -> Self::Future {
+        let (breakdown, span) = match self.kind {
+            Kind::Root => {
+                let breakdown = Breakdown::new();
+                req.extensions_mut().insert(breakdown.clone());
+                (Some(breakdown), None)
+            }
+            Kind::Layer(name) => {
+                let span = req
+                    .extensions()
+                    .get::<Breakdown>()
+                    .map(|b| b.enter(name));
+                (None, span)
+            }
+        };
+
+        ResponseFuture {
+            inner: self.inner.call(req),
+            breakdown,
+            span,
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: Future<Item = http::Response<B>>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let poll = self.inner.poll();
+        if let Ok(Async::NotReady) = poll {
+            return Ok(Async::NotReady);
+        }
+        if let Some(span) = self.span.take() {
+            span.exit();
+        }
+        let mut rsp = try_ready!(poll);
+
+        if let Some(breakdown) = self.breakdown.take() {
+            let report = breakdown.report();
+            debug!("latency breakdown: {}", report);
+            if let Ok(v) = HeaderValue::from_str(&report) {
+                rsp.headers_mut().insert(BREAKDOWN_HEADER, v);
+            }
+        }
+
+        Ok(Async::Ready(rsp))
+    }
+}
+
+// === impl Breakdown ===
+
+impl Breakdown {
+    fn new() -> Self {
+        let spans = Spans {
+            start: clock::now(),
+            spans: Vec::new(),
+        };
+        Breakdown(Arc::new(Mutex::new(spans)))
+    }
+
+    fn enter(&self, name: &'static str) -> Span {
+        let mut spans = self.0.lock().expect("latency breakdown lock poisoned");
+        let index = spans.spans.len();
+        spans.spans.push((name, clock::now(), None));
+        Span {
+            breakdown: self.clone(),
+            index,
+        }
+    }
+
+    /// Formats the time spent exclusively within each layer.
+    fn report(&self) -> String {
+        let spans = self.0.lock().expect("latency breakdown lock poisoned");
+        let now = clock::now();
+
+        // The total time spent within each span, including inner spans.
+        let inclusive = spans
+            .spans
+            .iter()
+            .map(|&(name, enter, exit)| (name, exit.unwrap_or(now) - enter))
+            .collect::<Vec<_>>();
+
+        let total = now - spans.start;
+        let first = inclusive.first().map(|&(_, d)| d).unwrap_or_default();
+        let mut report = String::new();
+        fmt_duration(&mut report, UNATTRIBUTED, saturating_sub(total, first));
+        for (i, &(name, d)) in inclusive.iter().enumerate() {
+            let inner = inclusive.get(i + 1).map(|&(_, d)| d).unwrap_or_default();
+            report.push_str(", ");
+            fmt_duration(&mut report, name, saturating_sub(d, inner));
+        }
+        report
+    }
+}
+
+fn saturating_sub(a: Duration, b: Duration) -> Duration {
+    a.checked_sub(b).unwrap_or_default()
+}
+
+fn fmt_duration(out: &mut String, name: &str, d: Duration) {
+    let ms = d.as_secs() as f64 * 1_000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0;
+    let _ = write!(out, "{};dur={:.3}", name, ms);
+}
+
+// === impl Span ===
+
+impl Span {
+    fn exit(self) {
+        if let Ok(mut spans) = self.breakdown.0.lock() {
+            if let Some(span) = spans.spans.get_mut(self.index) {
+                span.2 = Some(clock::now());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::thread;
+
+    use super::*;
+    use svc::{Layer as _Layer, Service as _Service, Stack as _Stack};
+
+    /// Takes `delay` to respond.
+    struct Slow {
+        delay: Duration,
+    }
+
+    impl svc::Service<http::Request<()>> for Slow {
+        type Response = http::Response<()>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            thread::sleep(self.delay);
+            future::ok(http::Response::new(()))
+        }
+    }
+
+    fn svc<S>(kind: Kind, inner: S) -> Service<S> {
+        Service { inner, kind }
+    }
+
+    /// Parses a breakdown header into per-layer durations.
+    fn parse(rsp: &http::Response<()>) -> Vec<(String, Duration)> {
+        let report = rsp
+            .headers()
+            .get(BREAKDOWN_HEADER)
+            .expect("breakdown header")
+            .to_str()
+            .unwrap();
+        report
+            .split(", ")
+            .map(|part| {
+                let mut kv = part.splitn(2, ";dur=");
+                let name = kv.next().unwrap().to_owned();
+                let ms = kv.next().unwrap().parse::<f64>().unwrap();
+                (name, Duration::from_micros((ms * 1_000.0) as u64))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn breakdown_sums_to_total_latency() {
+        let delay = Duration::from_millis(20);
+        let inner = svc(Kind::Layer("inner"), Slow { delay });
+        let outer = svc(Kind::Layer("outer"), inner);
+        let mut root = svc(Kind::Root, outer);
+
+        let start = Instant::now();
+        let rsp = root.call(http::Request::new(())).wait().expect("response");
+        let total = start.elapsed();
+
+        let breakdown = parse(&rsp);
+        let names = breakdown.iter().map(|&(ref n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec![UNATTRIBUTED, "outer", "inner"]);
+
+        // The slow service's latency is attributed to the innermost layer.
+        assert!(breakdown[2].1 >= delay);
+
+        // Each duration is truncated to the microsecond when formatted.
+        let sum = breakdown.iter().fold(Duration::from_secs(0), |sum, &(_, d)| sum + d);
+        assert!(sum <= total, "{:?} > {:?}", sum, total);
+        assert!(total - sum < Duration::from_millis(5), "{:?} vs {:?}", sum, total);
+    }
+
+    #[test]
+    fn disabled_layers_are_not_stacked() {
+        let stack = svc::shared::stack(Instant::now());
+        let stack = root(false).bind(layer("inner", false).bind(stack));
+        match stack.make(&()).expect("make") {
+            svc::Either::B(svc::Either::B(_)) => {}
+            _ => panic!("disabled layers must return the inner value"),
+        }
+
+        let stack = svc::shared::stack(Instant::now());
+        let stack = root(true).bind(layer("inner", true).bind(stack));
+        match stack.make(&()).expect("make") {
+            svc::Either::A(Service {
+                inner: svc::Either::A(_),
+                ..
+            }) => {}
+            _ => panic!("enabled layers must be stacked"),
+        }
+    }
+}
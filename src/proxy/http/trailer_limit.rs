@@ -0,0 +1,301 @@
+use futures::{Future, Poll};
+use h2;
+use http;
+use tower_h2::Body;
+
+use svc;
+
+/// A stack module that limits the number of trailer fields, and the total
+/// size of the trailers, on both requests and responses.
+///
+/// When a body's trailers exceed either limit, the stream is reset rather
+/// than forwarding the trailers.
+#[derive(Clone, Copy, Debug)]
+pub struct Layer {
+    limits: Limits,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+    limits: Limits,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    inner: S,
+    limits: Limits,
+}
+
+pub struct ResponseFuture<F> {
+    inner: F,
+    limits: Limits,
+}
+
+/// Enforces trailer limits on an inner request or response body.
+#[derive(Debug)]
+pub struct LimitBody<B> {
+    inner: B,
+    limits: Limits,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    max_fields: usize,
+    /// The maximum total length of all trailer names and values.
+    max_bytes: usize,
+}
+
+// === impl Layer ===
+
+pub fn layer(max_fields: usize, max_bytes: usize) -> Layer {
+    Layer {
+        limits: Limits {
+            max_fields,
+            max_bytes,
+        },
+    }
+}
+
+impl<T, M> svc::Layer<T, T, M> for Layer
+where
+    M: svc::Stack<T>,
+{
+    type Value = <Stack<M> as svc::Stack<T>>::Value;
+    type Error = <Stack<M> as svc::Stack<T>>::Error;
+    type Stack = Stack<M>;
+
+    fn bind(&self, inner: M) -> Self::Stack {
+        Stack {
+            inner,
+            limits: self.limits,
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service {
+            inner,
+            limits: self.limits,
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, A, B> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<LimitBody<A>>, Response = http::Response<B>>,
+    A: Body,
+    B: Body,
+{
+    type Response = http::Response<LimitBody<B>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let limits = self.limits;
+        let req = req.map(|inner| LimitBody { inner, limits });
+        ResponseFuture {
+            inner: self.inner.call(req),
+            limits,
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: Future<Item = http::Response<B>>,
+    B: Body,
+{
+    type Item = http::Response<LimitBody<B>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rsp = try_ready!(self.inner.poll());
+        let limits = self.limits;
+        Ok(rsp.map(|inner| LimitBody { inner, limits }).into())
+    }
+}
+
+// === impl LimitBody ===
+
+impl<B: Default> Default for LimitBody<B> {
+    fn default() -> Self {
+        // Default bodies are produced by the proxy, so they are not limited.
+        LimitBody {
+            inner: B::default(),
+            limits: Limits {
+                max_fields: usize::max_value(),
+                max_bytes: usize::max_value(),
+            },
+        }
+    }
+}
+
+impl<B: Body> Body for LimitBody<B> {
+    type Data = B::Data;
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, h2::Error> {
+        self.inner.poll_data()
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+        let trailers = try_ready!(self.inner.poll_trailers());
+        if let Some(ref trailers) = trailers {
+            self.limits.check(trailers)?;
+        }
+        Ok(trailers.into())
+    }
+}
+
+// === impl Limits ===
+
+impl Limits {
+    fn check(&self, trailers: &http::HeaderMap) -> Result<(), h2::Error> {
+        let fields = trailers.len();
+        if fields > self.max_fields {
+            warn!(
+                "resetting stream with {} trailer fields; limit={}",
+                fields, self.max_fields
+            );
+            return Err(h2::Reason::PROTOCOL_ERROR.into());
+        }
+
+        let bytes = trailers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>();
+        if bytes > self.max_bytes {
+            warn!(
+                "resetting stream with {}B of trailers; limit={}B",
+                bytes, self.max_bytes
+            );
+            return Err(h2::Reason::PROTOCOL_ERROR.into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{future, Async};
+
+    use super::*;
+    use svc::Service as _Service;
+
+    /// A body with no data, followed by the given trailers.
+    #[derive(Default)]
+    struct TrailersBody(Option<http::HeaderMap>);
+
+    /// Echoes the request's trailers in its response.
+    struct Echo;
+
+    impl Body for TrailersBody {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            self.0.is_none()
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::Ready(self.0.take()))
+        }
+    }
+
+    impl svc::Service<http::Request<LimitBody<TrailersBody>>> for Echo {
+        type Response = http::Response<TrailersBody>;
+        type Error = h2::Error;
+        type Future = future::FutureResult<Self::Response, h2::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), h2::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, req: http::Request<LimitBody<TrailersBody>>) -> Self::Future {
+            let mut body = req.into_body();
+            let trailers = match body.poll_trailers() {
+                Ok(Async::Ready(trailers)) => trailers,
+                Ok(Async::NotReady) => panic!("trailers must be ready"),
+                Err(e) => return future::err(e),
+            };
+            future::ok(http::Response::new(TrailersBody(trailers)))
+        }
+    }
+
+    fn trailers(n: usize) -> http::HeaderMap {
+        let mut trailers = http::HeaderMap::new();
+        for i in 0..n {
+            let name = format!("x-trailer-{}", i);
+            let name = http::header::HeaderName::from_bytes(name.as_bytes()).unwrap();
+            trailers.insert(name, "value".parse().unwrap());
+        }
+        trailers
+    }
+
+    fn svc() -> Service<Echo> {
+        Service {
+            inner: Echo,
+            limits: Limits {
+                max_fields: 4,
+                max_bytes: 64,
+            },
+        }
+    }
+
+    #[test]
+    fn trailers_within_limits_pass() {
+        let req = http::Request::new(TrailersBody(Some(trailers(2))));
+        let rsp = svc().call(req).wait().expect("response");
+
+        let mut body = rsp.into_body();
+        let rsp_trailers = body.poll_trailers().expect("trailers");
+        assert_eq!(rsp_trailers, Async::Ready(Some(trailers(2))));
+    }
+
+    #[test]
+    fn too_many_request_trailers_reset_stream() {
+        let req = http::Request::new(TrailersBody(Some(trailers(5))));
+        let err = svc().call(req).wait().unwrap_err();
+        assert_eq!(err.reason(), Some(h2::Reason::PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn oversized_response_trailers_reset_stream() {
+        let mut body = LimitBody {
+            inner: TrailersBody(Some(trailers(3))),
+            limits: Limits {
+                max_fields: 4,
+                max_bytes: 16,
+            },
+        };
+        let err = body.poll_trailers().unwrap_err();
+        assert_eq!(err.reason(), Some(h2::Reason::PROTOCOL_ERROR));
+    }
+}
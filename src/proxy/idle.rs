@@ -0,0 +1,285 @@
+//! Closes HTTP/2 server connections that have been idle for too long.
+//!
+//! A connection is idle while it has no open streams. Once a connection has
+//! been idle for the configured timeout, it is sent a GOAWAY and closed. This
+//! reclaims the memory held by idle clients' flow-control windows and stream
+//! state.
+
+use futures::{Async, Future, Poll};
+use h2;
+use http;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_timer::{clock, Delay};
+use tower_h2::Body;
+
+use svc;
+
+/// Tracks the streams open on a connection.
+#[derive(Clone, Debug)]
+pub struct Activity(Arc<Mutex<Streams>>);
+
+/// Wraps a connection's route stack so that its streams are tracked.
+#[derive(Clone, Debug)]
+pub struct Stack<M> {
+    inner: M,
+    activity: Activity,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service<S> {
+    inner: S,
+    activity: Activity,
+}
+
+pub struct ResponseFuture<F> {
+    inner: F,
+    stream: Option<Stream>,
+}
+
+/// Holds a stream open until the response body completes.
+pub struct ResponseBody<B> {
+    inner: B,
+    stream: Option<Stream>,
+}
+
+/// Drives a connection, shutting it down gracefully once it has been idle
+/// for the timeout.
+pub struct Reaper<C, F> {
+    conn: C,
+    shutdown: F,
+    activity: Activity,
+    /// When `None`, idle connections are not closed.
+    timeout: Option<Duration>,
+    delay: Option<Delay>,
+    closing: bool,
+}
+
+#[derive(Debug)]
+struct Streams {
+    open: usize,
+    /// The last time a stream was opened or closed.
+    last: Instant,
+}
+
+/// Closes a stream when dropped.
+struct Stream(Activity);
+
+// === impl Activity ===
+
+impl Default for Activity {
+    fn default() -> Self {
+        let streams = Streams {
+            open: 0,
+            last: clock::now(),
+        };
+        Activity(Arc::new(Mutex::new(streams)))
+    }
+}
+
+impl Activity {
+    fn open(&self) -> Stream {
+        if let Ok(mut streams) = self.0.lock() {
+            streams.open += 1;
+            streams.last = clock::now();
+        }
+        Stream(self.clone())
+    }
+
+    /// Returns the time at which the connection became idle, if it has no
+    /// open streams.
+    fn idle_since(&self) -> Option<Instant> {
+        let streams = self.0.lock().ok()?;
+        if streams.open == 0 {
+            Some(streams.last)
+        } else {
+            None
+        }
+    }
+}
+
+// === impl Stack ===
+
+impl<M> Stack<M> {
+    pub fn new(inner: M, activity: Activity) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<T, M> svc::Stack<T> for Stack<M>
+where
+    M: svc::Stack<T>,
+{
+    type Value = Service<M::Value>;
+    type Error = M::Error;
+
+    fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
+        let inner = self.inner.make(target)?;
+        Ok(Service {
+            inner,
+            activity: self.activity.clone(),
+        })
+    }
+}
+
+// === impl Service ===
+
+impl<S, A, B> svc::Service<http::Request<A>> for Service<S>
+where
+    S: svc::Service<http::Request<A>, Response = http::Response<B>>,
+{
+    type Response = http::Response<ResponseBody<B>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: http::Request<A>) -> Self::Future {
+        let stream = Some(self.activity.open());
+        ResponseFuture {
+            inner: self.inner.call(req),
+            stream,
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: Future<Item = http::Response<B>>,
+{
+    type Item = http::Response<ResponseBody<B>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rsp = try_ready!(self.inner.poll());
+        let stream = self.stream.take();
+        Ok(Async::Ready(rsp.map(|inner| ResponseBody { inner, stream })))
+    }
+}
+
+// === impl ResponseBody ===
+
+impl<B: Default> Default for ResponseBody<B> {
+    fn default() -> Self {
+        ResponseBody {
+            inner: B::default(),
+            stream: None,
+        }
+    }
+}
+
+impl<B: Body> Body for ResponseBody<B> {
+    type Data = B::Data;
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, h2::Error> {
+        let data = try_ready!(self.inner.poll_data());
+        if self.inner.is_end_stream() {
+            self.stream = None;
+        }
+        Ok(Async::Ready(data))
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+        let trailers = try_ready!(self.inner.poll_trailers());
+        self.stream = None;
+        Ok(Async::Ready(trailers))
+    }
+}
+
+// === impl Stream ===
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Ok(mut streams) = (self.0).0.lock() {
+            streams.open -= 1;
+            streams.last = clock::now();
+        }
+    }
+}
+
+// === impl Reaper ===
+
+impl<C, F> Reaper<C, F>
+where
+    C: Future,
+    F: FnMut(&mut C),
+{
+    /// Drives `conn`, calling `shutdown` once it has been idle for `timeout`.
+    pub fn new(conn: C, activity: Activity, timeout: Option<Duration>, shutdown: F) -> Self {
+        let delay = timeout.map(|t| Delay::new(clock::now() + t));
+        Self {
+            conn,
+            shutdown,
+            activity,
+            timeout,
+            delay,
+            closing: false,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.conn
+    }
+
+    fn poll_idle(&mut self) {
+        let timeout = match self.timeout {
+            Some(t) if !self.closing => t,
+            _ => return,
+        };
+
+        loop {
+            {
+                let delay = self.delay.as_mut().expect("delay must be set");
+                match delay.poll() {
+                    Ok(Async::NotReady) => return,
+                    Ok(Async::Ready(())) => {}
+                    Err(e) => {
+                        error!("idle connection timer failed: {}", e);
+                        self.timeout = None;
+                        return;
+                    }
+                }
+            }
+
+            let now = clock::now();
+            let deadline = match self.activity.idle_since() {
+                Some(since) if since + timeout <= now => {
+                    debug!("closing connection idle for {:?}", now - since);
+                    (self.shutdown)(&mut self.conn);
+                    self.closing = true;
+                    return;
+                }
+                Some(since) => since + timeout,
+                // Streams are open, so the connection cannot become idle
+                // before this deadline.
+                None => now + timeout,
+            };
+            self.delay
+                .as_mut()
+                .expect("delay must be set")
+                .reset(deadline);
+        }
+    }
+}
+
+impl<C, F> Future for Reaper<C, F>
+where
+    C: Future,
+    F: FnMut(&mut C),
+{
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.poll_idle();
+        self.conn.poll()
+    }
+}
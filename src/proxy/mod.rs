@@ -4,13 +4,15 @@ use tokio::io::{AsyncRead, AsyncWrite};
 
 pub mod buffer;
 pub mod canonicalize;
+pub mod h2c;
 pub mod http;
+mod idle;
 pub mod limit;
 mod protocol;
 pub mod reconnect;
 pub mod resolve;
 pub mod server;
-mod tcp;
+pub mod tcp;
 pub mod timeout;
 
 pub use self::resolve::{Resolve, Resolution};
@@ -2,9 +2,10 @@ extern crate tower_reconnect;
 
 
 use futures::{task, Async, Future, Poll};
-use std::fmt;
+use rand;
+use std::{error, fmt};
 use std::time::Duration;
-pub use self::tower_reconnect::{Error, Reconnect};
+pub use self::tower_reconnect::Reconnect;
 use tokio_timer::{clock, Delay};
 
 use svc;
@@ -12,11 +13,13 @@ use svc;
 #[derive(Clone, Debug)]
 pub struct Layer {
     backoff: Backoff,
+    max_attempts: Option<usize>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Stack<M> {
     backoff: Backoff,
+    max_attempts: Option<usize>,
     inner: M,
 }
 
@@ -37,6 +40,15 @@ where
     backoff: Backoff,
     active_backoff: Option<Delay>,
 
+    /// The number of consecutive connect errors, used to grow the backoff.
+    ///
+    /// Reset after a connect succeeds.
+    failures: u32,
+
+    /// When set, the service fails once this many consecutive connect
+    /// attempts have failed.
+    max_attempts: Option<usize>,
+
     /// Prevents logging repeated connect errors.
     ///
     /// Set back to false after a connect succeeds, to log about future errors.
@@ -47,17 +59,40 @@ where
 enum Backoff {
     None,
     Fixed(Duration),
+    Exponential(ExponentialBackoff),
+}
+
+/// Doubles from `min` after each consecutive failure, up to `max`.
+///
+/// Each backoff is reduced by a random fraction of up to `jitter` (between
+/// 0 and 1) so that reconnects to a failed target are spread out.
+#[derive(Copy, Clone, Debug)]
+pub struct ExponentialBackoff {
+    min: Duration,
+    max: Duration,
+    jitter: f64,
 }
 
 pub struct ResponseFuture<F> {
     inner: F,
 }
 
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The connected service failed.
+    Service(E),
+
+    /// The target could not be connected to in the maximum number of
+    /// attempts, so the service will never become ready.
+    ConnectFailed { attempts: usize },
+}
+
 // === impl Layer ===
 
 pub fn layer() -> Layer {
     Layer {
         backoff: Backoff::None,
+        max_attempts: None,
     }
 }
 
@@ -68,6 +103,26 @@ impl Layer {
             .. self
         }
     }
+
+    /// Waits between `min` and `max` before reconnecting after a connect
+    /// error, growing exponentially with consecutive errors.
+    pub fn with_exponential_backoff(self, min: Duration, max: Duration, jitter: f64) -> Self {
+        Self {
+            backoff: Backoff::Exponential(ExponentialBackoff::new(min, max, jitter)),
+            .. self
+        }
+    }
+
+    /// Fails services once `max_attempts` consecutive connect attempts have
+    /// failed, so that they may be evicted rather than retried forever.
+    ///
+    /// When `None`, connects are retried indefinitely.
+    pub fn with_max_attempts(self, max_attempts: Option<usize>) -> Self {
+        Self {
+            max_attempts,
+            .. self
+        }
+    }
 }
 
 impl<T, M> svc::Layer<T, T, M> for Layer
@@ -84,6 +139,7 @@ where
         Stack {
             inner,
             backoff: self.backoff.clone(),
+            max_attempts: self.max_attempts,
         }
     }
 }
@@ -106,6 +162,8 @@ where
             target: target.clone(),
             backoff: self.backoff.clone(),
             active_backoff: None,
+            failures: 0,
+            max_attempts: self.max_attempts,
             mute_connect_error_log: false,
         })
     }
@@ -125,13 +183,22 @@ where
             target: "test",
             backoff: Backoff::None,
             active_backoff: None,
+            failures: 0,
+            max_attempts: None,
             mute_connect_error_log: false,
         }
     }
 
-    fn with_fixed_backoff(self, wait: Duration) -> Self {
+    fn with_backoff(self, backoff: Backoff) -> Self {
         Self {
-            backoff: Backoff::Fixed(wait),
+            backoff,
+            .. self
+        }
+    }
+
+    fn with_max_attempts(self, max_attempts: usize) -> Self {
+        Self {
+            max_attempts: Some(max_attempts),
             .. self
         }
     }
@@ -145,39 +212,44 @@ where
     S: svc::Service<Req>,
 {
     type Response = S::Response;
-    type Error = S::Error;
+    type Error = Error<S::Error>;
     type Future = ResponseFuture<<Reconnect<N, ()> as svc::Service<Req>>::Future>;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-        match self.backoff {
-            Backoff::None => {}
-            Backoff::Fixed(_) => {
-                if let Some(delay) = self.active_backoff.as_mut() {
-                    match delay.poll() {
-                        Ok(Async::NotReady) => return Ok(Async::NotReady),
-                        Ok(Async::Ready(())) => {},
-                        Err(e) => {
-                            error!("timer failed; continuing without backoff: {}", e);
-                        }
-                    }
+        if let Some(max) = self.max_attempts {
+            let attempts = self.failures as usize;
+            if attempts >= max {
+                debug!("giving up on {:?} after {} connect attempts", self.target, attempts);
+                return Err(Error::ConnectFailed { attempts });
+            }
+        }
+
+        if let Some(delay) = self.active_backoff.as_mut() {
+            match delay.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => {},
+                Err(e) => {
+                    error!("timer failed; continuing without backoff: {}", e);
                 }
             }
-        };
+        }
         self.active_backoff = None;
 
         match self.inner.poll_ready() {
             Ok(Async::NotReady) => Ok(Async::NotReady),
             Ok(ready) => {
                 self.mute_connect_error_log = false;
+                self.failures = 0;
                 Ok(ready)
             }
 
-            Err(Error::Service(err)) => {
+            Err(tower_reconnect::Error::Service(err)) => {
                 self.mute_connect_error_log = false;
-                Err(err)
+                self.failures = 0;
+                Err(Error::Service(err))
             }
 
-            Err(Error::Connect(err)) => {
+            Err(tower_reconnect::Error::Connect(err)) => {
                 // A connection could not be established to the target.
 
                 // This is only logged as a warning at most once. Subsequent
@@ -193,10 +265,9 @@ where
                 //
                 // This future need not be polled immediately because the
                 // task is notified below.
-                self.active_backoff = match self.backoff {
-                    Backoff::None => None,
-                    Backoff::Fixed(ref wait) => Some(Delay::new(clock::now() + *wait)),
-                };
+                let wait = self.backoff.duration(self.failures, rand::random());
+                self.active_backoff = wait.map(|wait| Delay::new(clock::now() + wait));
+                self.failures = self.failures.saturating_add(1);
 
                 // The inner service is now idle and will renew its internal
                 // state on the next poll. Instead of doing this immediately,
@@ -209,7 +280,7 @@ where
                 Ok(Async::NotReady)
             }
 
-            Err(Error::NotReady) => {
+            Err(tower_reconnect::Error::NotReady) => {
                 unreachable!("poll_ready can't fail with NotReady");
             }
         }
@@ -222,6 +293,50 @@ where
     }
 }
 
+// === impl Backoff ===
+
+impl Backoff {
+    /// Returns how long to wait after the `failures`+1th consecutive connect
+    /// error, given a random number `rand` in [0, 1).
+    fn duration(&self, failures: u32, rand: f64) -> Option<Duration> {
+        match *self {
+            Backoff::None => None,
+            Backoff::Fixed(wait) => Some(wait),
+            Backoff::Exponential(ref backoff) => Some(backoff.duration(failures, rand)),
+        }
+    }
+}
+
+// === impl ExponentialBackoff ===
+
+impl ExponentialBackoff {
+    pub fn new(min: Duration, max: Duration, jitter: f64) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            jitter: jitter.max(0.0).min(1.0),
+        }
+    }
+
+    /// The backoff after the first failure, before jitter is applied.
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    /// Returns how long to wait after the `failures`+1th consecutive failure,
+    /// given a random number `rand` in [0, 1).
+    pub fn duration(&self, failures: u32, rand: f64) -> Duration {
+        let wait = 1u32
+            .checked_shl(failures)
+            .and_then(|factor| self.min.checked_mul(factor))
+            .map(|wait| wait.min(self.max))
+            .unwrap_or(self.max);
+        let nanos = wait.as_secs() as f64 * 1e9 + f64::from(wait.subsec_nanos());
+        let nanos = nanos * (1.0 - self.jitter * rand);
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
 impl<T, N> fmt::Debug for Service<T, N>
 where
     T: fmt::Debug,
@@ -236,19 +351,41 @@ where
 
 impl<F, E, Cant> Future for ResponseFuture<F>
 where
-    F: Future<Error = Error<E, Cant>>,
+    F: Future<Error = tower_reconnect::Error<E, Cant>>,
 {
     type Item = F::Item;
-    type Error = E;
+    type Error = Error<E>;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         self.inner.poll().map_err(|e| match e {
-            Error::Service(err) => err,
+            tower_reconnect::Error::Service(err) => Error::Service(err),
             _ => unreachable!("response future must fail with inner error"),
         })
     }
 }
 
+// === impl Error ===
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Service(e) => fmt::Display::fmt(e, f),
+            Error::ConnectFailed { attempts } => {
+                write!(f, "failed to connect after {} attempts", attempts)
+            }
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for Error<E> {
+    fn cause(&self) -> Option<&error::Error> {
+        match self {
+            Error::Service(e) => e.cause(),
+            Error::ConnectFailed { .. } => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -325,7 +462,7 @@ mod tests {
     fn reconnects_with_backoff() {
         let mock = NewService { fails: 2.into() };
         let mut backoff = super::Service::for_test(mock)
-            .with_fixed_backoff(Duration::from_millis(100));
+            .with_backoff(Backoff::Fixed(Duration::from_millis(100)));
         let mut rt = Runtime::new().unwrap();
 
         // Checks that, after the inner NewService fails to connect twice, it
@@ -336,4 +473,69 @@ mod tests {
 
         assert!(t0.elapsed() >= Duration::from_millis(200))
     }
+
+    #[test]
+    fn exponential_backoff_grows_until_reset() {
+        let mock = NewService { fails: 5.into() };
+        let backoff = Backoff::Exponential(ExponentialBackoff::new(
+            Duration::from_millis(100),
+            Duration::from_millis(400),
+            0.0,
+        ));
+        let mut svc = super::Service::for_test(mock).with_backoff(backoff);
+
+        future::lazy(|| {
+            for &wait in &[100, 200, 400, 400, 400] {
+                // Each connect attempt fails, scheduling a reconnect after a
+                // growing backoff.
+                let before = clock::now();
+                assert!(svc.poll_ready().unwrap().is_not_ready());
+                let after = clock::now();
+
+                // Rather than waiting, the backoff is elapsed immediately.
+                let deadline = svc.active_backoff.take().expect("backoff").deadline();
+                let wait = Duration::from_millis(wait);
+                assert!(before + wait <= deadline && deadline <= after + wait);
+            }
+
+            // Once a connect succeeds, the backoff is reset.
+            assert!(svc.poll_ready().unwrap().is_ready());
+            assert_eq!(svc.failures, 0);
+            Ok::<_, ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn exponential_backoff_is_jittered() {
+        let backoff = ExponentialBackoff::new(
+            Duration::from_millis(100),
+            Duration::from_secs(10),
+            0.5,
+        );
+        assert_eq!(backoff.duration(0, 0.0), Duration::from_millis(100));
+        assert_eq!(backoff.duration(2, 0.5), Duration::from_millis(300));
+        assert_eq!(backoff.duration(3, 0.75), Duration::from_millis(500));
+        // The backoff never exceeds the maximum.
+        assert_eq!(backoff.duration(31, 0.0), Duration::from_secs(10));
+        assert_eq!(backoff.duration(32, 0.0), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn fails_after_max_attempts() {
+        let mock = NewService { fails: 10.into() };
+        let mut svc = super::Service::for_test(mock).with_max_attempts(3);
+
+        future::lazy(|| {
+            for _ in 0..3 {
+                assert!(svc.poll_ready().unwrap().is_not_ready());
+            }
+
+            // The fourth attempt is not made; the service fails instead.
+            match svc.poll_ready() {
+                Err(Error::ConnectFailed { attempts }) => assert_eq!(attempts, 3),
+                _ => panic!("service must fail after 3 connect attempts"),
+            }
+            Ok::<_, ()>(())
+        }).wait().unwrap();
+    }
 }
@@ -1,6 +1,7 @@
 extern crate tower_discover;
 
 use futures::{Async, Poll};
+use indexmap::IndexMap;
 use std::net::SocketAddr;
 use std::{error, fmt};
 
@@ -23,9 +24,19 @@ pub trait Resolution {
     fn poll(&mut self) -> Poll<Update<Self::Endpoint>, Self::Error>;
 }
 
+/// Determines whether an endpoint's service must be rebuilt when the
+/// endpoint's metadata changes.
+pub trait Refresh {
+    /// Returns true if a service built for `self` may continue to serve
+    /// requests for `new`, so that its connections need not be torn down.
+    fn can_refresh(&self, new: &Self) -> bool;
+}
+
 #[derive(Clone, Debug)]
 pub enum Update<T> {
     Add(SocketAddr, T),
+    /// Changes the metadata of an endpoint that was previously added.
+    Update(SocketAddr, T),
     Remove(SocketAddr),
 }
 
@@ -46,6 +57,8 @@ pub struct Stack<R, M> {
 pub struct Discover<R: Resolution, M: svc::Stack<R::Endpoint>> {
     resolution: R,
     make: M,
+    /// The metadata of each endpoint for which a service has been built.
+    endpoints: IndexMap<SocketAddr, R::Endpoint>,
 }
 
 // === impl Layer ===
@@ -53,7 +66,7 @@ pub struct Discover<R: Resolution, M: svc::Stack<R::Endpoint>> {
 pub fn layer<T, R>(resolve: R) -> Layer<R>
 where
     R: Resolve<T> + Clone,
-    R::Endpoint: fmt::Debug,
+    R::Endpoint: Refresh + Clone + fmt::Debug,
 {
     Layer {
         resolve,
@@ -63,7 +76,7 @@ where
 impl<T, R, M> svc::Layer<T, R::Endpoint, M> for Layer<R>
 where
     R: Resolve<T> + Clone,
-    R::Endpoint: fmt::Debug,
+    R::Endpoint: Refresh + Clone + fmt::Debug,
     M: svc::Stack<R::Endpoint> + Clone,
 {
     type Value = <Stack<R, M> as svc::Stack<T>>::Value;
@@ -83,7 +96,7 @@ where
 impl<T, R, M> svc::Stack<T> for Stack<R, M>
 where
     R: Resolve<T>,
-    R::Endpoint: fmt::Debug,
+    R::Endpoint: Refresh + Clone + fmt::Debug,
     M: svc::Stack<R::Endpoint> + Clone,
 {
     type Value = Discover<R::Resolution, M>;
@@ -94,6 +107,7 @@ where
         Ok(Discover {
             resolution,
             make: self.inner.clone(),
+            endpoints: IndexMap::new(),
         })
     }
 }
@@ -103,7 +117,7 @@ where
 impl<R, M>  tower_discover::Discover for Discover<R, M>
 where
     R: Resolution,
-    R::Endpoint: fmt::Debug,
+    R::Endpoint: Refresh + Clone + fmt::Debug,
     M: svc::Stack<R::Endpoint>,
 {
     type Key = SocketAddr;
@@ -115,15 +129,26 @@ where
             let up = try_ready!(self.resolution.poll().map_err(Error::Resolve));
             trace!("watch: {:?}", up);
             match up {
+                Update::Update(addr, target) => {
+                    let refresh = self
+                        .endpoints
+                        .get(&addr)
+                        .map(|old| old.can_refresh(&target))
+                        .unwrap_or(false);
+                    if refresh {
+                        trace!("refreshing {} in place", addr);
+                        self.endpoints.insert(addr, target);
+                        continue;
+                    }
+
+                    // Otherwise, the endpoint's service is replaced.
+                    return self.insert(addr, target);
+                }
                 Update::Add(addr, target) => {
-                    // We expect the load balancer to handle duplicate inserts
-                    // by replacing the old endpoint with the new one, so
-                    // insertions of new endpoints and metadata changes for
-                    // existing ones can be handled in the same way.
-                    let svc = self.make.make(&target).map_err(Error::Stack)?;
-                    return Ok(Async::Ready(Change::Insert(addr, svc)));
+                    return self.insert(addr, target);
                 }
                 Update::Remove(addr) => {
+                    self.endpoints.remove(&addr);
                     return Ok(Async::Ready(Change::Remove(addr)));
                 }
             }
@@ -131,6 +156,27 @@ where
     }
 }
 
+impl<R, M> Discover<R, M>
+where
+    R: Resolution,
+    R::Endpoint: Clone,
+    M: svc::Stack<R::Endpoint>,
+{
+    fn insert(
+        &mut self,
+        addr: SocketAddr,
+        target: R::Endpoint,
+    ) -> Poll<Change<SocketAddr, M::Value>, Error<R::Error, M::Error>> {
+        // We expect the load balancer to handle duplicate inserts by
+        // replacing the old endpoint with the new one, so insertions of new
+        // endpoints and metadata changes for existing ones can be handled in
+        // the same way.
+        let svc = self.make.make(&target).map_err(Error::Stack)?;
+        self.endpoints.insert(addr, target);
+        Ok(Async::Ready(Change::Insert(addr, svc)))
+    }
+}
+
 // === impl Error ===
 
 #[derive(Debug)]
@@ -152,3 +198,149 @@ where
 }
 
 impl<M> error::Error for Error<(), M> where M: error::Error {}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Async, Poll};
+    use std::collections::VecDeque;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::tower_discover::Discover as _Discover;
+    use super::*;
+    use never::Never;
+    use svc::{Layer as _Layer, Stack as _Stack};
+
+    /// An endpoint whose service must be rebuilt when its identity changes,
+    /// but not when its label does.
+    #[derive(Clone, Debug)]
+    struct Endpoint {
+        identity: &'static str,
+        label: &'static str,
+    }
+
+    #[derive(Clone, Debug)]
+    struct Resolve(Vec<Update<Endpoint>>);
+
+    struct Resolution(VecDeque<Update<Endpoint>>);
+
+    /// Builds services, counting how many are live.
+    #[derive(Clone, Debug, Default)]
+    struct MakeSvc(Arc<AtomicUsize>);
+
+    #[derive(Debug)]
+    struct Svc(Endpoint, Arc<AtomicUsize>);
+
+    impl Refresh for Endpoint {
+        fn can_refresh(&self, new: &Self) -> bool {
+            self.identity == new.identity
+        }
+    }
+
+    impl super::Resolve<()> for Resolve {
+        type Endpoint = Endpoint;
+        type Resolution = Resolution;
+
+        fn resolve(&self, _: &()) -> Self::Resolution {
+            Resolution(self.0.iter().cloned().collect())
+        }
+    }
+
+    impl super::Resolution for Resolution {
+        type Endpoint = Endpoint;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Update<Endpoint>, ()> {
+            match self.0.pop_front() {
+                Some(up) => Ok(up.into()),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl svc::Stack<Endpoint> for MakeSvc {
+        type Value = Svc;
+        type Error = Never;
+
+        fn make(&self, ep: &Endpoint) -> Result<Svc, Never> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Svc(ep.clone(), self.0.clone()))
+        }
+    }
+
+    impl Drop for Svc {
+        fn drop(&mut self) {
+            self.1.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    impl MakeSvc {
+        fn live(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn ep(identity: &'static str, label: &'static str) -> Endpoint {
+        Endpoint { identity, label }
+    }
+
+    #[test]
+    fn metadata_updates_refresh_endpoints_in_place() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 8080));
+        let updates = vec![
+            Update::Add(addr, ep("web-0", "a")),
+            Update::Update(addr, ep("web-0", "b")),
+        ];
+        let make = MakeSvc::default();
+        let mut discover = layer::<(), _>(Resolve(updates))
+            .bind(make.clone())
+            .make(&())
+            .unwrap();
+
+        let svc = match discover.poll() {
+            Ok(Async::Ready(Change::Insert(a, svc))) => {
+                assert_eq!(a, addr);
+                svc
+            }
+            _ => panic!("endpoint must be inserted"),
+        };
+        assert_eq!(svc.0.label, "a");
+
+        // The label change is applied without replacing the service.
+        match discover.poll() {
+            Ok(Async::NotReady) => {}
+            _ => panic!("a refreshed endpoint must not be replaced"),
+        }
+        assert_eq!(make.live(), 1);
+        assert_eq!(discover.endpoints[&addr].label, "b");
+    }
+
+    #[test]
+    fn metadata_updates_replace_endpoints_that_cannot_be_refreshed() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 8080));
+        let other = SocketAddr::from(([10, 0, 0, 2], 8080));
+        let updates = vec![
+            Update::Add(addr, ep("web-0", "a")),
+            Update::Update(addr, ep("web-1", "a")),
+            // An update for an unknown endpoint adds it.
+            Update::Update(other, ep("web-2", "a")),
+        ];
+        let mut discover = layer::<(), _>(Resolve(updates))
+            .bind(MakeSvc::default())
+            .make(&())
+            .unwrap();
+
+        let mut inserted = Vec::new();
+        while let Ok(Async::Ready(change)) = discover.poll() {
+            match change {
+                Change::Insert(a, svc) => inserted.push((a, svc.0.identity)),
+                Change::Remove(_) => panic!("endpoints must not be removed"),
+            }
+        }
+        assert_eq!(
+            inserted,
+            vec![(addr, "web-0"), (addr, "web-1"), (other, "web-2")]
+        );
+    }
+}
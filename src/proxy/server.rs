@@ -1,17 +1,25 @@
-use futures::{future::Either, Future};
+use futures::{future::{self, Either}, Async, Future, Poll};
 use h2;
 use http;
 use hyper;
-use indexmap::IndexSet;
+use indexmap::{IndexMap, IndexSet};
 use std::{error, fmt};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_timer::{clock, Delay};
 use tower_h2;
 
 use Conditional;
 use drain;
 use never::Never;
 use svc::{Stack, Service, stack::StackMakeService};
+use tap;
 use transport::{connect, tls, Connection, GetOriginalDst, Peek};
+use proxy::h2c;
+use proxy::idle;
 use proxy::http::glue::{HttpBody, HttpBodyNewSvc, HyperServerSvc};
 use proxy::protocol::Protocol;
 use proxy::tcp;
@@ -21,6 +29,9 @@ use super::Accept;
 ///
 /// As TCP streams are passed to `Server::serve`, the following occurs:
 ///
+/// 0. If `max_connections` connections are already being served, the
+///    connection is closed immediately.
+///
 /// 1. A `G`-typed `GetOriginalDst` is used to determine the socket's original
 ///    destination address (i.e. before iptables redirected the connection to the
 ///    proxy).
@@ -30,18 +41,31 @@ use super::Accept;
 /// 3. An `A`-typed `Accept` is used to decorate the transport (i.e., for
 ///    telemetry).
 ///
-/// 4. If the original destination address's port is not specified in
+/// 4. If the connection's detection port (by default, the original
+///    destination address's port) is not specified in
 ///    `disable_protocol_detection_ports`, then data received on the connection is
 ///    buffered until the server can determine whether the streams begins with a
-///    HTTP/1 or HTTP/2 preamble.
+///    HTTP/1 or HTTP/2 preamble. If the client sends nothing before the
+///    `detect_protocol_timeout` elapses, the connection is either closed or
+///    forwarded, according to `OnDetectTimeout`.
 ///
 /// 5. If the stream is not determined to be HTTP, then the orignal destination
 ///    address is used to transparently forward the TCP stream. A `C`-typed
 ///    `Connect` `Stack` is used to build a connection to the destination (i.e.,
-///    instrumented with telemetry, etc).
+///    instrumented with telemetry, etc). If TCP taps are configured, the
+///    forwarded connection's events are inspected by them. If an `SniRoute`
+///    is configured and the stream begins with a TLS ClientHello, the stream
+///    may instead be forwarded to an address chosen by its SNI server name.
+///    (Connections that skip protocol detection are never routed by SNI.)
 ///
 /// 6. Otherwise, an `R`-typed `Service` `Stack` is used to build a service that
-///    can routeHTTP  requests for the `Source`.
+///    can routeHTTP  requests for the `Source`. If h2c upgrades are enabled,
+///    HTTP/1.1 connections that ask to upgrade to HTTP/2 are served as
+///    HTTP/2 once the upgrade completes.
+///
+/// When a drain is signaled, connections are asked to shut down gracefully.
+/// If a `drain_timeout` is set, connections that are still being served once
+/// it elapses are aborted.
 pub struct Server<A, C, R, B, G>
 where
     // Prepares a server transport, e.g. with telemetry.
@@ -61,7 +85,18 @@ where
     G: GetOriginalDst,
 {
     disable_protocol_detection_ports: IndexSet<u16>,
+    detection_port: Box<DetectionPort + Send + Sync>,
+    detect_protocol_timeout: Duration,
+    on_detect_timeout: OnDetectTimeout,
+    tcp_shutdown: tcp::Shutdown,
+    tcp_taps: Option<Arc<Mutex<tap::Taps>>>,
+    sni_route: Option<Arc<SniRoute + Send + Sync>>,
+    h2c_upgrades: bool,
+    h2_idle_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    active: Active,
     drain_signal: drain::Watch,
+    drain_timeout: Option<Duration>,
     get_orig_dst: G,
     h1: hyper::server::conn::Http,
     h2_settings: h2::server::Builder,
@@ -79,9 +114,71 @@ pub struct Source {
     pub local: SocketAddr,
     pub orig_dst: Option<SocketAddr>,
     pub tls_status: tls::Status,
+    /// The SNI server name of a terminated TLS connection, if the client
+    /// provided one.
+    pub tls_server_name: Option<tls::Identity>,
     _p: (),
 }
 
+/// Counts the connections being served.
+#[derive(Clone, Debug, Default)]
+struct Active(Arc<AtomicUsize>);
+
+/// Decrements the active connection count when dropped.
+struct ActiveConnection(Active);
+
+/// Determines the port that is checked against the
+/// `disable_protocol_detection_ports` for a `Source`.
+///
+/// Implemented for functions of `&Source`, so that the decision to skip
+/// protocol detection may be based on a connection's effective destination
+/// rather than its `SO_ORIGINAL_DST` address.
+pub trait DetectionPort {
+    fn detection_port(&self, source: &Source) -> Option<u16>;
+}
+
+/// Chooses the address to which an opaque TLS connection is forwarded by the
+/// SNI server name of its ClientHello.
+///
+/// Connections for which no address is chosen are forwarded to their original
+/// destination.
+pub trait SniRoute {
+    fn route_sni(&self, sni: &tls::Identity, source: &Source) -> Option<SocketAddr>;
+}
+
+/// The default amount of time to wait for a client to send enough data for
+/// its protocol to be detected.
+pub const DEFAULT_DETECT_PROTOCOL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Uses the port of a connection's original destination address.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OrigDstPort;
+
+/// Determines what happens to a connection on which no protocol has been
+/// detected when the protocol detection timeout elapses.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OnDetectTimeout {
+    /// The connection is closed.
+    Close,
+
+    /// The connection is forwarded to its original destination as opaque
+    /// TCP, as if no protocol had been detected. This supports protocols in
+    /// which the server speaks first.
+    Forward,
+}
+
+/// Peeks at a connection to detect its protocol, giving up once a timeout
+/// elapses.
+///
+/// If `read_client_hello` is set, peeking continues until a complete TLS
+/// ClientHello is available, so that the connection may be routed by SNI.
+struct DetectProtocol<T> {
+    io: Option<T>,
+    timeout: Delay,
+    on_timeout: OnDetectTimeout,
+    read_client_hello: bool,
+}
+
 /// Establishes connections for forwarded connections.
 ///
 /// Fails to produce a `Connect` if a `Source`'s `orig_dst` is None.
@@ -130,6 +227,7 @@ impl Source {
            local,
            orig_dst,
            tls_status,
+           tls_server_name: None,
            _p: (),
        }
    }
@@ -156,7 +254,8 @@ where
         };
 
         let tls = Conditional::None(tls::ReasonForNoIdentity::NotHttp.into());
-        match self.0.make(&connect::Target::new(addr, tls)) {
+        let target = connect::Target::new(addr, tls).with_source(s.remote);
+        match self.0.make(&target) {
             Ok(c) => Ok(c),
             // Matching never allows LLVM to eliminate this entirely.
             Err(never) => match never {},
@@ -220,7 +319,18 @@ where
         let log = ::logging::Server::proxy(proxy_name, listen_addr);
         Server {
             disable_protocol_detection_ports,
+            detection_port: Box::new(OrigDstPort),
+            detect_protocol_timeout: DEFAULT_DETECT_PROTOCOL_TIMEOUT,
+            on_detect_timeout: OnDetectTimeout::Forward,
+            tcp_shutdown: tcp::Shutdown::default(),
+            tcp_taps: None,
+            sni_route: None,
+            h2c_upgrades: false,
+            h2_idle_timeout: None,
+            max_connections: None,
+            active: Active::default(),
             drain_signal,
+            drain_timeout: None,
             get_orig_dst,
             h1: hyper::server::conn::Http::new(),
             h2_settings,
@@ -232,6 +342,86 @@ where
         }
     }
 
+    /// Sets how long to wait for a client to send enough data for its
+    /// protocol to be detected, and what happens to the connection if it
+    /// doesn't.
+    pub fn with_detect_protocol_timeout(
+        self,
+        detect_protocol_timeout: Duration,
+        on_detect_timeout: OnDetectTimeout,
+    ) -> Self {
+        Self {
+            detect_protocol_timeout,
+            on_detect_timeout,
+            ..self
+        }
+    }
+
+    /// Determines how forwarded TCP connections are closed once one side
+    /// finishes writing.
+    pub fn with_tcp_shutdown(self, tcp_shutdown: tcp::Shutdown) -> Self {
+        Self { tcp_shutdown, ..self }
+    }
+
+    /// Aborts connections that are still being served `drain_timeout` after
+    /// a drain is signaled, if it is set.
+    pub fn with_drain_timeout(self, drain_timeout: Option<Duration>) -> Self {
+        Self { drain_timeout, ..self }
+    }
+
+    /// Enables upgrading HTTP/1.1 connections to HTTP/2 when a client sends
+    /// an `Upgrade: h2c` request.
+    pub fn with_h2c_upgrades(self, h2c_upgrades: bool) -> Self {
+        Self { h2c_upgrades, ..self }
+    }
+
+    /// Closes HTTP/2 connections that have had no open streams for
+    /// `h2_idle_timeout`, if it is set.
+    pub fn with_h2_idle_timeout(self, h2_idle_timeout: Option<Duration>) -> Self {
+        Self { h2_idle_timeout, ..self }
+    }
+
+    /// Emits tap events for connections that are forwarded as opaque TCP.
+    pub fn with_tcp_taps(self, taps: Arc<Mutex<tap::Taps>>) -> Self {
+        Self {
+            tcp_taps: Some(taps),
+            ..self
+        }
+    }
+
+    /// Forwards opaque TLS connections to the address that `sni_route`
+    /// chooses for their SNI server name.
+    ///
+    /// Connections to ports on which protocol detection is disabled are not
+    /// peeked, since the server may be expected to speak first.
+    pub fn with_sni_route<S>(self, sni_route: S) -> Self
+    where
+        S: SniRoute + Send + Sync + 'static,
+    {
+        Self {
+            sni_route: Some(Arc::new(sni_route)),
+            ..self
+        }
+    }
+
+    /// Closes new connections while `max_connections` connections are being
+    /// served, if it is set.
+    pub fn with_max_connections(self, max_connections: Option<usize>) -> Self {
+        Self { max_connections, ..self }
+    }
+
+    /// Determines the port on which protocol detection may be disabled for
+    /// each connection. By default, the original destination port is used.
+    pub fn with_detection_port<P>(self, detection_port: P) -> Self
+    where
+        P: DetectionPort + Send + Sync + 'static,
+    {
+        Self {
+            detection_port: Box::new(detection_port),
+            ..self
+        }
+    }
+
     pub fn log(&self) -> &::logging::Server {
         &self.log
     }
@@ -244,6 +434,34 @@ where
     /// executor.
     pub fn serve(&self, connection: Connection, remote_addr: SocketAddr)
         -> impl Future<Item=(), Error=()>
+    {
+        let active = match self.active.open(self.max_connections) {
+            Some(active) => active,
+            None => {
+                warn!(
+                    "closing connection from {}; {} connections are already being served",
+                    remote_addr,
+                    self.active.count(),
+                );
+                drop(connection);
+                return Either::A(future::ok(()));
+            }
+        };
+
+        // The connection is counted until it has been served (or aborted).
+        let serve = self.serve_connection(connection, remote_addr);
+        let serve = match self.drain_timeout {
+            Some(timeout) => Either::A(self.drain_signal.clone().abort_after(timeout, serve)),
+            None => Either::B(serve),
+        };
+        Either::B(serve.then(move |res| {
+            drop(active);
+            res
+        }))
+    }
+
+    fn serve_connection(&self, connection: Connection, remote_addr: SocketAddr)
+        -> impl Future<Item=(), Error=()>
     {
         let orig_dst = connection.original_dst_addr(&self.get_orig_dst);
 
@@ -255,6 +473,7 @@ where
             local: connection.local_addr().unwrap_or(self.listen_addr),
             orig_dst,
             tls_status: connection.tls_status(),
+            tls_server_name: connection.tls_server_name().cloned(),
             _p: (),
         };
 
@@ -264,81 +483,125 @@ where
             Err(never) => match never {},
         };
 
-        // We are using the port from the connection's SO_ORIGINAL_DST to
-        // determine whether to skip protocol detection, not any port that
-        // would be found after doing discovery.
-        let disable_protocol_detection = orig_dst
-            .map(|addr| {
-                self.disable_protocol_detection_ports.contains(&addr.port())
-            })
+        // Unless a `DetectionPort` is configured, the port from the
+        // connection's SO_ORIGINAL_DST is used to determine whether to skip
+        // protocol detection, not any port that would be found after doing
+        // discovery.
+        let detection_port = self.detection_port.detection_port(&source);
+        let disable_protocol_detection = detection_port
+            .map(|port| self.disable_protocol_detection_ports.contains(&port))
             .unwrap_or(false);
 
         if disable_protocol_detection {
-            trace!("protocol detection disabled for {:?}", orig_dst);
-            let fwd = tcp::forward(io, &self.connect, &source);
+            trace!("protocol detection disabled for port {:?}", detection_port);
+            // The connection is not peeked (even to route it by SNI), since
+            // the server may be expected to speak first.
+            let fwd = forward_tcp(
+                io,
+                &self.connect,
+                &source,
+                self.tcp_shutdown,
+                self.tcp_taps.as_ref(),
+                None,
+            );
             let fut = self.drain_signal.clone().watch(fwd, |_| {});
             return log.future(Either::B(fut));
         }
 
-        let detect_protocol = io.peek()
-            .map_err(|e| debug!("peek error: {}", e))
-            .map(|io| {
-                let p = Protocol::detect(io.peeked());
-                (p, io)
-            });
+        let detect_protocol = DetectProtocol {
+            io: Some(io),
+            timeout: Delay::new(clock::now() + self.detect_protocol_timeout),
+            on_timeout: self.on_detect_timeout,
+            read_client_hello: self.sni_route.is_some(),
+        };
 
         let h1 = self.h1.clone();
         let h2_settings = self.h2_settings.clone();
         let route = self.route.clone();
         let connect = self.connect.clone();
+        let tcp_shutdown = self.tcp_shutdown;
+        let tcp_taps = self.tcp_taps.clone();
+        let sni_route = self.sni_route.clone();
+        let h2c_upgrades = self.h2c_upgrades;
+        let h2_idle_timeout = self.h2_idle_timeout;
         let drain_signal = self.drain_signal.clone();
         let log_clone = log.clone();
         let serve = detect_protocol
             .and_then(move |(proto, io)| match proto {
                 None => Either::A({
                     trace!("did not detect protocol; forwarding TCP");
-                    let fwd = tcp::forward(io, &connect, &source);
+                    let fwd = forward_tcp(
+                        io,
+                        &connect,
+                        &source,
+                        tcp_shutdown,
+                        tcp_taps.as_ref(),
+                        sni_route.as_ref(),
+                    );
                     drain_signal.watch(fwd, |_| {})
                 }),
 
                 Some(proto) => Either::B(match proto {
                     Protocol::Http1 => Either::A({
-                        trace!("detected HTTP/1");
-                        match route.make(&source) {
-                            Err(never) => match never {},
-                            Ok(s) => {
-                                let svc = HyperServerSvc::new(
-                                    s,
-                                    drain_signal.clone(),
-                                    log_clone.executor(),
-                                );
-                                // Enable support for HTTP upgrades (CONNECT and websockets).
-                                let conn = h1
-                                    .serve_connection(io, svc)
-                                    .with_upgrades();
-                                drain_signal
-                                    .watch(conn, |conn| {
-                                        conn.graceful_shutdown();
+                        let upgrade = if h2c_upgrades {
+                            h2c::Upgrade::detect(io.peeked())
+                        } else {
+                            None
+                        };
+                        match upgrade {
+                            Some(upgrade) => Either::A({
+                                trace!("detected HTTP/1 upgrade to h2c");
+                                upgrade
+                                    .accept(io)
+                                    .map_err(|e| debug!("h2c upgrade error: {}", e))
+                                    .and_then(move |io| {
+                                        serve_h2(
+                                            io,
+                                            source,
+                                            route,
+                                            h2_settings,
+                                            h2_idle_timeout,
+                                            drain_signal,
+                                            &log_clone,
+                                        )
                                     })
-                                    .map(|_| ())
-                                    .map_err(|e| trace!("http1 server error: {:?}", e))
-                            },
+                            }),
+                            None => Either::B({
+                                trace!("detected HTTP/1");
+                                match route.make(&source) {
+                                    Err(never) => match never {},
+                                    Ok(s) => {
+                                        let svc = HyperServerSvc::new(
+                                            s,
+                                            drain_signal.clone(),
+                                            log_clone.executor(),
+                                        );
+                                        // Enable support for HTTP upgrades (CONNECT and websockets).
+                                        let conn = h1
+                                            .serve_connection(io, svc)
+                                            .with_upgrades();
+                                        drain_signal
+                                            .watch(conn, |conn| {
+                                                conn.graceful_shutdown();
+                                            })
+                                            .map(|_| ())
+                                            .map_err(|e| trace!("http1 server error: {:?}", e))
+                                    },
+                                }
+                            }),
                         }
                     }),
                     Protocol::Http2 => Either::B({
                         trace!("detected HTTP/2");
-                        let new_service = StackMakeService::new(route, source.clone());
-                        let mut h2 = tower_h2::Server::new(
-                            HttpBodyNewSvc::new(new_service),
+                        serve_h2(
+                            io,
+                            source,
+                            route,
                             h2_settings,
-                            log_clone.executor(),
-                        );
-                        let serve = h2.serve_modified(io, move |r: &mut http::Request<()>| {
-                            r.extensions_mut().insert(source.clone());
-                        });
-                        drain_signal
-                            .watch(serve, |conn| conn.graceful_shutdown())
-                            .map_err(|e| trace!("h2 server error: {:?}", e))
+                            h2_idle_timeout,
+                            drain_signal,
+                            &log_clone,
+                        )
                     }),
                 }),
             });
@@ -346,3 +609,789 @@ where
         log.future(Either::A(serve))
     }
 }
+
+/// Forwards a connection to its original destination as opaque TCP.
+///
+/// If `taps` is set, the connection's events are inspected by them. If
+/// `sni_route` is set, the connection may instead be forwarded to the address
+/// it chooses for the SNI server name in the connection's peeked bytes.
+fn forward_tcp<I, C>(
+    io: I,
+    connect: &ForwardConnect<C>,
+    source: &Source,
+    shutdown: tcp::Shutdown,
+    taps: Option<&Arc<Mutex<tap::Taps>>>,
+    sni_route: Option<&Arc<SniRoute + Send + Sync>>,
+) -> impl Future<Item = (), Error = ()> + Send + 'static
+where
+    I: AsyncRead + AsyncWrite + Peek + Send + 'static,
+    C: Stack<connect::Target, Error = Never>,
+    C::Value: connect::Connect,
+    <C::Value as connect::Connect>::Connected: Send + 'static,
+    <C::Value as connect::Connect>::Future: Send + 'static,
+    <C::Value as connect::Connect>::Error: fmt::Debug + 'static,
+{
+    let source = &match sni_route {
+        Some(sni_route) => route_sni(io.peeked(), source, &**sni_route),
+        None => source.clone(),
+    };
+
+    match (taps, source.orig_dst) {
+        (Some(taps), Some(destination)) => {
+            let conn = tap::event::Connection {
+                source: source.clone(),
+                destination,
+            };
+            let io = tap::tcp::Io::new(io, conn, taps.clone());
+            Either::A(tcp::forward(io, connect, source, shutdown))
+        }
+        _ => Either::B(tcp::forward(io, connect, source, shutdown)),
+    }
+}
+
+/// Returns the `Source` with which an opaque connection is forwarded.
+///
+/// If the `peeked` bytes begin a TLS ClientHello whose SNI server name is
+/// routed by `sni_route`, the routed address replaces the source's original
+/// destination, so that the connection is forwarded (and tapped) as if it had
+/// been sent there.
+fn route_sni(peeked: &[u8], source: &Source, sni_route: &SniRoute) -> Source {
+    use transport::tls::conditional_accept::{parse_sni, Sni};
+
+    match parse_sni(peeked) {
+        Sni::Found(sni) => match sni_route.route_sni(&sni, source) {
+            Some(addr) => {
+                debug!("forwarding TLS by SNI: sni={:?}; addr={}", sni, addr);
+                Source {
+                    orig_dst: Some(addr),
+                    ..source.clone()
+                }
+            }
+            None => {
+                trace!("no route for SNI: {:?}", sni);
+                source.clone()
+            }
+        },
+        sni => {
+            trace!("not routing by SNI: {:?}", sni);
+            source.clone()
+        }
+    }
+}
+
+/// Serves an HTTP/2 connection, routing its requests for the `Source`.
+///
+/// If `idle_timeout` is set, the connection is closed once it has had no open
+/// streams for that long.
+fn serve_h2<I, R, B>(
+    io: I,
+    source: Source,
+    route: R,
+    h2_settings: h2::server::Builder,
+    idle_timeout: Option<Duration>,
+    drain_signal: drain::Watch,
+    log: &::logging::Server,
+) -> impl Future<Item = (), Error = ()>
+where
+    I: AsyncRead + AsyncWrite + Send + 'static,
+    R: Stack<Source, Error = Never> + Clone,
+    R::Value: Service<
+        http::Request<HttpBody>,
+        Response = http::Response<B>,
+    >,
+    R::Value: 'static,
+    <R::Value as Service<http::Request<HttpBody>>>::Error: error::Error + Send + Sync + 'static,
+    <R::Value as Service<http::Request<HttpBody>>>::Future: Send + 'static,
+    B: tower_h2::Body + Default + Send + 'static,
+    B::Data: Send,
+    <B::Data as ::bytes::IntoBuf>::Buf: Send,
+{
+    let activity = idle::Activity::default();
+    let route = idle::Stack::new(route, activity.clone());
+    let new_service = StackMakeService::new(route, source.clone());
+    let mut h2 = tower_h2::Server::new(
+        HttpBodyNewSvc::new(new_service),
+        h2_settings,
+        log.executor(),
+    );
+    let serve = h2.serve_modified(io, move |r: &mut http::Request<()>| {
+        r.extensions_mut().insert(source.clone());
+    });
+    let serve = idle::Reaper::new(serve, activity, idle_timeout, |conn| {
+        conn.graceful_shutdown()
+    });
+    drain_signal
+        .watch(serve, |conn| conn.get_mut().graceful_shutdown())
+        .map_err(|e| trace!("h2 server error: {:?}", e))
+}
+
+// === impl Active ===
+
+impl Active {
+    /// Counts a new connection, unless `max` connections are already active.
+    fn open(&self, max: Option<usize>) -> Option<ActiveConnection> {
+        let count = self.0.fetch_add(1, Ordering::AcqRel);
+        let active = ActiveConnection(self.clone());
+        match max {
+            // Dropping the connection un-counts it.
+            Some(max) if count >= max => None,
+            _ => Some(active),
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        (self.0).0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// === impl DetectionPort ===
+
+impl<F> DetectionPort for F
+where
+    F: Fn(&Source) -> Option<u16>,
+{
+    fn detection_port(&self, source: &Source) -> Option<u16> {
+        (self)(source)
+    }
+}
+
+// === impl SniRoute ===
+
+impl<F> SniRoute for F
+where
+    F: Fn(&tls::Identity, &Source) -> Option<SocketAddr>,
+{
+    fn route_sni(&self, sni: &tls::Identity, source: &Source) -> Option<SocketAddr> {
+        (self)(sni, source)
+    }
+}
+
+impl SniRoute for IndexMap<tls::Identity, SocketAddr> {
+    fn route_sni(&self, sni: &tls::Identity, _: &Source) -> Option<SocketAddr> {
+        self.get(sni).cloned()
+    }
+}
+
+impl DetectionPort for OrigDstPort {
+    fn detection_port(&self, source: &Source) -> Option<u16> {
+        source.orig_dst.map(|addr| addr.port())
+    }
+}
+
+// === impl DetectProtocol ===
+
+impl<T> DetectProtocol<T> {
+    fn is_incomplete_client_hello(&self, peeked: &[u8]) -> bool {
+        use transport::tls::conditional_accept::{parse_sni, Sni};
+
+        self.read_client_hello && parse_sni(peeked) == Sni::Incomplete
+    }
+}
+
+impl<T: Peek> Future for DetectProtocol<T> {
+    type Item = (Option<Protocol>, T);
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, ()> {
+        loop {
+            let peeked = {
+                let io = self.io.as_mut().expect("polled after complete");
+                if io.peeked().is_empty() {
+                    io.poll_peek()
+                } else {
+                    io.poll_peek_more()
+                }
+            };
+            match peeked {
+                Ok(Async::Ready(sz)) => {
+                    let io = self.io.take().expect("polled after complete");
+                    let p = Protocol::detect(io.peeked());
+                    if sz == 0 || p.is_some() || !self.is_incomplete_client_hello(io.peeked()) {
+                        return Ok(Async::Ready((p, io)));
+                    }
+                    // The ClientHello may span several reads.
+                    trace!("peeked an incomplete ClientHello; peeking more");
+                    self.io = Some(io);
+                }
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    debug!("peek error: {}", e);
+                    return Err(());
+                }
+            }
+        }
+
+        match self.timeout.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(())) => {}
+            // A timer error is treated as the timeout elapsing.
+            Err(e) => error!("protocol detection timer failed: {}", e),
+        }
+
+        let io = self.io.take().expect("polled after complete");
+        if !io.peeked().is_empty() {
+            // The connection is not HTTP, since it began with a partial
+            // ClientHello, so it is forwarded without being routed by SNI.
+            debug!("timed out reading ClientHello; forwarding TCP");
+            return Ok(Async::Ready((None, io)));
+        }
+        match self.on_timeout {
+            OnDetectTimeout::Close => {
+                debug!("protocol detection timed out; closing connection");
+                drop(io);
+                Err(())
+            }
+            OnDetectTimeout::Forward => {
+                debug!("protocol detection timed out; forwarding TCP");
+                Ok(Async::Ready((None, io)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{future, Async, Poll, Stream};
+    use hyper;
+    use std::time::Instant;
+    use tokio::io::{read_exact, read_to_end, write_all};
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::*;
+    use transport::memory;
+
+    /// A response body with a single frame.
+    #[derive(Default)]
+    struct Frame(Option<Bytes>);
+
+    impl tower_h2::Body for Frame {
+        type Data = Bytes;
+
+        fn is_end_stream(&self) -> bool {
+            self.0.is_none()
+        }
+
+        fn poll_data(&mut self) -> Poll<Option<Bytes>, h2::Error> {
+            Ok(Async::Ready(self.0.take()))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, h2::Error> {
+            Ok(Async::Ready(None))
+        }
+    }
+
+    /// Responds to each request with its version and path.
+    #[derive(Clone)]
+    struct Describe;
+
+    impl Stack<Source> for Describe {
+        type Value = Describe;
+        type Error = Never;
+
+        fn make(&self, _: &Source) -> Result<Describe, Never> {
+            Ok(Describe)
+        }
+    }
+
+    impl Service<http::Request<HttpBody>> for Describe {
+        type Response = http::Response<Frame>;
+        type Error = h2::Error;
+        type Future = future::FutureResult<Self::Response, h2::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), h2::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, req: http::Request<HttpBody>) -> Self::Future {
+            let desc = format!("{:?} {}", req.version(), req.uri().path());
+            future::ok(http::Response::new(Frame(Some(desc.into()))))
+        }
+    }
+
+    const LISTEN: &str = "127.0.0.1:4140";
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// Serves an in-memory connection from `remote` through the full server
+    /// stack, returning the client's end of the connection.
+    fn serve(
+        rt: &mut Runtime,
+        connect: memory::Connect,
+        remote: SocketAddr,
+    ) -> (memory::Duplex, drain::Signal) {
+        let timeout = Duration::from_secs(10);
+        serve_with_detect_timeout(rt, connect, remote, timeout, OnDetectTimeout::Forward)
+    }
+
+    fn serve_with_detect_timeout(
+        rt: &mut Runtime,
+        connect: memory::Connect,
+        remote: SocketAddr,
+        detect_protocol_timeout: Duration,
+        on_detect_timeout: OnDetectTimeout,
+    ) -> (memory::Duplex, drain::Signal) {
+        let (drain_tx, drain_rx) = drain::channel();
+        let server = server(
+            connect,
+            IndexSet::new(),
+            detect_protocol_timeout,
+            on_detect_timeout,
+            drain_rx,
+            None,
+        );
+        (accept(rt, &server, remote), drain_tx)
+    }
+
+    /// Builds a server whose connections have an original destination of
+    /// 10.1.1.1:8080.
+    fn server(
+        connect: memory::Connect,
+        disable_protocol_detection_ports: IndexSet<u16>,
+        detect_protocol_timeout: Duration,
+        on_detect_timeout: OnDetectTimeout,
+        drain_rx: drain::Watch,
+        drain_timeout: Option<Duration>,
+    ) -> Server<(), memory::Connect, Describe, Frame, memory::OrigDst> {
+        Server::new(
+            "test",
+            addr(LISTEN),
+            memory::OrigDst::new(addr("10.1.1.1:8080")),
+            (),
+            connect,
+            Describe,
+            disable_protocol_detection_ports,
+            drain_rx,
+            h2::server::Builder::new(),
+        )
+        .with_detect_protocol_timeout(detect_protocol_timeout, on_detect_timeout)
+        .with_tcp_shutdown(tcp::Shutdown::Full)
+        .with_drain_timeout(drain_timeout)
+    }
+
+    /// Serves an in-memory connection from `remote` with `server`, returning
+    /// the client's end of the connection.
+    fn accept(
+        rt: &mut Runtime,
+        server: &Server<(), memory::Connect, Describe, Frame, memory::OrigDst>,
+        remote: SocketAddr,
+    ) -> memory::Duplex {
+        let (client, server_io) = memory::duplex(remote, addr(LISTEN));
+        rt.spawn(server.serve(Connection::in_memory(server_io), remote));
+        client
+    }
+
+    fn request() -> http::Request<hyper::Body> {
+        http::Request::builder()
+            .uri("http://web.example.com/hello")
+            .body(hyper::Body::empty())
+            .unwrap()
+    }
+
+    fn roundtrip(rt: &mut Runtime, builder: &hyper::client::conn::Builder) -> String {
+        let (connect, _listener) = memory::listen(addr("10.1.1.1:8080"));
+        let (io, _drain) = serve(rt, connect, addr("10.2.2.2:50000"));
+
+        let (mut client, conn) = rt.block_on(builder.handshake(io)).expect("handshake");
+        rt.spawn(conn.map_err(|e| debug!("client connection failed: {}", e)));
+        let rsp = rt.block_on(client.send_request(request())).expect("response");
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+        let body = rt.block_on(rsp.into_body().concat2()).expect("body");
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn http1_is_routed_in_memory() {
+        let mut rt = Runtime::new().unwrap();
+        let body = roundtrip(&mut rt, &hyper::client::conn::Builder::new());
+        assert_eq!(body, "HTTP/1.1 /hello");
+    }
+
+    #[test]
+    fn http2_is_routed_in_memory() {
+        let mut rt = Runtime::new().unwrap();
+        let mut builder = hyper::client::conn::Builder::new();
+        builder.http2_only(true);
+        let body = roundtrip(&mut rt, &builder);
+        assert_eq!(body, "HTTP/2.0 /hello");
+    }
+
+    #[test]
+    fn opaque_tcp_is_forwarded_in_memory() {
+        let mut rt = Runtime::new().unwrap();
+        let (connect, listener) = memory::listen(addr("10.1.1.1:8080"));
+        let (io, _drain) = serve(&mut rt, connect, addr("10.2.2.2:50000"));
+
+        // Bytes that are neither HTTP/1 nor HTTP/2 are forwarded to the
+        // original destination.
+        let io = rt.block_on(write_all(io, b"\x00ping")).expect("write").0;
+        let (conn, _) = rt.block_on(listener.into_future()).expect("accept");
+        let conn = conn.expect("listener must accept a connection");
+        let (conn, ping) = rt.block_on(read_exact(conn, [0u8; 5])).expect("read");
+        assert_eq!(&ping, b"\x00ping");
+
+        rt.block_on(write_all(conn, b"pong")).expect("write");
+        let (_, pong) = rt.block_on(read_exact(io, [0u8; 4])).expect("read");
+        assert_eq!(&pong, b"pong");
+    }
+
+    #[test]
+    fn opaque_tcp_is_tapped() {
+        use api::tap::observe_request::{match_, Match};
+
+        let mut rt = Runtime::new().unwrap();
+        let (connect, listener) = memory::listen(addr("10.1.1.1:8080"));
+        let (_drain_tx, drain_rx) = drain::channel();
+
+        // Taps all events.
+        let all = Match {
+            match_: Some(match_::Match::All(match_::Seq::default())),
+        };
+        let (tap, events) = tap::Tap::new(&all, 8, None)
+            .ok()
+            .expect("tap must be valid");
+        let taps = Arc::new(Mutex::new(tap::Taps::default()));
+        taps.lock().unwrap().insert(0, tap);
+
+        let skip = IndexSet::new();
+        let timeout = Duration::from_secs(10);
+        let server = server(connect, skip, timeout, OnDetectTimeout::Close, drain_rx, None)
+            .with_tcp_taps(taps);
+        let io = accept(&mut rt, &server, addr("10.2.2.2:50000"));
+
+        let io = rt.block_on(write_all(io, b"\x00ping")).expect("write").0;
+        let (conn, _) = rt.block_on(listener.into_future()).expect("accept");
+        let conn = conn.expect("listener must accept a connection");
+        let (conn, _) = rt.block_on(read_exact(conn, [0u8; 5])).expect("read");
+        let conn = rt.block_on(write_all(conn, b"pong")).expect("write").0;
+        let (io, _) = rt.block_on(read_exact(io, [0u8; 4])).expect("read");
+
+        // Closing both ends completes the forwarded connection.
+        drop(io);
+        drop(conn);
+        drop(server);
+        let events = rt
+            .block_on(events.take(2).collect())
+            .expect("tap events");
+
+        match events[0] {
+            tap::Event::TcpOpen(ref c) => {
+                assert_eq!(c.source.remote, addr("10.2.2.2:50000"));
+                assert_eq!(c.destination, addr("10.1.1.1:8080"));
+            }
+            ref ev => panic!("unexpected event: {:?}", ev),
+        }
+        match events[1] {
+            tap::Event::TcpClose(_, ref close) => {
+                assert_eq!(close.bytes_received, 5);
+                assert_eq!(close.bytes_sent, 4);
+            }
+            ref ev => panic!("unexpected event: {:?}", ev),
+        }
+    }
+
+    /// Records the address of each connection made through a
+    /// `memory::Connect`.
+    #[derive(Clone)]
+    struct Recorded(memory::Connect, Arc<Mutex<Vec<SocketAddr>>>);
+
+    impl Stack<connect::Target> for Recorded {
+        type Value = memory::Connect;
+        type Error = Never;
+
+        fn make(&self, target: &connect::Target) -> Result<memory::Connect, Never> {
+            self.1.lock().unwrap().push(target.addr);
+            self.0.make(target)
+        }
+    }
+
+    /// Builds a TLS 1.2 ClientHello record with a single `server_name`
+    /// extension.
+    fn client_hello(sni: &str) -> Vec<u8> {
+        fn vec_u16(body: Vec<u8>) -> Vec<u8> {
+            let mut v = vec![(body.len() >> 8) as u8, body.len() as u8];
+            v.extend(body);
+            v
+        }
+
+        let server_name = {
+            let mut name = vec![0]; // NameType::host_name
+            name.extend(vec_u16(sni.as_bytes().to_vec()));
+            vec_u16(name)
+        };
+        let extensions = {
+            let mut ext = vec![0, 0]; // ExtensionType::server_name
+            ext.extend(vec_u16(server_name));
+            vec_u16(ext)
+        };
+
+        let mut hello = vec![0x03, 0x03]; // TLS 1.2
+        hello.extend(&[0; 32]); // random
+        hello.push(0); // session_id
+        hello.extend(vec_u16(vec![0xc0, 0x2f])); // cipher_suites
+        hello.extend(&[1, 0]); // compression_methods
+        hello.extend(extensions);
+
+        let mut handshake = vec![1, 0]; // HandshakeType::client_hello
+        handshake.extend(vec_u16(hello));
+
+        let mut record = vec![22, 0x03, 0x01]; // ContentType::handshake
+        record.extend(vec_u16(handshake));
+        record
+    }
+
+    fn sni_routes() -> IndexMap<tls::Identity, SocketAddr> {
+        let db = tls::Identity::from_sni_hostname(b"db.example.com").unwrap();
+        vec![(db, addr("10.3.3.3:5432"))].into_iter().collect()
+    }
+
+    /// Forwards a ClientHello for `sni`, written in segments of at most
+    /// `segment_len` bytes, through a server that routes `db.example.com` to
+    /// 10.3.3.3:5432, returning the addresses to which connections were made.
+    fn forward_client_hello(sni: &str, segment_len: usize) -> Vec<SocketAddr> {
+        let mut rt = Runtime::new().unwrap();
+        let (connect, listener) = memory::listen(addr("10.1.1.1:8080"));
+        let connects = Arc::new(Mutex::new(Vec::new()));
+        let (_drain_tx, drain_rx) = drain::channel();
+        let server = Server::new(
+            "test",
+            addr(LISTEN),
+            memory::OrigDst::new(addr("10.1.1.1:8080")),
+            (),
+            Recorded(connect, connects.clone()),
+            Describe,
+            IndexSet::new(),
+            drain_rx,
+            h2::server::Builder::new(),
+        )
+        .with_detect_protocol_timeout(Duration::from_secs(10), OnDetectTimeout::Close)
+        .with_tcp_shutdown(tcp::Shutdown::Full)
+        .with_sni_route(sni_routes());
+
+        let remote = addr("10.2.2.2:50000");
+        let (mut io, server_io) = memory::duplex(remote, addr(LISTEN));
+        rt.spawn(server.serve(Connection::in_memory(server_io), remote));
+
+        let hello = client_hello(sni);
+        for segment in hello.chunks(segment_len) {
+            io = rt.block_on(write_all(io, segment.to_vec())).expect("write").0;
+            // Give the server a chance to peek each segment separately.
+            let delay = Delay::new(clock::now() + Duration::from_millis(10));
+            rt.block_on(delay).expect("delay");
+        }
+        let (conn, _) = rt.block_on(listener.into_future()).expect("accept");
+        let conn = conn.expect("listener must accept a connection");
+        let (_, buf) = rt
+            .block_on(read_exact(conn, vec![0u8; hello.len()]))
+            .expect("read");
+        assert_eq!(buf, hello, "the ClientHello must be forwarded");
+
+        let connects = connects.lock().unwrap().clone();
+        connects
+    }
+
+    #[test]
+    fn opaque_tls_is_forwarded_by_sni() {
+        let connects = forward_client_hello("db.example.com", usize::max_value());
+        assert_eq!(connects, vec![addr("10.3.3.3:5432")]);
+    }
+
+    #[test]
+    fn opaque_tls_split_across_segments_is_forwarded_by_sni() {
+        let connects = forward_client_hello("db.example.com", 16);
+        assert_eq!(connects, vec![addr("10.3.3.3:5432")]);
+    }
+
+    #[test]
+    fn opaque_tls_with_unrouted_sni_is_forwarded_to_orig_dst() {
+        let connects = forward_client_hello("web.example.com", usize::max_value());
+        assert_eq!(connects, vec![addr("10.1.1.1:8080")]);
+    }
+
+    #[test]
+    fn server_speaks_first_with_sni_routes() {
+        let mut rt = Runtime::new().unwrap();
+        let (connect, listener) = memory::listen(addr("10.1.1.1:8080"));
+        let (_drain_tx, drain_rx) = drain::channel();
+        let skip = vec![8080].into_iter().collect();
+        let timeout = Duration::from_secs(10);
+        let server = server(connect, skip, timeout, OnDetectTimeout::Close, drain_rx, None)
+            .with_sni_route(sni_routes());
+        let io = accept(&mut rt, &server, addr("10.2.2.2:50000"));
+
+        // The client waits for the server's greeting, so the connection must
+        // be forwarded before the client has written anything.
+        let (conn, _) = rt.block_on(listener.into_future()).expect("accept");
+        let conn = conn.expect("listener must accept a connection");
+        rt.block_on(write_all(conn, b"220 hello\r\n")).expect("write");
+        let (_, buf) = rt.block_on(read_exact(io, [0u8; 11])).expect("read");
+        assert_eq!(&buf[..], &b"220 hello\r\n"[..]);
+    }
+
+    #[test]
+    fn detection_is_skipped_for_mapped_port() {
+        let mut rt = Runtime::new().unwrap();
+        let (connect, listener) = memory::listen(addr("10.1.1.1:8080"));
+        let (_drain_tx, drain_rx) = drain::channel();
+        let skip = vec![9090].into_iter().collect();
+        let timeout = Duration::from_secs(10);
+        let server = server(connect, skip, timeout, OnDetectTimeout::Close, drain_rx, None)
+            .with_detection_port(|_: &Source| Some(9090));
+        let io = accept(&mut rt, &server, addr("10.2.2.2:50000"));
+
+        // Although the original destination port is not in the skip set, the
+        // mapped port is, so HTTP is forwarded as opaque TCP.
+        let msg = b"GET / HTTP/1.1\r\n\r\n";
+        rt.block_on(write_all(io, &msg[..])).expect("write");
+        let (conn, _) = rt.block_on(listener.into_future()).expect("accept");
+        let conn = conn.expect("listener must accept a connection");
+        let (_, buf) = rt.block_on(read_exact(conn, [0u8; 18])).expect("read");
+        assert_eq!(&buf[..], &msg[..]);
+    }
+
+    #[test]
+    fn detection_is_not_skipped_for_unmapped_port() {
+        let mut rt = Runtime::new().unwrap();
+        let (connect, _listener) = memory::listen(addr("10.1.1.1:8080"));
+        let (_drain_tx, drain_rx) = drain::channel();
+        let skip = vec![8080].into_iter().collect();
+        let timeout = Duration::from_secs(10);
+        let server = server(connect, skip, timeout, OnDetectTimeout::Close, drain_rx, None)
+            .with_detection_port(|_: &Source| Some(9090));
+        let io = accept(&mut rt, &server, addr("10.2.2.2:50000"));
+
+        // Although the original destination port is in the skip set, the
+        // mapped port is not, so HTTP is detected and routed.
+        let builder = hyper::client::conn::Builder::new();
+        let (mut client, conn) = rt.block_on(builder.handshake(io)).expect("handshake");
+        rt.spawn(conn.map_err(|e| debug!("client connection failed: {}", e)));
+        let rsp = rt.block_on(client.send_request(request())).expect("response");
+        let body = rt.block_on(rsp.into_body().concat2()).expect("body");
+        assert_eq!(&body[..], &b"HTTP/1.1 /hello"[..]);
+    }
+
+    #[test]
+    fn connections_beyond_max_are_closed() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+
+        let mut rt = Runtime::new().unwrap();
+        let (connect, _listener) = memory::listen(addr("10.1.1.1:8080"));
+        let (_drain_tx, drain_rx) = drain::channel();
+        let skip = IndexSet::new();
+        let server = server(connect, skip, TIMEOUT, OnDetectTimeout::Close, drain_rx, None)
+            .with_max_connections(Some(2));
+        let remote = addr("10.2.2.2:50000");
+
+        // Silent connections are held open until the detection timeout.
+        let start = Instant::now();
+        let held = vec![accept(&mut rt, &server, remote), accept(&mut rt, &server, remote)];
+
+        // A connection beyond the limit is closed before the held
+        // connections time out.
+        let rejected = accept(&mut rt, &server, remote);
+        let (_, buf) = rt.block_on(read_to_end(rejected, Vec::new())).expect("read");
+        assert!(buf.is_empty());
+        assert!(start.elapsed() < TIMEOUT);
+
+        // Once the held connections are closed, new connections are served
+        // until they time out.
+        for io in held {
+            rt.block_on(read_to_end(io, Vec::new())).expect("read");
+        }
+        let start = Instant::now();
+        let io = accept(&mut rt, &server, remote);
+        rt.block_on(read_to_end(io, Vec::new())).expect("read");
+        assert!(start.elapsed() >= TIMEOUT);
+    }
+
+    #[test]
+    fn connections_are_aborted_after_drain_timeout() {
+        const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+        let mut rt = Runtime::new().unwrap();
+        let (connect, listener) = memory::listen(addr("10.1.1.1:8080"));
+        let (drain_tx, drain_rx) = drain::channel();
+        let timeout = Duration::from_secs(10);
+        let server = server(
+            connect,
+            IndexSet::new(),
+            timeout,
+            OnDetectTimeout::Close,
+            drain_rx,
+            Some(DRAIN_TIMEOUT),
+        );
+        let io = accept(&mut rt, &server, addr("10.2.2.2:50000"));
+        drop(server);
+
+        // A forwarded connection is not closed when a drain is signaled, so
+        // it never completes unless it is aborted.
+        let io = rt.block_on(write_all(io, b"\x00ping")).expect("write").0;
+        let (conn, _) = rt.block_on(listener.into_future()).expect("accept");
+        let _conn = conn.expect("listener must accept a connection");
+
+        let start = Instant::now();
+        rt.block_on(drain_tx.drain()).expect("drain");
+        assert!(start.elapsed() >= DRAIN_TIMEOUT);
+
+        // The aborted connection is closed.
+        let (_, buf) = rt.block_on(read_to_end(io, Vec::new())).expect("read");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn silent_connection_is_forwarded_after_detect_timeout() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+
+        let mut rt = Runtime::new().unwrap();
+        let (connect, listener) = memory::listen(addr("10.1.1.1:8080"));
+        let start = Instant::now();
+        let (io, _drain) = serve_with_detect_timeout(
+            &mut rt,
+            connect,
+            addr("10.2.2.2:50000"),
+            TIMEOUT,
+            OnDetectTimeout::Forward,
+        );
+
+        // The client never writes, so the connection is forwarded once the
+        // timeout elapses.
+        let (conn, _) = rt.block_on(listener.into_future()).expect("accept");
+        let conn = conn.expect("listener must accept a connection");
+        assert!(start.elapsed() >= TIMEOUT);
+
+        // The server may speak first.
+        rt.block_on(write_all(conn, b"hello")).expect("write");
+        let (_, hello) = rt.block_on(read_exact(io, [0u8; 5])).expect("read");
+        assert_eq!(&hello, b"hello");
+    }
+
+    #[test]
+    fn silent_connection_is_closed_after_detect_timeout() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+
+        let mut rt = Runtime::new().unwrap();
+        let (connect, _listener) = memory::listen(addr("10.1.1.1:8080"));
+        let start = Instant::now();
+        let (io, _drain) = serve_with_detect_timeout(
+            &mut rt,
+            connect,
+            addr("10.2.2.2:50000"),
+            TIMEOUT,
+            OnDetectTimeout::Close,
+        );
+
+        // The client never writes, so the server closes the connection once
+        // the timeout elapses.
+        let (_, buf) = rt.block_on(read_to_end(io, Vec::new())).expect("read");
+        assert!(buf.is_empty());
+        assert!(start.elapsed() >= TIMEOUT);
+    }
+}
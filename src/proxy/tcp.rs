@@ -7,6 +7,20 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use svc;
 use transport::connect::Connect;
 
+/// Determines how a forwarded connection is closed once one of its sides
+/// finishes writing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Shutdown {
+    /// The end of one side's stream is propagated to the other side by
+    /// shutting down its write half, while data continues to flow in the
+    /// other direction until that side also finishes writing.
+    HalfClose,
+
+    /// The forwarded connection is torn down as soon as either side finishes
+    /// writing.
+    Full,
+}
+
 /// Attempt to proxy the `server_io` stream to a `T`-typed target.
 ///
 /// If the trget is not valid, an error is logged and the server stream is
@@ -15,6 +29,7 @@ pub(super) fn forward<I, C, T>(
     server_io: I,
     connect: &C,
     target: &T,
+    shutdown: Shutdown,
 ) -> impl Future<Item=(), Error=()> + Send + 'static
 where
     T: fmt::Debug,
@@ -38,6 +53,7 @@ where
         .map_err(|e| info!("forward connect error: {:?}", e))
         .and_then(move |io| {
             Duplex::new(server_io, io)
+                .with_shutdown(shutdown)
                 .map_err(|e| info!("forward duplex error: {}", e))
         });
 
@@ -48,6 +64,7 @@ where
 pub struct Duplex<In, Out> {
     half_in: HalfDuplex<In>,
     half_out: HalfDuplex<Out>,
+    shutdown: Shutdown,
 }
 
 struct HalfDuplex<T> {
@@ -79,8 +96,13 @@ where
         Duplex {
             half_in: HalfDuplex::new(in_io),
             half_out: HalfDuplex::new(out_io),
+            shutdown: Shutdown::default(),
         }
     }
+
+    pub(super) fn with_shutdown(self, shutdown: Shutdown) -> Self {
+        Duplex { shutdown, ..self }
+    }
 }
 
 impl<In, Out> Future for Duplex<In, Out>
@@ -97,7 +119,12 @@ where
         // could make progress.
         self.half_in.copy_into(&mut self.half_out)?;
         self.half_out.copy_into(&mut self.half_in)?;
-        if self.half_in.is_done() && self.half_out.is_done() {
+
+        let is_done = match self.shutdown {
+            Shutdown::HalfClose => self.half_in.is_done() && self.half_out.is_done(),
+            Shutdown::Full => self.half_in.is_done() || self.half_out.is_done(),
+        };
+        if is_done {
             Ok(Async::Ready(()))
         } else {
             Ok(Async::NotReady)
@@ -105,6 +132,12 @@ where
     }
 }
 
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::HalfClose
+    }
+}
+
 impl<T> HalfDuplex<T>
 where
     T: AsyncRead,
@@ -235,7 +268,10 @@ impl BufMut for CopyBuf {
 
 #[cfg(test)]
 mod tests {
-    use std::io::{Error, Read, Write, Result};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{Error, ErrorKind, Read, Write, Result};
+    use std::rc::Rc;
     use std::sync::atomic::{AtomicBool, Ordering};
 
     use tokio::io::{AsyncRead, AsyncWrite};
@@ -278,6 +314,109 @@ mod tests {
         }
     }
 
+    /// A transport that reads scripted chunks and records what is written.
+    #[derive(Clone, Default)]
+    struct ScriptedIo(Rc<RefCell<Script>>);
+
+    #[derive(Default)]
+    struct Script {
+        reads: VecDeque<&'static [u8]>,
+        eof: bool,
+        written: Vec<u8>,
+        is_shutdown: bool,
+    }
+
+    impl ScriptedIo {
+        fn read(&self, chunk: &'static [u8]) -> &Self {
+            self.0.borrow_mut().reads.push_back(chunk);
+            self
+        }
+
+        fn eof(&self) -> &Self {
+            self.0.borrow_mut().eof = true;
+            self
+        }
+
+        fn written(&self) -> Vec<u8> {
+            self.0.borrow().written.clone()
+        }
+
+        fn is_shutdown(&self) -> bool {
+            self.0.borrow().is_shutdown
+        }
+    }
+
+    impl Read for ScriptedIo {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let mut script = self.0.borrow_mut();
+            match script.reads.pop_front() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    Ok(chunk.len())
+                }
+                None if script.eof => Ok(0),
+                None => Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl AsyncRead for ScriptedIo {}
+
+    impl Write for ScriptedIo {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.borrow_mut().written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for ScriptedIo {
+        fn shutdown(&mut self) -> Poll<(), Error> {
+            self.0.borrow_mut().is_shutdown = true;
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn half_close_keeps_other_direction_open() {
+        let client = ScriptedIo::default();
+        let server = ScriptedIo::default();
+        client.read(b"ping").eof();
+        let mut duplex = Duplex::new(client.clone(), server.clone());
+
+        // The client's EOF is propagated to the server, but the server may
+        // still respond.
+        assert_eq!(duplex.poll().unwrap(), Async::NotReady);
+        assert_eq!(server.written(), b"ping");
+        assert!(server.is_shutdown());
+        assert!(!client.is_shutdown());
+
+        server.read(b"pong");
+        assert_eq!(duplex.poll().unwrap(), Async::NotReady);
+        assert_eq!(client.written(), b"pong");
+        assert!(!client.is_shutdown());
+
+        server.eof();
+        assert_eq!(duplex.poll().unwrap(), Async::Ready(()));
+        assert!(client.is_shutdown());
+    }
+
+    #[test]
+    fn full_shutdown_tears_down_when_one_direction_closes() {
+        let client = ScriptedIo::default();
+        let server = ScriptedIo::default();
+        client.read(b"ping").eof();
+        let mut duplex = Duplex::new(client.clone(), server.clone())
+            .with_shutdown(Shutdown::Full);
+
+        assert_eq!(duplex.poll().unwrap(), Async::Ready(()));
+        assert_eq!(server.written(), b"ping");
+        assert!(server.is_shutdown());
+        assert!(client.written().is_empty());
+    }
+
     #[test]
     fn duplex_doesnt_hang_when_one_half_finishes() {
         // Test reproducing an infinite loop in Duplex that caused issue #519,
@@ -48,3 +48,45 @@ where
         Ok(Timeout::new(inner, self.timeout))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::io;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::*;
+    use svc::{shared, Layer as _Layer, Stack as _Stack};
+    use transport::Connect;
+
+    /// Never establishes a connection, like a blackholed address.
+    #[derive(Clone, Debug)]
+    struct Blackhole;
+
+    impl Connect for Blackhole {
+        type Connected = ();
+        type Error = io::Error;
+        type Future = future::Empty<(), io::Error>;
+
+        fn connect(&self) -> Self::Future {
+            future::empty()
+        }
+    }
+
+    #[test]
+    fn connect_fails_once_timeout_elapses() {
+        let timeout = Duration::from_millis(100);
+        let stack = layer(timeout).bind(shared::stack(Blackhole));
+        let connect = stack.make(&()).expect("make");
+
+        let mut rt = Runtime::new().unwrap();
+        let start = Instant::now();
+        let err = rt.block_on(connect.connect()).expect_err("connect must fail");
+        let elapsed = start.elapsed();
+
+        assert!(err.is_timeout(), "not a timeout: {}", err);
+        assert!(elapsed >= timeout, "failed after {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "failed after {:?}", elapsed);
+    }
+}
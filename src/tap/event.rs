@@ -1,6 +1,7 @@
 use h2;
 use http;
 use indexmap::IndexMap;
+use std::net::SocketAddr;
 use std::time::Instant;
 
 use proxy::Source;
@@ -43,6 +44,27 @@ pub enum Event {
     StreamResponseOpen(Response, StreamResponseOpen),
     StreamResponseFail(Response, StreamResponseFail),
     StreamResponseEnd(Response, StreamResponseEnd),
+
+    TcpOpen(Connection),
+    TcpClose(Connection, TcpClose),
+}
+
+/// Describes a connection that is forwarded as opaque TCP.
+#[derive(Clone, Debug)]
+pub struct Connection {
+    /// Describes the accepted connection, including its TLS status.
+    pub source: Source,
+    pub destination: SocketAddr,
+}
+
+#[derive(Clone, Debug)]
+pub struct TcpClose {
+    pub open_at: Instant,
+    pub close_at: Instant,
+    /// The number of bytes read from the source.
+    pub bytes_received: u64,
+    /// The number of bytes written to the source.
+    pub bytes_sent: u64,
 }
 
 #[derive(Clone, Debug)]
@@ -83,3 +105,46 @@ pub struct StreamResponseEnd {
     pub grpc_status: Option<u32>,
     pub bytes_sent: u64,
 }
+
+// === impl Request ===
+
+impl Request {
+    #[cfg(test)]
+    pub fn for_test(id: usize) -> Self {
+        use std::net::SocketAddr;
+        use transport::tls;
+        use Conditional;
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let no_tls = tls::ReasonForNoTls::Disabled;
+        Request {
+            id,
+            source: Source::for_test(addr, addr, None, Conditional::None(no_tls)),
+            endpoint: Endpoint {
+                direction: Direction::Out,
+                target: connect::Target::new(addr, Conditional::None(no_tls)),
+                labels: IndexMap::default(),
+            },
+            method: http::Method::GET,
+            scheme: None,
+            authority: None,
+            path: "/".into(),
+        }
+    }
+}
+
+// === impl Connection ===
+
+impl Connection {
+    #[cfg(test)]
+    pub fn for_test(remote: SocketAddr, destination: SocketAddr) -> Self {
+        use transport::tls;
+        use Conditional;
+
+        let tls_status = Conditional::None(tls::ReasonForNoTls::Disabled);
+        Connection {
+            source: Source::for_test(remote, destination, Some(destination), tls_status),
+            destination,
+        }
+    }
+}
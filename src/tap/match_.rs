@@ -1,6 +1,7 @@
 use indexmap::IndexMap;
 use std::boxed::Box;
-use std::net;
+use std::{fmt, net};
+use std::time::Duration;
 
 use http;
 use ipnet::{Contains, Ipv4Net, Ipv6Net};
@@ -21,16 +22,43 @@ pub(super) enum Match {
     Http(HttpMatch),
 }
 
-#[derive(Eq, PartialEq)]
+/// Describes why a match could not be compiled.
+///
+/// Invalid values are wrapped in `Field`s naming the (possibly nested) field
+/// that holds them, e.g. `source.ports: invalid port 70000`.
+#[derive(Debug, Eq, PartialEq)]
 pub enum InvalidMatch {
     Empty,
-    InvalidPort,
-    InvalidNetwork,
-    InvalidHttpMethod,
-    InvalidScheme,
+    InvalidPort(u32),
+    InvalidNetwork(String),
+    InvalidHttpMethod(String),
+    InvalidScheme(String),
+    InvalidStatus(String),
+    InvalidLatency(String),
+    InvalidStride(String),
     Unimplemented,
+    Field(&'static str, Box<InvalidMatch>),
 }
 
+/// Restricts a tap to requests whose responses match, e.g. so that only
+/// failed or slow requests are tapped.
+#[derive(Clone, Debug)]
+pub enum ResponseMatch {
+    Any(Vec<ResponseMatch>),
+    /// Inclusive
+    Status(http::StatusCode, http::StatusCode),
+    /// The minimum time between the request opening and the response opening.
+    MinLatency(Duration),
+}
+
+/// Requests only responses with statuses in the given inclusive range, e.g.
+/// `500-599`.
+const RESPONSE_STATUS_HEADER: &str = "l5d-tap-response-status";
+
+/// Requests only responses that take at least the given number of
+/// milliseconds.
+const RESPONSE_MIN_LATENCY_HEADER: &str = "l5d-tap-response-min-latency-ms";
+
 #[derive(Clone, Debug)]
 pub(super) struct LabelMatch {
     key: String,
@@ -90,6 +118,9 @@ impl Match {
                 Event::StreamResponseOpen(ref rsp, _) |
                 Event::StreamResponseFail(ref rsp, _) |
                 Event::StreamResponseEnd(ref rsp, _) => src.matches(&rsp.request.source.remote),
+                Event::TcpOpen(ref conn) | Event::TcpClose(ref conn, _) => {
+                    src.matches(&conn.source.remote)
+                }
                 _ => false,
             },
 
@@ -101,6 +132,9 @@ impl Match {
                 Event::StreamResponseFail(ref rsp, _) |
                 Event::StreamResponseEnd(ref rsp, _) =>
                     dst.matches(&rsp.request.endpoint.target.addr),
+                Event::TcpOpen(ref conn) | Event::TcpClose(ref conn, _) => {
+                    dst.matches(&conn.destination)
+                }
                 _ => false,
             },
 
@@ -140,12 +174,15 @@ impl Match {
             .unwrap_or_else(|| Err(InvalidMatch::Empty))
     }
 
-    fn from_seq(seq: &observe_request::match_::Seq) -> Result<Vec<Match>, InvalidMatch> {
+    fn from_seq(
+        field: &'static str,
+        seq: &observe_request::match_::Seq,
+    ) -> Result<Vec<Match>, InvalidMatch> {
         let mut new = Vec::with_capacity(seq.matches.len());
 
         for m in &seq.matches {
             if let Some(m) = m.match_.as_ref() {
-                new.push(Self::try_from(m)?);
+                new.push(Self::try_from(m).map_err(|e| e.in_field(field))?);
             }
         }
 
@@ -161,30 +198,73 @@ impl<'a> TryFrom<&'a observe_request::match_::Match> for Match {
         use api::tap::observe_request::match_;
 
         let match_ = match *m {
-            match_::Match::All(ref seq) => Match::All(Self::from_seq(seq)?),
+            match_::Match::All(ref seq) => Match::All(Self::from_seq("all", seq)?),
 
-            match_::Match::Any(ref seq) => Match::Any(Self::from_seq(seq)?),
+            match_::Match::Any(ref seq) => Match::Any(Self::from_seq("any", seq)?),
 
             match_::Match::Not(ref m) => match m.match_.as_ref() {
-                Some(m) => Match::Not(Box::new(Self::try_from(m)?)),
-                None => return Err(InvalidMatch::Empty),
+                Some(m) => {
+                    let m = Self::try_from(m).map_err(|e| e.in_field("not"))?;
+                    Match::Not(Box::new(m))
+                }
+                None => return Err(InvalidMatch::Empty.in_field("not")),
             },
 
-            match_::Match::Source(ref src) => Match::Source(TcpMatch::try_from(src)?),
+            match_::Match::Source(ref src) => {
+                let src = TcpMatch::try_from(src).map_err(|e| e.in_field("source"))?;
+                Match::Source(src)
+            }
 
-            match_::Match::Destination(ref dst) => Match::Destination(TcpMatch::try_from(dst)?),
+            match_::Match::Destination(ref dst) => {
+                let dst = TcpMatch::try_from(dst).map_err(|e| e.in_field("destination"))?;
+                Match::Destination(dst)
+            }
 
             match_::Match::DestinationLabel(ref label) => {
-                Match::DestinationLabel(LabelMatch::try_from(label)?)
+                let label =
+                    LabelMatch::try_from(label).map_err(|e| e.in_field("destination_label"))?;
+                Match::DestinationLabel(label)
             }
 
-            match_::Match::Http(ref http) => Match::Http(HttpMatch::try_from(http)?),
+            match_::Match::Http(ref http) => {
+                let http = HttpMatch::try_from(http).map_err(|e| e.in_field("http"))?;
+                Match::Http(http)
+            }
         };
 
         Ok(match_)
     }
 }
 
+// ===== impl InvalidMatch ======
+
+impl InvalidMatch {
+    /// Indicates that this error occurred in the named field.
+    pub fn in_field(self, field: &'static str) -> Self {
+        InvalidMatch::Field(field, Box::new(self))
+    }
+}
+
+impl fmt::Display for InvalidMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvalidMatch::Empty => write!(f, "missing value"),
+            InvalidMatch::InvalidPort(port) => write!(f, "invalid port {}", port),
+            InvalidMatch::InvalidNetwork(ref net) => write!(f, "invalid network {}", net),
+            InvalidMatch::InvalidHttpMethod(ref m) => write!(f, "invalid HTTP method {}", m),
+            InvalidMatch::InvalidScheme(ref s) => write!(f, "invalid scheme {}", s),
+            InvalidMatch::InvalidStatus(ref s) => write!(f, "invalid status range {:?}", s),
+            InvalidMatch::InvalidLatency(ref l) => write!(f, "invalid latency {}", l),
+            InvalidMatch::InvalidStride(ref s) => write!(f, "invalid sample stride {}", s),
+            InvalidMatch::Unimplemented => write!(f, "unimplemented"),
+            InvalidMatch::Field(field, ref e) => match **e {
+                InvalidMatch::Field(..) => write!(f, "{}.{}", field, e),
+                _ => write!(f, "{}: {}", field, e),
+            },
+        }
+    }
+}
+
 // ===== impl LabelMatch ======
 
 impl LabelMatch {
@@ -197,8 +277,11 @@ impl<'a> TryFrom<&'a observe_request::match_::Label> for LabelMatch {
     type Err = InvalidMatch;
 
     fn try_from(m: &observe_request::match_::Label) -> Result<Self, InvalidMatch> {
-        if m.key.is_empty() || m.value.is_empty() {
-            return Err(InvalidMatch::Empty);
+        if m.key.is_empty() {
+            return Err(InvalidMatch::Empty.in_field("key"));
+        }
+        if m.value.is_empty() {
+            return Err(InvalidMatch::Empty.in_field("value"));
         }
 
         Ok(LabelMatch {
@@ -240,15 +323,21 @@ impl<'a> TryFrom<&'a observe_request::match_::Tcp> for TcpMatch {
                 let min = if range.min == 0 { range.max } else { range.min };
                 let max = if range.max == 0 { range.min } else { range.max };
                 if min == 0 || max == 0 {
-                    return Err(InvalidMatch::Empty);
+                    return Err(InvalidMatch::Empty.in_field("ports"));
                 }
-                if min > u32::from(::std::u16::MAX) || max > u32::from(::std::u16::MAX) {
-                    return Err(InvalidMatch::InvalidPort);
+                for &(field, port) in &[("min", min), ("max", max)] {
+                    if port > u32::from(::std::u16::MAX) {
+                        let e = InvalidMatch::InvalidPort(port).in_field(field);
+                        return Err(e.in_field("ports"));
+                    }
                 }
                 TcpMatch::PortRange(min as u16, max as u16)
             }
 
-            tcp::Match::Netmask(ref netmask) => TcpMatch::Net(NetMatch::try_from(netmask)?),
+            tcp::Match::Netmask(ref netmask) => {
+                let net = NetMatch::try_from(netmask).map_err(|e| e.in_field("netmask"))?;
+                TcpMatch::Net(net)
+            }
         };
 
         Ok(match_)
@@ -275,27 +364,29 @@ impl NetMatch {
 impl<'a> TryFrom<&'a observe_request::match_::tcp::Netmask> for NetMatch {
     type Err = InvalidMatch;
     fn try_from(m: &'a observe_request::match_::tcp::Netmask) -> Result<Self, InvalidMatch> {
+        let invalid_mask = || {
+            InvalidMatch::InvalidNetwork(format!("/{}", m.mask)).in_field("mask")
+        };
         let mask = if m.mask == 0 {
-            return Err(InvalidMatch::Empty);
+            return Err(InvalidMatch::Empty.in_field("mask"));
         } else if m.mask > u32::from(::std::u8::MAX) {
-            return Err(InvalidMatch::InvalidNetwork);
+            return Err(invalid_mask());
         } else {
             m.mask as u8
         };
 
         let ip = match m.ip.as_ref().and_then(|a| a.ip.as_ref()) {
             Some(ip) => ip,
-            None => return Err(InvalidMatch::Empty),
+            None => return Err(InvalidMatch::Empty.in_field("ip")),
         };
 
         let net = match *ip {
             ip_address::Ip::Ipv4(ref n) => {
-                let net =
-                    Ipv4Net::new((*n).into(), mask).map_err(|_| InvalidMatch::InvalidNetwork)?;
+                let net = Ipv4Net::new((*n).into(), mask).map_err(|_| invalid_mask())?;
                 NetMatch::Net4(net)
             }
             ip_address::Ip::Ipv6(ref ip6) => {
-                let net = Ipv6Net::new(ip6.into(), mask).map_err(|_| InvalidMatch::InvalidNetwork)?;
+                let net = Ipv6Net::new(ip6.into(), mask).map_err(|_| invalid_mask())?;
                 NetMatch::Net6(net)
             }
         };
@@ -351,8 +442,9 @@ impl<'a> TryFrom<&'a observe_request::match_::Http> for HttpMatch {
                     .and_then(|s| {
                         s.try_to_string()
                             .map(HttpMatch::Scheme)
-                            .map_err(|_| InvalidMatch::InvalidScheme)
-                    }),
+                            .map_err(|_| InvalidMatch::InvalidScheme(format!("{:?}", s)))
+                    })
+                    .map_err(|e| e.in_field("scheme")),
 
                 Pb::Method(ref m) => m.type_
                     .as_ref()
@@ -360,25 +452,96 @@ impl<'a> TryFrom<&'a observe_request::match_::Http> for HttpMatch {
                     .and_then(|m| {
                         m.try_as_http()
                             .map(HttpMatch::Method)
-                            .map_err(|_| InvalidMatch::InvalidHttpMethod)
-                    }),
+                            .map_err(|_| InvalidMatch::InvalidHttpMethod(format!("{:?}", m)))
+                    })
+                    .map_err(|e| e.in_field("method")),
 
                 Pb::Authority(ref a) => a.match_
                     .as_ref()
-                    .ok_or_else(|| InvalidMatch::Empty)
+                    .ok_or_else(|| InvalidMatch::Empty.in_field("authority"))
                     .map(|a| HttpMatch::Authority(a.clone())),
 
                 Pb::Path(ref p) => p.match_
                     .as_ref()
-                    .ok_or_else(|| InvalidMatch::Empty)
+                    .ok_or_else(|| InvalidMatch::Empty.in_field("path"))
                     .map(|p| HttpMatch::Path(p.clone())),
             })
     }
 }
 
+// ===== impl ResponseMatch ======
+
+impl ResponseMatch {
+    /// Parses a response match from the headers of an `ObserveRequest`.
+    ///
+    /// If both a status range and a minimum latency are requested, responses
+    /// that satisfy either are matched.
+    pub fn from_headers(headers: &http::HeaderMap) -> Result<Option<Self>, InvalidMatch> {
+        let mut matches = Vec::new();
+
+        if let Some(v) = headers.get(RESPONSE_STATUS_HEADER) {
+            let m = v
+                .to_str()
+                .map_err(|_| InvalidMatch::InvalidStatus(format!("{:?}", v)))
+                .and_then(Self::parse_status)
+                .map_err(|e| e.in_field(RESPONSE_STATUS_HEADER))?;
+            matches.push(m);
+        }
+
+        if let Some(v) = headers.get(RESPONSE_MIN_LATENCY_HEADER) {
+            let ms = v
+                .to_str()
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .ok_or_else(|| {
+                    InvalidMatch::InvalidLatency(format!("{:?}", v))
+                        .in_field(RESPONSE_MIN_LATENCY_HEADER)
+                })?;
+            matches.push(ResponseMatch::MinLatency(Duration::from_millis(ms)));
+        }
+
+        match matches.len() {
+            0 => Ok(None),
+            1 => Ok(matches.pop()),
+            _ => Ok(Some(ResponseMatch::Any(matches))),
+        }
+    }
+
+    fn parse_status(s: &str) -> Result<Self, InvalidMatch> {
+        let invalid = || InvalidMatch::InvalidStatus(s.to_owned());
+        let status = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .ok()
+                .and_then(|s| http::StatusCode::from_u16(s).ok())
+                .ok_or_else(invalid)
+        };
+
+        let mut parts = s.splitn(2, '-');
+        let min = status(parts.next().unwrap_or(""))?;
+        let max = match parts.next() {
+            Some(max) => status(max)?,
+            None => min,
+        };
+        if max < min {
+            return Err(invalid());
+        }
+        Ok(ResponseMatch::Status(min, max))
+    }
+
+    pub(super) fn matches(&self, rsp: &event::Response, latency: Duration) -> bool {
+        match *self {
+            ResponseMatch::Any(ref any) => any.iter().any(|m| m.matches(rsp, latency)),
+            ResponseMatch::Status(min, max) => min <= rsp.status && rsp.status <= max,
+            ResponseMatch::MinLatency(min) => latency >= min,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::mem;
     use ipnet::{Contains, Ipv4Net, Ipv6Net};
     use quickcheck::*;
 
@@ -420,6 +583,14 @@ mod tests {
         }
     }
 
+    /// Describes an error without the field and value it names.
+    fn kind(e: &InvalidMatch) -> mem::Discriminant<InvalidMatch> {
+        match *e {
+            InvalidMatch::Field(_, ref e) => kind(e),
+            ref e => mem::discriminant(e),
+        }
+    }
+
     quickcheck! {
         fn tcp_from_proto(tcp: observe_request::match_::Tcp) -> bool {
             use self::observe_request::match_::tcp;
@@ -431,7 +602,7 @@ mod tests {
                             let ok = 0 < ps.min &&
                                 ps.min <= ps.max &&
                                 ps.max < u32::from(::std::u16::MAX);
-                            if ok { None } else { Some(InvalidMatch::InvalidPort) }
+                            if ok { None } else { Some(InvalidMatch::InvalidPort(0)) }
                         }
                         tcp::Match::Netmask(ref n) => {
                             let ip = n.ip.as_ref().and_then(|a| a.ip.as_ref());
@@ -440,7 +611,7 @@ mod tests {
                     })
                     .unwrap_or(Some(InvalidMatch::Empty));
 
-            err == TcpMatch::try_from(&tcp).err()
+            err.as_ref().map(kind) == TcpMatch::try_from(&tcp).err().as_ref().map(kind)
         }
 
         fn tcp_matches(m: TcpMatch, addr: net::SocketAddr) -> bool {
@@ -468,7 +639,7 @@ mod tests {
                     None
                 };
 
-            err == LabelMatch::try_from(&label).err()
+            err.as_ref().map(kind) == LabelMatch::try_from(&label).err().as_ref().map(kind)
         }
 
         fn label_matches(l: LabelMatch, labels: HashMap<String, String>) -> bool {
@@ -491,16 +662,16 @@ mod tests {
                         Some(&http_types::http_method::Type::Unregistered(ref m)) => if m.len() <= 15 {
                             let mut err = None;
                             if let Err(_) = ::http::Method::from_bytes(m.as_bytes()) {
-                                err = Some(InvalidMatch::InvalidHttpMethod);
+                                err = Some(InvalidMatch::InvalidHttpMethod(String::new()));
                             }
                             err
                         } else {
-                            Some(InvalidMatch::InvalidHttpMethod)
+                            Some(InvalidMatch::InvalidHttpMethod(String::new()))
                         }
                         Some(&http_types::http_method::Type::Registered(m)) => if m < 9 {
                             None
                         } else {
-                            Some(InvalidMatch::InvalidHttpMethod)
+                            Some(InvalidMatch::InvalidHttpMethod(String::new()))
                         }
                     }
                 }
@@ -512,7 +683,7 @@ mod tests {
                             if m < 2 {
                                 None
                             } else {
-                                Some(InvalidMatch::InvalidScheme)
+                                Some(InvalidMatch::InvalidScheme(String::new()))
                             }
                         }
                     }
@@ -531,7 +702,7 @@ mod tests {
                 }
             };
 
-            err == HttpMatch::try_from(&http).err()
+            err.as_ref().map(kind) == HttpMatch::try_from(&http).err().as_ref().map(kind)
         }
 
         // TODO
@@ -540,4 +711,116 @@ mod tests {
         //     m.matches(&addr) == matches
         // }
     }
+
+    #[test]
+    fn response_matches_from_headers() {
+        let mut headers = http::HeaderMap::new();
+        assert!(ResponseMatch::from_headers(&headers).ok().unwrap().is_none());
+
+        headers.insert(RESPONSE_STATUS_HEADER, "500-599".parse().unwrap());
+        let m = ResponseMatch::from_headers(&headers).ok().unwrap().unwrap();
+        let rsp = |status| event::Response {
+            request: event::Request::for_test(0),
+            status: http::StatusCode::from_u16(status).unwrap(),
+        };
+        assert!(m.matches(&rsp(503), Duration::from_millis(0)));
+        assert!(!m.matches(&rsp(200), Duration::from_millis(0)));
+
+        headers.insert(RESPONSE_MIN_LATENCY_HEADER, "500".parse().unwrap());
+        let m = ResponseMatch::from_headers(&headers).ok().unwrap().unwrap();
+        assert!(m.matches(&rsp(200), Duration::from_millis(500)));
+        assert!(!m.matches(&rsp(200), Duration::from_millis(499)));
+
+        headers.insert(RESPONSE_STATUS_HEADER, "599-500".parse().unwrap());
+        let err = ResponseMatch::from_headers(&headers).err().unwrap();
+        assert_eq!(
+            err,
+            InvalidMatch::InvalidStatus("599-500".into()).in_field(RESPONSE_STATUS_HEADER)
+        );
+        assert_eq!(
+            err.to_string(),
+            "l5d-tap-response-status: invalid status range \"599-500\""
+        );
+    }
+
+    #[test]
+    fn destination_labels_filter_events() {
+        use self::observe_request::match_ as pb;
+
+        let m = observe_request::Match {
+            match_: Some(pb::Match::DestinationLabel(pb::Label {
+                key: "deployment".into(),
+                value: "foo".into(),
+            })),
+        };
+        let m = Match::new(&m).ok().expect("label match must be valid");
+
+        let req = |id, deployment: &str| {
+            let mut req = event::Request::for_test(id);
+            req.endpoint
+                .labels
+                .insert("deployment".into(), deployment.into());
+            req.endpoint.labels.insert("namespace".into(), "ns".into());
+            req
+        };
+        let now = ::std::time::Instant::now();
+        let open = |req| {
+            let rsp = event::Response {
+                request: req,
+                status: http::StatusCode::OK,
+            };
+            let open = event::StreamResponseOpen {
+                request_open_at: now,
+                response_open_at: now,
+            };
+            Event::StreamResponseOpen(rsp, open)
+        };
+
+        assert!(m.matches(&Event::StreamRequestOpen(req(0, "foo"))));
+        assert!(m.matches(&open(req(0, "foo"))));
+
+        assert!(!m.matches(&Event::StreamRequestOpen(req(1, "bar"))));
+        assert!(!m.matches(&open(req(1, "bar"))));
+        assert!(!m.matches(&Event::StreamRequestOpen(event::Request::for_test(2))));
+    }
+
+    #[test]
+    fn invalid_port_names_field() {
+        use self::observe_request::match_::{self as pb, tcp};
+
+        let m = observe_request::Match {
+            match_: Some(pb::Match::Destination(pb::Tcp {
+                match_: Some(tcp::Match::Ports(tcp::PortRange { min: 80, max: 70000 })),
+            })),
+        };
+        let err = Match::new(&m).err().expect("port must be invalid");
+        assert_eq!(err.to_string(), "destination.ports.max: invalid port 70000");
+    }
+
+    #[test]
+    fn invalid_http_method_names_field() {
+        use self::observe_request::match_::{self as pb, http};
+
+        let method = http_types::HttpMethod {
+            type_: Some(http_types::http_method::Type::Unregistered("NOT A METHOD".into())),
+        };
+        let all = pb::Seq {
+            matches: vec![observe_request::Match {
+                match_: Some(pb::Match::Http(pb::Http {
+                    match_: Some(http::Match::Method(method)),
+                })),
+            }],
+        };
+        let m = observe_request::Match {
+            match_: Some(pb::Match::All(all)),
+        };
+        let err = Match::new(&m).err().expect("method must be invalid");
+        let msg = err.to_string();
+        assert!(
+            msg.starts_with("all.http.method: invalid HTTP method "),
+            "{}",
+            msg
+        );
+        assert!(msg.contains("NOT A METHOD"), "{}", msg);
+    }
 }
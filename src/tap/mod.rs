@@ -1,5 +1,5 @@
 use futures_mpsc_lossy;
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
 use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
 
 use api::tap::observe_request;
@@ -7,9 +7,10 @@ use api::tap::observe_request;
 pub mod event;
 mod match_;
 mod service;
+pub mod tcp;
 
 pub use self::event::{Direction, Endpoint, Event};
-pub use self::match_::InvalidMatch;
+pub use self::match_::{InvalidMatch, ResponseMatch};
 use self::match_::*;
 pub use self::service::layer;
 
@@ -25,6 +26,13 @@ pub struct Taps {
 pub struct Tap {
     match_: Match,
     tx: futures_mpsc_lossy::Sender<Event>,
+    /// When set, a request's events are withheld until its response matches.
+    response_match: Option<ResponseMatch>,
+    /// Events withheld for requests whose responses are not yet known.
+    pending: IndexMap<usize, Vec<Event>>,
+    /// Requests whose responses matched, so that their remaining events are
+    /// emitted.
+    emitting: IndexSet<usize>,
 }
 
 /// Indicates the tap is no longer receiving
@@ -56,7 +64,7 @@ impl Taps {
         let mut idx = 0;
         while idx < self.by_id.len() {
             let (tap_id, inspect) = {
-                let (id, tap) = self.by_id.get_index(idx).unwrap();
+                let (id, tap) = self.by_id.get_index_mut(idx).unwrap();
                 (*id, tap.inspect(ev))
             };
 
@@ -84,23 +92,112 @@ impl Tap {
     pub fn new(
         match_: &observe_request::Match,
         capacity: usize,
+        response_match: Option<ResponseMatch>,
     ) -> Result<(Tap, futures_mpsc_lossy::Receiver<Event>), InvalidMatch> {
         let (tx, rx) = futures_mpsc_lossy::channel(capacity);
         let match_ = Match::new(match_)?;
-        let tap = Tap { match_, tx };
+        let tap = Tap {
+            match_,
+            tx,
+            response_match,
+            pending: IndexMap::default(),
+            emitting: IndexSet::default(),
+        };
         Ok((tap, rx))
     }
 
-    fn inspect(&self, ev: &Event) -> Result<bool, Ended> {
-        if self.match_.matches(ev) {
-            return self
-                .tx
-                .lossy_send(ev.clone())
-                .map_err(|_| Ended)
-                .map(|_| true);
+    fn inspect(&mut self, ev: &Event) -> Result<bool, Ended> {
+        if !self.match_.matches(ev) {
+            return Ok(false);
         }
 
-        Ok(false)
+        for ev in self.filter_by_response(ev) {
+            self.send(ev)?;
+        }
+        Ok(true)
+    }
+
+    fn send(&self, ev: Event) -> Result<(), Ended> {
+        self.tx.lossy_send(ev).map_err(|_| Ended)
+    }
+
+    /// Returns the events that should be emitted for `ev`.
+    ///
+    /// When the tap has a `ResponseMatch`, a request's events are withheld
+    /// until its response opens (or fails). If the response matches, the
+    /// withheld events are emitted along with all subsequent events for the
+    /// request; otherwise, all of the request's events are suppressed.
+    fn filter_by_response(&mut self, ev: &Event) -> Vec<Event> {
+        if self.response_match.is_none() {
+            return vec![ev.clone()];
+        }
+
+        let (id, response) = match *ev {
+            Event::StreamRequestOpen(ref req) => {
+                self.pending.insert(req.id, vec![ev.clone()]);
+                return vec![];
+            }
+            Event::StreamRequestEnd(ref req, _) => (req.id, None),
+            Event::StreamRequestFail(ref req, _) => {
+                // The request will not have a response to match.
+                self.pending.swap_remove(&req.id);
+                (req.id, None)
+            }
+            Event::StreamResponseOpen(ref rsp, ref open) => {
+                let latency = open.response_open_at.duration_since(open.request_open_at);
+                (rsp.request.id, Some((rsp, latency)))
+            }
+            Event::StreamResponseFail(ref rsp, ref fail) => {
+                let latency = fail.response_open_at.duration_since(fail.request_open_at);
+                (rsp.request.id, Some((rsp, latency)))
+            }
+            Event::StreamResponseEnd(ref rsp, _) => (rsp.request.id, None),
+            // Forwarded connections have no responses to match.
+            Event::TcpOpen(..) | Event::TcpClose(..) => return vec![],
+        };
+        let is_end = match *ev {
+            Event::StreamRequestFail(..)
+            | Event::StreamResponseFail(..)
+            | Event::StreamResponseEnd(..) => true,
+            _ => false,
+        };
+
+        if let Some(mut pending) = self.pending.swap_remove(&id) {
+            match response {
+                // The response is not yet known.
+                None => {
+                    pending.push(ev.clone());
+                    self.pending.insert(id, pending);
+                    return vec![];
+                }
+                Some((rsp, latency)) => {
+                    let is_match = self
+                        .response_match
+                        .as_ref()
+                        .map(|m| m.matches(rsp, latency))
+                        .unwrap_or(true);
+                    if !is_match {
+                        return vec![];
+                    }
+                    if !is_end {
+                        self.emitting.insert(id);
+                    }
+                    pending.push(ev.clone());
+                    return pending;
+                }
+            }
+        }
+
+        let is_emitting = if is_end {
+            self.emitting.swap_remove(&id)
+        } else {
+            self.emitting.contains(&id)
+        };
+        if is_emitting {
+            vec![ev.clone()]
+        } else {
+            vec![]
+        }
     }
 }
 
@@ -109,3 +206,85 @@ impl NextId {
         self.0.fetch_add(1, Ordering::Relaxed)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::Stream;
+    use http;
+    use tokio_timer::clock;
+
+    use super::*;
+
+    fn response(id: usize, status: u16) -> Vec<Event> {
+        let request = event::Request::for_test(id);
+        let rsp = event::Response {
+            request: request.clone(),
+            status: http::StatusCode::from_u16(status).unwrap(),
+        };
+        let now = clock::now();
+        vec![
+            Event::StreamRequestOpen(request),
+            Event::StreamResponseOpen(
+                rsp.clone(),
+                event::StreamResponseOpen {
+                    request_open_at: now,
+                    response_open_at: now,
+                },
+            ),
+            Event::StreamResponseEnd(
+                rsp,
+                event::StreamResponseEnd {
+                    request_open_at: now,
+                    response_open_at: now,
+                    response_first_frame_at: now,
+                    response_end_at: now,
+                    grpc_status: None,
+                    bytes_sent: 0,
+                },
+            ),
+        ]
+    }
+
+    fn tapped(response_match: Option<ResponseMatch>, status: u16) -> Vec<Event> {
+        let (tx, rx) = futures_mpsc_lossy::channel(8);
+        let mut tap = Tap {
+            match_: Match::All(vec![]),
+            tx,
+            response_match,
+            pending: IndexMap::default(),
+            emitting: IndexSet::default(),
+        };
+        for ev in response(0, status) {
+            assert!(tap.inspect(&ev).ok().expect("tap must not end"));
+        }
+        drop(tap);
+
+        rx.wait().map(|ev| ev.expect("event")).collect()
+    }
+
+    fn server_errors() -> Option<ResponseMatch> {
+        let min = http::StatusCode::INTERNAL_SERVER_ERROR;
+        let max = http::StatusCode::from_u16(599).unwrap();
+        Some(ResponseMatch::Status(min, max))
+    }
+
+    #[test]
+    fn unmatched_responses_are_suppressed() {
+        assert!(tapped(server_errors(), 200).is_empty());
+    }
+
+    #[test]
+    fn matched_responses_are_tapped() {
+        let events = tapped(server_errors(), 503);
+        assert_eq!(events.len(), 3);
+        match events[0] {
+            Event::StreamRequestOpen(_) => {}
+            ref ev => panic!("unexpected event: {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn all_responses_are_tapped_without_response_match() {
+        assert_eq!(tapped(None, 200).len(), 3);
+    }
+}
@@ -0,0 +1,171 @@
+//! Taps connections that are forwarded as opaque TCP.
+//!
+//! This parallels the HTTP taps recorded by `tap::layer`: a forwarded
+//! connection emits a `TcpOpen` event when it is accepted and a `TcpClose`
+//! event, describing the number of bytes forwarded in each direction, once
+//! it is closed.
+
+use futures::Poll;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_timer::clock;
+
+use super::{event, Event, Taps};
+
+/// Receives the events of forwarded TCP connections.
+pub trait Inspect {
+    fn inspect(&self, ev: &Event);
+}
+
+/// Wraps a forwarded connection's server transport to record TCP taps.
+#[derive(Debug)]
+pub struct Io<T, I: Inspect> {
+    io: T,
+    inspect: I,
+    connection: event::Connection,
+    open_at: Instant,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+// === impl Inspect ===
+
+impl Inspect for Arc<Mutex<Taps>> {
+    fn inspect(&self, ev: &Event) {
+        if let Ok(mut taps) = self.lock() {
+            taps.inspect(ev);
+        }
+    }
+}
+
+// === impl Io ===
+
+impl<T: AsyncRead + AsyncWrite, I: Inspect> Io<T, I> {
+    /// Wraps `io`, emitting a `TcpOpen` event for `connection`.
+    pub fn new(io: T, connection: event::Connection, inspect: I) -> Self {
+        inspect.inspect(&Event::TcpOpen(connection.clone()));
+        Self {
+            io,
+            inspect,
+            connection,
+            open_at: clock::now(),
+            bytes_received: 0,
+            bytes_sent: 0,
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite, I: Inspect> io::Read for Io<T, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.io.read(buf)?;
+        self.bytes_received += bytes as u64;
+        Ok(bytes)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite, I: Inspect> io::Write for Io<T, I> {
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes = self.io.write(buf)?;
+        self.bytes_sent += bytes as u64;
+        Ok(bytes)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite, I: Inspect> AsyncRead for Io<T, I> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.io.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite, I: Inspect> AsyncWrite for Io<T, I> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+impl<T, I: Inspect> Drop for Io<T, I> {
+    fn drop(&mut self) {
+        let close = event::TcpClose {
+            open_at: self.open_at,
+            close_at: clock::now(),
+            bytes_received: self.bytes_received,
+            bytes_sent: self.bytes_sent,
+        };
+        self.inspect
+            .inspect(&Event::TcpClose(self.connection.clone(), close));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{Read, Write};
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+
+    use super::*;
+    use proxy::Source;
+    use transport::{memory, tls};
+    use Conditional;
+
+    /// Records the events it receives.
+    #[derive(Clone, Default)]
+    struct Events(Rc<RefCell<Vec<Event>>>);
+
+    impl Inspect for Events {
+        fn inspect(&self, ev: &Event) {
+            self.0.borrow_mut().push(ev.clone());
+        }
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn open_and_close_are_inspected_with_byte_counts() {
+        let remote = addr("10.2.2.2:50000");
+        let dst = addr("10.1.1.1:8080");
+        let (mut client, server) = memory::duplex(remote, addr("127.0.0.1:4140"));
+        let tls_status = Conditional::None(tls::ReasonForNoTls::Disabled);
+        let connection = event::Connection {
+            source: Source::for_test(remote, dst, Some(dst), tls_status),
+            destination: dst,
+        };
+
+        let events = Events::default();
+        let mut io = Io::new(server, connection, events.clone());
+        assert_eq!(events.0.borrow().len(), 1);
+        match events.0.borrow()[0] {
+            Event::TcpOpen(ref c) => {
+                assert_eq!(c.source.remote, remote);
+                assert_eq!(c.destination, dst);
+            }
+            ref ev => panic!("unexpected event: {:?}", ev),
+        }
+
+        client.write_all(b"ping!").expect("write");
+        let mut buf = [0u8; 5];
+        io.read_exact(&mut buf).expect("read");
+        io.write_all(b"pong").expect("write");
+        drop(io);
+
+        let events = events.0.borrow();
+        assert_eq!(events.len(), 2);
+        match events[1] {
+            Event::TcpClose(ref c, ref close) => {
+                assert_eq!(c.destination, dst);
+                assert_eq!(close.bytes_received, 5);
+                assert_eq!(close.bytes_sent, 4);
+                assert!(close.open_at <= close.close_at);
+            }
+            ref ev => panic!("unexpected event: {:?}", ev),
+        }
+    }
+}
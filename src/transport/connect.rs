@@ -1,25 +1,33 @@
 extern crate tokio_connect;
 
 pub use self::tokio_connect::Connect;
+pub use transport::connection::SocketOptions;
 
 use std::net::SocketAddr;
 use std::{hash, io};
 
 use never::Never;
 use svc;
-use transport::{connection, tls};
+use transport::{connection, proxy_protocol, tls};
 
 #[derive(Debug, Clone)]
-pub struct Stack {}
+pub struct Stack {
+    options: SocketOptions,
+    proxy_protocol: Option<proxy_protocol::Version>,
+}
 
 /// A TCP connection target, optionally with TLS.
 ///
-/// Comparison operations ignore the TLS ClientConfig and only account for the
-/// TLS status.
+/// Comparison operations ignore the TLS ClientConfig, socket options, and
+/// PROXY protocol configuration and only account for the TLS status.
 #[derive(Clone, Debug)]
 pub struct Target {
     pub addr: SocketAddr,
     pub tls: tls::ConditionalConnectionConfig<tls::ClientConfig>,
+    /// The address of the client on whose behalf the connection is made.
+    source: Option<SocketAddr>,
+    options: SocketOptions,
+    proxy_protocol: Option<proxy_protocol::Version>,
     _p: (),
 }
 
@@ -27,7 +35,37 @@ pub struct Target {
 
 impl Target {
     pub fn new(addr: SocketAddr, tls: tls::ConditionalConnectionConfig<tls::ClientConfig>) -> Self {
-        Self { addr, tls, _p: () }
+        Self {
+            addr,
+            tls,
+            source: None,
+            options: SocketOptions::default(),
+            proxy_protocol: None,
+            _p: (),
+        }
+    }
+
+    /// Sets the options applied to the socket once it has connected.
+    pub fn with_socket_options(self, options: SocketOptions) -> Self {
+        Self { options, ..self }
+    }
+
+    /// Sets the address of the client on whose behalf the connection is
+    /// made, so that it may be described in a PROXY protocol header.
+    pub fn with_source(self, source: SocketAddr) -> Self {
+        Self {
+            source: Some(source),
+            ..self
+        }
+    }
+
+    /// Sets the PROXY protocol version with which the connection's source is
+    /// described, if it has one.
+    pub fn with_proxy_protocol(self, proxy_protocol: Option<proxy_protocol::Version>) -> Self {
+        Self {
+            proxy_protocol,
+            ..self
+        }
     }
 
     pub fn tls_status(&self) -> tls::Status {
@@ -41,7 +79,11 @@ impl Connect for Target {
     type Future = connection::Connecting;
 
     fn connect(&self) -> Self::Future {
-        connection::connect(&self.addr, self.tls.clone())
+        let proxy_header = match (self.proxy_protocol, self.source) {
+            (Some(version), Some(src)) => Some(proxy_protocol::header(version, src, self.addr)),
+            _ => None,
+        };
+        connection::connect(&self.addr, self.tls.clone(), self.options, proxy_header)
     }
 }
 
@@ -64,7 +106,25 @@ impl Eq for Target {}
 
 impl Stack {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            options: SocketOptions::default(),
+            proxy_protocol: None,
+        }
+    }
+
+    /// Sets the options applied to each target's socket once it has
+    /// connected.
+    pub fn with_socket_options(self, options: SocketOptions) -> Self {
+        Self { options, ..self }
+    }
+
+    /// Configures targets that have a source address to send a PROXY
+    /// protocol header with the given version.
+    pub fn with_proxy_protocol(self, proxy_protocol: Option<proxy_protocol::Version>) -> Self {
+        Self {
+            proxy_protocol,
+            ..self
+        }
     }
 }
 
@@ -77,6 +137,9 @@ where
     type Error = Never;
 
     fn make(&self, t: &T) -> Result<Self::Value, Self::Error> {
-        Ok(t.clone().into())
+        let target: Target = t.clone().into();
+        Ok(target
+            .with_socket_options(self.options)
+            .with_proxy_protocol(self.proxy_protocol))
     }
 }
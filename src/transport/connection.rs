@@ -1,19 +1,20 @@
 /// Tokio-level (not Tower-level) proxy-specific networking.
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use futures::{Async, Future, IntoFuture, Poll, Stream, future::{self, Either}, stream};
 use std;
 use std::cmp;
 use std::io;
 use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{self as io_util, AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream, ConnectFuture},
     reactor::Handle,
 };
 
 use Conditional;
-use transport::{AddrInfo, BoxedIo, GetOriginalDst, tls};
+use transport::{AddrInfo, BoxedIo, GetOriginalDst, proxy_protocol, tls};
 
 pub struct BoundPort {
     inner: std::net::TcpListener,
@@ -21,16 +22,33 @@ pub struct BoundPort {
     tls: tls::ConditionalConnectionConfig<tls::ServerConfigWatch>,
 }
 
+/// Options applied to a client socket once it has connected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Whether `TCP_NODELAY` is set.
+    pub nodelay: bool,
+    /// When set, `SO_KEEPALIVE` is enabled with the given idle time.
+    pub keepalive: Option<Duration>,
+}
+
 /// Initiates a client connection to the given address.
-pub(super) fn connect(addr: &SocketAddr, tls: tls::ConditionalConnectionConfig<tls::ClientConfig>)
-    -> Connecting
-{
+///
+/// If a `proxy_header` is provided, it is written before any other data,
+/// including the TLS handshake.
+pub(super) fn connect(
+    addr: &SocketAddr,
+    tls: tls::ConditionalConnectionConfig<tls::ClientConfig>,
+    options: SocketOptions,
+    proxy_header: Option<Bytes>,
+) -> Connecting {
     let state = ConnectingState::Plaintext {
         connect: TcpStream::connect(addr),
         tls: Some(tls),
     };
     Connecting {
         addr: *addr,
+        options,
+        proxy_header,
         state,
     }
 }
@@ -50,6 +68,8 @@ struct ConditionallyUpgradeServerToTlsInner {
 /// A socket that is in the process of connecting.
 pub struct Connecting {
     addr: SocketAddr,
+    options: SocketOptions,
+    proxy_header: Option<Bytes>,
     state: ConnectingState,
 }
 
@@ -58,12 +78,18 @@ enum ConnectingState {
         connect: ConnectFuture,
         tls: Option<tls::ConditionalConnectionConfig<tls::ClientConfig>>
     },
+    /// Writes the PROXY protocol header before the TLS handshake.
+    WriteProxyHeader {
+        write: io_util::WriteAll<TcpStream, Bytes>,
+        tls: Option<tls::ConnectionConfig<tls::ClientConfig>>,
+    },
     UpgradeToTls(tls::UpgradeClientToTls),
 }
 
 /// Abstracts a plaintext socket vs. a TLS decorated one.
 ///
-/// A `Connection` has the `TCP_NODELAY` option set automatically. Also
+/// An accepted `Connection` has the `TCP_NODELAY` option set automatically,
+/// while a client `Connection` is configured by its `SocketOptions`. Also
 /// it strictly controls access to information about the underlying
 /// socket to reduce the chance of TLS protections being accidentally
 /// subverted.
@@ -80,6 +106,10 @@ pub struct Connection {
 
     /// Whether or not the connection is secured with TLS.
     tls_status: tls::Status,
+
+    /// The server name requested by the client of a terminated TLS
+    /// connection.
+    tls_server_name: Option<tls::Identity>,
 }
 
 /// A trait describing that a type can peek bytes.
@@ -89,6 +119,13 @@ pub trait Peek {
     /// Returns number of bytes that have been peeked.
     fn poll_peek(&mut self) -> Poll<usize, io::Error>;
 
+    /// An async attempt to peek more bytes, in addition to those that have
+    /// already been peeked.
+    ///
+    /// Returns the number of additional bytes that have been peeked, which is
+    /// 0 once the underlying stream has ended.
+    fn poll_peek_more(&mut self) -> Poll<usize, io::Error>;
+
     /// Returns a reference to the bytes that have been peeked.
     // Instead of passing a buffer into `peek()`, the bytes are kept in
     // a buffer owned by the `Peek` type. This allows looking at the
@@ -274,7 +311,9 @@ impl Future for ConditionallyUpgradeServerToTls {
                 },
                 ConditionallyUpgradeServerToTls::UpgradeToTls(upgrading) => {
                     let tls_stream = try_ready!(upgrading.poll());
-                    return Ok(Async::Ready(Connection::tls(BoxedIo::new(tls_stream))));
+                    let server_name = tls_stream.server_name();
+                    let conn = Connection::tls(BoxedIo::new(tls_stream), server_name);
+                    return Ok(Async::Ready(conn));
                 }
             }
         }
@@ -313,6 +352,17 @@ impl ConditionallyUpgradeServerToTlsInner {
     }
 }
 
+// ===== impl SocketOptions =====
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
 // ===== impl Connecting =====
 
 impl Future for Connecting {
@@ -321,6 +371,7 @@ impl Future for Connecting {
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let addr = &self.addr;
+        let options = self.options;
         loop {
             self.state = match &mut self.state {
                 ConnectingState::Plaintext { connect, tls } => {
@@ -333,25 +384,50 @@ impl Future for Connecting {
                         io::Error::new(e.kind(), details)
                     }));
                     trace!("Connecting: state=plaintext; tls={:?};",tls);
-                    set_nodelay_or_warn(&plaintext_stream);
+                    set_socket_options_or_warn(&plaintext_stream, options);
                     match tls.take().expect("Polled after ready") {
-                        Conditional::Some(config) => {
-                            trace!("plaintext connection established; trying to upgrade");
-                            let upgrade = tls::Connection::connect(
-                                plaintext_stream, &config.server_identity, config.config);
-                            ConnectingState::UpgradeToTls(upgrade)
+                        Conditional::Some(config) => match self.proxy_header.clone() {
+                            // The header is cloned so that it may be sent
+                            // again if the handshake fails and the connection
+                            // falls back to plaintext.
+                            Some(header) => {
+                                trace!("plaintext connection established; writing PROXY header");
+                                ConnectingState::WriteProxyHeader {
+                                    write: io_util::write_all(plaintext_stream, header),
+                                    tls: Some(config),
+                                }
+                            },
+                            None => {
+                                trace!("plaintext connection established; trying to upgrade");
+                                let upgrade = tls::Connection::connect(
+                                    plaintext_stream, &config.server_identity, config.config);
+                                ConnectingState::UpgradeToTls(upgrade)
+                            },
                         },
                         Conditional::None(why) => {
                             trace!("plaintext connection established; no TLS ({:?})", why);
-                            return Ok(Async::Ready(Connection::plain(plaintext_stream, why)));
+                            let conn = match self.proxy_header.take() {
+                                Some(header) => Connection::plain_with_proxy_header(
+                                    plaintext_stream, header, why),
+                                None => Connection::plain(plaintext_stream, why),
+                            };
+                            return Ok(Async::Ready(conn));
                         },
                     }
                 },
+                ConnectingState::WriteProxyHeader { write, tls } => {
+                    let (plaintext_stream, _) = try_ready!(write.poll());
+                    let config = tls.take().expect("Polled after ready");
+                    trace!("PROXY header written; trying to upgrade");
+                    let upgrade = tls::Connection::connect(
+                        plaintext_stream, &config.server_identity, config.config);
+                    ConnectingState::UpgradeToTls(upgrade)
+                },
                 ConnectingState::UpgradeToTls(upgrade) => {
                     match upgrade.poll() {
                         Ok(Async::NotReady) => return Ok(Async::NotReady),
                         Ok(Async::Ready(tls_stream)) => {
-                            let conn = Connection::tls(BoxedIo::new(tls_stream));
+                            let conn = Connection::tls(BoxedIo::new(tls_stream), None);
                             return Ok(Async::Ready(conn));
                         },
                         Err(e) => {
@@ -390,14 +466,38 @@ impl Connection {
             io: BoxedIo::new(io),
             peek_buf,
             tls_status: Conditional::None(why_no_tls),
+            tls_server_name: None,
+        }
+    }
+
+    fn plain_with_proxy_header(io: TcpStream, header: Bytes, why_no_tls: tls::ReasonForNoTls)
+        -> Self
+    {
+        Connection {
+            io: BoxedIo::new(proxy_protocol::WithHeader::new(header, io)),
+            peek_buf: BytesMut::new(),
+            tls_status: Conditional::None(why_no_tls),
+            tls_server_name: None,
         }
     }
 
-    fn tls(io: BoxedIo) -> Self {
+    /// Wraps one end of an in-memory stream as a plaintext connection.
+    #[cfg(test)]
+    pub fn in_memory(io: super::memory::Duplex) -> Self {
+        Connection {
+            io: BoxedIo::new(io),
+            peek_buf: BytesMut::new(),
+            tls_status: Conditional::None(tls::ReasonForNoTls::Disabled),
+            tls_server_name: None,
+        }
+    }
+
+    fn tls(io: BoxedIo, tls_server_name: Option<tls::Identity>) -> Self {
         Connection {
             io: io,
             peek_buf: BytesMut::new(),
             tls_status: Conditional::Some(()),
+            tls_server_name,
         }
     }
 
@@ -412,6 +512,10 @@ impl Connection {
     pub fn tls_status(&self) -> tls::Status {
         self.tls_status
     }
+
+    pub fn tls_server_name(&self) -> Option<&tls::Identity> {
+        self.tls_server_name.as_ref()
+    }
 }
 
 impl io::Read for Connection {
@@ -484,6 +588,11 @@ impl Peek for Connection {
         }
     }
 
+    fn poll_peek_more(&mut self) -> Poll<usize, io::Error> {
+        self.peek_buf.reserve(8192);
+        self.io.read_buf(&mut self.peek_buf)
+    }
+
     fn peeked(&self) -> &[u8] {
         self.peek_buf.as_ref()
     }
@@ -520,3 +629,22 @@ fn set_nodelay_or_warn(socket: &TcpStream) {
         );
     }
 }
+
+pub(super) fn set_socket_options_or_warn(socket: &TcpStream, options: SocketOptions) {
+    if let Err(e) = socket.set_nodelay(options.nodelay) {
+        warn!(
+            "could not set TCP_NODELAY on {:?}/{:?}: {}",
+            socket.local_addr(),
+            socket.peer_addr(),
+            e
+        );
+    }
+    if let Err(e) = socket.set_keepalive(options.keepalive) {
+        warn!(
+            "could not set SO_KEEPALIVE on {:?}/{:?}: {}",
+            socket.local_addr(),
+            socket.peer_addr(),
+            e
+        );
+    }
+}
@@ -4,10 +4,15 @@
 // by these tests.
 
 use std::{
-    net::SocketAddr,
+    net::{self, SocketAddr},
     sync::mpsc,
+    thread,
+    time::Duration,
 };
 
+use futures::future::{self, Loop};
+use h2;
+use http;
 use tokio::{
     self,
     io,
@@ -15,9 +20,11 @@ use tokio::{
 };
 
 use Conditional;
+use proxy::h2c;
 
 use super::{
-    connection::{self, Connection},
+    connection::{self, Connection, Peek},
+    proxy_protocol,
     tls,
 };
 
@@ -68,6 +75,119 @@ fn proxy_to_proxy_tls_pass_through_when_identity_does_not_match() {
     assert_eq!(&server_result.result.unwrap()[..], START_OF_TLS);
 }
 
+#[test]
+fn h2c_upgrade_is_served_as_http2() {
+    let (client_result, server_result) = run_test(
+        Conditional::None(tls::ReasonForNoTls::Disabled),
+        |conn| upgrade_then_read_headers(conn),
+        Conditional::None(tls::ReasonForNoTls::Disabled),
+        |conn| serve_h2c_upgrade(conn));
+
+    // The upgrade request was received by the HTTP/2 server...
+    assert_eq!(server_result.result.unwrap(), "/h2c");
+
+    // ...and its response was sent on stream 1 rather than as HTTP/1.
+    let (head, stream_id) = client_result.result.unwrap();
+    assert!(head.starts_with(b"HTTP/1.1 101 "), "not switching protocols: {:?}", head);
+    assert_eq!(stream_id, 1);
+}
+
+#[test]
+fn socket_options_are_applied_to_connected_sockets() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+    let keepalive = Duration::from_secs(60);
+    let options = connection::SocketOptions {
+        nodelay: true,
+        keepalive: Some(keepalive),
+    };
+    let conn = rt.block_on(connection::connect(
+        &addr,
+        Conditional::None(tls::ReasonForNoTls::Disabled),
+        options,
+        None,
+    ));
+    assert!(conn.is_ok());
+
+    let socket = rt.block_on(tokio::net::TcpStream::connect(&addr)).unwrap();
+    connection::set_socket_options_or_warn(&socket, options);
+    assert_eq!(socket.nodelay().unwrap(), true);
+    assert_eq!(socket.keepalive().unwrap(), Some(keepalive));
+
+    let options = connection::SocketOptions {
+        nodelay: false,
+        keepalive: None,
+    };
+    connection::set_socket_options_or_warn(&socket, options);
+    assert_eq!(socket.nodelay().unwrap(), false);
+    assert_eq!(socket.keepalive().unwrap(), None);
+}
+
+#[test]
+fn proxy_protocol_v1_header_is_sent_first() {
+    let src = "10.1.2.3:4567".parse::<SocketAddr>().unwrap();
+    let (dst, received) = connect_with_proxy_header(proxy_protocol::Version::V1, src);
+    let expected = format!("PROXY TCP4 10.1.2.3 127.0.0.1 4567 {}\r\n", dst.port());
+    assert_eq!(received, [expected.as_bytes(), PING].concat());
+}
+
+#[test]
+fn proxy_protocol_v2_header_is_sent_first() {
+    let src = "10.1.2.3:4567".parse::<SocketAddr>().unwrap();
+    let (dst, received) = connect_with_proxy_header(proxy_protocol::Version::V2, src);
+    let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    // Version 2 PROXY command, TCP over IPv4, 12 bytes of addresses.
+    expected.extend_from_slice(&[0x21, 0x11, 0, 12]);
+    expected.extend_from_slice(&[10, 1, 2, 3, 127, 0, 0, 1]);
+    expected.extend_from_slice(&[0x11, 0xd7, (dst.port() >> 8) as u8, dst.port() as u8]);
+    assert_eq!(received, [&expected[..], PING].concat());
+}
+
+#[test]
+fn proxy_protocol_header_is_sent_before_tls_handshake() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dst = listener.local_addr().unwrap();
+    let src = "10.1.2.3:4567".parse::<SocketAddr>().unwrap();
+    let header = proxy_protocol::header(proxy_protocol::Version::V1, src, dst);
+
+    // Reads the header and the first byte of the TLS handshake, then closes
+    // the connection so that the handshake fails.
+    let len = header.len() + 1;
+    let server = thread::spawn(move || {
+        let (mut accepted, _) = listener.accept().unwrap();
+        let mut received = vec![0; len];
+        ::std::io::Read::read_exact(&mut accepted, &mut received).unwrap();
+        received
+    });
+
+    let server_tls = tls::config_test_util::FOO_NS1.server();
+    let client_tls = tls::config_test_util::BAR_NS1.client(server_tls.server_identity.clone());
+    let config = (*client_tls.config.borrow()).clone().expect("client config");
+    let tls = Conditional::Some(tls::ConnectionConfig {
+        server_identity: client_tls.server_identity.clone(),
+        config,
+    });
+
+    let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let conn = rt.block_on(connection::connect(
+        &dst,
+        tls,
+        connection::SocketOptions::default(),
+        Some(header.clone()),
+    )).unwrap();
+    assert_eq!(
+        conn.tls_status(),
+        Conditional::None(tls::ReasonForNoTls::HandshakeFailed),
+    );
+
+    let received = server.join().unwrap();
+    assert_eq!(&received[..header.len()], &header[..]);
+    // A TLS handshake record follows the header.
+    assert_eq!(received[header.len()], 0x16);
+}
+
 struct Transported<R> {
     /// The value of `Connection::tls_status()` for the established connection.
     ///
@@ -157,7 +277,7 @@ fn run_test<C, CF, CR, S, SF, SR>(
         let (sender, receiver) = mpsc::channel::<Transported<CR>>();
         let sender_clone = sender.clone();
 
-        let client = connection::connect(&server_addr, tls)
+        let client = connection::connect(&server_addr, tls, connection::SocketOptions::default(), None)
             .map_err(move |e| {
                 sender_clone.send(Transported { tls_status: None, result: Err(e) }).unwrap();
                 ()
@@ -190,6 +310,32 @@ fn run_test<C, CF, CR, S, SF, SR>(
     (client_result, server_result)
 }
 
+/// Connects to a loopback listener with a PROXY protocol header describing a
+/// connection from `src`, then writes `PING`, returning the listener's
+/// address and all of the bytes it received.
+fn connect_with_proxy_header(version: proxy_protocol::Version, src: SocketAddr)
+    -> (SocketAddr, Vec<u8>)
+{
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dst = listener.local_addr().unwrap();
+    let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+    let header = proxy_protocol::header(version, src, dst);
+    let conn = rt.block_on(connection::connect(
+        &dst,
+        Conditional::None(tls::ReasonForNoTls::Disabled),
+        connection::SocketOptions::default(),
+        Some(header),
+    )).unwrap();
+    let conn = rt.block_on(io::write_all(conn, PING)).unwrap().0;
+    drop(conn);
+
+    let (mut accepted, _) = listener.accept().unwrap();
+    let mut received = Vec::new();
+    ::std::io::Read::read_to_end(&mut accepted, &mut received).unwrap();
+    (dst, received)
+}
+
 /// Writes `to_write` and shuts down the write side, then reads until EOF,
 /// returning the bytes read.
 fn write_then_read(conn: Connection, to_write: &'static [u8])
@@ -224,6 +370,98 @@ fn write_and_shutdown(conn: connection::Connection, to_write: &'static [u8])
         })
 }
 
+/// Upgrades the connection to h2c, then reads HTTP/2 frames until a HEADERS
+/// frame is received, returning the HTTP/1 response head and the HEADERS
+/// frame's stream ID.
+fn upgrade_then_read_headers(conn: Connection)
+    -> impl Future<Item=(Vec<u8>, u32), Error=io::Error>
+{
+    io::write_all(conn, H2C_UPGRADE)
+        .and_then(|(conn, _)| {
+            future::loop_fn((conn, Vec::new()), |(conn, mut head)| {
+                io::read_exact(conn, [0u8; 1]).map(|(conn, b)| {
+                    head.push(b[0]);
+                    if head.ends_with(b"\r\n\r\n") {
+                        Loop::Break((conn, head))
+                    } else {
+                        Loop::Continue((conn, head))
+                    }
+                })
+            })
+        })
+        .and_then(|(conn, head)| {
+            io::write_all(conn, H2_PREFACE_AND_SETTINGS).map(|(conn, _)| (conn, head))
+        })
+        .and_then(|(conn, head)| {
+            future::loop_fn(conn, |conn| {
+                io::read_exact(conn, [0u8; 9]).and_then(|(conn, frame)| {
+                    let len = (usize::from(frame[0]) << 16)
+                        | (usize::from(frame[1]) << 8)
+                        | usize::from(frame[2]);
+                    let kind = frame[3];
+                    let stream_id = ((u32::from(frame[5]) << 24)
+                        | (u32::from(frame[6]) << 16)
+                        | (u32::from(frame[7]) << 8)
+                        | u32::from(frame[8])) & 0x7fff_ffff;
+                    io::read_exact(conn, vec![0; len]).map(move |(conn, _)| {
+                        if kind == 0x1 {
+                            Loop::Break(stream_id)
+                        } else {
+                            Loop::Continue(conn)
+                        }
+                    })
+                })
+            })
+            .map(|stream_id| (head, stream_id))
+        })
+}
+
+/// Accepts an h2c upgrade and serves the connection as HTTP/2, responding to
+/// the first request and returning its path.
+fn serve_h2c_upgrade(conn: Connection)
+    -> impl Future<Item=String, Error=io::Error>
+{
+    fn h2_error(e: h2::Error) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+
+    conn.peek()
+        .and_then(|conn| {
+            h2c::Upgrade::detect(conn.peeked())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an h2c upgrade"))
+                .map(|upgrade| upgrade.accept(conn))
+        })
+        .flatten()
+        .and_then(|io| h2::server::handshake(io).map_err(h2_error))
+        .and_then(|h2| h2.into_future().map_err(|(e, _)| h2_error(e)))
+        .and_then(|(req, h2)| -> io::Result<_> {
+            let (req, mut respond) = req.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "no request")
+            })?;
+            let rsp = http::Response::builder().status(204).body(()).unwrap();
+            respond.send_response(rsp, true).map_err(h2_error)?;
+            Ok((req.uri().path().to_owned(), h2))
+        })
+        .and_then(|(path, h2)| {
+            // Drive the connection so that the response is written, until
+            // the client disconnects.
+            h2.for_each(|_| Ok(())).then(move |_| Ok(path))
+        })
+}
+
+const H2C_UPGRADE: &[u8] = b"\
+    GET /h2c HTTP/1.1\r\n\
+    Host: h2c.test\r\n\
+    Connection: Upgrade, HTTP2-Settings\r\n\
+    Upgrade: h2c\r\n\
+    HTTP2-Settings: AAMAAABkAAQAAP__\r\n\
+    \r\n";
+
+/// The HTTP/2 connection preface, followed by an empty SETTINGS frame.
+const H2_PREFACE_AND_SETTINGS: &[u8] = b"\
+    PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\
+    \x00\x00\x00\x04\x00\x00\x00\x00\x00";
+
 const PING: &[u8] = b"ping";
 const PONG: &[u8] = b"pong";
 const START_OF_TLS: &[u8] = &[22, 3, 1]; // ContentType::handshake version 3.1
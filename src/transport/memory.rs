@@ -0,0 +1,214 @@
+//! An in-memory transport, so that the proxy's server and client stacks may
+//! be driven in tests without binding sockets.
+
+use bytes::{Buf, BytesMut};
+use futures::{future, sync::mpsc, task, Poll, Stream};
+use std::cmp;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use never::Never;
+use svc;
+use transport::{connect, io::internal::Io, AddrInfo, Connection};
+
+/// One end of an in-memory, bidirectional byte stream.
+#[derive(Debug)]
+pub struct Duplex {
+    local: SocketAddr,
+    rx: Arc<Mutex<Pipe>>,
+    tx: Arc<Mutex<Pipe>>,
+}
+
+/// Connects to a `Listener`, regardless of the target address.
+#[derive(Clone, Debug)]
+pub struct Connect {
+    addr: SocketAddr,
+    accept_tx: mpsc::UnboundedSender<Connection>,
+}
+
+/// Yields the server end of each connection made through a `Connect`.
+#[derive(Debug)]
+pub struct Listener {
+    accept_rx: mpsc::UnboundedReceiver<Connection>,
+}
+
+/// Reports the same original destination address for every connection.
+#[derive(Copy, Clone, Debug)]
+pub struct OrigDst(SocketAddr);
+
+/// Bytes written to one end of a `Duplex` that have not yet been read from
+/// the other.
+#[derive(Debug, Default)]
+struct Pipe {
+    buf: BytesMut,
+    /// Set when either end of the pipe is closed.
+    closed: bool,
+    /// The task waiting to read from the pipe.
+    reader: Option<task::Task>,
+}
+
+/// Creates a pair of connected streams with the given local addresses.
+pub fn duplex(a: SocketAddr, b: SocketAddr) -> (Duplex, Duplex) {
+    let ab = Arc::new(Mutex::new(Pipe::default()));
+    let ba = Arc::new(Mutex::new(Pipe::default()));
+    let a = Duplex {
+        local: a,
+        rx: ba.clone(),
+        tx: ab.clone(),
+    };
+    let b = Duplex {
+        local: b,
+        rx: ab,
+        tx: ba,
+    };
+    (a, b)
+}
+
+/// Creates a `Connect` whose connections are accepted by a `Listener` on
+/// `addr`.
+pub fn listen(addr: SocketAddr) -> (Connect, Listener) {
+    let (accept_tx, accept_rx) = mpsc::unbounded();
+    (Connect { addr, accept_tx }, Listener { accept_rx })
+}
+
+// === impl Duplex ===
+
+impl Duplex {
+    fn close_tx(&self) {
+        let mut tx = self.tx.lock().expect("pipe lock poisoned");
+        tx.closed = true;
+        if let Some(reader) = tx.reader.take() {
+            reader.notify();
+        }
+    }
+}
+
+impl io::Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut rx = self.rx.lock().expect("pipe lock poisoned");
+        if rx.buf.is_empty() {
+            if rx.closed {
+                return Ok(0);
+            }
+            rx.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let len = cmp::min(buf.len(), rx.buf.len());
+        buf[..len].copy_from_slice(&rx.buf[..len]);
+        rx.buf.advance(len);
+        Ok(len)
+    }
+}
+
+impl AsyncRead for Duplex {}
+
+impl io::Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut tx = self.tx.lock().expect("pipe lock poisoned");
+        if tx.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        tx.buf.extend_from_slice(buf);
+        if let Some(reader) = tx.reader.take() {
+            reader.notify();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for Duplex {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.close_tx();
+        Ok(().into())
+    }
+}
+
+impl AddrInfo for Duplex {
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        Ok(self.local)
+    }
+
+    fn get_original_dst(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl Io for Duplex {
+    fn shutdown_write(&mut self) -> Result<(), io::Error> {
+        self.close_tx();
+        Ok(())
+    }
+
+    fn write_buf_erased(&mut self, mut buf: &mut Buf) -> Poll<usize, io::Error> {
+        self.write_buf(&mut buf)
+    }
+}
+
+impl Drop for Duplex {
+    fn drop(&mut self) {
+        self.close_tx();
+        if let Ok(mut rx) = self.rx.lock() {
+            rx.closed = true;
+        }
+    }
+}
+
+// === impl Connect ===
+
+impl connect::Connect for Connect {
+    type Connected = Connection;
+    type Error = io::Error;
+    type Future = future::FutureResult<Connection, io::Error>;
+
+    fn connect(&self) -> Self::Future {
+        let local = SocketAddr::from(([127, 0, 0, 1], 0));
+        let (client, server) = duplex(local, self.addr);
+        if self.accept_tx.unbounded_send(Connection::in_memory(server)).is_err() {
+            return future::err(io::ErrorKind::ConnectionRefused.into());
+        }
+        future::ok(Connection::in_memory(client))
+    }
+}
+
+impl svc::Stack<connect::Target> for Connect {
+    type Value = Self;
+    type Error = Never;
+
+    fn make(&self, target: &connect::Target) -> Result<Self, Never> {
+        trace!("connecting to {} in memory", target.addr);
+        Ok(self.clone())
+    }
+}
+
+// === impl Listener ===
+
+impl Stream for Listener {
+    type Item = Connection;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Connection>, ()> {
+        self.accept_rx.poll()
+    }
+}
+
+// === impl OrigDst ===
+
+impl OrigDst {
+    pub fn new(addr: SocketAddr) -> Self {
+        OrigDst(addr)
+    }
+}
+
+impl super::GetOriginalDst for OrigDst {
+    fn get_original_dst(&self, _: &AddrInfo) -> Option<SocketAddr> {
+        Some(self.0)
+    }
+}
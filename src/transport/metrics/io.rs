@@ -105,6 +105,10 @@ impl<T: AsyncRead + AsyncWrite + Peek> Peek for Io<T> {
         self.sense_err(|io| io.poll_peek())
     }
 
+    fn poll_peek_more(&mut self) -> Poll<usize, io::Error> {
+        self.sense_err(|io| io.poll_peek_more())
+    }
+
     fn peeked(&self) -> &[u8] {
         self.io.peeked()
     }
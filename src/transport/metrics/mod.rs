@@ -576,3 +576,36 @@ impl FmtLabels for Eos {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Conditional;
+
+    #[test]
+    fn connections_are_labeled_by_direction_and_tls_status() {
+        let (registry, report) = new();
+        let open = |key| {
+            let metrics = registry.0.lock().unwrap().get_or_default(key).clone();
+            Sensor::open(Some(metrics))
+        };
+
+        let tls = Conditional::Some(());
+        let no_tls = Conditional::None(tls::ReasonForNoTls::Disabled);
+        let in_tls = open(Key::accept(Direction("inbound"), tls));
+        let _in_tls = open(Key::accept(Direction("inbound"), tls));
+        let _out_plain = open(Key::connect(Direction("outbound"), no_tls));
+        drop(in_tls);
+
+        let rendered = format!("{}", report.as_display());
+        for line in &[
+            "tcp_open_total{direction=\"inbound\",peer=\"src\",tls=\"true\"} 2",
+            "tcp_open_connections{direction=\"inbound\",peer=\"src\",tls=\"true\"} 1",
+            "tcp_close_total{direction=\"inbound\",peer=\"src\",tls=\"true\",errno=\"\"} 1",
+            "tcp_open_total{direction=\"outbound\",peer=\"dst\",tls=\"disabled\"} 1",
+            "tcp_open_connections{direction=\"outbound\",peer=\"dst\",tls=\"disabled\"} 1",
+        ] {
+            assert!(rendered.contains(line), "missing {:?} in:\n{}", line, rendered);
+        }
+    }
+}
@@ -4,10 +4,13 @@ mod addr_info;
 mod io;
 pub mod metrics;
 mod prefixed;
+pub mod proxy_protocol;
 pub mod tls;
 
 #[cfg(test)]
 mod connection_tests;
+#[cfg(test)]
+pub mod memory;
 
 pub use self::{
     addr_info::{
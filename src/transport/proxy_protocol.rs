@@ -0,0 +1,173 @@
+//! Emits PROXY protocol headers on client connections.
+//!
+//! Load balancers and servers that accept the PROXY protocol learn a
+//! connection's original client address from a header sent before any other
+//! data. See https://www.haproxy.org/download/1.8/doc/proxy-protocol.txt.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{io, net::{IpAddr, SocketAddr}};
+use tokio::prelude::*;
+
+use super::io::internal::Io;
+use transport::AddrInfo;
+
+/// The signature that begins every version 2 header.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The version 2 protocol version and the `PROXY` command.
+const V2_PROXY: u8 = 0x21;
+
+/// The version 2 address families (TCP over IPv4 or IPv6).
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// The PROXY protocol version with which headers are encoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Version {
+    /// The human-readable text format.
+    V1,
+    /// The binary format.
+    V2,
+}
+
+/// An `Io` that writes a PROXY protocol header before any other data.
+#[derive(Debug)]
+pub struct WithHeader<S> {
+    header: Bytes,
+    io: S,
+}
+
+/// Encodes a header describing a connection from `src` to `dst`.
+///
+/// When the addresses' families differ, both are described as IPv6
+/// addresses.
+pub fn header(version: Version, src: SocketAddr, dst: SocketAddr) -> Bytes {
+    let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V6(d)) => (IpAddr::V6(s.to_ipv6_mapped()), IpAddr::V6(d)),
+        (IpAddr::V6(s), IpAddr::V4(d)) => (IpAddr::V6(s), IpAddr::V6(d.to_ipv6_mapped())),
+        (s, d) => (s, d),
+    };
+
+    match version {
+        Version::V1 => {
+            let family = if src_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+            let line = format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                src_ip,
+                dst_ip,
+                src.port(),
+                dst.port(),
+            );
+            Bytes::from(line)
+        }
+        Version::V2 => {
+            let mut buf = BytesMut::with_capacity(V2_SIGNATURE.len() + 4 + 36);
+            buf.put_slice(V2_SIGNATURE);
+            buf.put_u8(V2_PROXY);
+            match (src_ip, dst_ip) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    buf.put_u8(V2_TCP4);
+                    buf.put_u16_be(12);
+                    buf.put_slice(&s.octets());
+                    buf.put_slice(&d.octets());
+                }
+                (IpAddr::V6(s), IpAddr::V6(d)) => {
+                    buf.put_u8(V2_TCP6);
+                    buf.put_u16_be(36);
+                    buf.put_slice(&s.octets());
+                    buf.put_slice(&d.octets());
+                }
+                _ => unreachable!("address families must match"),
+            }
+            buf.put_u16_be(src.port());
+            buf.put_u16_be(dst.port());
+            buf.freeze()
+        }
+    }
+}
+
+// === impl WithHeader ===
+
+impl<S: Io> WithHeader<S> {
+    pub fn new(header: Bytes, io: S) -> Self {
+        Self { header, io }
+    }
+
+    /// Writes any of the header that has not yet been written.
+    fn write_header(&mut self) -> io::Result<()> {
+        while !self.header.is_empty() {
+            let n = self.io.write(&self.header)?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.header.advance(n);
+        }
+        Ok(())
+    }
+
+    fn poll_write_header(&mut self) -> Poll<(), io::Error> {
+        match self.write_header() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<S: Io> io::Read for WithHeader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl<S: Io> AsyncRead for WithHeader<S> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.io.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<S: Io> io::Write for WithHeader<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_header()?;
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.io.flush()
+    }
+}
+
+impl<S: Io> AsyncWrite for WithHeader<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_write_header());
+        self.io.shutdown()
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        try_ready!(self.poll_write_header());
+        self.io.write_buf(buf)
+    }
+}
+
+impl<S: Io> AddrInfo for WithHeader<S> {
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.io.local_addr()
+    }
+
+    fn get_original_dst(&self) -> Option<SocketAddr> {
+        self.io.get_original_dst()
+    }
+}
+
+impl<S: Io> Io for WithHeader<S> {
+    fn shutdown_write(&mut self) -> Result<(), io::Error> {
+        self.io.shutdown_write()
+    }
+
+    fn write_buf_erased(&mut self, buf: &mut Buf) -> Poll<usize, io::Error> {
+        try_ready!(self.poll_write_header());
+        self.io.write_buf_erased(buf)
+    }
+}
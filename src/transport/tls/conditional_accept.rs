@@ -7,6 +7,14 @@ pub enum Match {
     NotMatched,
 }
 
+/// The SNI server name of a ClientHello.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Sni {
+    Incomplete,
+    Found(Identity),
+    NotFound,
+}
+
 /// Determintes whether the given `input` looks like the start of a TLS
 /// connection that the proxy should terminate.
 ///
@@ -22,36 +30,48 @@ pub enum Match {
 /// record, which is what all reasonable implementations do. (If they were not
 /// to, they wouldn't interoperate with picky servers.)
 pub fn match_client_hello(input: &[u8], identity: &Identity) -> Match {
-    let r = untrusted::Input::from(input).read_all(untrusted::EndOfInput, |input| {
-        let r = extract_sni(input);
-        input.skip_to_end(); // Ignore anything after what we parsed.
-        r
-    });
-    match r {
-        Ok(Some(sni)) => {
-            let matches = if let Ok(sni) = Identity::from_sni_hostname(sni.as_slice_less_safe()) {
-                if sni == *identity {
-                    Match::Matched
-                } else {
-                    Match::NotMatched
-                }
+    match parse_sni(input) {
+        Sni::Found(sni) => {
+            let matches = if sni == *identity {
+                Match::Matched
             } else {
                 Match::NotMatched
             };
             trace!("match_client_hello: parsed correctly up to SNI; matches: {:?}", matches);
             matches
         },
-        Ok(None) => {
+        Sni::NotFound => {
             trace!("match_client_hello: failed to parse up to SNI");
             Match::NotMatched
         },
-        Err(untrusted::EndOfInput) => {
+        Sni::Incomplete => {
             trace!("match_client_hello: needs more input");
             Match::Incomplete
         },
     }
 }
 
+/// Parses the SNI server name from the given `input`, if it looks like (the
+/// start of) a valid ClientHello.
+///
+/// This makes the same assumptions about the ClientHello as
+/// `match_client_hello`.
+pub fn parse_sni(input: &[u8]) -> Sni {
+    let r = untrusted::Input::from(input).read_all(untrusted::EndOfInput, |input| {
+        let r = extract_sni(input);
+        input.skip_to_end(); // Ignore anything after what we parsed.
+        r
+    });
+    match r {
+        Ok(Some(sni)) => match Identity::from_sni_hostname(sni.as_slice_less_safe()) {
+            Ok(sni) => Sni::Found(sni),
+            Err(()) => Sni::NotFound,
+        },
+        Ok(None) => Sni::NotFound,
+        Err(untrusted::EndOfInput) => Sni::Incomplete,
+    }
+}
+
 /// The result is `Ok(Some(hostname))` if the SNI extension was found, `Ok(None)`
 /// if we affirmatively rejected the input before we found the SNI extension, or
 /// `Err(EndOfInput)` if we don't have enough input to continue.
@@ -221,6 +241,14 @@ mod tests {
                            b"GET /TheProject.html HTTP/1.0\r\n\r\n");
     }
 
+    #[test]
+    fn parses_sni() {
+        let example_com = tls::Identity::from_sni_hostname(b"example.com").unwrap();
+        assert_eq!(parse_sni(VALID_EXAMPLE_COM), Sni::Found(example_com));
+        assert_eq!(parse_sni(&VALID_EXAMPLE_COM[..16]), Sni::Incomplete);
+        assert_eq!(parse_sni(b"GET /TheProject.html HTTP/1.0\r\n\r\n"), Sni::NotFound);
+    }
+
     fn check_all_prefixes(expected_match: Match, identity: &str, input: &[u8]) {
         assert!(expected_match == Match::Matched || expected_match == Match::NotMatched);
 
@@ -68,6 +68,14 @@ impl Connection<Prefixed<TcpStream>, rustls::ServerSession> {
     {
         UpgradeToTls(config.accept_async(Prefixed::new(prefix, socket)))
     }
+
+    /// The server name that the client requested via SNI, if any.
+    pub fn server_name(&self) -> Option<Identity> {
+        let (_, session) = self.0.get_ref();
+        session
+            .get_sni_hostname()
+            .and_then(|name| Identity::from_sni_hostname(name.as_bytes()).ok())
+    }
 }
 
 impl<S, C> io::Read for Connection<S, C>
@@ -1,10 +1,11 @@
 use api;
 use convert::TryFrom;
 use super::{DnsName, InvalidDnsName, webpki};
+use std::fmt;
 use std::sync::Arc;
 
 /// An endpoint's identity.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Identity(pub(super) Arc<DnsName>);
 
 impl Identity {
@@ -51,3 +52,9 @@ impl Identity {
         (self.0).0.as_ref()
     }
 }
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
@@ -82,6 +82,25 @@ fn h2_exercise_goaways_connections() {
     client.wait_for_closed();
 }
 
+#[test]
+fn h2_goaways_idle_connections() {
+    let _ = env_logger_init();
+
+    let srv = server::http2().route("/", "hello").run();
+    let mut env = app::config::TestEnv::new();
+    env.put(app::config::ENV_H2_IDLE_TIMEOUT, "100ms".to_owned());
+    let proxy = proxy::new()
+        .inbound(srv)
+        .run_with_test_env(env);
+    let client = client::http2(proxy.inbound, "idle.test.svc.cluster.local");
+
+    assert_eq!(client.get("/"), "hello");
+
+    // Once the connection has no open streams for the idle timeout, the
+    // proxy sends a GOAWAY and closes it.
+    client.wait_for_closed();
+}
+
 #[test]
 fn http1_closes_idle_connections() {
     use std::cell::RefCell;
@@ -1040,6 +1040,112 @@ fn http1_requests_without_host_have_unique_connections() {
     assert_eq!(inbound.connections(), 4);
 }
 
+#[test]
+fn http1_requests_wait_for_global_capacity() {
+    let _ = env_logger_init();
+
+    // Responses are delayed so that the requests are in flight concurrently.
+    let srv = server::http1()
+        .route_async("/", |_| {
+            let (tx, rx) = oneshot::channel();
+            ::std::thread::spawn(move || {
+                ::std::thread::sleep(Duration::from_millis(100));
+                let _ = tx.send(Response::new(Bytes::from("limited")));
+            });
+            rx.map_err(|_| ())
+        })
+        .run();
+    let mut env = app::config::TestEnv::new();
+    env.put(app::config::ENV_GLOBAL_MAX_IN_FLIGHT, "1".to_owned());
+    let proxy = proxy::new().inbound(srv).run_with_test_env(env);
+
+    let client = client::http1(proxy.inbound, "transparency.test.svc.cluster.local");
+
+    // hyper's server never polls the proxy's service for readiness, so these
+    // requests must be queued for capacity rather than being dispatched.
+    let rsps = (0..3)
+        .map(|_| client.request_async(&mut client.request_builder("/")))
+        .collect::<Vec<_>>();
+    for rsp in future::join_all(rsps).wait().expect("responses") {
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+    }
+}
+
+#[test]
+fn http1_idle_connections_are_reused_up_to_max_idle() {
+    let _ = env_logger_init();
+
+    // Responses are delayed so that concurrent requests are dispatched on
+    // separate connections.
+    let srv = server::http1()
+        .route_async("/", |_| {
+            let (tx, rx) = oneshot::channel();
+            ::std::thread::spawn(move || {
+                ::std::thread::sleep(Duration::from_millis(300));
+                let _ = tx.send(Response::new(Bytes::from("pooled")));
+            });
+            rx.map_err(|_| ())
+        })
+        .run();
+    let mut env = app::config::TestEnv::new();
+    env.put(app::config::ENV_HTTP1_MAX_IDLE_CONNECTIONS, "2".to_owned());
+    let proxy = proxy::new().inbound(srv).run_with_test_env(env);
+
+    let client = client::http1(proxy.inbound, "transparency.test.svc.cluster.local");
+
+    let inbound = &proxy.inbound_server.as_ref()
+        .expect("no inbound server!");
+
+    let send_concurrently = |n: usize| {
+        let rsps = (0..n)
+            .map(|_| client.request_async(&mut client.request_builder("/")))
+            .collect::<Vec<_>>();
+        for rsp in future::join_all(rsps).wait().expect("responses") {
+            assert_eq!(rsp.status(), http::StatusCode::OK);
+        }
+        // Give the connections a moment to be returned to the pool.
+        ::std::thread::sleep(Duration::from_millis(100));
+    };
+
+    // Each concurrent request requires its own connection.
+    send_concurrently(3);
+    assert_eq!(inbound.connections(), 3);
+
+    // Only two of those connections were retained, so one more connection
+    // is needed.
+    send_concurrently(3);
+    assert_eq!(inbound.connections(), 4);
+
+    // Requests that fit within the pool reuse its connections.
+    send_concurrently(2);
+    assert_eq!(inbound.connections(), 4);
+}
+
+#[test]
+fn http1_idle_connections_are_closed_after_idle_timeout() {
+    let _ = env_logger_init();
+
+    let srv = server::http1().route("/", "hello").run();
+    let mut env = app::config::TestEnv::new();
+    env.put(app::config::ENV_HTTP1_IDLE_TIMEOUT, "100ms".to_owned());
+    let proxy = proxy::new().inbound(srv).run_with_test_env(env);
+
+    let client = client::http1(proxy.inbound, "transparency.test.svc.cluster.local");
+
+    let inbound = &proxy.inbound_server.as_ref()
+        .expect("no inbound server!");
+
+    assert_eq!(client.get("/"), "hello");
+    assert_eq!(client.get("/"), "hello");
+    assert_eq!(inbound.connections(), 1);
+
+    // Once the pooled connection has been idle for the timeout, it is
+    // closed and a new connection is required.
+    ::std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.get("/"), "hello");
+    assert_eq!(inbound.connections(), 2);
+}
+
 #[test]
 #[cfg_attr(not(feature = "flaky_tests"), ignore)]
 fn retry_reconnect_errors() {